@@ -0,0 +1,535 @@
+// ==========================
+// ckton_minter/src/block.rs
+// Decoding of raw TON blocks.
+// A block BOC is only trusted as the block whose id it matches: the hash of
+// its root cell is the root hash of the block and the sha256 of the file is
+// its file hash. The light client reads from such blocks the shard blocks a
+// masterchain block commits, the transactions of an account and the
+// validator set a key block elects.
+// ==========================
+
+use base64::prelude::*;
+use ic_ton_lib::cell::{ArcCell, BagOfCells, CellParser};
+use sha2::{Digest, Sha256};
+
+use crate::lightclient::Validator;
+
+// block#11ef55aa
+const BLOCK_TAG: u32 = 0x11ef55aa;
+// block_info#9bc7a987
+const BLOCK_INFO_TAG: u32 = 0x9bc7a987;
+// block_extra#4a33f6fd
+const BLOCK_EXTRA_TAG: u32 = 0x4a33f6fd;
+// masterchain_block_extra#cca5
+const MC_BLOCK_EXTRA_TAG: u32 = 0xcca5;
+// acc_trans#5
+const ACCOUNT_BLOCK_TAG: u8 = 0x5;
+// shard_descr#b and shard_descr_new#a
+const SHARD_DESCR_TAGS: [u8; 2] = [0xb, 0xa];
+// validators#11 and validators_ext#12
+const VALIDATORS_TAG: u8 = 0x11;
+const VALIDATORS_EXT_TAG: u8 = 0x12;
+// validator#53 and validator_addr#73
+const VALIDATOR_TAGS: [u8; 2] = [0x53, 0x73];
+// ed25519_pubkey#8e81278a
+const ED25519_PUBKEY_TAG: u32 = 0x8e81278a;
+// Config param holding the current validator set
+const CUR_VALIDATORS_PARAM: u32 = 34;
+
+// A block as another block refers to it, `ext_blk_ref$_ end_lt:uint64
+// seq_no:uint32 root_hash:bits256 file_hash:bits256`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockRef {
+    pub shard: u64,
+    pub seqno: u32,
+    pub end_lt: u64,
+    pub root_hash: [u8; 32],
+    pub file_hash: [u8; 32],
+}
+
+// The fields of `BlockInfo` the light client reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockInfo {
+    pub workchain: i32,
+    // Shard id: the prefix bits followed by a 1 bit
+    pub shard: u64,
+    pub seqno: u32,
+    pub key_block: bool,
+    pub gen_utime: u32,
+    pub start_lt: u64,
+    pub end_lt: u64,
+    pub prev_key_block_seqno: u32,
+    // The previous block, or the two blocks merged into this one
+    pub prev: Vec<BlockRef>,
+}
+
+// Root cell of the block BOC `data` (base64) after checking it is the block
+// with these hashes
+pub fn parse_block(data: &str, root_hash: &[u8; 32], file_hash: &[u8; 32]) -> Result<ArcCell, String> {
+    let bytes = BASE64_STANDARD
+        .decode(data)
+        .map_err(|e| format!("Invalid block BOC: {}", e))?;
+
+    if Sha256::digest(&bytes).as_slice() != file_hash {
+        return Err("Block BOC doesn't match the file hash".to_string());
+    }
+
+    let root = BagOfCells::parse_base64(data)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| format!("Invalid block BOC: {}", e))?;
+
+    if root.cell_hash().as_slice() != root_hash {
+        return Err("Block BOC doesn't match the root hash".to_string());
+    }
+
+    Ok(root)
+}
+
+// block#11ef55aa global_id:int32 info:^BlockInfo value_flow:^ValueFlow
+// state_update:^(MERKLE_UPDATE ShardState) extra:^BlockExtra
+fn block_part(root: &ArcCell, index: usize) -> Result<&ArcCell, String> {
+    if root.parser().load_u32(32).map_err(|e| e.to_string())? != BLOCK_TAG {
+        return Err("Not a block".to_string());
+    }
+
+    root.reference(index).map_err(|e| e.to_string())
+}
+
+// block_info#9bc7a987 version:uint32 not_master:(## 1) after_merge:(## 1)
+// before_split:(## 1) after_split:(## 1) want_split:Bool want_merge:Bool
+// key_block:Bool vert_seqno_incr:(## 1) flags:(## 8) seq_no:# vert_seq_no:#
+// shard:ShardIdent gen_utime:uint32 start_lt:uint64 end_lt:uint64
+// gen_validator_list_hash_short:uint32 gen_catchain_seqno:uint32
+// min_ref_mc_seqno:uint32 prev_key_block_seqno:uint32
+// gen_software:flags . 0?GlobalVersion master_ref:not_master?^BlkMasterInfo
+// prev_ref:^(BlkPrevInfo after_merge) prev_vert_ref:vert_seqno_incr?^(BlkPrevInfo 0)
+pub fn block_info(root: &ArcCell) -> Result<BlockInfo, String> {
+    let info = block_part(root, 0)?;
+    let mut parser = info.parser();
+
+    if parser.load_u32(32).map_err(|e| e.to_string())? != BLOCK_INFO_TAG {
+        return Err("Invalid block info".to_string());
+    }
+
+    parser.skip_bits(32).map_err(|e| e.to_string())?;
+    let not_master = parser.load_bit().map_err(|e| e.to_string())?;
+    let after_merge = parser.load_bit().map_err(|e| e.to_string())?;
+    parser.skip_bits(1).map_err(|e| e.to_string())?;
+    let after_split = parser.load_bit().map_err(|e| e.to_string())?;
+    parser.skip_bits(2).map_err(|e| e.to_string())?;
+    let key_block = parser.load_bit().map_err(|e| e.to_string())?;
+    parser.skip_bits(1 + 8).map_err(|e| e.to_string())?;
+    let seqno = parser.load_u32(32).map_err(|e| e.to_string())?;
+    parser.skip_bits(32).map_err(|e| e.to_string())?;
+
+    // shard_ident$00 shard_pfx_bits:(#<= 60) workchain_id:int32 shard_prefix:uint64
+    parser.skip_bits(2).map_err(|e| e.to_string())?;
+    let prefix_bits = parser.load_u8(6).map_err(|e| e.to_string())?;
+    let workchain = parser.load_u32(32).map_err(|e| e.to_string())? as i32;
+    let prefix = parser.load_u64(64).map_err(|e| e.to_string())?;
+    if prefix_bits > 60 {
+        return Err("Invalid shard prefix".to_string());
+    }
+    let shard = prefix | (1 << (63 - prefix_bits));
+
+    let gen_utime = parser.load_u32(32).map_err(|e| e.to_string())?;
+    let start_lt = parser.load_u64(64).map_err(|e| e.to_string())?;
+    let end_lt = parser.load_u64(64).map_err(|e| e.to_string())?;
+    parser.skip_bits(3 * 32).map_err(|e| e.to_string())?;
+    let prev_key_block_seqno = parser.load_u32(32).map_err(|e| e.to_string())?;
+
+    let prev_ref = info
+        .reference(if not_master { 1 } else { 0 })
+        .map_err(|e| e.to_string())?;
+
+    let prev = if after_merge {
+        // prev_blks_info$_ prev1:^ExtBlkRef prev2:^ExtBlkRef, the halves of
+        // the merged shard
+        let half = lowest_bit(shard) >> 1;
+        vec![
+            ext_blk_ref(&mut prev_ref.reference(0).map_err(|e| e.to_string())?.parser(), shard - half)?,
+            ext_blk_ref(&mut prev_ref.reference(1).map_err(|e| e.to_string())?.parser(), shard + half)?,
+        ]
+    } else {
+        let prev_shard = if after_split { parent_shard(shard) } else { shard };
+        vec![ext_blk_ref(&mut prev_ref.parser(), prev_shard)?]
+    };
+
+    Ok(BlockInfo {
+        workchain,
+        shard,
+        seqno,
+        key_block,
+        gen_utime,
+        start_lt,
+        end_lt,
+        prev_key_block_seqno,
+        prev,
+    })
+}
+
+fn ext_blk_ref(parser: &mut CellParser, shard: u64) -> Result<BlockRef, String> {
+    Ok(BlockRef {
+        shard,
+        end_lt: parser.load_u64(64).map_err(|e| e.to_string())?,
+        seqno: parser.load_u32(32).map_err(|e| e.to_string())?,
+        root_hash: load_hash(parser)?,
+        file_hash: load_hash(parser)?,
+    })
+}
+
+// The ids, lt and hash, of the transactions of `account` in the block.
+// block_extra#4a33f6fd in_msg_descr:^InMsgDescr out_msg_descr:^OutMsgDescr
+// account_blocks:^ShardAccountBlocks rand_seed:bits256 created_by:bits256
+// custom:(Maybe ^McBlockExtra)
+pub fn account_transactions(root: &ArcCell, account: &[u8; 32]) -> Result<Vec<(u64, [u8; 32])>, String> {
+    let extra = block_extra(root)?;
+
+    // ShardAccountBlocks = HashmapAugE 256 AccountBlock CurrencyCollection
+    let account_blocks = extra.reference(2).map_err(|e| e.to_string())?;
+    if !account_blocks.parser().load_bit().map_err(|e| e.to_string())? {
+        return Ok(vec![]);
+    }
+
+    let dict = account_blocks.reference(0).map_err(|e| e.to_string())?;
+    let key = bits(account, 256);
+    let mut transactions = vec![];
+
+    visit_dict(dict, &mut dict.parser(), 0, 256, &mut vec![], &mut |path, cell, parser, first_ref| {
+        if path != key.as_slice() {
+            return Ok(());
+        }
+
+        // ahmn_leaf extra:CurrencyCollection value:AccountBlock
+        let first_ref = skip_currency_collection(parser, first_ref)?;
+
+        // acc_trans#5 account_addr:bits256
+        // transactions:(HashmapAug 64 ^Transaction CurrencyCollection)
+        // state_update:^(HASH_UPDATE Account)
+        if parser.load_u8(4).map_err(|e| e.to_string())? != ACCOUNT_BLOCK_TAG {
+            return Err("Invalid account block".to_string());
+        }
+        if load_hash(parser)? != *account {
+            return Err("Account block of another account".to_string());
+        }
+
+        visit_dict(cell, parser, first_ref, 64, &mut vec![], &mut |lt, cell, parser, first_ref| {
+            let tx_ref = skip_currency_collection(parser, first_ref)?;
+            let tx = cell.reference(tx_ref).map_err(|e| e.to_string())?;
+            let hash = tx
+                .cell_hash()
+                .as_slice()
+                .try_into()
+                .map_err(|_| "Invalid transaction hash".to_string())?;
+            transactions.push((bits_to_u64(lt), hash));
+            Ok(())
+        })
+    })?;
+
+    Ok(transactions)
+}
+
+fn block_extra(root: &ArcCell) -> Result<&ArcCell, String> {
+    let extra = block_part(root, 3)?;
+
+    if extra.parser().load_u32(32).map_err(|e| e.to_string())? != BLOCK_EXTRA_TAG {
+        return Err("Invalid block extra".to_string());
+    }
+
+    Ok(extra)
+}
+
+// masterchain_block_extra#cca5 key_block:(## 1) shard_hashes:ShardHashes
+// shard_fees:ShardFees ^[ ... ] config:key_block?ConfigParams
+fn mc_block_extra(root: &ArcCell) -> Result<&ArcCell, String> {
+    let extra = block_extra(root)?;
+
+    let mut parser = extra.parser();
+    parser.skip_bits(32 + 256 + 256).map_err(|e| e.to_string())?;
+    if !parser.load_bit().map_err(|e| e.to_string())? {
+        return Err("Not a masterchain block".to_string());
+    }
+
+    let custom = extra.reference(3).map_err(|e| e.to_string())?;
+    if custom.parser().load_u32(16).map_err(|e| e.to_string())? != MC_BLOCK_EXTRA_TAG {
+        return Err("Invalid masterchain block extra".to_string());
+    }
+
+    Ok(custom)
+}
+
+// The block of the shard holding `account` in `workchain` that the
+// masterchain block commits.
+// ShardHashes = HashmapE 32 ^(BinTree ShardDescr), keyed by workchain
+pub fn shard_top(mc_root: &ArcCell, workchain: i32, account: &[u8; 32]) -> Result<BlockRef, String> {
+    let custom = mc_block_extra(mc_root)?;
+
+    let mut parser = custom.parser();
+    parser.skip_bits(16 + 1).map_err(|e| e.to_string())?;
+    if !parser.load_bit().map_err(|e| e.to_string())? {
+        return Err("The masterchain block commits no shard".to_string());
+    }
+
+    let shard_hashes = custom.reference(0).map_err(|e| e.to_string())?;
+    let key = bits(&workchain.to_be_bytes(), 32);
+    let mut tree = None;
+
+    visit_dict(shard_hashes, &mut shard_hashes.parser(), 0, 32, &mut vec![], &mut |path, cell, _, first_ref| {
+        if path == key.as_slice() {
+            tree = Some(cell.reference(first_ref).map_err(|e| e.to_string())?.clone());
+        }
+        Ok(())
+    })?;
+
+    let mut node = tree.ok_or(format!("The masterchain block commits no shard of workchain {}", workchain))?;
+
+    // bt_leaf$0 leaf:X | bt_fork$1 left:^(BinTree X) right:^(BinTree X),
+    // following the bits of the account down to its shard
+    let account_bits = bits(account, 64);
+    let mut depth = 0;
+    loop {
+        let mut parser = node.parser();
+        if !parser.load_bit().map_err(|e| e.to_string())? {
+            let bit = 1u64 << (63 - depth);
+            let prefix = u64::from_be_bytes(account[..8].try_into().unwrap()) & !(bit | (bit - 1));
+            return shard_descr(&mut parser, prefix | bit);
+        }
+
+        if depth >= 60 {
+            return Err("Invalid shard tree".to_string());
+        }
+
+        let next = node
+            .reference(account_bits[depth] as usize)
+            .map_err(|e| e.to_string())?
+            .clone();
+        node = next;
+        depth += 1;
+    }
+}
+
+// shard_descr#b seq_no:uint32 reg_mc_seqno:uint32 start_lt:uint64 end_lt:uint64
+// root_hash:bits256 file_hash:bits256 ...
+fn shard_descr(parser: &mut CellParser, shard: u64) -> Result<BlockRef, String> {
+    if !SHARD_DESCR_TAGS.contains(&parser.load_u8(4).map_err(|e| e.to_string())?) {
+        return Err("Invalid shard description".to_string());
+    }
+
+    let seqno = parser.load_u32(32).map_err(|e| e.to_string())?;
+    parser.skip_bits(32 + 64).map_err(|e| e.to_string())?;
+
+    Ok(BlockRef {
+        shard,
+        seqno,
+        end_lt: parser.load_u64(64).map_err(|e| e.to_string())?,
+        root_hash: load_hash(parser)?,
+        file_hash: load_hash(parser)?,
+    })
+}
+
+// The masterchain validators elected by a key block, from config param 34:
+// validators_ext#12 utime_since:uint32 utime_until:uint32 total:(## 16)
+// main:(## 16) total_weight:uint64 list:(HashmapE 16 ValidatorDescr).
+// Only the first `main` validators sign masterchain blocks.
+pub fn validators(key_block_root: &ArcCell) -> Result<Vec<Validator>, String> {
+    let custom = mc_block_extra(key_block_root)?;
+
+    // Refs of the extra: shard hashes and shard fees if present, the extra
+    // currencies of the fees, the signatures then the config
+    let mut parser = custom.parser();
+    parser.skip_bits(16).map_err(|e| e.to_string())?;
+    if !parser.load_bit().map_err(|e| e.to_string())? {
+        return Err("Not a key block".to_string());
+    }
+    let mut next_ref = 0;
+    for _ in 0..2 {
+        if parser.load_bit().map_err(|e| e.to_string())? {
+            next_ref += 1;
+        }
+    }
+    next_ref = skip_currency_collection(&mut parser, next_ref)?;
+    next_ref = skip_currency_collection(&mut parser, next_ref)?;
+
+    // config_params$_ config_addr:bits256 config:^(Hashmap 32 ^Cell)
+    let config = custom.reference(next_ref + 1).map_err(|e| e.to_string())?;
+    let key = bits(&CUR_VALIDATORS_PARAM.to_be_bytes(), 32);
+    let mut param = None;
+
+    visit_dict(config, &mut config.parser(), 0, 32, &mut vec![], &mut |path, cell, _, first_ref| {
+        if path == key.as_slice() {
+            param = Some(cell.reference(first_ref).map_err(|e| e.to_string())?.clone());
+        }
+        Ok(())
+    })?;
+
+    let param = param.ok_or("The key block has no validator set".to_string())?;
+    let mut parser = param.parser();
+
+    let tag = parser.load_u8(8).map_err(|e| e.to_string())?;
+    parser.skip_bits(32 + 32 + 16).map_err(|e| e.to_string())?;
+    let main = parser.load_u32(16).map_err(|e| e.to_string())?;
+
+    let mut validators = vec![];
+
+    match tag {
+        // list:(Hashmap 16 ValidatorDescr) stored in place
+        VALIDATORS_TAG => visit_dict(&param, &mut parser, 0, 16, &mut vec![], &mut |index, _, parser, _| {
+            if bits_to_u64(index) < main as u64 {
+                validators.push(validator_descr(parser)?);
+            }
+            Ok(())
+        })?,
+        VALIDATORS_EXT_TAG => {
+            parser.skip_bits(64).map_err(|e| e.to_string())?;
+            if parser.load_bit().map_err(|e| e.to_string())? {
+                let list = param.reference(0).map_err(|e| e.to_string())?;
+                visit_dict(list, &mut list.parser(), 0, 16, &mut vec![], &mut |index, _, parser, _| {
+                    if bits_to_u64(index) < main as u64 {
+                        validators.push(validator_descr(parser)?);
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        _ => return Err("Invalid validator set".to_string()),
+    }
+
+    if validators.is_empty() {
+        return Err("The key block elects no validator".to_string());
+    }
+
+    Ok(validators)
+}
+
+// validator#53 public_key:SigPubKey weight:uint64 | validator_addr#73
+// public_key:SigPubKey weight:uint64 adnl_addr:bits256
+fn validator_descr(parser: &mut CellParser) -> Result<Validator, String> {
+    if !VALIDATOR_TAGS.contains(&parser.load_u8(8).map_err(|e| e.to_string())?) {
+        return Err("Invalid validator description".to_string());
+    }
+    if parser.load_u32(32).map_err(|e| e.to_string())? != ED25519_PUBKEY_TAG {
+        return Err("Validator key is not ed25519".to_string());
+    }
+
+    Ok(Validator {
+        public_key: hex::encode(load_hash(parser)?),
+        weight: parser.load_u64(64).map_err(|e| e.to_string())?,
+    })
+}
+
+// Visits the leaves of a `Hashmap n X` or `HashmapAug n X Y` in key order,
+// its root edge starting at `parser` in `cell`. A fork stores its children in
+// its first two references, from `first_ref` on for the root edge. `visit`
+// gets the key, the leaf cell with `parser` at the leaf node and the first
+// reference of the cell the node may use.
+// hm_edge#_ label:(HmLabel ~l n) node:(HashmapNode m X), n = m + l
+type Visitor<'v> = dyn FnMut(&[bool], &ArcCell, &mut CellParser, usize) -> Result<(), String> + 'v;
+
+fn visit_dict(
+    cell: &ArcCell,
+    parser: &mut CellParser,
+    first_ref: usize,
+    n: usize,
+    key: &mut Vec<bool>,
+    visit: &mut Visitor<'_>,
+) -> Result<(), String> {
+    let label = load_label(parser, n)?;
+    let m = n.checked_sub(label.len()).ok_or("Invalid dictionary label".to_string())?;
+    key.extend_from_slice(&label);
+
+    if m == 0 {
+        visit(key, cell, parser, first_ref)?;
+    } else {
+        for bit in [false, true] {
+            let child = cell.reference(first_ref + bit as usize).map_err(|e| e.to_string())?;
+            key.push(bit);
+            visit_dict(child, &mut child.parser(), 0, m - 1, key, visit)?;
+            key.pop();
+        }
+    }
+
+    key.truncate(key.len() - label.len());
+    Ok(())
+}
+
+// hml_short$0 len:(Unary ~n) s:(n * Bit) | hml_long$10 n:(#<= m) s:(n * Bit)
+// | hml_same$11 v:Bit n:(#<= m)
+fn load_label(parser: &mut CellParser, m: usize) -> Result<Vec<bool>, String> {
+    // Bits of a number up to m
+    let len_bits = (usize::BITS - m.leading_zeros()) as usize;
+    let load_len = |parser: &mut CellParser| -> Result<usize, String> {
+        match len_bits {
+            0 => Ok(0),
+            _ => Ok(parser.load_u32(len_bits).map_err(|e| e.to_string())? as usize),
+        }
+    };
+
+    let (same, len) = if !parser.load_bit().map_err(|e| e.to_string())? {
+        let mut len = 0;
+        while parser.load_bit().map_err(|e| e.to_string())? {
+            len += 1;
+        }
+        (None, len)
+    } else if !parser.load_bit().map_err(|e| e.to_string())? {
+        (None, load_len(parser)?)
+    } else {
+        let bit = parser.load_bit().map_err(|e| e.to_string())?;
+        (Some(bit), load_len(parser)?)
+    };
+
+    if len > m {
+        return Err("Invalid dictionary label".to_string());
+    }
+
+    match same {
+        Some(bit) => Ok(vec![bit; len]),
+        None => (0..len)
+            .map(|_| parser.load_bit().map_err(|e| e.to_string()))
+            .collect(),
+    }
+}
+
+// currencies$_ grams:Grams other:ExtraCurrencyCollection, returns the next
+// free reference
+fn skip_currency_collection(parser: &mut CellParser, next_ref: usize) -> Result<usize, String> {
+    parser.load_coins().map_err(|e| e.to_string())?;
+    // extra_currencies$_ dict:(HashmapE 32 (VarUInteger 32))
+    match parser.load_bit().map_err(|e| e.to_string())? {
+        true => Ok(next_ref + 1),
+        false => Ok(next_ref),
+    }
+}
+
+fn load_hash(parser: &mut CellParser) -> Result<[u8; 32], String> {
+    parser
+        .load_bytes(32)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "Invalid hash".to_string())
+}
+
+// The first `count` bits of `bytes`, most significant first
+fn bits(bytes: &[u8], count: usize) -> Vec<bool> {
+    (0..count).map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0).collect()
+}
+
+fn bits_to_u64(bits: &[bool]) -> u64 {
+    bits.iter().fold(0, |value, bit| (value << 1) | *bit as u64)
+}
+
+fn lowest_bit(shard: u64) -> u64 {
+    shard & shard.wrapping_neg()
+}
+
+// The shard that split into `shard` and its sibling
+pub fn parent_shard(shard: u64) -> u64 {
+    let bit = lowest_bit(shard);
+    (shard - bit) | (bit << 1)
+}
+
+// Whether the account id falls in the shard
+pub fn shard_contains(shard: u64, account: &[u8; 32]) -> bool {
+    let bit = lowest_bit(shard);
+    let mask = !(bit | (bit - 1));
+    u64::from_be_bytes(account[..8].try_into().unwrap()) & mask == shard & mask
+}
@@ -1,17 +1,58 @@
-// pub const TEST_NET_TON_RPC_API_KEY : &str = "0d1616aea9f9fa3f0b155a1c20d61bbdca6ef393b35814bbd2e438727df799d0";
-// pub const TON_API_KEY : &str = "0d1616aea9f9fa3f0b155a1c20d61bbdca6ef393b35814bbd2e438727df799d0";
-pub const TON_API_KEY : &str = env!("TON_API_KEY");
-pub const TON_RPC_URL : &str = env!("TON_RPC_URL");
-pub const PROXY_URL : &str = env!("PROXY_URL");
-pub const PROXY_API_KEY : &str = "";
-
-#[cfg(network = "local")]
-pub const SCHNORR_KEY_NAME : &str = "dfx_test_key";
-
-#[cfg(app_env = "prod")]
-#[cfg(network = "ic")]
-pub const SCHNORR_KEY_NAME : &str = "key_1";
-
-#[cfg(app_env = "dev")]
-#[cfg(network = "ic")]
-pub const SCHNORR_KEY_NAME : &str = "test_key_1";
\ No newline at end of file
+// Deposit wallets holding less than this (in nanotons) are not swept, unless
+// the init or upgrade argument sets another limit
+pub const DEFAULT_MIN_SWEEP_BALANCE : u64 = 10_000_000;
+// Number of deposit addresses checked by one run of the deposit scanner, same
+pub const DEFAULT_MAX_ACCOUNTS_PER_SCAN : u32 = 10;
+// Deposit addresses an owner can register for automatic deposits
+pub const MAX_DEPOSIT_ACCOUNTS_PER_OWNER : u64 = 16;
+// Scans without a new transaction after which the scanner skips a deposit
+// address, until its owner calls `update_balance` again
+pub const MAX_IDLE_SCANS : u32 = 30;
+// Interval between two runs of the deposit scanner
+pub const DEPOSIT_SCAN_INTERVAL_SECS : u64 = 60;
+// Interval between two runs of the withdrawal batcher
+pub const WITHDRAWAL_BATCH_INTERVAL_SECS : u64 = 10;
+// Number of payouts in one external message, the V4R2 wallet sends at most 4.
+// A highload minter wallet sends up to `highload::MAX_MESSAGES_PER_EXTERNAL`.
+pub const MAX_WITHDRAWALS_PER_BATCH : usize = 4;
+// Subwallet id of the highload minter wallet
+pub const HIGHLOAD_SUBWALLET_ID : u32 = 0x10ad;
+// Highload wallet timeout: messages are accepted for this long after their `created_at`
+pub const HIGHLOAD_TIMEOUT_SECS : u32 = 600;
+// How far `created_at` of a highload message lies in the past when it is signed
+pub const HIGHLOAD_CREATED_AT_LAG_SECS : u32 = 300;
+// Interval between two checks of the unresolved withdrawals
+pub const WITHDRAWAL_CHECK_INTERVAL_SECS : u64 = 60;
+// Number of withdrawals checked by one run of the withdrawal check
+pub const MAX_WITHDRAWALS_PER_CHECK : usize = 10;
+// Allowed clock drift between the IC and TON before a payout message counts as expired
+pub const WITHDRAWAL_EXPIRY_MARGIN_SECS : u32 = 30;
+// Transactions requested per page of account history
+pub const TX_PAGE_SIZE : u32 = 10;
+// Pages of new transactions fetched by one scan of an address, the scan resumes from there
+pub const MAX_TX_PAGES_PER_SCAN : usize = 5;
+// Pages walked back when looking up a specific transaction
+pub const MAX_TX_PAGES_PER_LOOKUP : usize = 10;
+// Interval between two syncs of the light client with the masterchain head
+pub const LIGHT_CLIENT_SYNC_INTERVAL_SECS : u64 = 60;
+// Key blocks the light client follows in one sync
+pub const MAX_KEY_BLOCKS_PER_SYNC : usize = 8;
+// A deposit is proven from the first masterchain block generated this long
+// after it, by which time its shard block is committed
+pub const PROOF_MC_BLOCK_DELAY_SECS : u64 = 30;
+// Shard blocks walked back from a masterchain block to the one holding a deposit
+pub const MAX_SHARD_BLOCKS_PER_PROOF : usize = 16;
+// Nodes of a regular application subnet, outcalls are priced per node
+pub const DEFAULT_SUBNET_SIZE : u32 = 13;
+// Cycles each new ledger and index canister is created with
+pub const LEDGER_SUITE_CREATION_CYCLES : u128 = 20_000_000_000_000;
+// Cycles a ledger gives each archive it spawns
+pub const LEDGER_ARCHIVE_CREATION_CYCLES : u64 = 5_000_000_000_000;
+// Ledger suite canisters below this balance are topped up
+pub const LEDGER_SUITE_MIN_CYCLES : u128 = 5_000_000_000_000;
+// Cycles sent by one top-up
+pub const LEDGER_SUITE_TOP_UP_CYCLES : u128 = 10_000_000_000_000;
+// Balance the minter keeps for itself when topping up
+pub const MINTER_CYCLES_RESERVE : u128 = 10_000_000_000_000;
+// Interval between two top-up rounds of the ledger suites
+pub const LEDGER_SUITE_TOP_UP_INTERVAL_SECS : u64 = 6 * 60 * 60;
@@ -0,0 +1,108 @@
+// ==========================
+// ckton_minter/src/cycles.rs
+// Cycles accounting of the HTTP outcalls to the TON providers.
+// The price of an outcall is computed from its size and the subnet size, and
+// the cycles actually spent are added up per operation in stable memory.
+// ==========================
+
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::CanisterHttpRequestArgument;
+use ic_stable_structures::{memory_manager::MemoryId, StableCell};
+use serde::Deserialize;
+
+use crate::state::{candid_storable, get_memory, Memory};
+
+const CYCLES_STATS_MEMORY_ID: MemoryId = MemoryId::new(12);
+
+// Response size assumed by the IC when `max_response_bytes` is not set
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+thread_local! {
+    static CYCLES_STATS: RefCell<StableCell<CyclesStats, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CYCLES_STATS_MEMORY_ID), CyclesStats::default())
+            .expect("failed to initialize the cycles stats")
+    );
+}
+
+// What an outcall was made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Operation {
+    // Detecting, minting and sweeping deposits
+    Deposit,
+    // Paying out and resolving withdrawals
+    Withdrawal,
+    // Deploying the TON wallets of accounts and of the minter
+    WalletDeployment,
+    // Following the masterchain head, see `lightclient`
+    LightClient,
+    // Balance lookups and other calls on behalf of users
+    Other,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct OperationCycles {
+    pub operation: Operation,
+    pub outcalls: u64,
+    pub cycles: u128,
+}
+
+#[derive(Debug, Clone, Default, CandidType, Deserialize)]
+pub struct CyclesStats {
+    pub total_outcalls: u64,
+    pub total_cycles: u128,
+    pub operations: Vec<OperationCycles>,
+}
+
+candid_storable!(CyclesStats);
+
+// Price of an HTTP outcall on a subnet of `subnet_size` nodes:
+// (3M + 60K * n) * n + 400 * n * request bytes + 800 * n * max response bytes
+pub fn http_request_cost(arg: &CanisterHttpRequestArgument, subnet_size: u32) -> u128 {
+    let request_bytes = arg.url.len()
+        + arg
+            .headers
+            .iter()
+            .map(|header| header.name.len() + header.value.len())
+            .sum::<usize>()
+        + arg.body.as_ref().map_or(0, |body| body.len())
+        + arg
+            .transform
+            .as_ref()
+            .map_or(0, |transform| transform.function.0.method.len() + transform.context.len());
+
+    let response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+
+    let n = subnet_size as u128;
+
+    (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes as u128 + 800 * n * response_bytes as u128
+}
+
+// Adds the cycles spent by one outcall to the stats
+pub fn record_outcall(operation: Operation, cycles: u128) {
+    CYCLES_STATS.with_borrow_mut(|cell| {
+        let mut stats = cell.get().clone();
+
+        stats.total_outcalls += 1;
+        stats.total_cycles += cycles;
+
+        match stats.operations.iter_mut().find(|entry| entry.operation == operation) {
+            Some(entry) => {
+                entry.outcalls += 1;
+                entry.cycles += cycles;
+            }
+            None => stats.operations.push(OperationCycles {
+                operation,
+                outcalls: 1,
+                cycles,
+            }),
+        }
+
+        cell.set(stats).expect("failed to write the cycles stats");
+    });
+}
+
+pub fn cycles_stats() -> CyclesStats {
+    CYCLES_STATS.with_borrow(|cell| cell.get().clone())
+}
@@ -0,0 +1,763 @@
+// ==========================
+// ckton_minter/src/deposits.rs
+// Automatic detection of TON deposits sent to the derived deposit addresses.
+// Every confirmed incoming transfer is credited exactly once, then the
+// deposit wallet is swept into the minter wallet.
+// ==========================
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::BTreeSet,
+    future::Future,
+};
+
+use base64::prelude::*;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{memory_manager::MemoryId, storable::Bound, StableBTreeMap, Storable};
+use ic_ton_lib::{
+    cell::BagOfCells,
+    message::{CommonMsgInfo, InternalMessage, TonMessage, TransferMessage},
+    num_bigint::BigUint,
+    TonAddress,
+};
+use icrc_ledger_types::icrc1::{
+    account::Account,
+    transfer::{TransferArg, TransferError},
+};
+use serde::Deserialize;
+
+use crate::{
+    consts::{MAX_DEPOSIT_ACCOUNTS_PER_OWNER, MAX_IDLE_SCANS},
+    create_ton_wallet,
+    cycles::Operation,
+    events::{record_event, EventType},
+    finality, jettons,
+    get_ton_address_from_address, get_ton_address_from_wallet, history, memo, nanos_to_seconds,
+    state::{candid_storable, get_memory, read_config, Memory, StorableAccount, DEPLOYED_WALLET, PENDING_TASKS},
+    ton_api::{self, TonTransaction},
+    types::{MinterError, PendingTasks, TonWalletVersion},
+    utils::{ledger_transfer, mint_deduplicated},
+    wallet_version,
+};
+
+const DEPOSIT_ACCOUNTS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const CREDITED_DEPOSITS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const DETECTED_DEPOSITS_MEMORY_ID: MemoryId = MemoryId::new(19);
+
+thread_local! {
+    // Accounts whose deposit address is watched by the scanner
+    static DEPOSIT_ACCOUNTS: RefCell<StableBTreeMap<StorableAccount, DepositAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_ACCOUNTS_MEMORY_ID)));
+    // TON transactions that were already credited
+    static CREDITED_DEPOSITS: RefCell<StableBTreeMap<TonTxId, CreditedDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CREDITED_DEPOSITS_MEMORY_ID)));
+    // TON transactions whose detection was recorded in the event log
+    static DETECTED_DEPOSITS: RefCell<StableBTreeMap<TonTxId, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DETECTED_DEPOSITS_MEMORY_ID)));
+    // TON transactions with a mint in progress
+    static MINTING: RefCell<BTreeSet<TonTxId>> = const { RefCell::new(BTreeSet::new()) };
+    // Accounts currently processed by `update_balance` or the scanner
+    static LOCKED_ACCOUNTS: RefCell<BTreeSet<Account>> = RefCell::new(BTreeSet::new());
+    // Last account checked by the scanner
+    static SCAN_CURSOR: RefCell<Option<StorableAccount>> = const { RefCell::new(None) };
+    // Set while the minter wallet is scanned for memo deposits
+    static MINTER_WALLET_SCAN_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct DepositAccount {
+    // The account exactly as registered, used to derive the wallet signer
+    pub account: Account,
+    pub ton_address: String,
+    // Accounts registered before versions were tracked are V4R2
+    pub version: Option<TonWalletVersion>,
+    // Scheduled scans in a row that found no new transaction
+    pub idle_scans: Option<u32>,
+}
+
+impl DepositAccount {
+    pub fn is_idle(&self) -> bool {
+        self.idle_scans.unwrap_or_default() >= MAX_IDLE_SCANS
+    }
+}
+
+candid_storable!(DepositAccount);
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct CreditedDeposit {
+    pub account: Account,
+    pub amount: u64,
+    pub block_index: u64,
+}
+
+candid_storable!(CreditedDeposit);
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct MintedDeposit {
+    pub tx_hash: String,
+    pub lt: u64,
+    pub amount: u64,
+    pub block_index: u64,
+}
+
+// Identity of a TON transaction: logical time and hash of the transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TonTxId {
+    pub lt: u64,
+    pub hash: [u8; 32],
+}
+
+impl TonTxId {
+    pub fn from_transaction(tx: &TonTransaction) -> Result<Self, String> {
+        let lt = tx
+            .transaction_id
+            .lt
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid transaction lt: {}", e))?;
+        let hash = BASE64_STANDARD
+            .decode(&tx.transaction_id.hash)
+            .map_err(|e| format!("Invalid transaction hash: {}", e))?;
+        let hash: [u8; 32] = hash
+            .try_into()
+            .map_err(|_| "Transaction hash must be 32 bytes".to_string())?;
+        Ok(Self { lt, hash })
+    }
+
+    pub fn hash_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.hash)
+    }
+}
+
+// Layout: big-endian lt followed by the hash, so keys sort by lt
+impl Storable for TonTxId {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.lt.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.hash);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (lt, hash) = bytes.split_at(8);
+        Self {
+            lt: u64::from_be_bytes(lt.try_into().unwrap()),
+            hash: hash.try_into().unwrap(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 40,
+        is_fixed_size: true,
+    };
+}
+
+// Releases the account lock when dropped
+struct AccountGuard(Account);
+
+impl AccountGuard {
+    fn new(account: Account) -> Result<Self, String> {
+        LOCKED_ACCOUNTS.with_borrow_mut(|locked| {
+            if !locked.insert(account) {
+                return Err("Deposits of this account are already being processed".to_string());
+            }
+            Ok(Self(account))
+        })
+    }
+}
+
+impl Drop for AccountGuard {
+    fn drop(&mut self) {
+        LOCKED_ACCOUNTS.with_borrow_mut(|locked| locked.remove(&self.0));
+    }
+}
+
+pub fn is_deposit_account(account: &Account) -> bool {
+    DEPOSIT_ACCOUNTS.with_borrow(|accounts| accounts.contains_key(&(*account).into()))
+}
+
+pub fn get_deposit_account(account: &Account) -> Option<DepositAccount> {
+    DEPOSIT_ACCOUNTS.with_borrow(|accounts| accounts.get(&(*account).into()))
+}
+
+pub fn deposit_wallet_version(account: &Account) -> Option<TonWalletVersion> {
+    DEPOSIT_ACCOUNTS.with_borrow(|accounts| accounts.get(&(*account).into()).map(|account| account.version.unwrap_or_default()))
+}
+
+pub fn is_credited(tx_id: &TonTxId) -> bool {
+    CREDITED_DEPOSITS.with_borrow(|credited| credited.contains_key(tx_id))
+}
+
+// Marks the transaction as detected, true the first time. Deposits that are
+// not final or whose mint failed are seen again by every scan, their
+// detection is recorded once.
+fn mark_detected(tx_id: TonTxId) -> bool {
+    DETECTED_DEPOSITS.with_borrow_mut(|detected| detected.insert(tx_id, ()).is_none())
+}
+
+// Identity of a scanned transaction. A malformed transaction is logged and
+// skipped rather than failing the whole scan.
+fn scanned_tx_id(tx: &TonTransaction) -> Option<TonTxId> {
+    match TonTxId::from_transaction(tx) {
+        Ok(tx_id) => Some(tx_id),
+        Err(err) => {
+            ic_cdk::println!("Skipping transaction {} at lt {}: {}", tx.transaction_id.hash, tx.transaction_id.lt, err);
+            None
+        }
+    }
+}
+
+// Number of accounts of `owner` registered for automatic deposits
+pub fn owner_deposit_accounts(owner: Principal) -> u64 {
+    let first = StorableAccount(Account { owner, subaccount: None });
+    let last = StorableAccount(Account { owner, subaccount: Some([u8::MAX; 32]) });
+
+    DEPOSIT_ACCOUNTS.with_borrow(|accounts| accounts.range(first..=last).count() as u64)
+}
+
+// Public for the tests, accounts are registered by `update_balance`
+pub fn store_deposit_account(deposit_account: &DepositAccount) {
+    DEPOSIT_ACCOUNTS.with_borrow_mut(|accounts| {
+        accounts.insert(deposit_account.account.into(), deposit_account.clone());
+    });
+}
+
+// Counts a scheduled scan without new transactions, any other scan makes the
+// account active again
+pub fn record_scan(deposit_account: &DepositAccount, idle: bool) {
+    let idle_scans = if idle { deposit_account.idle_scans.unwrap_or_default().saturating_add(1) } else { 0 };
+
+    if deposit_account.idle_scans.unwrap_or_default() == idle_scans {
+        return;
+    }
+
+    store_deposit_account(&DepositAccount {
+        idle_scans: Some(idle_scans),
+        ..deposit_account.clone()
+    });
+}
+
+// Registers the account for automatic deposits and returns its deposit address
+async fn register_deposit_account(account: Account) -> Result<DepositAccount, String> {
+    if let Some(deposit_account) = get_deposit_account(&account) {
+        return Ok(deposit_account);
+    }
+
+    // Wallets used with the manual `mint` flow already had their deposits swept and
+    // credited, scanning their history again would credit them twice
+    if DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&account.into())) {
+        return Err("Account uses manual deposits through `mint`".to_string());
+    }
+
+    if owner_deposit_accounts(account.owner) >= MAX_DEPOSIT_ACCOUNTS_PER_OWNER {
+        return Err(format!("At most {} accounts of an owner can be registered for automatic deposits", MAX_DEPOSIT_ACCOUNTS_PER_OWNER));
+    }
+
+    let version = wallet_version(&account);
+
+    let wallet = create_ton_wallet(account.owner, account.subaccount).await?;
+
+    let deposit_account = DepositAccount {
+        account,
+        ton_address: get_ton_address_from_wallet(&wallet),
+        version: Some(version),
+        idle_scans: None,
+    };
+
+    store_deposit_account(&deposit_account);
+
+    Ok(deposit_account)
+}
+
+// Returns the credited amount of an incoming transfer, if the transaction is one
+pub fn incoming_value(tx: &TonTransaction) -> Option<u64> {
+    // External messages carry no value
+    if tx.in_msg.source.is_empty() {
+        return None;
+    }
+
+    // A wallet only emits messages for an incoming transfer when it bounces it
+    if !tx.out_msgs.is_empty() {
+        return None;
+    }
+
+    tx.in_msg.value.parse::<u64>().ok().filter(|value| *value > 0)
+}
+
+// Checks the deposit address of the account and mints ckTON for every new
+// transfer. An idle account is scanned periodically again afterwards.
+pub async fn update_balance(account: Account) -> Result<Vec<MintedDeposit>, MinterError> {
+    check_deposits(account, false).await
+}
+
+// `scheduled` is set for the runs of the scanner, which give up on accounts
+// that stay idle
+async fn check_deposits(account: Account, scheduled: bool) -> Result<Vec<MintedDeposit>, MinterError> {
+    let _guard = AccountGuard::new(account)?;
+
+    let deposit_account = register_deposit_account(account).await?;
+
+    let scan = history::scan_new_transactions(&deposit_account.ton_address, Operation::Deposit).await?;
+
+    record_scan(&deposit_account, scheduled && scan.transactions.is_empty());
+
+    let mut minted = vec![];
+    // The cursor only moves past transactions that were all credited
+    let mut credited_all = true;
+    let mut mc_head = None;
+
+    for tx in scan.transactions.iter() {
+        let Some(value) = incoming_value(tx) else {
+            continue;
+        };
+
+        let Some(tx_id) = scanned_tx_id(tx) else {
+            continue;
+        };
+
+        if is_credited(&tx_id) {
+            continue;
+        }
+
+        if mark_detected(tx_id) {
+            record_event(EventType::DepositDetected {
+                account: deposit_account.account,
+                ton_address: deposit_account.ton_address.clone(),
+                hash: tx_id.hash_base64(),
+                lt: tx_id.lt,
+                amount: value,
+            });
+        }
+
+        let deposit = finality::Deposit {
+            account: deposit_account.account,
+            ton_address: deposit_account.ton_address.clone(),
+            tx_id,
+            utime: tx.utime,
+            amount: value,
+        };
+
+        match finality::is_final(&deposit, &mut mc_head).await {
+            Ok(true) => {}
+            Ok(false) => {
+                credited_all = false;
+                continue;
+            }
+            Err(err) => {
+                ic_cdk::println!("Failed to check finality of deposit {}: {}", tx_id.hash_base64(), err);
+                credited_all = false;
+                continue;
+            }
+        }
+
+        match mint_deposit(deposit_account.account, tx_id, tx.utime, value).await {
+            Ok(Some(deposit)) => minted.push(deposit),
+            Ok(None) => {}
+            Err(err) => {
+                ic_cdk::println!("Failed to mint deposit {}: {}", tx_id.hash_base64(), err);
+                credited_all = false;
+            }
+        }
+    }
+
+    if credited_all {
+        history::commit_scan(&deposit_account.ton_address, scan.cursor);
+    }
+
+    if !minted.is_empty() {
+        PENDING_TASKS.with_borrow_mut(|tasks| {
+            tasks.push_back(PendingTasks::Sweep(deposit_account.account, 0));
+        });
+    }
+
+    Ok(minted)
+}
+
+// Mints a deposit, deposits that don't cover the fee are ignored
+async fn mint_deposit(account: Account, tx_id: TonTxId, utime: u64, value: u64) -> Result<Option<MintedDeposit>, String> {
+    let ckton_transfer_fee = read_config(|config| config.ckton_transfer_fee);
+
+    let amount = value.saturating_sub(ckton_transfer_fee);
+
+    if amount == 0 {
+        ic_cdk::println!("Deposit {} of {} is below the fee of {}", tx_id.hash_base64(), value, ckton_transfer_fee);
+        finality::forget(&tx_id);
+        return Ok(None);
+    }
+
+    let block_index = mint_once(account, tx_id, utime, amount).await?;
+
+    record_event(EventType::CkTonMinted {
+        to: account,
+        amount,
+        hash: tx_id.hash_base64(),
+        block_index,
+    });
+
+    Ok(Some(MintedDeposit {
+        tx_hash: tx_id.hash_base64(),
+        lt: tx_id.lt,
+        amount,
+        block_index,
+    }))
+}
+
+// Holds the right to mint for a TON transaction, released when dropped
+struct MintClaim(TonTxId);
+
+impl MintClaim {
+    fn new(tx_id: TonTxId) -> Result<Self, String> {
+        if is_credited(&tx_id) {
+            return Err(format!("Transaction {} is already credited", tx_id.hash_base64()));
+        }
+        MINTING.with_borrow_mut(|minting| {
+            if !minting.insert(tx_id) {
+                return Err(format!("Transaction {} is already being credited", tx_id.hash_base64()));
+            }
+            Ok(Self(tx_id))
+        })
+    }
+}
+
+impl Drop for MintClaim {
+    fn drop(&mut self) {
+        MINTING.with_borrow_mut(|minting| minting.remove(&self.0));
+    }
+}
+
+// Mints `amount` ckTON to `account` for the TON transaction `tx_id`, at most once.
+// The transfer carries the transaction hash as memo and the transaction time as
+// `created_at_time`, so the ledger's deduplication rejects a second mint too.
+// Returns the index of the (possibly earlier) mint block.
+pub async fn mint_once(account: Account, tx_id: TonTxId, utime: u64, amount: u64) -> Result<u64, String> {
+    let ledger_canister = read_config(|config| config.ledger_canister);
+
+    mint_once_on(ledger_canister, account, tx_id, utime, amount).await
+}
+
+// Same as `mint_once` on the ledger of a bridged jetton
+pub async fn mint_once_on(ledger_canister: Principal, account: Account, tx_id: TonTxId, utime: u64, amount: u64) -> Result<u64, String> {
+    mint_once_with(|arg| ledger_transfer(ledger_canister, arg), account, tx_id, utime, amount).await
+}
+
+// Same as `mint_once` through `transfer`, see `utils::mint_deduplicated`.
+// Public for the tests, which replace the ledger.
+pub async fn mint_once_with<F, Fut>(transfer: F, account: Account, tx_id: TonTxId, utime: u64, amount: u64) -> Result<u64, String>
+where
+    F: Fn(TransferArg) -> Fut,
+    Fut: Future<Output = Result<Result<Nat, TransferError>, String>>,
+{
+    let _claim = MintClaim::new(tx_id)?;
+
+    // Transactions outside of the ledger's deduplication window are only
+    // deduplicated by the credited set
+    let block_index = mint_deduplicated(transfer, account, amount, tx_id.hash.to_vec(), utime.saturating_mul(1_000_000_000)).await?;
+
+    CREDITED_DEPOSITS.with_borrow_mut(|credited| {
+        credited.insert(tx_id, CreditedDeposit { account, amount, block_index });
+    });
+
+    finality::forget(&tx_id);
+
+    Ok(block_index)
+}
+
+// Sends the whole balance of the deposit wallet to the minter wallet.
+// Deploys the wallet along the way if it was never used before.
+pub async fn sweep_deposit(account: Account) -> Result<Option<String>, String> {
+    let wallet = create_ton_wallet(account.owner, account.subaccount).await?;
+
+    let result = ton_api::get_ton_wallet_info(get_ton_address_from_wallet(&wallet), Operation::Deposit).await?;
+
+    let balance = result.balance.parse::<u64>().map_err(|e| e.to_string())?;
+
+    if balance < read_config(|config| config.min_sweep_balance()) {
+        return Ok(None);
+    }
+
+    let seqno = if result.wallet { result.seqno.unwrap_or(0) } else { 0 };
+    let seqno_u32 = u32::try_from(seqno)
+        .map_err(|_| "Sequence number too large to convert to u32".to_string())?;
+
+    let app_ton_address = read_config(|config| config.app_ton_address());
+
+    if app_ton_address == TonAddress::NULL {
+        return Err("Minter TON address is not set".to_string());
+    }
+
+    let common_message = CommonMsgInfo::InternalMessage(InternalMessage {
+        ihr_disabled: false,
+        bounce: false,
+        bounced: false,
+        src: TonAddress::NULL,
+        dest: app_ton_address,
+        value: BigUint::ZERO,
+        ihr_fee: BigUint::ZERO,
+        fwd_fee: BigUint::ZERO,
+        created_lt: 0,
+        created_at: 0,
+    });
+
+    let transfer_message = TransferMessage::new(common_message)
+        .build()
+        .map_err(|s| s.to_string())?;
+
+    let expire_at = nanos_to_seconds(time()) + 60;
+
+    // Mode 128: carry all the remaining balance of the wallet
+    let external_mssg = wallet
+        .create_external_body_with_mode(expire_at, seqno_u32, vec![(128, transfer_message.to_arc())])
+        .map_err(|s| s.to_string())?;
+
+    let signed = wallet
+        .sign_external_body(&external_mssg)
+        .await
+        .map_err(|s| s.to_string())?;
+
+    let wrapped = wallet
+        .wrap_signed_body(signed, !result.wallet)
+        .map_err(|s| s.to_string())?;
+
+    let boc = BagOfCells::from_root(wrapped);
+    let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
+
+    let hash = ton_api::send_boc_to_ton(enc, Operation::Deposit).await?.hash;
+
+    record_event(EventType::DepositSwept {
+        account,
+        hash: hash.clone(),
+        amount: balance,
+    });
+
+    Ok(Some(hash))
+}
+
+// Releases the minter wallet scan flag when dropped
+struct MinterWalletScanGuard;
+
+impl MinterWalletScanGuard {
+    fn new() -> Option<Self> {
+        MINTER_WALLET_SCAN_RUNNING.with_borrow_mut(|running| {
+            if *running {
+                return None;
+            }
+            *running = true;
+            Some(Self)
+        })
+    }
+}
+
+impl Drop for MinterWalletScanGuard {
+    fn drop(&mut self) {
+        MINTER_WALLET_SCAN_RUNNING.set(false);
+    }
+}
+
+// Credits transfers sent straight to the minter wallet with the target account
+// encoded in the message body (see `memo`), and jetton deposits announced to
+// the minter wallet by its jetton wallets (see `jettons`)
+pub async fn scan_minter_wallet() -> Result<Vec<MintedDeposit>, String> {
+    let Some(_guard) = MinterWalletScanGuard::new() else {
+        return Err("Minter wallet is already being scanned".to_string());
+    };
+
+    let app_ton_address = read_config(|config| config.app_ton_address());
+
+    if app_ton_address == TonAddress::NULL {
+        return Err("Minter TON address is not set".to_string());
+    }
+
+    let minter_address = get_ton_address_from_address(&app_ton_address);
+
+    let scan = history::scan_new_transactions(&minter_address, Operation::Deposit).await?;
+
+    let memo_deposits = read_config(|config| config.memo_deposits_enabled());
+
+    let mut minted = vec![];
+    let mut credited_all = true;
+    let mut mc_head = None;
+
+    for tx in scan.transactions.iter() {
+        if let Some(deposit) = jettons::deposit_from_message(&tx.in_msg) {
+            let Some(tx_id) = scanned_tx_id(tx) else {
+                continue;
+            };
+
+            if is_credited(&tx_id) {
+                continue;
+            }
+
+            // Notifications without an account in the forward payload can't be credited
+            let deposit = match deposit {
+                Ok(deposit) => deposit,
+                Err(err) => {
+                    ic_cdk::println!("Ignoring jetton notification {}: {}", tx_id.hash_base64(), err);
+                    continue;
+                }
+            };
+
+            if mark_detected(tx_id) {
+                record_event(EventType::JettonDepositDetected {
+                    symbol: deposit.jetton.symbol.clone(),
+                    account: deposit.account,
+                    hash: tx_id.hash_base64(),
+                    lt: tx_id.lt,
+                    amount: deposit.amount,
+                });
+            }
+
+            let pending = finality::Deposit {
+                account: deposit.account,
+                ton_address: minter_address.clone(),
+                tx_id,
+                utime: tx.utime,
+                amount: deposit.amount,
+            };
+
+            match finality::is_final(&pending, &mut mc_head).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    credited_all = false;
+                    continue;
+                }
+                Err(err) => {
+                    ic_cdk::println!("Failed to check finality of jetton deposit {}: {}", tx_id.hash_base64(), err);
+                    credited_all = false;
+                    continue;
+                }
+            }
+
+            match jettons::mint_deposit(&deposit, tx_id, tx.utime).await {
+                Ok(Some(deposit)) => minted.push(deposit),
+                Ok(None) => finality::forget(&tx_id),
+                Err(err) => {
+                    ic_cdk::println!("Failed to mint jetton deposit {}: {}", tx_id.hash_base64(), err);
+                    credited_all = false;
+                }
+            }
+
+            continue;
+        }
+
+        if !memo_deposits {
+            continue;
+        }
+
+        let Some(value) = incoming_value(tx) else {
+            continue;
+        };
+
+        let Some(tx_id) = scanned_tx_id(tx) else {
+            continue;
+        };
+
+        if is_credited(&tx_id) {
+            continue;
+        }
+
+        // Sweeps of deposit wallets and plain top-ups carry no account
+        let account = match memo::account_from_message(&tx.in_msg) {
+            Ok(account) => account,
+            Err(_) => continue,
+        };
+
+        if mark_detected(tx_id) {
+            record_event(EventType::DepositDetected {
+                account,
+                ton_address: minter_address.clone(),
+                hash: tx_id.hash_base64(),
+                lt: tx_id.lt,
+                amount: value,
+            });
+        }
+
+        let deposit = finality::Deposit {
+            account,
+            ton_address: minter_address.clone(),
+            tx_id,
+            utime: tx.utime,
+            amount: value,
+        };
+
+        match finality::is_final(&deposit, &mut mc_head).await {
+            Ok(true) => {}
+            Ok(false) => {
+                credited_all = false;
+                continue;
+            }
+            Err(err) => {
+                ic_cdk::println!("Failed to check finality of memo deposit {}: {}", tx_id.hash_base64(), err);
+                credited_all = false;
+                continue;
+            }
+        }
+
+        match mint_deposit(account, tx_id, tx.utime, value).await {
+            Ok(Some(deposit)) => minted.push(deposit),
+            Ok(None) => {}
+            Err(err) => {
+                ic_cdk::println!("Failed to mint memo deposit {}: {}", tx_id.hash_base64(), err);
+                credited_all = false;
+            }
+        }
+    }
+
+    if credited_all {
+        history::commit_scan(&minter_address, scan.cursor);
+    }
+
+    Ok(minted)
+}
+
+// Picks the next batch of deposit accounts that are not idle, continuing
+// after the last scanned one
+pub fn next_accounts_to_scan(count: usize) -> Vec<Account> {
+    let cursor = SCAN_CURSOR.with_borrow(|cursor| *cursor);
+
+    let batch: Vec<(StorableAccount, DepositAccount)> = DEPOSIT_ACCOUNTS.with_borrow(|accounts| {
+        let after_cursor = match cursor {
+            Some(cursor) => accounts
+                .range(cursor..)
+                .filter(|(key, account)| *key != cursor && !account.is_idle())
+                .take(count)
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        let missing = count - after_cursor.len();
+        let wrapped = accounts
+            .iter()
+            .filter(|(key, account)| !account.is_idle() && !after_cursor.iter().any(|(taken, _)| taken == key))
+            .take(missing);
+
+        after_cursor.iter().cloned().chain(wrapped).collect()
+    });
+
+    SCAN_CURSOR.set(batch.last().map(|(key, _)| *key));
+
+    batch.into_iter().map(|(_, deposit_account)| deposit_account.account).collect()
+}
+
+// Periodic scan of the registered deposit addresses and, if memo deposits or
+// jettons are enabled, the minter wallet
+pub async fn scan_deposit_addresses() {
+    if read_config(|config| config.memo_deposits_enabled() || !config.jettons().is_empty()) {
+        match scan_minter_wallet().await {
+            Ok(minted) if !minted.is_empty() => {
+                ic_cdk::println!("Minted {} memo deposits", minted.len());
+            }
+            Ok(_) => {}
+            Err(err) => ic_cdk::println!("Failed to scan the minter wallet: {}", err),
+        }
+    }
+
+    for account in next_accounts_to_scan(read_config(|config| config.max_accounts_per_scan())) {
+        match check_deposits(account, true).await {
+            Ok(minted) if !minted.is_empty() => {
+                ic_cdk::println!("Minted {} deposits for {}", minted.len(), account);
+            }
+            Ok(_) => {}
+            Err(err) => ic_cdk::println!("Failed to scan deposits of {}: {}", account, err),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+// ==========================
+// ckton_minter/src/events.rs
+// Append-only audit log of every minter state transition.
+// The log lives in stable memory next to the state it describes, which stays
+// authoritative: the log is not replayed on upgrades. `replay` folds it into
+// the config, deposits and withdrawals it describes, for auditors through
+// `get_events` or the `replay_events` query.
+// ==========================
+
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{memory_manager::MemoryId, StableLog};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+
+use crate::{
+    jettons::JettonConfig,
+    ledger_args::UpgradeArgs,
+    ledger_suite::WasmKind,
+    metadata::TokenMetadata,
+    state::{candid_storable, get_memory, Memory},
+    ton_api::{ProxyConfig, TonProvider},
+    types::{FinalityPolicy, TonNetwork, TonOutcallMode, TonWalletVersion},
+};
+
+const EVENT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const EVENT_DATA_MEMORY_ID: MemoryId = MemoryId::new(5);
+
+// Upper bound on the number of events returned by a single `get_events` call
+pub const MAX_EVENTS_PER_QUERY: u64 = 2000;
+
+thread_local! {
+    static EVENTS: RefCell<StableLog<Event, Memory, Memory>> = RefCell::new(
+        StableLog::init(get_memory(EVENT_INDEX_MEMORY_ID), get_memory(EVENT_DATA_MEMORY_ID))
+            .expect("failed to initialize the event log")
+    );
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct Event {
+    // IC time in nanoseconds when the event was recorded
+    pub timestamp: u64,
+    pub payload: EventType,
+}
+
+candid_storable!(Event);
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum EventType {
+    // Minter configuration changed, only the updated fields are set. API keys
+    // of providers and of the proxy are left out.
+    ConfigChanged {
+        ledger_canister: Option<Principal>,
+        indexer_canister: Option<Principal>,
+        ckton_transfer_fee: Option<u64>,
+        ton_fee: Option<u64>,
+        app_ton_address: Option<String>,
+        memo_deposits: Option<bool>,
+        // Hex hash of the highload wallet code cell, the code is not logged
+        highload_wallet_code_hash: Option<String>,
+        default_wallet_version: Option<TonWalletVersion>,
+        ton_providers: Option<Vec<TonProvider>>,
+        ton_providers_threshold: Option<u8>,
+        ton_outcall_mode: Option<TonOutcallMode>,
+        subnet_size: Option<u32>,
+        finality_policy: Option<FinalityPolicy>,
+        jettons: Option<Vec<JettonConfig>>,
+        ckton_metadata: Option<TokenMetadata>,
+        ton_network: Option<TonNetwork>,
+        schnorr_key_name: Option<String>,
+        proxy: Option<ProxyConfig>,
+        min_sweep_balance: Option<u64>,
+        max_accounts_per_scan: Option<u32>,
+    },
+    // A TON wallet of an account is active on chain
+    WalletDeployed {
+        account: Account,
+        ton_address: String,
+    },
+    // A deposit transfer into the minter wallet was broadcast on behalf of `from`
+    DepositSent {
+        from: Account,
+        to: Account,
+        amount: u64,
+        ton_address: String,
+        hash: String,
+    },
+    // The deposit transfer was found on chain
+    DepositSeen {
+        hash: String,
+    },
+    // An incoming transfer to the deposit address of `account` was detected
+    DepositDetected {
+        account: Account,
+        ton_address: String,
+        hash: String,
+        lt: u64,
+        amount: u64,
+    },
+    // The balance of a deposit wallet was moved into the minter wallet
+    DepositSwept {
+        account: Account,
+        hash: String,
+        amount: u64,
+    },
+    CkTonMinted {
+        to: Account,
+        amount: u64,
+        hash: String,
+        block_index: u64,
+    },
+    WithdrawalRequested {
+        caller: Principal,
+        to_ton_address: String,
+        amount: u64,
+    },
+    TonSent {
+        caller: Principal,
+        to_ton_address: String,
+        amount: u64,
+        burn_amount: u64,
+        hash: String,
+    },
+    BurnFinalized {
+        caller: Principal,
+        amount: u64,
+        hash: String,
+        block_index: u64,
+    },
+    // ckTON of a withdrawal was burned, the id is the burn block index
+    CkTonBurned {
+        withdrawal_id: u64,
+        from: Account,
+        to_ton_address: String,
+        amount: u64,
+        ton_amount: u64,
+    },
+    // The TON payout of a withdrawal was broadcast
+    WithdrawalSent {
+        withdrawal_id: u64,
+        hash: String,
+        seqno: u64,
+    },
+    // The payout message of a withdrawal expired before any provider accepted
+    // it, the withdrawal is pending again
+    WithdrawalRequeued {
+        withdrawal_id: u64,
+        reason: String,
+    },
+    // The TON payout of a withdrawal was found on chain
+    WithdrawalConfirmed {
+        withdrawal_id: u64,
+        tx_hash: String,
+    },
+    // The TON payout of a withdrawal could not be delivered
+    WithdrawalFailed {
+        withdrawal_id: u64,
+        reason: String,
+    },
+    // The burned ckTON of a failed withdrawal was minted back
+    WithdrawalReimbursed {
+        withdrawal_id: u64,
+        to: Account,
+        amount: u64,
+        block_index: u64,
+    },
+    // A jetton wallet of the minter announced a deposit for `account`
+    JettonDepositDetected {
+        symbol: String,
+        account: Account,
+        hash: String,
+        lt: u64,
+        amount: u64,
+    },
+    JettonMinted {
+        symbol: String,
+        to: Account,
+        amount: u64,
+        hash: String,
+        block_index: u64,
+    },
+    // Tokens of a jetton withdrawal were burned in `burn_block_index` of the
+    // jetton ledger, `ckton_gas` ckTON were burned for the TON attached to the
+    // payout
+    JettonBurned {
+        symbol: String,
+        withdrawal_id: u64,
+        burn_block_index: u64,
+        from: Account,
+        to_ton_address: String,
+        amount: u64,
+        jetton_amount: u64,
+        ckton_gas: Option<u64>,
+    },
+    // The burned tokens of a failed jetton withdrawal were minted back
+    JettonReimbursed {
+        symbol: String,
+        withdrawal_id: u64,
+        to: Account,
+        amount: u64,
+        block_index: u64,
+    },
+    // The ledger and index of a new token were created and installed
+    LedgerSuiteCreated {
+        token_symbol: String,
+        ledger: Principal,
+        index: Principal,
+        ledger_module_hash: Option<String>,
+        index_module_hash: Option<String>,
+    },
+    LedgerSuiteUpgraded {
+        token_symbol: String,
+        ledger_module_hash: Option<String>,
+        index_module_hash: Option<String>,
+    },
+    // An upgrade of the ckTON ledger or index, `error` is set when it failed
+    CkTonCanisterUpgraded {
+        kind: WasmKind,
+        canister: Principal,
+        upgrade_args: Option<UpgradeArgs>,
+        module_hash: Option<String>,
+        error: Option<String>,
+    },
+}
+
+impl EventType {
+    // Without API keys. Logs written before keys were left out of
+    // `ConfigChanged` still hold them, they are stripped when read.
+    fn redacted(self) -> Self {
+        match self {
+            EventType::ConfigChanged {
+                ledger_canister,
+                indexer_canister,
+                ckton_transfer_fee,
+                ton_fee,
+                app_ton_address,
+                memo_deposits,
+                highload_wallet_code_hash,
+                default_wallet_version,
+                ton_providers,
+                ton_providers_threshold,
+                ton_outcall_mode,
+                subnet_size,
+                finality_policy,
+                jettons,
+                ckton_metadata,
+                ton_network,
+                schnorr_key_name,
+                proxy,
+                min_sweep_balance,
+                max_accounts_per_scan,
+            } => EventType::ConfigChanged {
+                ledger_canister,
+                indexer_canister,
+                ckton_transfer_fee,
+                ton_fee,
+                app_ton_address,
+                memo_deposits,
+                highload_wallet_code_hash,
+                default_wallet_version,
+                ton_providers: ton_providers.map(|providers| providers.iter().map(TonProvider::redacted).collect()),
+                ton_providers_threshold,
+                ton_outcall_mode,
+                subnet_size,
+                finality_policy,
+                jettons,
+                ckton_metadata,
+                ton_network,
+                schnorr_key_name,
+                proxy: proxy.as_ref().map(ProxyConfig::redacted),
+                min_sweep_balance,
+                max_accounts_per_scan,
+            },
+            payload => payload,
+        }
+    }
+}
+
+pub fn record_event(payload: EventType) {
+    let event = Event {
+        timestamp: time(),
+        payload: payload.redacted(),
+    };
+    EVENTS.with_borrow_mut(|events| {
+        events.append(&event).expect("failed to append an event");
+    });
+}
+
+pub fn total_event_count() -> u64 {
+    EVENTS.with_borrow(|events| events.len())
+}
+
+pub fn get_events(start: u64, length: u64) -> Vec<Event> {
+    EVENTS.with_borrow(|events| {
+        let end = start
+            .saturating_add(length.min(MAX_EVENTS_PER_QUERY))
+            .min(events.len());
+        (start..end)
+            .filter_map(|idx| events.get(idx))
+            .map(|event| Event {
+                timestamp: event.timestamp,
+                payload: event.payload.redacted(),
+            })
+            .collect()
+    })
+}
+
+// State reconstructed from the event log. API keys are never logged, the
+// replayed providers and proxy carry none.
+#[derive(Debug, Default, CandidType, Deserialize)]
+pub struct ReplayedState {
+    pub config: crate::state::Config,
+    pub deployed_wallets: BTreeMap<Account, String>,
+    // Deposits broadcast or detected but not minted yet, keyed by the TON
+    // message hash
+    pub pending_mints: BTreeMap<String, (Account, u64)>,
+    // Minted deposits, keyed by the TON message hash
+    pub minted_deposits: BTreeMap<String, (Account, u64)>,
+    // TON payouts sent but not burned yet, keyed by the TON message hash
+    pub pending_burns: BTreeMap<String, (Principal, u64)>,
+    // Withdrawals by id
+    pub withdrawals: BTreeMap<u64, ReplayedWithdrawal>,
+    pub total_minted: u64,
+    pub total_burned: u64,
+    // Jettons minted and burned, by symbol
+    pub jetton_minted: BTreeMap<String, u64>,
+    pub jetton_burned: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct ReplayedWithdrawal {
+    pub from: Account,
+    // Tokens burned
+    pub amount: u64,
+    // Symbol of the jetton paid out, `None` for TON
+    pub jetton: Option<String>,
+    pub status: ReplayedWithdrawalStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum ReplayedWithdrawalStatus {
+    // Tokens burned, the payout was not sent yet
+    Burned,
+    Sent { hash: String },
+    Confirmed { tx_hash: String },
+    Failed { reason: String },
+    Reimbursed { block_index: u64 },
+}
+
+impl ReplayedState {
+    fn set_withdrawal_status(&mut self, withdrawal_id: u64, status: ReplayedWithdrawalStatus) {
+        if let Some(withdrawal) = self.withdrawals.get_mut(&withdrawal_id) {
+            withdrawal.status = status;
+        }
+    }
+}
+
+// Replays the whole log
+pub fn replay_log() -> ReplayedState {
+    EVENTS.with_borrow(|events| {
+        replay(events.iter().map(|event| Event {
+            timestamp: event.timestamp,
+            payload: event.payload.redacted(),
+        }))
+    })
+}
+
+pub fn replay(events: impl Iterator<Item = Event>) -> ReplayedState {
+    let mut state = ReplayedState::default();
+
+    for event in events {
+        match event.payload {
+            EventType::ConfigChanged {
+                ledger_canister,
+                indexer_canister,
+                ckton_transfer_fee,
+                ton_fee,
+                app_ton_address,
+                memo_deposits,
+                highload_wallet_code_hash: _,
+                default_wallet_version,
+                ton_providers,
+                ton_providers_threshold,
+                ton_outcall_mode,
+                subnet_size,
+                finality_policy,
+                jettons,
+                ckton_metadata,
+                ton_network,
+                schnorr_key_name,
+                proxy,
+                min_sweep_balance,
+                max_accounts_per_scan,
+            } => {
+                if let Some(ledger_canister) = ledger_canister {
+                    state.config.ledger_canister = ledger_canister;
+                }
+                if let Some(indexer_canister) = indexer_canister {
+                    state.config.indexer_canister = indexer_canister;
+                }
+                if let Some(ckton_transfer_fee) = ckton_transfer_fee {
+                    state.config.ckton_transfer_fee = ckton_transfer_fee;
+                }
+                if let Some(ton_fee) = ton_fee {
+                    state.config.ton_fee = ton_fee;
+                }
+                if app_ton_address.is_some() {
+                    state.config.app_ton_address = app_ton_address;
+                }
+                if memo_deposits.is_some() {
+                    state.config.memo_deposits = memo_deposits;
+                }
+                if default_wallet_version.is_some() {
+                    state.config.default_wallet_version = default_wallet_version;
+                }
+                if ton_providers.is_some() {
+                    state.config.ton_providers = ton_providers;
+                }
+                if ton_providers_threshold.is_some() {
+                    state.config.ton_providers_threshold = ton_providers_threshold;
+                }
+                if ton_outcall_mode.is_some() {
+                    state.config.ton_outcall_mode = ton_outcall_mode;
+                }
+                if subnet_size.is_some() {
+                    state.config.subnet_size = subnet_size;
+                }
+                if finality_policy.is_some() {
+                    state.config.finality_policy = finality_policy;
+                }
+                if jettons.is_some() {
+                    state.config.jettons = jettons;
+                }
+                if ckton_metadata.is_some() {
+                    state.config.ckton_metadata = ckton_metadata;
+                }
+                if ton_network.is_some() {
+                    state.config.ton_network = ton_network;
+                }
+                if schnorr_key_name.is_some() {
+                    state.config.schnorr_key_name = schnorr_key_name;
+                }
+                if proxy.is_some() {
+                    state.config.proxy = proxy;
+                }
+                if min_sweep_balance.is_some() {
+                    state.config.min_sweep_balance = min_sweep_balance;
+                }
+                if max_accounts_per_scan.is_some() {
+                    state.config.max_accounts_per_scan = max_accounts_per_scan;
+                }
+            }
+            EventType::WalletDeployed { account, ton_address } => {
+                state.deployed_wallets.insert(account, ton_address);
+            }
+            EventType::DepositSent { to, amount, hash, .. } => {
+                state.pending_mints.insert(hash, (to, amount));
+            }
+            EventType::DepositSeen { .. } => {}
+            EventType::DepositDetected { account, amount, hash, .. } => {
+                state.pending_mints.insert(hash, (account, amount));
+            }
+            EventType::DepositSwept { .. } => {}
+            EventType::CkTonMinted { to, amount, hash, .. } => {
+                state.pending_mints.remove(&hash);
+                state.minted_deposits.insert(hash, (to, amount));
+                state.total_minted += amount;
+            }
+            EventType::WithdrawalRequested { .. } => {}
+            EventType::TonSent { caller, burn_amount, hash, .. } => {
+                state.pending_burns.insert(hash, (caller, burn_amount));
+            }
+            EventType::BurnFinalized { amount, hash, .. } => {
+                state.pending_burns.remove(&hash);
+                state.total_burned += amount;
+            }
+            EventType::CkTonBurned { withdrawal_id, from, amount, .. } => {
+                state.withdrawals.insert(withdrawal_id, ReplayedWithdrawal {
+                    from,
+                    amount,
+                    jetton: None,
+                    status: ReplayedWithdrawalStatus::Burned,
+                });
+                state.total_burned += amount;
+            }
+            EventType::WithdrawalSent { withdrawal_id, hash, .. } => {
+                state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Sent { hash });
+            }
+            EventType::WithdrawalRequeued { withdrawal_id, .. } => {
+                state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Burned);
+            }
+            EventType::WithdrawalConfirmed { withdrawal_id, tx_hash } => {
+                state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Confirmed { tx_hash });
+            }
+            EventType::WithdrawalFailed { withdrawal_id, reason } => {
+                state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Failed { reason });
+            }
+            // Also recorded for the ckTON gas of a jetton withdrawal, which is
+            // resolved by `JettonReimbursed`
+            EventType::WithdrawalReimbursed { withdrawal_id, amount, block_index, .. } => {
+                if state.withdrawals.get(&withdrawal_id).is_some_and(|withdrawal| withdrawal.jetton.is_none()) {
+                    state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Reimbursed { block_index });
+                }
+                state.total_minted += amount;
+            }
+            EventType::JettonDepositDetected { account, hash, amount, .. } => {
+                state.pending_mints.insert(hash, (account, amount));
+            }
+            EventType::JettonMinted { symbol, to, amount, hash, .. } => {
+                state.pending_mints.remove(&hash);
+                state.minted_deposits.insert(hash, (to, amount));
+                *state.jetton_minted.entry(symbol).or_default() += amount;
+            }
+            EventType::JettonBurned { symbol, withdrawal_id, from, amount, ckton_gas, .. } => {
+                state.withdrawals.insert(withdrawal_id, ReplayedWithdrawal {
+                    from,
+                    amount,
+                    jetton: Some(symbol.clone()),
+                    status: ReplayedWithdrawalStatus::Burned,
+                });
+                *state.jetton_burned.entry(symbol).or_default() += amount;
+                state.total_burned += ckton_gas.unwrap_or_default();
+            }
+            EventType::JettonReimbursed { symbol, withdrawal_id, amount, block_index, .. } => {
+                state.set_withdrawal_status(withdrawal_id, ReplayedWithdrawalStatus::Reimbursed { block_index });
+                *state.jetton_minted.entry(symbol).or_default() += amount;
+            }
+            // Ledger suites are kept in their own stable map
+            EventType::LedgerSuiteCreated { .. } | EventType::LedgerSuiteUpgraded { .. } => {}
+            // The ledger charges the new fee, so the minter keeps it from deposits
+            EventType::CkTonCanisterUpgraded { upgrade_args, error: None, .. } => {
+                if let Some(fee) = upgrade_args
+                    .and_then(|args| args.transfer_fee)
+                    .and_then(|fee| u64::try_from(fee.0).ok())
+                {
+                    state.config.ckton_transfer_fee = fee;
+                }
+            }
+            EventType::CkTonCanisterUpgraded { .. } => {}
+        }
+    }
+
+    state
+}
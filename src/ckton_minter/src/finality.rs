@@ -0,0 +1,178 @@
+// ==========================
+// ckton_minter/src/finality.rs
+// Finality policy of deposits.
+// A deposit is only minted once it is final under the configured policy.
+// Deposits waiting for finality are kept in stable memory with the
+// masterchain block of their transaction, which masterchain confirmations are
+// counted from, and why their proof failed under the block proof policy.
+// ==========================
+
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_cdk::api::time;
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use ic_ton_lib::TonAddress;
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+
+use crate::{
+    cycles::Operation,
+    deposits::TonTxId,
+    lightclient, nanos_to_seconds,
+    state::{candid_storable, get_memory, read_config, Memory},
+    ton_api,
+    types::FinalityPolicy,
+};
+
+const AWAITING_FINALITY_MEMORY_ID: MemoryId = MemoryId::new(15);
+
+thread_local! {
+    // Deposits seen on chain that are not final yet
+    static AWAITING_FINALITY: RefCell<StableBTreeMap<TonTxId, PendingDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AWAITING_FINALITY_MEMORY_ID)));
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct PendingDeposit {
+    pub account: Account,
+    pub tx_hash: String,
+    pub lt: u64,
+    // Value of the transfer in nanotons, before the fee
+    pub amount: u64,
+    pub utime: u64,
+    // First masterchain block generated at or after the transaction, resolved
+    // once from its time
+    pub tx_mc_seqno: Option<u32>,
+    // Masterchain head at the last check
+    pub checked_mc_seqno: Option<u32>,
+    // Policy of the last check
+    pub policy: FinalityPolicy,
+    // Why the transaction couldn't be proven at the last check
+    pub proof_error: Option<String>,
+}
+
+candid_storable!(PendingDeposit);
+
+// A deposit found on chain, before the policy is checked
+pub struct Deposit {
+    pub account: Account,
+    // Address whose transaction it is
+    pub ton_address: String,
+    pub tx_id: TonTxId,
+    pub utime: u64,
+    pub amount: u64,
+}
+
+// Head of the masterchain as verified by the light client, or as known to
+// every provider when the light client is not seeded
+async fn masterchain_head() -> Result<u32, String> {
+    if let Some(seqno) = lightclient::verified_head()? {
+        return Ok(seqno);
+    }
+
+    ton_api::get_masterchain_info(Operation::Deposit)
+        .await?
+        .iter()
+        .map(|info| info.last.seqno)
+        .min()
+        .ok_or("No masterchain head".to_string())
+}
+
+// Masterchain block of a transaction at `utime`. The latest block any provider
+// returns is taken, so providers can only make the deposit wait longer.
+async fn transaction_mc_seqno(utime: u64) -> Result<u32, String> {
+    ton_api::lookup_masterchain_block(utime, Operation::Deposit)
+        .await?
+        .iter()
+        .map(|block| block.seqno)
+        .max()
+        .ok_or(format!("No masterchain block at {}", utime))
+}
+
+// Whether the deposit can be minted under the current policy. A deposit that
+// isn't final is remembered until `forget` is called for it. `head` caches the
+// masterchain head across the deposits of one scan.
+pub async fn is_final(deposit: &Deposit, head: &mut Option<u32>) -> Result<bool, String> {
+    let policy = read_config(|config| config.finality_policy());
+
+    let mut pending = AWAITING_FINALITY
+        .with_borrow(|awaiting| awaiting.get(&deposit.tx_id))
+        .unwrap_or_else(|| PendingDeposit {
+            account: deposit.account,
+            tx_hash: deposit.tx_id.hash_base64(),
+            lt: deposit.tx_id.lt,
+            amount: deposit.amount,
+            utime: deposit.utime,
+            tx_mc_seqno: None,
+            checked_mc_seqno: None,
+            policy,
+            proof_error: None,
+        });
+
+    pending.policy = policy;
+
+    let is_final = match policy {
+        FinalityPolicy::Immediate => true,
+        FinalityPolicy::MinAgeSecs(secs) => nanos_to_seconds(time()) as u64 >= deposit.utime.saturating_add(secs),
+        FinalityPolicy::MasterchainBlocks(blocks) => {
+            let seqno = match head {
+                Some(seqno) => *seqno,
+                None => *head.insert(masterchain_head().await?),
+            };
+
+            let tx_seqno = match pending.tx_mc_seqno {
+                Some(tx_seqno) => tx_seqno,
+                None => *pending.tx_mc_seqno.insert(transaction_mc_seqno(deposit.utime).await?),
+            };
+            pending.checked_mc_seqno = Some(seqno);
+
+            seqno >= tx_seqno.saturating_add(blocks)
+        }
+        FinalityPolicy::BlockProof => {
+            let address = deposit
+                .ton_address
+                .parse::<TonAddress>()
+                .map_err(|e| format!("Invalid address {}: {}", deposit.ton_address, e))?;
+
+            let proof = lightclient::prove_transaction(
+                &address,
+                deposit.tx_id.lt,
+                &deposit.tx_id.hash,
+                deposit.utime,
+                Operation::Deposit,
+            )
+            .await;
+
+            pending.proof_error = proof.err();
+            pending.proof_error.is_none()
+        }
+    };
+
+    if !is_final {
+        remember(deposit.tx_id, pending);
+    }
+
+    Ok(is_final)
+}
+
+// Remembers a deposit waiting for finality, pub for the tests
+pub fn remember(tx_id: TonTxId, pending: PendingDeposit) {
+    AWAITING_FINALITY.with_borrow_mut(|awaiting| awaiting.insert(tx_id, pending));
+}
+
+// Drops a deposit that was credited or ignored
+pub fn forget(tx_id: &TonTxId) {
+    AWAITING_FINALITY.with_borrow_mut(|awaiting| awaiting.remove(tx_id));
+}
+
+// Deposits of the account waiting for finality, oldest first
+pub fn pending_deposits(account: &Account) -> Vec<PendingDeposit> {
+    AWAITING_FINALITY.with_borrow(|awaiting| {
+        awaiting
+            .iter()
+            .filter(|(_, pending)| pending.account == *account)
+            .map(|(_, pending)| pending)
+            .collect()
+    })
+}
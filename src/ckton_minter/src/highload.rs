@@ -0,0 +1,264 @@
+// ==========================
+// ckton_minter/src/highload.rs
+// Highload Wallet V3 used as the minter hot wallet.
+// Replay protection is based on query ids instead of a seqno, and one external
+// message pays out up to 254 transfers through an `internal_transfer` the
+// wallet sends to itself. The contract code is supplied by the operator
+// (`highload_wallet_code` in the config) as a base64 BOC.
+// ==========================
+
+use std::sync::Arc;
+
+use ic_ton_lib::{
+    cell::{ArcCell, BagOfCells, Cell, CellBuilder},
+    num_bigint::BigUint,
+    types::ICSigner,
+    TonAddress,
+};
+
+use crate::{
+    consts::{HIGHLOAD_CREATED_AT_LAG_SECS, HIGHLOAD_SUBWALLET_ID, HIGHLOAD_TIMEOUT_SECS},
+    types::ICTonSigner,
+};
+
+// internal_transfer#ae42e5a4 query_id:uint64 actions:^OutList = InternalMsgBody
+const INTERNAL_TRANSFER_OP: u32 = 0xae42_e5a4;
+// action_send_msg#0ec3c86d mode:uint8 out_msg:^MessageRelaxed = OutAction
+const ACTION_SEND_MSG_OP: u32 = 0x0ec3_c86d;
+// Send mode of the payouts: pay the fees separately, ignore errors
+pub const PAYOUT_SEND_MODE: u8 = 3;
+// Send mode of the `internal_transfer` to itself: carry the whole balance
+const INTERNAL_TRANSFER_SEND_MODE: u8 = 128;
+
+// Number of payouts one external message can carry
+pub const MAX_MESSAGES_PER_EXTERNAL: usize = 254;
+
+// Query id of a highload wallet: shift:uint13 bit_number:uint10, bit_number < 1023
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueryId {
+    pub shift: u16,
+    pub bit_number: u16,
+}
+
+impl QueryId {
+    const MAX_SHIFT: u16 = (1 << 13) - 1;
+    const MAX_BIT_NUMBER: u16 = 1022;
+
+    // The query id after this one, wrapping around once every id was used.
+    // Ids are only rejected as replays within the wallet timeout.
+    pub fn next(&self) -> Self {
+        if self.bit_number < Self::MAX_BIT_NUMBER {
+            Self { shift: self.shift, bit_number: self.bit_number + 1 }
+        } else if self.shift < Self::MAX_SHIFT {
+            Self { shift: self.shift + 1, bit_number: 0 }
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        ((self.shift as u64) << 10) | self.bit_number as u64
+    }
+
+    pub fn from_u64(query_id: u64) -> Self {
+        Self {
+            shift: ((query_id >> 10) as u16) & Self::MAX_SHIFT,
+            bit_number: (query_id & 0x3ff) as u16,
+        }
+    }
+}
+
+// Hex hash of the code cell, as logged instead of the code itself
+pub fn code_hash(code_boc: &str) -> Result<String, String> {
+    let code = BagOfCells::parse_base64(code_boc)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| format!("Invalid highload wallet code: {}", e))?;
+
+    Ok(hex::encode(code.cell_hash()))
+}
+
+pub struct HighloadWalletV3 {
+    pub address: TonAddress,
+    signer: ICTonSigner,
+    code: ArcCell,
+}
+
+impl HighloadWalletV3 {
+    pub fn new(signer: ICTonSigner, code_boc: &str) -> Result<Self, String> {
+        let code = BagOfCells::parse_base64(code_boc)
+            .and_then(|boc| boc.single_root())
+            .map_err(|e| format!("Invalid highload wallet code: {}", e))?;
+
+        let mut wallet = Self {
+            address: TonAddress::NULL,
+            signer,
+            code,
+        };
+        wallet.address = TonAddress::new(0, &wallet.state_init()?.cell_hash());
+
+        Ok(wallet)
+    }
+
+    // public_key:bits256 subwallet_id:uint32 old_queries:(HashmapE 14 ^Cell)
+    // queries:(HashmapE 14 ^Cell) last_clean_time:uint64 timeout:uint22
+    fn data(&self) -> Result<Cell, String> {
+        CellBuilder::new()
+            .store_slice(self.signer.public_key())
+            .and_then(|b| b.store_u32(32, HIGHLOAD_SUBWALLET_ID))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_u64(64, 0))
+            .and_then(|b| b.store_u32(22, HIGHLOAD_TIMEOUT_SECS))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())
+    }
+
+    // split_depth:none special:none code:^Cell data:^Cell library:none
+    fn state_init(&self) -> Result<Cell, String> {
+        let data = self.data()?;
+
+        CellBuilder::new()
+            .store_bit(false)
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_bit(true))
+            .and_then(|b| b.store_reference(&self.code))
+            .and_then(|b| b.store_bit(true))
+            .and_then(|b| b.store_reference(&Arc::new(data)))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())
+    }
+
+    // Internal message to the wallet itself whose body makes the wallet send
+    // `messages` with `send_mode` in its action phase
+    pub fn internal_transfer(&self, messages: Vec<ArcCell>, send_mode: u8, query_id: QueryId) -> Result<Cell, String> {
+        if messages.len() > MAX_MESSAGES_PER_EXTERNAL {
+            return Err(format!("At most {} messages per external", MAX_MESSAGES_PER_EXTERNAL));
+        }
+
+        // out_list$_ prev:^OutList action:OutAction, starting from the empty list
+        let mut actions = Cell::default();
+        for message in messages {
+            actions = CellBuilder::new()
+                .store_reference(&Arc::new(actions))
+                .and_then(|b| b.store_u32(32, ACTION_SEND_MSG_OP))
+                .and_then(|b| b.store_u8(8, send_mode))
+                .and_then(|b| b.store_reference(&message))
+                .and_then(|b| b.build())
+                .map_err(|e| e.to_string())?;
+        }
+
+        let body = CellBuilder::new()
+            .store_u32(32, INTERNAL_TRANSFER_OP)
+            .and_then(|b| b.store_u64(64, query_id.to_u64()))
+            .and_then(|b| b.store_reference(&Arc::new(actions)))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())?;
+
+        // int_msg_info$0 ihr_disabled:1 bounce:0 bounced:0 src:none dest value:0
+        // ihr_fee:0 fwd_fee:0 created_lt:0 created_at:0 init:none body:^Cell
+        CellBuilder::new()
+            .store_bit(false)
+            .and_then(|b| b.store_bit(true))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_address(&TonAddress::NULL))
+            .and_then(|b| b.store_address(&self.address))
+            .and_then(|b| b.store_coins(&BigUint::ZERO))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_coins(&BigUint::ZERO))
+            .and_then(|b| b.store_coins(&BigUint::ZERO))
+            .and_then(|b| b.store_u64(64, 0))
+            .and_then(|b| b.store_u32(32, 0))
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_bit(true))
+            .and_then(|b| b.store_reference(&Arc::new(body)))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())
+    }
+
+    // Signs an external message sending out `messages` with `send_mode`. The message is accepted
+    // until `created_at + HIGHLOAD_TIMEOUT_SECS`, see `created_at`.
+    pub async fn create_external(
+        &self,
+        messages: Vec<ArcCell>,
+        send_mode: u8,
+        query_id: QueryId,
+        created_at: u32,
+        with_state_init: bool,
+    ) -> Result<Cell, String> {
+        let inner = self.signed_part(messages, send_mode, query_id, created_at)?;
+
+        let signature = self.signer.sign(&inner.cell_hash()).await?;
+
+        self.external(&signature, inner, with_state_init)
+    }
+
+    // The part of the external message the signature covers. Public for the
+    // tests, which check the layout without signing.
+    // subwallet_id:uint32 message_to_send:^Cell send_mode:uint8
+    // query_id:QueryId created_at:uint64 timeout:uint22
+    pub fn signed_part(&self, messages: Vec<ArcCell>, send_mode: u8, query_id: QueryId, created_at: u32) -> Result<Cell, String> {
+        let message_to_send = self.internal_transfer(messages, send_mode, query_id)?;
+
+        CellBuilder::new()
+            .store_u32(32, HIGHLOAD_SUBWALLET_ID)
+            .and_then(|b| b.store_reference(&Arc::new(message_to_send)))
+            .and_then(|b| b.store_u8(8, INTERNAL_TRANSFER_SEND_MODE))
+            .and_then(|b| b.store_u32(13, query_id.shift as u32))
+            .and_then(|b| b.store_u32(10, query_id.bit_number as u32))
+            .and_then(|b| b.store_u64(64, created_at as u64))
+            .and_then(|b| b.store_u32(22, HIGHLOAD_TIMEOUT_SECS))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())
+    }
+
+    // The external message carrying `inner` and its signature
+    // ext_in_msg_info$10 src:addr_none dest import_fee:0 init:(Maybe ^StateInit)
+    // body:^[ signature:bits512 ^inner ]
+    pub fn external(&self, signature: &[u8], inner: Cell, with_state_init: bool) -> Result<Cell, String> {
+        let body = CellBuilder::new()
+            .store_slice(signature)
+            .and_then(|b| b.store_reference(&Arc::new(inner)))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())?;
+
+        let mut builder = CellBuilder::new();
+        builder
+            .store_u8(2, 0b10)
+            .and_then(|b| b.store_address(&TonAddress::NULL))
+            .and_then(|b| b.store_address(&self.address))
+            .and_then(|b| b.store_coins(&BigUint::ZERO))
+            .map_err(|e| e.to_string())?;
+
+        if with_state_init {
+            let state_init = self.state_init()?;
+            builder
+                .store_bit(true)
+                .and_then(|b| b.store_bit(true))
+                .and_then(|b| b.store_reference(&Arc::new(state_init)))
+                .map_err(|e| e.to_string())?;
+        } else {
+            builder.store_bit(false).map_err(|e| e.to_string())?;
+        }
+
+        builder
+            .store_bit(true)
+            .and_then(|b| b.store_reference(&Arc::new(body)))
+            .and_then(|b| b.build())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// `created_at` of a message signed at `now` (seconds). The message is
+// backdated so that, once it expired, its query id is still recorded by the
+// wallet for `HIGHLOAD_CREATED_AT_LAG_SECS` if it was processed, which makes
+// the `processed?` get-method conclusive in that window.
+pub fn created_at(now: u32) -> u32 {
+    now.saturating_sub(HIGHLOAD_CREATED_AT_LAG_SECS)
+}
+
+// Last second a message created at `created_at` is accepted
+pub fn expire_at(created_at: u32) -> u32 {
+    created_at + HIGHLOAD_TIMEOUT_SECS
+}
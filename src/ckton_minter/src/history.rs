@@ -0,0 +1,235 @@
+// ==========================
+// ckton_minter/src/history.rs
+// Paginated walk through the transaction history of TON accounts.
+// Scans keep a cursor per address in stable memory: they resume where the
+// previous one stopped and only return transactions that were not scanned yet.
+// Lookups of a sent message keep a cursor per message hash, so that a retried
+// lookup only reads the transactions that landed since the previous one.
+// ==========================
+
+use std::{cell::RefCell, future::Future};
+
+use candid::CandidType;
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use serde::Deserialize;
+
+use crate::{
+    consts::{MAX_TX_PAGES_PER_LOOKUP, MAX_TX_PAGES_PER_SCAN, TX_PAGE_SIZE},
+    cycles::Operation,
+    state::{candid_storable, get_memory, Memory},
+    ton_api::{self, TonApiError, TonTransaction, TonTransactionId},
+};
+
+const SCAN_CURSORS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const LOOKUP_CURSORS_MEMORY_ID: MemoryId = MemoryId::new(21);
+
+thread_local! {
+    // Scan cursor by TON address
+    static SCAN_CURSORS: RefCell<StableBTreeMap<String, ScanCursor, Memory>> = RefCell::new(
+        StableBTreeMap::init(get_memory(SCAN_CURSORS_MEMORY_ID))
+    );
+
+    // Logical time down to which the history was searched, by message hash
+    static LOOKUP_CURSORS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(get_memory(LOOKUP_CURSORS_MEMORY_ID))
+    );
+}
+
+// How far the history of an address was scanned
+#[derive(Debug, Clone, Default, PartialEq, CandidType, Deserialize)]
+pub struct ScanCursor {
+    // Every transaction up to this logical time was scanned
+    pub synced_lt: u64,
+    // A walk from the newest transactions down to `synced_lt` that ran out of
+    // pages, it continues before `next`
+    pub walk: Option<Walk>,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct Walk {
+    // Newest transaction of the walk, `synced_lt` once the walk completes
+    pub top_lt: u64,
+    // Oldest transaction returned so far
+    pub next: TonTransactionId,
+}
+
+candid_storable!(ScanCursor);
+
+// Transactions returned by a scan, and the cursor to store once they were all
+// handled
+pub struct Scan {
+    pub transactions: Vec<TonTransaction>,
+    pub cursor: ScanCursor,
+}
+
+fn lt(tx: &TonTransaction) -> u64 {
+    tx.transaction_id.lt.parse::<u64>().unwrap_or_default()
+}
+
+pub fn scan_cursor(address: &str) -> Option<ScanCursor> {
+    SCAN_CURSORS.with_borrow(|cursors| cursors.get(&address.to_string()))
+}
+
+// Stores the cursor of a scan whose transactions were all handled
+pub fn commit_scan(address: &str, cursor: ScanCursor) {
+    SCAN_CURSORS.with_borrow_mut(|cursors| cursors.insert(address.to_string(), cursor));
+}
+
+// Transactions of `address` not scanned yet, newest first, at most
+// `MAX_TX_PAGES_PER_SCAN` pages. The stored cursor is left untouched, see
+// `commit_scan`.
+pub async fn scan_new_transactions(address: &str, operation: Operation) -> Result<Scan, TonApiError> {
+    scan_new_transactions_with(address, |from, to_lt| {
+        ton_api::get_ton_transactions_page(address.to_string(), from, to_lt, TX_PAGE_SIZE, operation)
+    })
+    .await
+}
+
+// Same as `scan_new_transactions` with the pages returned by `get_page`, from a
+// transaction (included) back to a logical time (excluded). Public for the
+// tests, which replace the providers.
+pub async fn scan_new_transactions_with<F, Fut>(address: &str, get_page: F) -> Result<Scan, TonApiError>
+where
+    F: Fn(Option<TonTransactionId>, Option<u64>) -> Fut,
+    Fut: Future<Output = Result<Vec<TonTransaction>, TonApiError>>,
+{
+    let cursor = scan_cursor(address).unwrap_or_default();
+
+    let (mut top_lt, mut next) = match &cursor.walk {
+        Some(walk) => (Some(walk.top_lt), Some(walk.next.clone())),
+        None => (None, None),
+    };
+
+    let to_lt = Some(cursor.synced_lt).filter(|lt| *lt > 0);
+
+    let mut transactions = vec![];
+
+    for _ in 0..MAX_TX_PAGES_PER_SCAN {
+        let page = get_page(next.clone(), to_lt).await?;
+
+        // A page starts with the transaction it was requested from
+        let page: Vec<TonTransaction> = page
+            .into_iter()
+            .filter(|tx| lt(tx) > cursor.synced_lt)
+            .filter(|tx| next.as_ref().map_or(true, |next| tx.transaction_id != *next))
+            .collect();
+
+        // Only a page requested from a known transaction is the same for every
+        // provider, an empty one means the walk reached `synced_lt`
+        let Some(oldest) = page.last() else {
+            return Ok(Scan {
+                transactions,
+                cursor: ScanCursor {
+                    synced_lt: top_lt.unwrap_or(cursor.synced_lt),
+                    walk: None,
+                },
+            });
+        };
+
+        top_lt.get_or_insert(lt(&page[0]));
+        next = Some(oldest.transaction_id.clone());
+
+        transactions.extend(page);
+    }
+
+    Ok(Scan {
+        transactions,
+        cursor: ScanCursor {
+            synced_lt: cursor.synced_lt,
+            walk: top_lt.zip(next).map(|(top_lt, next)| Walk { top_lt, next }),
+        },
+    })
+}
+
+// Transactions of `address` newest first, back to the first one older than
+// `since` (unix seconds) or `MAX_TX_PAGES_PER_LOOKUP` pages. The flag is set
+// when the list reaches back to `since` or to the first transaction.
+pub async fn transactions_since(address: &str, since: u64, operation: Operation) -> Result<(Vec<TonTransaction>, bool), TonApiError> {
+    let mut transactions: Vec<TonTransaction> = vec![];
+    let mut next: Option<TonTransactionId> = None;
+
+    for _ in 0..MAX_TX_PAGES_PER_LOOKUP {
+        let page = ton_api::get_ton_transactions_page(address.to_string(), next.clone(), None, TX_PAGE_SIZE, operation).await?;
+
+        let page: Vec<TonTransaction> = page
+            .into_iter()
+            .filter(|tx| next.as_ref().map_or(true, |next| tx.transaction_id != *next))
+            .collect();
+
+        // Providers may disagree on the newest page, only an empty page after a
+        // known transaction shows the history is exhausted
+        let Some(oldest) = page.last() else {
+            return Ok((transactions, next.is_some()));
+        };
+
+        let reached = oldest.utime < since;
+        next = Some(oldest.transaction_id.clone());
+
+        transactions.extend(page);
+
+        if reached {
+            return Ok((transactions, true));
+        }
+    }
+
+    Ok((transactions, false))
+}
+
+// The transaction of `address` whose incoming message hashes to `hash`. Pages
+// are read newest first and the walk stops at the first match, or at the
+// transactions a previous lookup of the same message already searched. At
+// most `MAX_TX_PAGES_PER_LOOKUP` pages are read.
+pub async fn find_transaction(address: &str, hash: &str, operation: Operation) -> Result<Option<TonTransaction>, TonApiError> {
+    find_transaction_with(hash, |from, to_lt| {
+        ton_api::get_ton_transactions_page(address.to_string(), from, to_lt, TX_PAGE_SIZE, operation)
+    })
+    .await
+}
+
+// Same as `find_transaction` with the pages returned by `get_page`, see
+// `scan_new_transactions_with`
+pub async fn find_transaction_with<F, Fut>(hash: &str, get_page: F) -> Result<Option<TonTransaction>, TonApiError>
+where
+    F: Fn(Option<TonTransactionId>, Option<u64>) -> Fut,
+    Fut: Future<Output = Result<Vec<TonTransaction>, TonApiError>>,
+{
+    let searched_lt = LOOKUP_CURSORS.with_borrow(|cursors| cursors.get(&hash.to_string())).unwrap_or_default();
+    let to_lt = Some(searched_lt).filter(|lt| *lt > 0);
+
+    let mut top_lt = None;
+    let mut next: Option<TonTransactionId> = None;
+
+    for _ in 0..MAX_TX_PAGES_PER_LOOKUP {
+        let page = get_page(next.clone(), to_lt).await?;
+
+        let page: Vec<TonTransaction> = page
+            .into_iter()
+            .filter(|tx| lt(tx) > searched_lt)
+            .filter(|tx| next.as_ref().map_or(true, |next| tx.transaction_id != *next))
+            .collect();
+
+        if let Some(tx) = page.iter().find(|tx| tx.in_msg.hash == hash) {
+            forget_lookup(hash);
+            return Ok(Some(tx.clone()));
+        }
+
+        // Only a page requested from a known transaction is the same for every
+        // provider, an empty one means the walk reached the searched ones
+        let Some(oldest) = page.last() else {
+            if let Some(top_lt) = top_lt {
+                LOOKUP_CURSORS.with_borrow_mut(|cursors| cursors.insert(hash.to_string(), top_lt));
+            }
+            return Ok(None);
+        };
+
+        top_lt.get_or_insert(lt(&page[0]));
+        next = Some(oldest.transaction_id.clone());
+    }
+
+    Ok(None)
+}
+
+// Drops the cursor of a lookup that found its transaction or was given up
+pub fn forget_lookup(hash: &str) {
+    LOOKUP_CURSORS.with_borrow_mut(|cursors| cursors.remove(&hash.to_string()));
+}
@@ -0,0 +1,375 @@
+// ==========================
+// ckton_minter/src/jettons.rs
+// Bridging of TEP-74 jettons, e.g. USDT as ckUSDT.
+// Every configured jetton has its own ICRC ledger. Jettons are deposited to
+// the jetton wallet of the minter, which notifies the minter wallet with a
+// `transfer_notification` carrying the target account in its forward payload.
+// Withdrawals are paid out with `transfer` messages sent by the minter wallet
+// to its jetton wallet, in the same batches as TON payouts. A payout is only
+// done once the jetton wallet forwarded the jettons in an `internal_transfer`.
+// ==========================
+
+use std::sync::Arc;
+
+use candid::{CandidType, Principal};
+use ic_ton_lib::{
+    cell::{BagOfCells, Cell, CellBuilder},
+    num_bigint::BigUint,
+    TonAddress,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cycles::Operation,
+    deposits::{self, MintedDeposit, TonTxId},
+    events::{record_event, EventType},
+    history, ledger_suite, memo,
+    state::read_config,
+    ton_api::{self, StackArg, TonTransaction, TonTransactionMessage},
+    transaction,
+};
+
+// transfer#0f8a7ea5 query_id:uint64 amount:(VarUInteger 16) destination:MsgAddress
+// response_destination:MsgAddress custom_payload:(Maybe ^Cell)
+// forward_ton_amount:(VarUInteger 16) forward_payload:(Either Cell ^Cell)
+pub const JETTON_TRANSFER_OP: u32 = 0x0f8a7ea5;
+// transfer_notification#7362d09c query_id:uint64 amount:(VarUInteger 16)
+// sender:MsgAddress forward_payload:(Either Cell ^Cell)
+pub const JETTON_TRANSFER_NOTIFICATION_OP: u32 = 0x7362d09c;
+// internal_transfer#178d4519 query_id:uint64 amount:(VarUInteger 16) from:MsgAddress
+// response_address:MsgAddress forward_ton_amount:(VarUInteger 16)
+// forward_payload:(Either Cell ^Cell)
+pub const JETTON_INTERNAL_TRANSFER_OP: u32 = 0x178d4519;
+// Prefix of a bounced body, followed by the first 256 bits of the original one
+const BOUNCED_OP: u32 = 0xffffffff;
+
+// TON attached to a payout `transfer`, the excess comes back to the minter
+// wallet. It is charged to the user in ckTON, see `withdrawals::withdraw_jetton`.
+pub const JETTON_TRANSFER_TON_AMOUNT: u64 = 50_000_000;
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct JettonConfig {
+    // Name of the ck token, e.g. "ckUSDT", used to pick the jetton on withdrawal
+    pub symbol: String,
+    // Address of the jetton master contract
+    pub master_address: String,
+    // ICRC ledger of the ck token
+    pub ledger_canister: Principal,
+    pub decimals: u8,
+    // Fee of the ck ledger, kept from every deposit
+    pub transfer_fee: u64,
+    // Kept from every withdrawal, in jettons
+    pub withdrawal_fee: u64,
+    // Jetton wallet of the minter, derived from the master by `admin_setup`
+    pub minter_wallet: Option<String>,
+}
+
+pub fn jettons() -> Vec<JettonConfig> {
+    read_config(|config| config.jettons())
+}
+
+pub fn jetton(symbol: &str) -> Result<JettonConfig, String> {
+    jettons()
+        .into_iter()
+        .find(|jetton| jetton.symbol == symbol)
+        .ok_or(format!("Unknown jetton {}", symbol))
+}
+
+// The jetton wallet of the minter, where payouts are sent from
+pub fn minter_wallet(jetton: &JettonConfig) -> Result<TonAddress, String> {
+    jetton
+        .minter_wallet
+        .as_ref()
+        .ok_or(format!("Jetton wallet of {} is not known", jetton.symbol))?
+        .parse::<TonAddress>()
+        .map_err(|e| e.to_string())
+}
+
+// Checks the jettons of an admin setup, links them to their ledger suites and
+// derives the jetton wallet of the minter wallet `owner` for each of them
+pub async fn resolve_jettons(jettons: Vec<JettonConfig>, owner: &TonAddress) -> Result<Vec<JettonConfig>, String> {
+    let mut resolved: Vec<JettonConfig> = vec![];
+
+    for mut jetton in jettons {
+        if jetton.symbol.is_empty() || resolved.iter().any(|other| other.symbol == jetton.symbol) {
+            return Err(format!("Jetton symbols must be unique and not empty: {:?}", jetton.symbol));
+        }
+
+        // An anonymous ledger stands for the suite created for the symbol
+        if jetton.ledger_canister == Principal::anonymous() {
+            jetton.ledger_canister = ledger_suite::installed_ledger(&jetton.symbol)?;
+        }
+
+        jetton.minter_wallet = Some(wallet_address(&jetton.master_address, owner).await?.to_base64_url());
+        resolved.push(jetton);
+    }
+
+    Ok(resolved)
+}
+
+// Jetton wallet of `owner`, through the `get_wallet_address` get-method of
+// the jetton master
+async fn wallet_address(master_address: &str, owner: &TonAddress) -> Result<TonAddress, String> {
+    let result = ton_api::run_get_method(
+        master_address.to_string(),
+        "get_wallet_address",
+        vec![StackArg::Address(owner.clone())],
+        Operation::Other,
+    )
+    .await?;
+
+    if result.exit_code != 0 {
+        return Err(format!("get_wallet_address failed with exit code {}", result.exit_code));
+    }
+
+    // ["cell", { "bytes": <base64 BOC> }]
+    let bytes = result
+        .stack
+        .first()
+        .and_then(|(_, value)| value.get("bytes"))
+        .and_then(|bytes| bytes.as_str())
+        .ok_or("get_wallet_address returned no slice".to_string())?;
+
+    let root = BagOfCells::parse_base64(bytes)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| e.to_string())?;
+
+    root.parser().load_address().map_err(|e| e.to_string())
+}
+
+// A jetton deposit announced by a `transfer_notification`
+pub struct JettonDeposit {
+    pub jetton: JettonConfig,
+    pub account: Account,
+    pub amount: u64,
+}
+
+// Decodes the jetton deposit carried by an incoming message. Only
+// notifications sent by a jetton wallet of the minter are genuine, any
+// contract can send the same body.
+pub fn deposit_from_message(msg: &TonTransactionMessage) -> Option<Result<JettonDeposit, String>> {
+    let source = msg.source.parse::<TonAddress>().ok()?;
+
+    let jetton = jettons()
+        .into_iter()
+        .find(|jetton| minter_wallet(jetton).is_ok_and(|wallet| wallet == source))?;
+
+    let body = msg.msg_data.as_ref().and_then(|data| data.body.as_ref())?;
+
+    Some(decode_notification(body).map(|(amount, account)| JettonDeposit { jetton, account, amount }))
+}
+
+// Amount and target account of a `transfer_notification` body
+pub fn decode_notification(body: &str) -> Result<(u64, Account), String> {
+    let root = BagOfCells::parse_base64(body)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| e.to_string())?;
+
+    let mut parser = root.parser();
+
+    let op = parser.load_u32(32).map_err(|e| e.to_string())?;
+    if op != JETTON_TRANSFER_NOTIFICATION_OP {
+        return Err(format!("Not a transfer notification: {:#x}", op));
+    }
+
+    parser.skip_bits(64).map_err(|e| e.to_string())?;
+    let amount = parser.load_coins().map_err(|e| e.to_string())?;
+    let amount = u64::try_from(amount).map_err(|_| "Jetton amount too large".to_string())?;
+    parser.load_address().map_err(|e| e.to_string())?;
+
+    let payload = if parser.load_bit().map_err(|e| e.to_string())? {
+        root.reference(0).map_err(|e| e.to_string())?.clone()
+    } else {
+        Arc::new(transaction::remainder(&root, &mut parser, 0)?)
+    };
+
+    Ok((amount, memo::account_from_cell(&payload)?))
+}
+
+// Mints a jetton deposit on the ledger of the jetton, deposits that don't
+// cover the fee are ignored
+pub async fn mint_deposit(deposit: &JettonDeposit, tx_id: TonTxId, utime: u64) -> Result<Option<MintedDeposit>, String> {
+    let amount = deposit.amount.saturating_sub(deposit.jetton.transfer_fee);
+
+    if amount == 0 {
+        ic_cdk::println!("Deposit {} of {} {} is below the fee", tx_id.hash_base64(), deposit.amount, deposit.jetton.symbol);
+        return Ok(None);
+    }
+
+    let block_index = deposits::mint_once_on(deposit.jetton.ledger_canister, deposit.account, tx_id, utime, amount).await?;
+
+    record_event(EventType::JettonMinted {
+        symbol: deposit.jetton.symbol.clone(),
+        to: deposit.account,
+        amount,
+        hash: tx_id.hash_base64(),
+        block_index,
+    });
+
+    Ok(Some(MintedDeposit {
+        tx_hash: tx_id.hash_base64(),
+        lt: tx_id.lt,
+        amount,
+        block_index,
+    }))
+}
+
+// Internal message of the minter wallet asking its jetton wallet to send
+// `amount` jettons to `destination`. The excess TON is returned to `response`.
+pub fn transfer_message(
+    jetton_wallet: &TonAddress,
+    query_id: u64,
+    amount: u64,
+    destination: &TonAddress,
+    response: &TonAddress,
+) -> Result<Cell, String> {
+    let body = CellBuilder::new()
+        .store_u32(32, JETTON_TRANSFER_OP)
+        .and_then(|b| b.store_u64(64, query_id))
+        .and_then(|b| b.store_coins(&BigUint::from(amount)))
+        .and_then(|b| b.store_address(destination))
+        .and_then(|b| b.store_address(response))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.build())
+        .map_err(|e| e.to_string())?;
+
+    // int_msg_info$0 ihr_disabled:1 bounce:1 bounced:0 src:none dest value
+    // ihr_fee:0 fwd_fee:0 created_lt:0 created_at:0 init:none body:^Cell
+    CellBuilder::new()
+        .store_bit(false)
+        .and_then(|b| b.store_bit(true))
+        .and_then(|b| b.store_bit(true))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_address(&TonAddress::NULL))
+        .and_then(|b| b.store_address(jetton_wallet))
+        .and_then(|b| b.store_coins(&BigUint::from(JETTON_TRANSFER_TON_AMOUNT)))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_u64(64, 0))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(true))
+        .and_then(|b| b.store_reference(&Arc::new(body)))
+        .and_then(|b| b.build())
+        .map_err(|e| e.to_string())
+}
+
+// Where a jetton payout stands on the jetton wallet of the minter
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayoutTrace {
+    // The jetton wallet did not process the transfer yet
+    InFlight,
+    // The jetton wallet sent the jettons on in its transaction `tx_hash`
+    Delivered { tx_hash: String },
+    // No jettons were sent, the withdrawal can be reimbursed
+    Failed { reason: String },
+}
+
+// Follows the payout `transfer` of withdrawal `query_id` from the minter
+// wallet transaction `tx` to the jetton wallet of the minter. The payouts of
+// a batch all go to the same jetton wallet, the query id tells them apart.
+pub async fn trace_payout(jetton: &JettonConfig, tx: &TonTransaction, query_id: u64, amount: u64) -> Result<PayoutTrace, String> {
+    let wallet = minter_wallet(jetton)?;
+
+    // Missing if the action phase of the minter wallet failed, e.g. on its balance
+    let Some(transfer) = tx.out_msgs.iter().find(|msg| {
+        msg.destination.parse::<TonAddress>().is_ok_and(|dest| dest == wallet)
+            && body_header(msg).is_ok_and(|header| {
+                !header.bounced && header.op == JETTON_TRANSFER_OP && header.query_id == query_id
+            })
+    }) else {
+        return Ok(PayoutTrace::Failed {
+            reason: "No transfer was sent to the jetton wallet".to_string(),
+        });
+    };
+
+    match history::find_transaction(&wallet.to_base64_url(), &transfer.hash, Operation::Withdrawal).await? {
+        Some(jetton_tx) => payout_outcome(&jetton_tx, query_id, amount),
+        None => Ok(PayoutTrace::InFlight),
+    }
+}
+
+// Outcome of the jetton wallet transaction `tx` that processed the payout
+// `transfer` of withdrawal `query_id`. The wallet only debits the jettons
+// when it forwards them in an `internal_transfer`, a failed transfer leaves
+// its balance untouched and bounces back to the minter wallet.
+pub fn payout_outcome(tx: &TonTransaction, query_id: u64, amount: u64) -> Result<PayoutTrace, String> {
+    let headers: Vec<BodyHeader> = tx
+        .out_msgs
+        .iter()
+        .filter_map(|msg| body_header(msg).ok())
+        .filter(|header| header.query_id == query_id)
+        .collect();
+
+    if let Some(header) = headers
+        .iter()
+        .find(|header| !header.bounced && header.op == JETTON_INTERNAL_TRANSFER_OP)
+    {
+        if header.amount != Some(amount) {
+            return Err(format!(
+                "Jetton wallet forwarded {:?} instead of {} for withdrawal {}",
+                header.amount, amount, query_id
+            ));
+        }
+        return Ok(PayoutTrace::Delivered {
+            tx_hash: tx.transaction_id.hash.clone(),
+        });
+    }
+
+    let reason = if headers
+        .iter()
+        .any(|header| header.bounced && header.op == JETTON_TRANSFER_OP)
+    {
+        "Jetton transfer bounced"
+    } else {
+        "Jetton wallet did not forward the transfer"
+    };
+
+    Ok(PayoutTrace::Failed {
+        reason: reason.to_string(),
+    })
+}
+
+// Start of a jetton message body: op, query id and, unless the body bounced
+// and was cut short, the amount
+struct BodyHeader {
+    bounced: bool,
+    op: u32,
+    query_id: u64,
+    amount: Option<u64>,
+}
+
+fn body_header(msg: &TonTransactionMessage) -> Result<BodyHeader, String> {
+    let body = msg
+        .msg_data
+        .as_ref()
+        .and_then(|data| data.body.as_ref())
+        .ok_or("Message has no body".to_string())?;
+
+    let root = BagOfCells::parse_base64(body)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| e.to_string())?;
+
+    let mut parser = root.parser();
+
+    let mut op = parser.load_u32(32).map_err(|e| e.to_string())?;
+    let bounced = op == BOUNCED_OP;
+    if bounced {
+        op = parser.load_u32(32).map_err(|e| e.to_string())?;
+    }
+
+    let query_id = parser.load_u64(64).map_err(|e| e.to_string())?;
+
+    let amount = if bounced {
+        None
+    } else {
+        let amount = parser.load_coins().map_err(|e| e.to_string())?;
+        Some(u64::try_from(amount).map_err(|_| "Jetton amount too large".to_string())?)
+    };
+
+    Ok(BodyHeader { bounced, op, query_id, amount })
+}
@@ -14,13 +14,13 @@ pub struct ArchiveOptions {
   pub controller_id: Principal,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub enum ChangeFeeCollector { SetTo(Account), Unset }
 
-#[derive(CandidType, Deserialize)]
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct FeatureFlags { pub icrc2: bool }
 
-#[derive(CandidType, Deserialize)]
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct ChangeArchiveOptions {
   pub num_blocks_to_archive: Option<u64>,
   pub max_transactions_per_response: Option<u64>,
@@ -32,7 +32,7 @@ pub struct ChangeArchiveOptions {
   pub controller_id: Option<Principal>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(Debug, Clone, Default, CandidType, Deserialize)]
 pub struct UpgradeArgs {
   pub change_archive_options: Option<ChangeArchiveOptions>,
   pub token_symbol: Option<String>,
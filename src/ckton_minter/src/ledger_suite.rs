@@ -0,0 +1,537 @@
+// ==========================
+// ckton_minter/src/ledger_suite.rs
+// Orchestration of the ICRC ledger suites of the bridged tokens.
+// The minter creates the ledger and index of a new token, keeps them and the
+// ledger archives topped up with cycles and upgrades them. The ledger and
+// index wasms are uploaded in chunks by a controller; local builds fall back
+// to the embedded ones.
+// ==========================
+
+use std::{cell::RefCell, collections::BTreeSet};
+
+use candid::{encode_args, CandidType, Nat, Principal};
+use ic_cdk::api::{
+    canister_balance128,
+    management_canister::main::{
+        canister_status, create_canister, deposit_cycles, install_code, CanisterIdRecord, CanisterInstallMode,
+        CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+    },
+    time,
+};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap};
+use icrc_ledger_types::{icrc::generic_metadata_value::MetadataValue, icrc1::account::Account};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    consts,
+    events::{record_event, EventType},
+    jettons,
+    ledger_args::{ArchiveOptions, FeatureFlags, IndexArg, InitArg, InitArgs, LedgerArgument, UpgradeArg, UpgradeArgs},
+    state::{candid_storable, get_memory, mutate_config, read_config, Memory},
+};
+
+const WASM_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const WASM_HASHES_MEMORY_ID: MemoryId = MemoryId::new(17);
+const LEDGER_SUITES_MEMORY_ID: MemoryId = MemoryId::new(18);
+
+thread_local! {
+    // Uploaded wasm chunks, keyed by wasm kind and chunk index
+    static WASM_CHUNKS: RefCell<StableBTreeMap<(u8, u32), Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WASM_CHUNKS_MEMORY_ID)));
+
+    // Hex sha256 of each complete upload, unset while an upload is in progress
+    static WASM_HASHES: RefCell<StableBTreeMap<u8, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WASM_HASHES_MEMORY_ID)));
+
+    // Ledger suites created by the minter, keyed by token symbol
+    static LEDGER_SUITES: RefCell<StableBTreeMap<String, LedgerSuite, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LEDGER_SUITES_MEMORY_ID)));
+
+    // Symbols of the suites currently being created
+    static ADDING: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum WasmKind {
+    Ledger,
+    Index,
+}
+
+impl WasmKind {
+    fn key(self) -> u8 {
+        match self {
+            WasmKind::Ledger => 0,
+            WasmKind::Index => 1,
+        }
+    }
+
+    // Wasm embedded with the `embedded-ledger-suite` feature only, see
+    // `crate::LEDGER_WASM`
+    fn embedded(self) -> Option<&'static [u8]> {
+        #[cfg(feature = "embedded-ledger-suite")]
+        return Some(match self {
+            WasmKind::Ledger => crate::LEDGER_WASM,
+            WasmKind::Index => crate::INDEXER_WASM,
+        });
+
+        #[cfg(not(feature = "embedded-ledger-suite"))]
+        None
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct LedgerSuiteArgs {
+    pub token_symbol: String,
+    pub token_name: String,
+    pub decimals: u8,
+    pub transfer_fee: u64,
+    // ICRC-1 metadata entries, e.g. `icrc1:logo`
+    pub metadata: Vec<(String, MetadataValue)>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct LedgerSuite {
+    pub token_symbol: String,
+    // Anonymous until the canister was created
+    pub ledger: Principal,
+    pub index: Principal,
+    // Hex module hashes reported by the IC after the last install, unset
+    // until the install succeeded
+    pub ledger_module_hash: Option<String>,
+    pub index_module_hash: Option<String>,
+    // Archives spawned by the ledger, as of the last top-up
+    pub archives: Vec<Principal>,
+    pub created_at: u64,
+}
+
+candid_storable!(LedgerSuite);
+
+pub fn ledger_suites() -> Vec<LedgerSuite> {
+    LEDGER_SUITES.with_borrow(|suites| suites.iter().map(|(_, suite)| suite).collect())
+}
+
+pub fn ledger_suite(token_symbol: &str) -> Result<LedgerSuite, String> {
+    LEDGER_SUITES
+        .with_borrow(|suites| suites.get(&token_symbol.to_string()))
+        .ok_or(format!("Unknown ledger suite {}", token_symbol))
+}
+
+// The ledger of a completely installed suite
+pub fn installed_ledger(token_symbol: &str) -> Result<Principal, String> {
+    let suite = ledger_suite(token_symbol)?;
+
+    if suite.ledger_module_hash.is_none() || suite.index_module_hash.is_none() {
+        return Err(format!("Ledger suite {} is not installed yet", token_symbol));
+    }
+
+    Ok(suite.ledger)
+}
+
+fn store_suite(suite: &LedgerSuite) {
+    LEDGER_SUITES.with_borrow_mut(|suites| suites.insert(suite.token_symbol.clone(), suite.clone()));
+}
+
+// Stores a chunk of a wasm upload. Chunk 0 starts a new upload and drops the
+// previous one, the other chunks must follow in order.
+pub fn upload_wasm_chunk(kind: WasmKind, index: u32, chunk: Vec<u8>) -> Result<(), String> {
+    let key = kind.key();
+
+    if index == 0 {
+        WASM_CHUNKS.with_borrow_mut(|chunks| {
+            let keys: Vec<_> = chunks.range((key, 0)..=(key, u32::MAX)).map(|(k, _)| k).collect();
+            for k in keys {
+                chunks.remove(&k);
+            }
+        });
+        WASM_HASHES.with_borrow_mut(|hashes| hashes.remove(&key));
+    } else if WASM_HASHES.with_borrow(|hashes| hashes.contains_key(&key))
+        || !WASM_CHUNKS.with_borrow(|chunks| chunks.contains_key(&(key, index - 1)))
+    {
+        return Err(format!("Chunk {} of the {:?} wasm is out of order, start again with chunk 0", index, kind));
+    }
+
+    WASM_CHUNKS.with_borrow_mut(|chunks| chunks.insert((key, index), chunk));
+    Ok(())
+}
+
+// Completes an upload once the chunks hash to `sha256`, the new wasm is used
+// by the next install. Returns the hash.
+pub fn finish_wasm_upload(kind: WasmKind, sha256: String) -> Result<String, String> {
+    let wasm = uploaded_wasm(kind);
+
+    if !wasm.starts_with(b"\0asm") && !wasm.starts_with(&[0x1f, 0x8b]) {
+        return Err(format!("The uploaded {:?} wasm is neither a wasm module nor gzipped", kind));
+    }
+
+    let hash = hex::encode(Sha256::digest(&wasm));
+    if hash != sha256.to_lowercase() {
+        return Err(format!("The uploaded {:?} wasm hashes to {}", kind, hash));
+    }
+
+    WASM_HASHES.with_borrow_mut(|hashes| hashes.insert(kind.key(), hash.clone()));
+    Ok(hash)
+}
+
+pub fn wasm_hash(kind: WasmKind) -> Option<String> {
+    WASM_HASHES.with_borrow(|hashes| hashes.get(&kind.key()))
+}
+
+fn uploaded_wasm(kind: WasmKind) -> Vec<u8> {
+    let key = kind.key();
+    WASM_CHUNKS.with_borrow(|chunks| {
+        chunks
+            .range((key, 0)..=(key, u32::MAX))
+            .flat_map(|(_, chunk)| chunk)
+            .collect()
+    })
+}
+
+// Hex sha256 of the wasm installed by the next create or upgrade, what the IC
+// reports as module hash once it is installed
+pub fn next_module_hash(kind: WasmKind) -> Result<String, String> {
+    match wasm_hash(kind) {
+        Some(hash) => Ok(hash),
+        None => wasm(kind).map(|wasm| hex::encode(Sha256::digest(wasm))),
+    }
+}
+
+// Wasm installed by the next create or upgrade
+fn wasm(kind: WasmKind) -> Result<Vec<u8>, String> {
+    if wasm_hash(kind).is_some() {
+        return Ok(uploaded_wasm(kind));
+    }
+
+    kind.embedded()
+        .map(|wasm| wasm.to_vec())
+        .ok_or(format!("No {:?} wasm was uploaded", kind))
+}
+
+struct AddClaim(String);
+
+impl AddClaim {
+    fn new(token_symbol: &str) -> Result<Self, String> {
+        ADDING.with_borrow_mut(|adding| {
+            if !adding.insert(token_symbol.to_string()) {
+                return Err(format!("Ledger suite {} is already being created", token_symbol));
+            }
+            Ok(Self(token_symbol.to_string()))
+        })
+    }
+}
+
+impl Drop for AddClaim {
+    fn drop(&mut self) {
+        ADDING.with_borrow_mut(|adding| adding.remove(&self.0));
+    }
+}
+
+// Creates and installs the ledger and index of a new token, with the minter
+// as minting account and controller. The suite is stored after every step, a
+// suite whose creation or install failed is completed on the same canisters.
+// A jetton is linked to the suite of its symbol by `jettons::resolve_jettons`.
+pub async fn add_ledger_suite(args: LedgerSuiteArgs, controller: Principal) -> Result<LedgerSuite, String> {
+    if args.token_symbol.is_empty() {
+        return Err("The token symbol is empty".to_string());
+    }
+
+    let _claim = AddClaim::new(&args.token_symbol)?;
+
+    // A configured jetton keeps the ledger its balances are on
+    if let Some(jetton) = jettons::jettons().into_iter().find(|jetton| jetton.symbol == args.token_symbol) {
+        let linked = LEDGER_SUITES.with_borrow(|suites| suites.get(&args.token_symbol)).map(|suite| suite.ledger);
+        if linked != Some(jetton.ledger_canister) {
+            return Err(format!("Jetton {} already uses the ledger {}", jetton.symbol, jetton.ledger_canister));
+        }
+    }
+
+    let existing = LEDGER_SUITES.with_borrow(|suites| suites.get(&args.token_symbol));
+    if existing
+        .as_ref()
+        .is_some_and(|suite| suite.ledger_module_hash.is_some() && suite.index_module_hash.is_some())
+    {
+        return Err(format!("Ledger suite {} already exists", args.token_symbol));
+    }
+
+    let ledger_wasm = wasm(WasmKind::Ledger)?;
+    let index_wasm = wasm(WasmKind::Index)?;
+
+    let mut suite = existing.unwrap_or_else(|| LedgerSuite {
+        token_symbol: args.token_symbol.clone(),
+        ledger: Principal::anonymous(),
+        index: Principal::anonymous(),
+        ledger_module_hash: None,
+        index_module_hash: None,
+        archives: vec![],
+        created_at: time(),
+    });
+
+    if suite.ledger == Principal::anonymous() {
+        suite.ledger = create(controller).await?;
+        store_suite(&suite);
+    }
+
+    if suite.index == Principal::anonymous() {
+        suite.index = create(controller).await?;
+        store_suite(&suite);
+    }
+
+    if suite.ledger_module_hash.is_none() {
+        let init_args = InitArgs {
+            decimals: Some(args.decimals),
+            token_symbol: args.token_symbol.clone(),
+            transfer_fee: args.transfer_fee.into(),
+            metadata: args.metadata.clone(),
+            minting_account: Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+            initial_balances: vec![],
+            fee_collector_account: Some(Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            }),
+            archive_options: ArchiveOptions {
+                num_blocks_to_archive: 1000,
+                max_transactions_per_response: None,
+                trigger_threshold: 2000,
+                more_controller_ids: Some(vec![controller]),
+                max_message_size_bytes: None,
+                cycles_for_archive_creation: Some(consts::LEDGER_ARCHIVE_CREATION_CYCLES),
+                node_max_memory_size_bytes: None,
+                controller_id: ic_cdk::id(),
+            },
+            max_memo_length: None,
+            token_name: args.token_name.clone(),
+            feature_flags: Some(FeatureFlags { icrc2: true }),
+        };
+
+        install(suite.ledger, ledger_wasm, CanisterInstallMode::Install, encode_args((LedgerArgument::Init(init_args),))).await?;
+        suite.ledger_module_hash = module_hash(suite.ledger).await?;
+        store_suite(&suite);
+    }
+
+    if suite.index_module_hash.is_none() {
+        let init_arg = InitArg {
+            ledger_id: suite.ledger,
+            retrieve_blocks_from_ledger_interval_seconds: None,
+        };
+
+        install(suite.index, index_wasm, CanisterInstallMode::Install, encode_args((IndexArg::Init(init_arg),))).await?;
+        suite.index_module_hash = module_hash(suite.index).await?;
+        store_suite(&suite);
+    }
+
+    record_event(EventType::LedgerSuiteCreated {
+        token_symbol: suite.token_symbol.clone(),
+        ledger: suite.ledger,
+        index: suite.index,
+        ledger_module_hash: suite.ledger_module_hash.clone(),
+        index_module_hash: suite.index_module_hash.clone(),
+    });
+
+    Ok(suite)
+}
+
+// Upgrades the ledger and index of a suite to the current wasms
+pub async fn upgrade_ledger_suite(token_symbol: &str, upgrade_args: Option<UpgradeArgs>) -> Result<LedgerSuite, String> {
+    let mut suite = ledger_suite(token_symbol)?;
+
+    // The ckTON suite goes through the ckTON upgrades, which keep the fee of
+    // deposits in step with the ledger
+    let (ckton_ledger, ckton_index) = read_config(|config| (config.ledger_canister, config.indexer_canister));
+    if suite.ledger == ckton_ledger && suite.index == ckton_index {
+        upgrade_ckton_ledger(upgrade_args).await?;
+        upgrade_ckton_index().await?;
+        return ledger_suite(token_symbol);
+    }
+
+    let ledger_wasm = wasm(WasmKind::Ledger)?;
+    let index_wasm = wasm(WasmKind::Index)?;
+
+    let upgrade_arg = UpgradeArg {
+        ledger_id: None,
+        retrieve_blocks_from_ledger_interval_seconds: None,
+    };
+
+    install(suite.ledger, ledger_wasm, CanisterInstallMode::Upgrade(None), encode_args((LedgerArgument::Upgrade(upgrade_args),))).await?;
+    suite.ledger_module_hash = module_hash(suite.ledger).await?;
+    store_suite(&suite);
+
+    install(suite.index, index_wasm, CanisterInstallMode::Upgrade(None), encode_args((IndexArg::Upgrade(upgrade_arg),))).await?;
+    suite.index_module_hash = module_hash(suite.index).await?;
+    store_suite(&suite);
+
+    record_event(EventType::LedgerSuiteUpgraded {
+        token_symbol: suite.token_symbol.clone(),
+        ledger_module_hash: suite.ledger_module_hash.clone(),
+        index_module_hash: suite.index_module_hash.clone(),
+    });
+
+    Ok(suite)
+}
+
+// Upgrades the ckTON ledger to the uploaded ledger wasm. A new transfer fee is
+// also kept from deposits from then on. Returns the new module hash.
+pub async fn upgrade_ckton_ledger(upgrade_args: Option<UpgradeArgs>) -> Result<Option<String>, String> {
+    let canister = read_config(|config| config.ledger_canister);
+
+    let transfer_fee = match upgrade_args.as_ref().and_then(|args| args.transfer_fee.as_ref()) {
+        Some(fee) => Some(u64::try_from(fee.0.clone()).map_err(|_| format!("Transfer fee {} is too large", fee))?),
+        None => None,
+    };
+
+    let arg = encode_args((LedgerArgument::Upgrade(upgrade_args.clone()),));
+    let result = upgrade_ckton_canister(WasmKind::Ledger, canister, arg).await;
+
+    if result.is_ok() {
+        if let Some(fee) = transfer_fee {
+            mutate_config(|config| config.ckton_transfer_fee = fee);
+        }
+    }
+
+    record_ckton_upgrade(WasmKind::Ledger, canister, upgrade_args, &result);
+    result
+}
+
+// Upgrades the ckTON index to the uploaded index wasm
+pub async fn upgrade_ckton_index() -> Result<Option<String>, String> {
+    let canister = read_config(|config| config.indexer_canister);
+
+    let arg = encode_args((IndexArg::Upgrade(UpgradeArg {
+        ledger_id: None,
+        retrieve_blocks_from_ledger_interval_seconds: None,
+    }),));
+    let result = upgrade_ckton_canister(WasmKind::Index, canister, arg).await;
+
+    record_ckton_upgrade(WasmKind::Index, canister, None, &result);
+    result
+}
+
+async fn upgrade_ckton_canister(
+    kind: WasmKind,
+    canister: Principal,
+    arg: Result<Vec<u8>, candid::Error>,
+) -> Result<Option<String>, String> {
+    if canister == Principal::anonymous() {
+        return Err(format!("The ckTON {:?} is not set up", kind));
+    }
+
+    install(canister, wasm(kind)?, CanisterInstallMode::Upgrade(None), arg).await?;
+    let module_hash = module_hash(canister).await?;
+
+    // The ckTON suite created by a local setup tracks the same canisters
+    for mut suite in ledger_suites() {
+        match kind {
+            WasmKind::Ledger if suite.ledger == canister => suite.ledger_module_hash = module_hash.clone(),
+            WasmKind::Index if suite.index == canister => suite.index_module_hash = module_hash.clone(),
+            _ => continue,
+        }
+        store_suite(&suite);
+    }
+
+    Ok(module_hash)
+}
+
+fn record_ckton_upgrade(
+    kind: WasmKind,
+    canister: Principal,
+    upgrade_args: Option<UpgradeArgs>,
+    result: &Result<Option<String>, String>,
+) {
+    record_event(EventType::CkTonCanisterUpgraded {
+        kind,
+        canister,
+        upgrade_args,
+        module_hash: result.as_ref().ok().cloned().flatten(),
+        error: result.as_ref().err().cloned(),
+    });
+}
+
+async fn create(controller: Principal) -> Result<Principal, String> {
+    let args = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(vec![ic_cdk::id(), controller]),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+            reserved_cycles_limit: None,
+            log_visibility: None,
+            wasm_memory_limit: None,
+        }),
+    };
+
+    let (record,) = create_canister(args, consts::LEDGER_SUITE_CREATION_CYCLES)
+        .await
+        .map_err(|(_, msg)| msg)?;
+
+    Ok(record.canister_id)
+}
+
+async fn install(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    mode: CanisterInstallMode,
+    arg: Result<Vec<u8>, candid::Error>,
+) -> Result<(), String> {
+    install_code(InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module,
+        arg: arg.map_err(|e| e.to_string())?,
+    })
+    .await
+    .map_err(|(_, msg)| format!("Install on {} failed: {}", canister_id, msg))
+}
+
+pub async fn module_hash(canister_id: Principal) -> Result<Option<String>, String> {
+    let (status,) = canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|(_, msg)| msg)?;
+
+    Ok(status.module_hash.map(hex::encode))
+}
+
+#[derive(CandidType, Deserialize)]
+struct ArchiveInfo {
+    canister_id: Principal,
+}
+
+// Keeps the ledgers, indexes and archives of every suite above
+// `LEDGER_SUITE_MIN_CYCLES`, as long as the minter itself can spare the cycles
+pub async fn top_up_ledger_suites() {
+    for mut suite in ledger_suites() {
+        match ic_cdk::call::<_, (Vec<ArchiveInfo>,)>(suite.ledger, "archives", ()).await {
+            Ok((archives,)) => {
+                suite.archives = archives.into_iter().map(|archive| archive.canister_id).collect();
+                store_suite(&suite);
+            }
+            Err((_, msg)) => ic_cdk::println!("Failed to list the archives of {}: {}", suite.token_symbol, msg),
+        }
+
+        let canisters = [suite.ledger, suite.index].into_iter().chain(suite.archives.iter().copied());
+        for canister_id in canisters.filter(|canister_id| *canister_id != Principal::anonymous()) {
+            if let Err(err) = top_up(canister_id).await {
+                ic_cdk::println!("Failed to top up {} of {}: {}", canister_id, suite.token_symbol, err);
+            }
+        }
+    }
+}
+
+async fn top_up(canister_id: Principal) -> Result<(), String> {
+    let (status,) = canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|(_, msg)| msg)?;
+
+    if status.cycles >= Nat::from(consts::LEDGER_SUITE_MIN_CYCLES) {
+        return Ok(());
+    }
+
+    if canister_balance128() < consts::LEDGER_SUITE_TOP_UP_CYCLES + consts::MINTER_CYCLES_RESERVE {
+        return Err("The minter is low on cycles".to_string());
+    }
+
+    deposit_cycles(CanisterIdRecord { canister_id }, consts::LEDGER_SUITE_TOP_UP_CYCLES)
+        .await
+        .map_err(|(_, msg)| msg)
+}
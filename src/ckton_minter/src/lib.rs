@@ -4,26 +4,15 @@
 // Handles TON wallet deployment, minting, burning, and admin setup logic.
 // ==========================
 
-use std::{
-    borrow::Cow, 
-    cell::RefCell, 
-    collections::{HashMap, VecDeque}, 
-    ops::{Deref, Div}, 
-    str::FromStr, 
-    time::Duration
-};
+use std::{str::FromStr, time::Duration};
 
 use base64::prelude::*;
-use candid::{candid_method, encode_args, Nat, Principal};
+use candid::{candid_method, Nat, Principal};
 use ic_cdk::{
     api::{
         is_controller,
         management_canister::{
             http_request::{HttpResponse, TransformArgs},
-            main::{
-                create_canister, install_code, CanisterSettings, CreateCanisterArgument,
-                InstallCodeArgument,
-            },
         },
         time,
     },
@@ -36,232 +25,423 @@ use ic_ton_lib::{
     wallet::TonWallet,
     TonAddress,
 };
-use icrc_ledger_types::icrc1::{
-    account::{principal_to_subaccount, Account},
-    transfer::TransferError,
+use icrc_ledger_types::{
+    icrc::generic_metadata_value::MetadataValue,
+    icrc1::{
+        account::{principal_to_subaccount, Account},
+        transfer::TransferError,
+    },
 };
-use ledger_args::{ArchiveOptions, FeatureFlags, IndexArg, InitArg, InitArgs, LedgerArgument};
+use cycles::{CyclesStats, Operation};
+use deposits::{MintedDeposit, TonTxId};
+use events::{record_event, Event, EventType, ReplayedState};
+use finality::PendingDeposit;
+use highload::HighloadWalletV3;
+use jettons::JettonConfig;
+use ledger_args::UpgradeArgs;
+use ledger_suite::{LedgerSuite, LedgerSuiteArgs, WasmKind};
+use lifecycle::MinterArg;
+use lightclient::{LightClientState, TrustedKeyBlock};
+use state::{mutate_config, read_config, TaskGuard, DEPLOYED_WALLET, PENDING_TASKS, WALLET_VERSIONS};
 use ton_api::TonTransaction;
-use types::{AdminSetup, ICTonSigner, MultiPOPVec, PendingTasks, TONDeployedWallet};
+use types::{AdminSetup, FinalityPolicy, ICTonSigner, MinterError, PendingTasks, TONDeployedWallet, TonWalletVersion};
 use utils::{get_ic_pub_key, get_path};
+use withdrawals::{NativeWithdrawal, WithdrawalRequest};
 
+mod block;
 mod consts;
+mod cycles;
+mod deposits;
+mod events;
+mod finality;
+mod highload;
+mod history;
+mod jettons;
+mod lifecycle;
+mod lightclient;
 mod ledger_args;
+mod ledger_suite;
+mod memo;
+mod metadata;
+mod state;
 mod ton_api;
+mod transaction;
 mod types;
 mod utils;
+mod withdrawals;
 
 #[cfg(test)]
 mod tests;
 
-// WASM binaries for local development
-#[cfg(network = "local")]
+// Ledger and index wasms for local development and the tests, behind the
+// `embedded-ledger-suite` feature. Deployments on the IC only install wasms a
+// controller uploaded and checked (see `ledger_suite`), and the minter wasm
+// stays small enough to be installed in one message.
+#[cfg(feature = "embedded-ledger-suite")]
 const INDEXER_WASM: &[u8] = include_bytes!("../bin/index-ng.wasm");
 
-#[cfg(network = "local")]
+#[cfg(feature = "embedded-ledger-suite")]
 const LEDGER_WASM: &[u8] = include_bytes!("../bin/ledger.wasm");
 
-// Thread-local storage for canister state
-thread_local! {
-    // Map of deployed wallets: Account -> TONDeployedWallet
-    static DEPLOYED_WALLET: RefCell<HashMap<Account, TONDeployedWallet>> = RefCell::new(HashMap::new());
-    // Map of account balances (not always used)
-    static ACCOUNT_BALANCES: RefCell<HashMap<Account, u64>> = RefCell::new(HashMap::new());
-    // Queue of pending tasks (mint, burn, deploy, etc.)
-    static PENDING_TASKS: RefCell<MultiPOPVec<PendingTasks>> = RefCell::new(MultiPOPVec::new());
-    // The main TON address for the minter canister
-    static APP_TON_ADDRESS: RefCell<TonAddress> = RefCell::new(TonAddress::NULL);
-    // Principal of the ICRC ledger canister
-    static CK_LEDGER_CANISTER: RefCell<Principal> = RefCell::new(Principal::anonymous());
-    // Principal of the indexer canister
-    static CK_INDEXER_CANISTER: RefCell<Principal> = RefCell::new(Principal::anonymous());
-    // Fee for transferring ckTON
-    static CKTON_TRANSFER_FEE: RefCell<u64> = RefCell::new(1000);
-    // Fee for sending TON
-    static TON_FEE: RefCell<u64> = RefCell::new(5_500u64);
+// Initialization function: applies the runtime config and sets up periodic
+// task processing
+#[ic_cdk::init]
+fn init(arg: MinterArg) {
+    if let Err(err) = lifecycle::init(arg) {
+        ic_cdk::trap(&err);
+    }
+    setup_timers();
 }
 
-// Initialization function: sets up periodic task processing
-#[ic_cdk::init]
-fn init() {
+// Pre-upgrade hook: tasks whose futures are still awaiting will never resume
+// after the upgrade, so they are put back into the queue
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let requeued = PENDING_TASKS.with_borrow_mut(|tasks| tasks.requeue_in_flight());
+    ic_cdk::println!("Requeued {} in-flight tasks before upgrade", requeued);
+}
+
+// Post-upgrade hook: apply the upgrade argument and re-initialize periodic
+// tasks. A failed upgrade argument rolls the upgrade back.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(arg: Option<MinterArg>) {
+    if let Err(err) = lifecycle::post_upgrade(arg) {
+        ic_cdk::trap(&err);
+    }
+    setup_timers();
+}
+
+fn setup_timers() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(consts::DEPOSIT_SCAN_INTERVAL_SECS), || {
+        ic_cdk::spawn(deposits::scan_deposit_addresses());
+    });
+
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(consts::WITHDRAWAL_BATCH_INTERVAL_SECS), || {
+        ic_cdk::spawn(withdrawals::send_withdrawals());
+    });
+
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(consts::WITHDRAWAL_CHECK_INTERVAL_SECS), || {
+        ic_cdk::spawn(withdrawals::resolve_withdrawals());
+    });
+
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(consts::LIGHT_CLIENT_SYNC_INTERVAL_SECS), || {
+        ic_cdk::spawn(lightclient::sync_masterchain());
+    });
+
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(consts::LEDGER_SUITE_TOP_UP_INTERVAL_SECS), || {
+        ic_cdk::spawn(ledger_suite::top_up_ledger_suites());
+    });
+
     ic_cdk_timers::set_timer_interval(Duration::from_secs(5), || {
         ic_cdk::println!("Starting task for {} pending tasks", PENDING_TASKS.with_borrow(|tasks| tasks.len()));
-        PENDING_TASKS.with_borrow_mut(|tasks| {
-
-            let tasks = tasks.pop_many(Some(8));
-
-            for task in tasks {
-                if let Some(task) = task {
-                    match task {
-                        // Handle wallet deployment confirmation
-                        PendingTasks::DeployWallet(account, ton_address, retry_count) => {
-                            ic_cdk::println!("Processing DeployWallet task for account: {}", account);
-                            ic_cdk::spawn(async move {
-                                if retry_count > 10 {
-                                    return;
-                                }
-    
-                                let ton_wallet_info = ton_api::get_ton_wallet_info(ton_address.clone())
-                                    .await
-                                    .unwrap();
-    
-                                if !ton_wallet_info.ok {
+        let tasks = PENDING_TASKS.with_borrow_mut(|tasks| tasks.pop_many(Some(8)));
+
+        for (task_id, task) in tasks.into_iter().flatten() {
+            match task {
+                // Handle wallet deployment confirmation
+                PendingTasks::DeployWallet(account, ton_address, retry_count) => {
+                    ic_cdk::println!("Processing DeployWallet task for account: {}", account);
+                    ic_cdk::spawn(async move {
+                        let _guard = TaskGuard::new(task_id);
+                        if retry_count > 10 {
+                            return;
+                        }
+
+                        let result = match ton_api::get_ton_wallet_info(ton_address.clone(), Operation::WalletDeployment).await {
+                            Ok(result) => result,
+                            Err(err) => {
+                                ic_cdk::println!("Failed to get wallet info of {}: {}", ton_address, err);
+                                if err.is_retryable() {
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
                                         tasks.push_back(PendingTasks::DeployWallet(account, ton_address, retry_count + 1));
                                     });
-                                    return;
                                 }
-                                let result = ton_wallet_info.result.unwrap();
-    
-                                if result.wallet {
-                                    DEPLOYED_WALLET.with_borrow_mut(|store| {
-                                        store.insert(
-                                            account,
-                                            TONDeployedWallet {
-                                                ton_address,
-                                            },
-                                        );
-                                    });
-                                } else {
+                                return;
+                            }
+                        };
+
+                        // Highload wallets are not recognized as wallets by the RPC
+                        if result.wallet || result.account_state == "active" {
+                            DEPLOYED_WALLET.with_borrow_mut(|store| {
+                                store.insert(
+                                    account.into(),
+                                    TONDeployedWallet {
+                                        ton_address: ton_address.clone(),
+                                        version: Some(wallet_version(&account)),
+                                    },
+                                );
+                            });
+                            record_event(EventType::WalletDeployed { account, ton_address });
+                        } else {
+                            PENDING_TASKS.with_borrow_mut(|tasks| {
+                                tasks.push_back(PendingTasks::DeployWallet(account, ton_address, retry_count + 1));
+                            });
+                        }
+                    });
+                }
+                // Handle minting: check for incoming TON tx, then mint ckTON
+                PendingTasks::Mint(account, amount, hash, ton_addr, retry_count) => {
+                    ic_cdk::println!("Processing Mint task for hash: {}", hash);
+                    ic_cdk::spawn(async move {
+                        let _guard = TaskGuard::new(task_id);
+                        if retry_count > 10 {
+                            history::forget_lookup(&hash);
+                            return;
+                        }
+
+                        ic_cdk::println!("Fetching TON transactions for address: {}", ton_addr);
+                        let tx = match history::find_transaction(&ton_addr, &hash, Operation::Deposit).await {
+                            Ok(tx) => tx,
+                            Err(err) => {
+                                ic_cdk::println!("Failed to get transactions of {}: {}", ton_addr, err);
+                                if err.is_retryable() {
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
-                                        tasks.push_back(PendingTasks::DeployWallet(account, ton_address, retry_count + 1));
+                                        tasks.push_back(PendingTasks::Mint(account, amount, hash, ton_addr, retry_count + 1));
                                     });
                                 }
-                            });
-                        }
-                        // Handle minting: check for incoming TON tx, then mint ckTON
-                        PendingTasks::Mint(account, amount, hash, ton_addr, retry_count) => {
-                            ic_cdk::println!("Processing Mint task for hash: {}", hash);
-                            ic_cdk::spawn(async move {
-                                if retry_count > 10 {
+                                return;
+                            }
+                        };
+
+                        if let Some(tx) = tx {
+                            ic_cdk::println!("Found matching transaction with hash: {}", hash);
+
+                            // Only what the wallet actually sent to the minter is minted
+                            let app_ton_address = read_config(|config| config.app_ton_address());
+                            let sent = match verify_mint_transaction(&tx, &app_ton_address) {
+                                Ok(sent) => sent,
+                                Err(err) => {
+                                    ic_cdk::println!("Transaction {} did not pay the minter: {}", hash, err);
                                     return;
                                 }
-    
-                                ic_cdk::println!("Fetching TON transactions for address: {}", ton_addr);
-                                let ton_transactions =
-                                    ton_api::get_ton_transactions(ton_addr.clone()).await.unwrap();
-    
-                                ic_cdk::println!("Received TON transactions response");
-                                let tx_list = ton_transactions.result.unwrap();
-                                ic_cdk::println!("Found {} transactions in the list", tx_list.len());
-                                let tx = tx_list
-                                    .iter()
-                                    .find(|tx| tx.in_msg.hash == hash);
-                                
-                                if tx.is_some() {
-                                    ic_cdk::println!("Found matching transaction with hash: {}", hash);
-    
-                                    let ledger_canister =
-                                        CK_LEDGER_CANISTER.with_borrow_mut(|canister| canister.clone());
-                                    ic_cdk::println!("Using ledger canister: {}", ledger_canister);
-    
-                                    let amount = amount - CKTON_TRANSFER_FEE.with_borrow_mut(|fee| *fee);
-                                    ic_cdk::println!("Calculated transfer amount: {} (original: {})", amount, amount + CKTON_TRANSFER_FEE.with_borrow_mut(|fee| *fee));
-    
-                                    let arg = icrc_ledger_types::icrc1::transfer::TransferArg {
-                                        from_subaccount: None,
-                                        to: account,
-                                        fee: None,
-                                        created_at_time: None,
-                                        memo: None,
-                                        amount: amount.into(),
-                                    };
-                                    ic_cdk::println!("Calling icrc1_transfer to account owner: {}", account.owner);
-                                    let (res,): (Result<Nat, TransferError>,) =
-                                        ic_cdk::call(ledger_canister, "icrc1_transfer", (arg,))
-                                            .await
-                                            .unwrap();
-                                    ic_cdk::println!("Transfer result: {:?}", res);
-                                } else {
+                            };
+                            let amount = amount.min(sent);
+
+                            record_event(EventType::DepositSeen { hash: hash.clone() });
+
+                            let tx_id = match TonTxId::from_transaction(&tx) {
+                                Ok(tx_id) => tx_id,
+                                Err(err) => {
+                                    ic_cdk::println!("Invalid transaction {}: {}", hash, err);
+                                    return;
+                                }
+                            };
+
+                            if deposits::is_credited(&tx_id) {
+                                ic_cdk::println!("Transaction {} is already credited", hash);
+                                return;
+                            }
+
+                            let deposit = finality::Deposit {
+                                account,
+                                ton_address: ton_addr.clone(),
+                                tx_id,
+                                utime: tx.utime,
+                                amount,
+                            };
+
+                            match finality::is_final(&deposit, &mut None).await {
+                                Ok(true) => {}
+                                // Waiting doesn't count as a retry
+                                Ok(false) => {
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
-                                        tasks.push_back(PendingTasks::Mint(account, amount, hash.clone(), ton_addr, retry_count + 1));
+                                        tasks.push_back(PendingTasks::Mint(account, amount, hash, ton_addr, retry_count));
                                     });
-                                    ic_cdk::println!("No matching transaction found for hash: {}", hash);
-                                }
-                            });
-                        },
-                        // Handle burning: check for outgoing TON tx, then burn ckTON
-                        PendingTasks::Burn(caller, amount, hash, ton_addr, retry_count) => {
-                            ic_cdk::spawn(async move {
-                                if retry_count > 10 {
                                     return;
                                 }
-    
-                                let app_ton_address = APP_TON_ADDRESS.with_borrow_mut(|address| address.clone());
-    
-                                let ton_response = ton_api::get_ton_transactions(get_ton_address_from_address(&app_ton_address)).await.unwrap();
-    
-                                if !ton_response.ok {
+                                Err(err) => {
+                                    ic_cdk::println!("Failed to check finality of {}: {}", hash, err);
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
-                                        tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
+                                        tasks.push_back(PendingTasks::Mint(account, amount, hash, ton_addr, retry_count + 1));
                                     });
-    
                                     return;
                                 }
-    
-                                let tx_list = ton_response.result.unwrap();
-    
-                                let tx = tx_list.iter().find(|tx| tx.in_msg.hash == hash);
-    
-                                if tx.is_none() {
+                            }
+
+                            let ckton_transfer_fee = read_config(|config| config.ckton_transfer_fee);
+                            let Some(mint_amount) = amount.checked_sub(ckton_transfer_fee).filter(|amount| *amount > 0) else {
+                                ic_cdk::println!("Deposit {} of {} is below the fee of {}", hash, amount, ckton_transfer_fee);
+                                finality::forget(&tx_id);
+                                return;
+                            };
+                            ic_cdk::println!("Calculated transfer amount: {} (original: {})", mint_amount, amount);
+
+                            ic_cdk::println!("Minting to account owner: {}", account.owner);
+                            match deposits::mint_once(account, tx_id, tx.utime, mint_amount).await {
+                                Ok(block_index) => {
+                                    record_event(EventType::CkTonMinted {
+                                        to: account,
+                                        amount: mint_amount,
+                                        hash,
+                                        block_index,
+                                    });
+                                }
+                                Err(err) => {
+                                    ic_cdk::println!("Mint for {} failed: {}", hash, err);
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
-                                        tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
+                                        tasks.push_back(PendingTasks::Mint(account, amount, hash, ton_addr, retry_count + 1));
                                     });
-    
-                                    return;
                                 }
-    
-                                let tx = tx.unwrap();
-    
-                                let recipient_ton_addr : TonAddress = ton_addr.parse::<TonAddress>().unwrap();
-    
-                                verify_mint_transaction(&tx, &recipient_ton_addr).unwrap();
-    
-                                let app_purse = Account {
-                                    owner: id(),
-                                    subaccount: None,
-                                };
-    
-                                let arg = icrc_ledger_types::icrc1::transfer::TransferArg {
-                                    from_subaccount: Some(principal_to_subaccount(caller)),
-                                    to: app_purse,
-                                    fee: None,
-                                    created_at_time: None,
-                                    memo: None,
-                                    amount: amount.into(),
-                                };
-    
-                                let ledger_canister = CK_LEDGER_CANISTER.with_borrow_mut(|canister| canister.clone());
-    
-                                let (res,): (Result<Nat, TransferError>,) =
-                                    ic_cdk::call(ledger_canister, "icrc1_transfer", (arg,))
-                                        .await
-                                        .unwrap();
-    
-                                if res.is_err() {
+                            }
+                        } else {
+                            PENDING_TASKS.with_borrow_mut(|tasks| {
+                                tasks.push_back(PendingTasks::Mint(account, amount, hash.clone(), ton_addr, retry_count + 1));
+                            });
+                            ic_cdk::println!("No matching transaction found for hash: {}", hash);
+                        }
+                    });
+                },
+                // Handle burning: check for outgoing TON tx, then burn ckTON
+                PendingTasks::Burn(caller, amount, hash, ton_addr, retry_count) => {
+                    ic_cdk::spawn(async move {
+                        let _guard = TaskGuard::new(task_id);
+                        if retry_count > 10 {
+                            history::forget_lookup(&hash);
+                            return;
+                        }
+
+                        let app_ton_address = read_config(|config| config.app_ton_address());
+
+                        let tx = match history::find_transaction(&get_ton_address_from_address(&app_ton_address), &hash, Operation::Withdrawal).await {
+                            Ok(tx) => tx,
+                            Err(err) => {
+                                ic_cdk::println!("Failed to get transactions of the minter wallet: {}", err);
+                                if err.is_retryable() {
                                     PENDING_TASKS.with_borrow_mut(|tasks| {
                                         tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
                                     });
-    
-                                    return;
                                 }
-    
-                                ic_cdk::println!("Burned {} CKTON", amount);
+                                return;
+                            }
+                        };
+
+                        let Some(tx) = tx else {
+                            PENDING_TASKS.with_borrow_mut(|tasks| {
+                                tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
                             });
+
+                            return;
+                        };
+
+                        let recipient_ton_addr : TonAddress = match ton_addr.parse::<TonAddress>() {
+                            Ok(address) => address,
+                            Err(err) => {
+                                ic_cdk::println!("Invalid recipient {}: {}", ton_addr, err);
+                                return;
+                            }
+                        };
+
+                        if let Err(err) = verify_mint_transaction(&tx, &recipient_ton_addr) {
+                            ic_cdk::println!("Transaction {} did not pay {}: {}", hash, ton_addr, err);
+                            return;
+                        }
+
+                        let app_purse = Account {
+                            owner: id(),
+                            subaccount: None,
+                        };
+
+                        let arg = icrc_ledger_types::icrc1::transfer::TransferArg {
+                            from_subaccount: Some(principal_to_subaccount(caller)),
+                            to: app_purse,
+                            fee: None,
+                            created_at_time: None,
+                            memo: None,
+                            amount: amount.into(),
+                        };
+
+                        let ledger_canister = read_config(|config| config.ledger_canister);
+
+                        let result: Result<(Result<Nat, TransferError>,), _> =
+                            ic_cdk::call(ledger_canister, "icrc1_transfer", (arg,)).await;
+
+                        let block_index = match result {
+                            Ok((Ok(block_index),)) => block_index,
+                            Ok((Err(err),)) => {
+                                ic_cdk::println!("Burn for {} failed: {:?}", hash, err);
+                                PENDING_TASKS.with_borrow_mut(|tasks| {
+                                    tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
+                                });
+
+                                return;
+                            }
+                            Err((code, message)) => {
+                                ic_cdk::println!("Burn for {} failed: {:?} {}", hash, code, message);
+                                PENDING_TASKS.with_borrow_mut(|tasks| {
+                                    tasks.push_back(PendingTasks::Burn(caller, amount, hash.clone(), ton_addr, retry_count + 1));
+                                });
+
+                                return;
+                            }
+                        };
+
+                        let Ok(block_index) = u64::try_from(block_index.0) else {
+                            ic_cdk::println!("Block index of the burn for {} doesn't fit in a u64", hash);
+                            return;
+                        };
+
+                        record_event(EventType::BurnFinalized {
+                            caller,
+                            amount,
+                            hash,
+                            block_index,
+                        });
+
+                        ic_cdk::println!("Burned {} CKTON", amount);
+                    });
+                }
+                // Withdrawals queued before batching, their payout is left to the batcher
+                PendingTasks::Withdraw(withdrawal_id, _) => {
+                    let _guard = TaskGuard::new(task_id);
+                    ic_cdk::println!("Withdrawal {} is paid out by the batcher", withdrawal_id);
+                }
+                // Handle confirmations: look up the payout of a sent withdrawal,
+                // withdrawals still unresolved afterwards are left to the periodic check
+                PendingTasks::ConfirmWithdrawal(withdrawal_id, retry_count) => {
+                    ic_cdk::spawn(async move {
+                        let _guard = TaskGuard::new(task_id);
+                        if retry_count > 10 {
+                            return;
+                        }
+
+                        match withdrawals::resolve_withdrawal(withdrawal_id).await {
+                            Ok(true) => ic_cdk::println!("Resolved withdrawal {}", withdrawal_id),
+                            result => {
+                                if let Err(err) = result {
+                                    ic_cdk::println!("Failed to resolve withdrawal {}: {}", withdrawal_id, err);
+                                }
+                                PENDING_TASKS.with_borrow_mut(|tasks| {
+                                    tasks.push_back(PendingTasks::ConfirmWithdrawal(withdrawal_id, retry_count + 1));
+                                });
+                            }
                         }
-                    }
+                    });
+                }
+                // Handle sweeping: move a credited deposit into the minter wallet
+                PendingTasks::Sweep(account, retry_count) => {
+                    ic_cdk::spawn(async move {
+                        let _guard = TaskGuard::new(task_id);
+                        if retry_count > 10 {
+                            return;
+                        }
+
+                        match deposits::sweep_deposit(account).await {
+                            Ok(Some(hash)) => ic_cdk::println!("Swept deposit wallet of {}: {}", account, hash),
+                            Ok(None) => {}
+                            Err(err) => {
+                                ic_cdk::println!("Failed to sweep deposit wallet of {}: {}", account, err);
+                                PENDING_TASKS.with_borrow_mut(|tasks| {
+                                    tasks.push_back(PendingTasks::Sweep(account, retry_count + 1));
+                                });
+                            }
+                        }
+                    });
                 }
             }
-        });
+        }
     });
 }
 
-// Post-upgrade hook: re-initialize periodic tasks
-#[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    init();
-}
-
 // Verifies that a TON transaction sent funds to the expected destination address
 fn verify_mint_transaction(tx: &TonTransaction, dest_addr: &TonAddress) -> Result<u64, String> {
     let mssg = tx.out_msgs.iter().find(|msg| {
@@ -286,89 +466,86 @@ fn verify_mint_transaction(tx: &TonTransaction, dest_addr: &TonAddress) -> Resul
     Ok(amount)
 }
 
-// Query wallet balance from TON network (IC network only)
-#[cfg(network = "ic")]
+// Query wallet balance from TON network
 #[ic_cdk::update]
-async fn wallet_balance(ton_address: String) -> u64 {
-    let ton_response = ton_api::get_ton_wallet_info(ton_address).await.unwrap();
-    
-    if !ton_response.ok {
-        return 0;
-    }
-
-    let result = ton_response.result.unwrap();
+async fn wallet_balance(ton_address: String) -> Result<u64, ton_api::TonApiError> {
+    let result = ton_api::get_ton_wallet_info(ton_address, Operation::Other).await?;
 
-    result.balance.parse::<u64>().unwrap()
+    result.balance.parse::<u64>().map_err(|e| ton_api::TonApiError::Decode(e.to_string()))
 }
 
-// Manual mint for local development/testing (not for production)
-#[cfg(network = "local")]
+// Generate a TON address for a given principal/subaccount. The owner may pick
+// the wallet version of an address that was not handed out yet, otherwise the
+// configured default is used. The version is kept for good.
 #[ic_cdk::update]
-async fn manual_mint(mssg_hash: String) -> Result<u64, String> {
-    let wallet = create_ton_wallet(caller(), None).await?;
-    let caller_ton_address = get_ton_address_from_wallet(&wallet);
+async fn generate_ton_address(owner: Option<Principal>, subaccount: Option<[u8; 32]>, version: Option<TonWalletVersion>) -> Result<String, MinterError> {
+    let owner = owner.unwrap_or_else(caller);
 
-    let ton_response = ton_api::get_ton_transactions(caller_ton_address).await?;
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to get transactions".to_string()));
-    }
-
-    let tx_list = ton_response.result.unwrap();
-
-    let tx = tx_list.iter().find(|tx| tx.in_msg.hash == mssg_hash);
-
-    if tx.is_none() {
-        return Err("Transaction not found".to_string());
+    if let Some(version) = version {
+        if owner != caller() {
+            return Err(MinterError::Other("Only the owner can choose the wallet version of an account".to_string()));
+        }
+        choose_wallet_version(&Account { owner, subaccount }, version)?;
     }
 
-    let tx = tx.unwrap();
+    let wallet = create_ton_wallet(owner, subaccount).await?;
 
-    let app_ton_address = APP_TON_ADDRESS.with_borrow_mut(|address| address.clone());
-
-    let amount = verify_mint_transaction(&tx, &app_ton_address)?;
-    
-    let ledger_canister = CK_LEDGER_CANISTER.with_borrow_mut(|canister| canister.clone());
+    Ok(get_ton_address_from_wallet(&wallet))
+}
 
-    let arg = icrc_ledger_types::icrc1::transfer::TransferArg {
-        from_subaccount: None,
-        to: Account {
-            owner: caller(),
-            subaccount: None,
-        },
-        fee: None,
-        created_at_time: None,
-        memo: None,
-        amount: amount.into(),
+// Check the deposit address of an account for new TON transfers and mint ckTON
+// for them. Only the owner can call it: the first call registers the account
+// for the periodic scan, a later one resumes the scan of an idle account.
+#[ic_cdk::update(guard = is_authenticated)]
+async fn update_balance(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> Result<Vec<MintedDeposit>, MinterError> {
+    let acc = Account {
+        owner: owner.unwrap_or_else(caller),
+        subaccount,
     };
 
-    let (res,): (Result<Nat, TransferError>,) =
-        ic_cdk::call(ledger_canister, "icrc1_transfer", (arg,))
-            .await
-            .unwrap();
-
-    if res.is_err() {
-        return Err(format!("Transfer failed: {:?}", res.err().unwrap()));
+    if acc.owner != caller() {
+        return Err(MinterError::Other("Only the owner can check the deposits of an account".to_string()));
     }
 
-    let block = res.unwrap().0.try_into().unwrap();
+    deposits::update_balance(acc).await
+}
+
+// Query the deposits of an account that wait for finality before being minted
+#[ic_cdk::query]
+fn get_pending_deposits(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> Vec<PendingDeposit> {
+    let acc = Account {
+        owner: owner.unwrap_or_else(caller),
+        subaccount,
+    };
 
-    Ok(block)
+    finality::pending_deposits(&acc)
 }
 
-// Generate a TON address for a given principal/subaccount
-#[ic_cdk::update]
-async fn generate_ton_address(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> String {
-    let path = get_path(owner, subaccount);
+// Query the wallet version the TON wallet of an account is derived with
+#[ic_cdk::query]
+fn get_wallet_version(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> TonWalletVersion {
+    let acc = Account {
+        owner: owner.unwrap_or_else(caller),
+        subaccount,
+    };
 
-    let pubkey = get_ic_pub_key(path.clone()).await.unwrap();
+    wallet_version(&acc)
+}
 
-    let ton_signer = ICTonSigner::new(pubkey, path);
+// Query the comment to attach to a TON transfer to the minter wallet so that it
+// is credited to the given account
+#[ic_cdk::query]
+fn get_deposit_memo(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> Result<String, String> {
+    if !read_config(|config| config.memo_deposits_enabled()) {
+        return Err("Memo deposits are disabled".to_string());
+    }
 
-    let wallet =
-        TonWallet::derive_default(ic_ton_lib::wallet::WalletVersion::V4R2, &ton_signer).unwrap();
+    let acc = Account {
+        owner: owner.unwrap_or_else(caller),
+        subaccount,
+    };
 
-    get_ton_address_from_wallet(&wallet)
+    Ok(acc.to_string())
 }
 
 // Query the deployed TON wallet address for a given principal/subaccount
@@ -382,7 +559,7 @@ fn get_ton_wallet_address(
         subaccount,
     };
 
-    DEPLOYED_WALLET.with_borrow(|store| store.get(&acc).map(|wallet| wallet.ton_address.clone()))
+    DEPLOYED_WALLET.with_borrow(|store| store.get(&acc.into()).map(|wallet| wallet.ton_address))
 }
 
 // Get the deposit address for a given principal (ICRC account)
@@ -398,40 +575,41 @@ async fn get_deposit_address(owner: Option<Principal>) -> String {
 
 // Deploy a TON wallet for the caller (async, guarded)
 #[ic_cdk::update(guard = is_authenticated)]
-async fn deploy_ton_wallet(subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, String> {
+async fn deploy_ton_wallet(subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, MinterError> {
    _deploy_wallet(caller(), subaccount, expire).await
 }
 
 // Internal wallet deployment logic
-async fn _deploy_wallet(owner: Principal, subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, String> {
+async fn _deploy_wallet(owner: Principal, subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, MinterError> {
     let acc = Account {
         owner,
         subaccount,
     };
 
-    let is_deployed = DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&acc));
+    let is_deployed = DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&acc.into()));
 
     if is_deployed {
-        return Err("Wallet already deployed".to_string());
+        return Err(MinterError::Other("Wallet already deployed".to_string()));
     };
 
     let wallet = create_ton_wallet(acc.owner, subaccount).await?;
 
     // removed it because i don't want to make cycles calling TON rpc to check wallet state
 
-    let ton_response = ton_api::get_ton_wallet_info(get_ton_address_from_wallet(&wallet)).await.unwrap();
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to get wallet info".to_string()));
-    }
+    let result = ton_api::get_ton_wallet_info(get_ton_address_from_wallet(&wallet), Operation::WalletDeployment).await?;
 
-    if ton_response.result.unwrap().wallet {
+    if result.wallet {
         DEPLOYED_WALLET.with_borrow_mut(|store| {
-            store.insert(acc, TONDeployedWallet {
+            store.insert(acc.into(), TONDeployedWallet {
                 ton_address: get_ton_address_from_wallet(&wallet),
+                version: Some(wallet_version(&acc)),
             });
         });
-        return Err("Wallet already deployed".to_string());
+        record_event(EventType::WalletDeployed {
+            account: acc,
+            ton_address: get_ton_address_from_wallet(&wallet),
+        });
+        return Err(MinterError::Other("Wallet already deployed".to_string()));
     }
 
     // let internal_mssg = wallet
@@ -463,11 +641,7 @@ async fn _deploy_wallet(owner: Principal, subaccount: Option<[u8; 32]>, expire :
 
     let enc = BASE64_STANDARD.encode(tx);
 
-    let ton_response = ton_api::send_boc_to_ton(enc).await?;
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Unknown error".to_string()));
-    }
+    let result = ton_api::send_boc_to_ton(enc, Operation::WalletDeployment).await?;
 
     PENDING_TASKS.with_borrow_mut(|tasks| {
         tasks.push_back(PendingTasks::DeployWallet(
@@ -477,37 +651,33 @@ async fn _deploy_wallet(owner: Principal, subaccount: Option<[u8; 32]>, expire :
         ));
     });
 
-    Ok(ton_response.result.unwrap().hash)
+    Ok(result.hash)
     
 }
 
 // Destroy a TON wallet (send all funds to another TON address)
 #[ic_cdk::update(guard = is_authenticated)]
-async fn destroy_ton_wallet(to_ton_address: String, subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, String> {
+async fn destroy_ton_wallet(to_ton_address: String, subaccount: Option<[u8; 32]>, expire : Option<u32>) -> Result<String, MinterError> {
     let acc = Account {
         owner: caller(),
         subaccount,
     };
 
-    #[cfg(network = "ic")]
-    let is_deployed = DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&acc));
+    let is_deployed = DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&acc.into()));
 
-    #[cfg(network = "ic")]
     if !is_deployed {
-        return Err("Wallet not deployed".to_string());
+        return Err(MinterError::Other("Wallet not deployed".to_string()));
     }
 
     let wallet = create_ton_wallet(acc.owner, subaccount).await?;
 
     let expire_at = expire.unwrap_or(nanos_to_seconds(time()) + 60);
 
-    let ton_response = ton_api::get_ton_wallet_info(get_ton_address_from_wallet(&wallet)).await?;
+    let result = ton_api::get_ton_wallet_info(get_ton_address_from_wallet(&wallet), Operation::Other).await?;
 
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to get wallet info".to_string()));
-    }
-    
-    let seqno = ton_response.result.unwrap().seqno.unwrap();
+    let seqno = result.seqno.ok_or("Wallet has no seqno".to_string())?;
+    let seqno = u32::try_from(seqno)
+        .map_err(|_| "Sequence number too large to convert to u32".to_string())?;
 
     let dest : TonAddress = to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
 
@@ -530,7 +700,7 @@ async fn destroy_ton_wallet(to_ton_address: String, subaccount: Option<[u8; 32]>
 
 
     let external_mssg = wallet
-        .create_external_body_with_mode(expire_at, seqno.try_into().unwrap(), vec![(160, transfer_message.to_arc())])
+        .create_external_body_with_mode(expire_at, seqno, vec![(160, transfer_message.to_arc())])
         .map_err(|s| s.to_string())?;
 
     let signed = wallet
@@ -545,13 +715,9 @@ async fn destroy_ton_wallet(to_ton_address: String, subaccount: Option<[u8; 32]>
     let boc = BagOfCells::from_root(wrapped);
     let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
 
-    let ton_response = ton_api::send_boc_to_ton(enc).await?;
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Unknown error".to_string()));
-    }
+    let result = ton_api::send_boc_to_ton(enc, Operation::Other).await?;
 
-    Ok(ton_response.result.unwrap().hash)
+    Ok(result.hash)
 }
 
 // Convert nanoseconds to seconds (u32)
@@ -561,152 +727,164 @@ fn nanos_to_seconds(nanos: u64) -> u32 {
 
 // Get TON address as string from wallet
 fn get_ton_address_from_wallet(wallet: &TonWallet<ICTonSigner>) -> String {
-    #[cfg(app_env = "dev")]
-    let address = wallet.address.to_base64_url_flags(true, true);
-
-    #[cfg(app_env = "prod")]
-    let address = wallet.address.to_base64_url();
-
-    address
+    get_ton_address_from_address(&wallet.address)
 }
 
-// Get TON address as string from TonAddress struct
+// Get TON address as string from TonAddress struct, in the form of the
+// configured network
 fn get_ton_address_from_address(address: &TonAddress) -> String {
-    #[cfg(app_env = "dev")]
-    let address = address.to_base64_url_flags(true, true);
-
-    #[cfg(app_env = "prod")]
-    let address = wallet.address.to_base64_url();
-
-    address
+    read_config(|config| config.ton_network()).format_address(address)
 }
 
-// Withdraw native tokens: burn ckTON and send TON to a destination address
+// Withdraw native tokens: burn the ckTON deposited on the caller's minter
+// subaccount and send TON to a destination address.
+// Once the ckTON is burned the call succeeds with the withdrawal id, the
+// message hash is only set if the payout was broadcast by this call.
 #[ic_cdk::update(guard = is_authenticated)]
-async fn withdraw_native(to_ton_address: String, amount: u64) -> Result<(String, u64), String> {
-   
-    let ledger_canister = CK_LEDGER_CANISTER.with_borrow_mut(|canister| canister.clone());
-
-    let app_ton_address = APP_TON_ADDRESS.with_borrow_mut(|address| address.clone());
-
-    let ton_response = ton_api::get_ton_wallet_info(app_ton_address.to_base64_url())
-        .await
-        .unwrap();
+async fn withdraw_native(to_ton_address: String, amount: u64) -> Result<NativeWithdrawal, String> {
+    let request = withdrawals::withdraw_deposited(caller(), to_ton_address, amount).await?;
+
+    // Sends the withdrawal right away, together with any other pending payout,
+    // unless a batch is already in flight. Otherwise the batcher sends it.
+    let hash = match withdrawals::send_withdrawal_batch().await {
+        Ok(Some((ids, hash))) if ids.contains(&request.id) => Some(hash),
+        Ok(_) => None,
+        Err(err) => {
+            ic_cdk::println!("Withdrawal {} queued, sending TON failed: {}", request.id, err);
+            None
+        }
+    };
 
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to get wallet info".to_string()));
-    }
+    Ok(NativeWithdrawal {
+        withdrawal_id: request.id,
+        ton_amount: request.ton_amount,
+        hash,
+    })
+}
 
-    let result = ton_response.result.unwrap();
+// Status of a withdrawal by id (burn block index)
+#[ic_cdk::query]
+fn retrieve_ton_status(withdrawal_id: u64) -> Option<WithdrawalRequest> {
+    withdrawals::get_request(withdrawal_id)
+}
 
-    if !result.wallet {
-        return Err("Wallet not deployed".to_string());
-    }
+// Withdrawals burned from an account, oldest first
+#[ic_cdk::query]
+fn withdrawal_status_by_account(account: Account) -> Vec<WithdrawalRequest> {
+    withdrawals::requests_by_account(account)
+}
 
-    // Check if the caller has enough balance in the ICRC ledger
-    let caller_account = Account {
-        owner: id(),
-        subaccount: Some(principal_to_subaccount(caller())),
+// Withdraw TON: burn ckTON approved to the minter (ICRC-2), then pay out the TON.
+// Returns the withdrawal id.
+#[ic_cdk::update(guard = is_authenticated)]
+async fn withdraw_ton(to_ton_address: String, amount: u64, from_subaccount: Option<[u8; 32]>) -> Result<u64, String> {
+    let from = Account {
+        owner: caller(),
+        subaccount: from_subaccount,
     };
-    
-    let (caller_balance,): (Nat,) = ic_cdk::call(ledger_canister, "icrc1_balance_of", (caller_account,))
-        .await
-        .map_err(|e| format!("Failed to check balance: {:?}", e))?;
-    
-    let caller_balance_u64: u64 = caller_balance.0.try_into().map_err(|_| "Balance too large to convert to u64".to_string())?;
-
-    let amount_to_burn = amount - CKTON_TRANSFER_FEE.with_borrow_mut(|fee| *fee);
-    
-    if caller_balance_u64 < amount {
-        return Err(format!("Insufficient balance: {} < {}", caller_balance_u64, amount));
-    }
-
-    let ton_fee = TON_FEE.with_borrow_mut(|fee| *fee);
-
-    let amount_to_send = amount_to_burn - ton_fee;
 
-    let ton_wallet = create_ton_wallet(id(), None).await?;
+    withdrawals::withdraw_ton(from, to_ton_address, amount).await
+}
 
+// Withdraw a jetton: burn the ck tokens approved to the minter on the ledger of
+// the jetton (ICRC-2) and 0.05 ckTON approved on the ckTON ledger for the TON
+// attached to the payout, then pay out the jettons. Returns the withdrawal id.
+#[ic_cdk::update(guard = is_authenticated)]
+async fn withdraw_jetton(symbol: String, to_ton_address: String, amount: u64, from_subaccount: Option<[u8; 32]>) -> Result<u64, String> {
+    let from = Account {
+        owner: caller(),
+        subaccount: from_subaccount,
+    };
 
-    let dest : TonAddress = to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
+    withdrawals::withdraw_jetton(from, symbol, to_ton_address, amount).await
+}
 
-    let common_message = CommonMsgInfo::InternalMessage(InternalMessage {
-        ihr_disabled: false,
-        bounce: false,
-        bounced: false,
-        src: TonAddress::NULL,
-        dest,
-        value: BigUint::from(amount_to_send),
-        ihr_fee: BigUint::ZERO,
-        fwd_fee: BigUint::ZERO,
-        created_lt: 0,
-        created_at: 0,
-    });
+// Query the bridged jettons
+#[ic_cdk::query]
+fn get_jettons() -> Vec<JettonConfig> {
+    jettons::jettons()
+}
 
+// Create a TON wallet for a principal/subaccount
+async fn create_ton_wallet(
+    owner: Principal,
+    subaccount: Option<[u8; 32]>,
+) -> Result<TonWallet<ICTonSigner>, String> {
+    let path = get_path(Some(owner), subaccount);
 
-    let expire_at = nanos_to_seconds(time()) + 60;
+    let pubkey = get_ic_pub_key(path.clone()).await?;
 
-    let transfer_message = TransferMessage::new(common_message)
-        .build()
-        .map_err(|s| s.to_string())?;
+    let ton_signer = ICTonSigner::new(pubkey, path);
 
-    let seqno = result.seqno.unwrap();
-    let seqno_u32 = u32::try_from(seqno)
-        .map_err(|_| "Sequence number too large to convert to u32".to_string())?;
+    let account = Account { owner, subaccount };
 
-    let internal_mssg = ton_wallet
-        .create_external_body(
-            expire_at,
-            seqno_u32,
-            vec![transfer_message.to_arc()],
-        )
-        .map_err(|s| s.to_string())?;
+    let version = wallet_version(&account);
 
-    let signed = ton_wallet
-        .sign_external_body(&internal_mssg)
-        .await
-        .map_err(|s| s.to_string())?;
+    let wallet = TonWallet::derive_default(version.into(), &ton_signer).map_err(|e| e.to_string())?;
 
-    let wrapped = ton_wallet
-        .wrap_signed_body(signed, false)
-        .map_err(|s| s.to_string())?;
+    // The first derivation fixes the version, and with it the address
+    if !is_minter_account(&account) {
+        WALLET_VERSIONS.with_borrow_mut(|versions| {
+            if !versions.contains_key(&account.into()) {
+                versions.insert(account.into(), version);
+            }
+        });
+    }
 
-    let boc = BagOfCells::from_root(wrapped);
+    Ok(wallet)
+}
 
-    let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
+// The V4R2 minter wallet
+fn is_minter_account(account: &Account) -> bool {
+    account.owner == id() && account.subaccount.is_none()
+}
 
-    let result = ton_api::send_boc_to_ton(enc).await?;
+// Wallet version an account's address was derived with, if it was handed out
+fn recorded_wallet_version(account: &Account) -> Option<TonWalletVersion> {
+    if let Some(wallet) = DEPLOYED_WALLET.with_borrow(|store| store.get(&(*account).into())) {
+        return Some(wallet.version.unwrap_or_default());
+    }
 
-    let hash = result.result.unwrap().hash;
+    deposits::deposit_wallet_version(account)
+        .or_else(|| WALLET_VERSIONS.with_borrow(|versions| versions.get(&(*account).into())))
+}
 
-    PENDING_TASKS.with_borrow_mut(|tasks| {
-        tasks.push_back(PendingTasks::Burn(
-            caller(),
-            amount_to_burn,
-            hash.clone(),
-            to_ton_address,
-            0,
-        ));
-    });
+// Wallet version of an account: the one its address was handed out with, or
+// the configured default for an account seen for the first time
+fn wallet_version(account: &Account) -> TonWalletVersion {
+    if is_minter_account(account) {
+        return TonWalletVersion::V4R2;
+    }
 
-    Ok((hash, amount_to_send))
+    recorded_wallet_version(account)
+        .unwrap_or_else(|| read_config(|config| config.default_wallet_version.unwrap_or_default()))
 }
 
-// Create a TON wallet for a principal/subaccount
-async fn create_ton_wallet(
-    owner: Principal,
-    subaccount: Option<[u8; 32]>,
-) -> Result<TonWallet<ICTonSigner>, String> {
-    let path = get_path(Some(owner), subaccount);
+// Picks the wallet version of an account before its address is handed out
+fn choose_wallet_version(account: &Account, version: TonWalletVersion) -> Result<(), String> {
+    if is_minter_account(account) {
+        return Err("The minter wallet version can't be chosen".to_string());
+    }
 
-    let pubkey = get_ic_pub_key(path.clone()).await.unwrap();
+    match recorded_wallet_version(account) {
+        Some(recorded) if recorded != version => {
+            Err(format!("The address of this account was already derived as a {:?} wallet", recorded))
+        }
+        Some(_) => Ok(()),
+        None => {
+            WALLET_VERSIONS.with_borrow_mut(|versions| versions.insert((*account).into(), version));
+            Ok(())
+        }
+    }
+}
 
-    let ton_signer = ICTonSigner::new(pubkey, path);
+// Create the Highload Wallet V3 of the minter from the configured contract code
+async fn create_highload_wallet(code: &str) -> Result<HighloadWalletV3, String> {
+    let path = get_path(Some(id()), None);
 
-    let wallet =
-        TonWallet::derive_default(ic_ton_lib::wallet::WalletVersion::V4R2, &ton_signer).unwrap();
+    let pubkey = get_ic_pub_key(path.clone()).await?;
 
-    Ok(wallet)
+    HighloadWalletV3::new(ICTonSigner::new(pubkey, path), code)
 }
 
 // Mint ckTON for a user after confirming TON deposit
@@ -715,7 +893,7 @@ async fn mint(
     to_account: String,
     amount: u64,
     subaccount: Option<[u8; 32]>, expire : Option<u32>
-) -> Result<String, String> {
+) -> Result<String, MinterError> {
     let caller_acc = Account {
         owner: caller(),
         subaccount,
@@ -723,55 +901,39 @@ async fn mint(
 
     let to_account =  Account::from_str(&to_account).map_err(|op| op.to_string())?;
 
-    #[cfg(network = "ic")]
-    let is_deployed = DEPLOYED_WALLET.with_borrow(|store| {
-        store
-            .get(&caller_acc)
-            .map(|wallet| wallet.ton_address.clone())
-    });
+    if deposits::is_deposit_account(&caller_acc) {
+        return Err(MinterError::Other("Deposits of this account are credited automatically, use update_balance".to_string()));
+    }
 
-    #[cfg(network = "ic")]
-    if is_deployed.is_none() {
-        return Err("Wallet not deployed".to_string());
+    let Some(ton_address) = DEPLOYED_WALLET.with_borrow(|store| {
+        store
+            .get(&caller_acc.into())
+            .map(|wallet| wallet.ton_address)
+    }) else {
+        return Err(MinterError::Other("Wallet not deployed".to_string()));
     };
 
     let wallet = create_ton_wallet(caller_acc.owner, subaccount).await?;
 
-    #[cfg(network = "ic")]
-    let ton_address = is_deployed.unwrap();
-
-    #[cfg(network = "local")]
-    let ton_address = get_ton_address_from_wallet(&wallet);
-
-    let ton_response = ton_api::get_ton_wallet_info(ton_address.clone())
-        .await
-        .unwrap();
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to get wallet info".to_string()));
-    }
-
-    let result = ton_response.result.unwrap();
+    let result = ton_api::get_ton_wallet_info(ton_address.clone(), Operation::Deposit).await?;
 
     if !result.wallet {
-        return Err("Wallet not deployed".to_string());
+        return Err(MinterError::Other("Wallet not deployed".to_string()));
     }
 
     let balance = result.balance;
-    let balance_int = balance.parse::<u64>().unwrap();
+    let balance_int = balance.parse::<u64>().map_err(|e| e.to_string())?;
     if balance_int < amount {
-        return Err("Insufficient balance".to_string());
+        return Err(MinterError::Other("Insufficient balance".to_string()));
     }
 
-    let seqno = result.seqno.unwrap();
+    let seqno = result.seqno.ok_or("Wallet has no seqno".to_string())?;
     let seqno_u32 = u32::try_from(seqno)
         .map_err(|_| "Sequence number too large to convert to u32".to_string())?;
 
     let expire_at = expire.unwrap_or(nanos_to_seconds(time()) + 60);
 
-    let app_ton_address = APP_TON_ADDRESS.with_borrow_mut(|address| {
-        address.clone()
-    });
+    let app_ton_address = read_config(|config| config.app_ton_address());
 
     let common_message = CommonMsgInfo::InternalMessage(InternalMessage {
         ihr_disabled: false,
@@ -812,13 +974,15 @@ async fn mint(
 
     let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
 
-    let ton_response = ton_api::send_boc_to_ton(enc).await?;
-
-    if !ton_response.ok {
-        return Err(ton_response.error.unwrap_or("Failed to send boc to ton".to_string()));
-    }
+    let result = ton_api::send_boc_to_ton(enc, Operation::Deposit).await?;
 
-    let result = ton_response.result.unwrap();
+    record_event(EventType::DepositSent {
+        from: caller_acc,
+        to: to_account,
+        amount,
+        ton_address: ton_address.clone(),
+        hash: result.hash.clone(),
+    });
 
     PENDING_TASKS.with_borrow_mut(|tasks| {
         tasks.push_back(PendingTasks::Mint(
@@ -836,7 +1000,7 @@ async fn mint(
 // Query the ledger canister principal as a string
 #[ic_cdk::query]
 async fn ledger_id() -> String {
-    let ledger_canister = CK_LEDGER_CANISTER.with_borrow_mut(|canister| canister.clone());
+    let ledger_canister = read_config(|config| config.ledger_canister);
 
     ledger_canister.to_string()
 }
@@ -844,7 +1008,7 @@ async fn ledger_id() -> String {
 // Query the minter's TON address as a string
 #[ic_cdk::query]
 async fn minter_ton_address() -> String {
-    let app_ton_address = APP_TON_ADDRESS.with_borrow_mut(|address| address.clone());
+    let app_ton_address = read_config(|config| config.app_ton_address());
 
     app_ton_address.to_string()
 }
@@ -852,152 +1016,316 @@ async fn minter_ton_address() -> String {
 // Query if a wallet is deployed for a given account
 #[ic_cdk::query]
 async fn wallet_deployed(account: Account) -> bool {
-    DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&account))
+    DEPLOYED_WALLET.with_borrow(|store| store.contains_key(&account.into()))
 }
 
 // Query the number of deployed wallets
 #[ic_cdk::query]
 async fn wallet_count() -> u64 {
-    DEPLOYED_WALLET.with_borrow(|store| store.len() as u64)
+    DEPLOYED_WALLET.with_borrow(|store| store.len())
+}
+
+// Query a page of the minter event log
+#[ic_cdk::query]
+fn get_events(start: u64, length: u64) -> Vec<Event> {
+    events::get_events(start, length)
+}
+
+// Query the total number of recorded events
+#[ic_cdk::query]
+fn get_event_count() -> u64 {
+    events::total_event_count()
+}
+
+// Query the state rebuilt from the whole event log
+#[ic_cdk::query]
+fn replay_events() -> ReplayedState {
+    events::replay_log()
+}
+
+// Query the cycles spent on HTTP outcalls, per operation
+#[ic_cdk::query]
+fn get_cycles_stats() -> CyclesStats {
+    cycles::cycles_stats()
+}
+
+// Trusted key block and the masterchain head verified against it
+#[ic_cdk::query]
+fn get_light_client_state() -> LightClientState {
+    lightclient::light_client_state()
 }
 
-// Admin setup for IC network: set canister principals and fees
-#[cfg(network = "ic")]
+// Admin: seed the light client with a key block and its validator set
 #[ic_cdk::update(guard = is_mint_controller)]
-async fn admin_setup(setup_args: AdminSetup) -> Result<(), String> {
-    let (ledger_record, indexer_record, ckton_transfer_fee, ton_fee) = (setup_args.ledger_canister, setup_args.indexer_canister, setup_args.ckton_transfer_fee, setup_args.ton_fee);
+fn set_trusted_key_block(key_block: TrustedKeyBlock) -> Result<(), String> {
+    lightclient::set_trusted_key_block(key_block)
+}
+
+// Ledger suites created by the minter
+#[ic_cdk::query]
+fn get_ledger_suites() -> Vec<LedgerSuite> {
+    ledger_suite::ledger_suites()
+}
+
+// Hash of the ledger or index wasm installed by the next create or upgrade
+#[ic_cdk::query]
+fn get_wasm_hash(kind: WasmKind) -> Option<String> {
+    ledger_suite::wasm_hash(kind)
+}
+
+// Admin: upload a chunk of the ledger or index wasm, chunk 0 starts a new upload
+#[ic_cdk::update(guard = is_mint_controller)]
+fn upload_wasm_chunk(kind: WasmKind, index: u32, chunk: Vec<u8>) -> Result<(), String> {
+    ledger_suite::upload_wasm_chunk(kind, index, chunk)
+}
+
+// Admin: complete a wasm upload, checked against its hex sha256
+#[ic_cdk::update(guard = is_mint_controller)]
+fn finish_wasm_upload(kind: WasmKind, sha256: String) -> Result<String, String> {
+    ledger_suite::finish_wasm_upload(kind, sha256)
+}
+
+// Admin: create the ledger and index of a new token
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn add_ledger_suite(args: LedgerSuiteArgs) -> Result<LedgerSuite, String> {
+    ledger_suite::add_ledger_suite(args, caller()).await
+}
+
+// Admin: upgrade the ledger and index of a token to the uploaded wasms
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn upgrade_ledger_suite(token_symbol: String, upgrade_args: Option<UpgradeArgs>) -> Result<LedgerSuite, String> {
+    ledger_suite::upgrade_ledger_suite(&token_symbol, upgrade_args).await
+}
+
+// Admin: upgrade the ckTON ledger to the uploaded ledger wasm, returns its module hash
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn upgrade_ckton_ledger(upgrade_args: Option<UpgradeArgs>) -> Result<Option<String>, String> {
+    ledger_suite::upgrade_ckton_ledger(upgrade_args).await
+}
 
-    CK_LEDGER_CANISTER.set(ledger_record);
-    CK_INDEXER_CANISTER.set(indexer_record);
+// Admin: upgrade the ckTON index to the uploaded index wasm, returns its module hash
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn upgrade_ckton_index() -> Result<Option<String>, String> {
+    ledger_suite::upgrade_ckton_index().await
+}
 
-    if let Some(ckton_transfer_fee) = ckton_transfer_fee {
-        CKTON_TRANSFER_FEE.set(ckton_transfer_fee);
+// ICRC-1 metadata the ckTON ledger is created or synced with
+#[ic_cdk::query]
+fn get_ckton_metadata() -> Vec<(String, MetadataValue)> {
+    metadata::ckton_metadata()
+}
+
+// Admin: push the ckTON metadata to the ledger with an upgrade, returns its module hash
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn sync_ckton_metadata() -> Result<Option<String>, String> {
+    metadata::sync_ckton_metadata().await
+}
+
+// Store the setup in the config and derive the minter TON address
+async fn apply_admin_setup(setup_args: AdminSetup) -> Result<(), String> {
+    let AdminSetup {
+        ledger_canister,
+        indexer_canister,
+        ckton_transfer_fee,
+        ton_fee,
+        memo_deposits,
+        highload_wallet_code,
+        default_wallet_version,
+        finality_policy,
+        jettons,
+        ckton_metadata,
+    } = setup_args;
+
+    // The providers are set by the init or upgrade argument
+    if finality_policy == Some(FinalityPolicy::BlockProof) {
+        ton_api::check_block_proof(&read_config(|config| config.ton_providers()))?;
     }
 
-    if let Some(ton_fee) = ton_fee {
-        TON_FEE.set(ton_fee);
+    let minter_address = match highload_wallet_code.clone().or(read_config(|config| config.highload_wallet_code.clone())) {
+        Some(code) => create_highload_wallet(&code).await?.address,
+        None => create_ton_wallet(id(), None).await?.address,
+    };
+
+    let app_ton_address = minter_address.to_base64_url();
+
+    let highload_wallet_code_hash = highload_wallet_code.as_deref().map(highload::code_hash).transpose()?;
+
+    // Jetton wallets belong to the minter wallet, they are derived again when it changes
+    let jettons = match jettons {
+        Some(jettons) => Some(jettons::resolve_jettons(jettons, &minter_address).await?),
+        None if read_config(|config| config.app_ton_address.as_ref() != Some(&app_ton_address) && !config.jettons().is_empty()) => {
+            Some(jettons::resolve_jettons(read_config(|config| config.jettons()), &minter_address).await?)
+        }
+        None => None,
+    };
+
+    let address_changed = read_config(|config| config.app_ton_address.as_ref() != Some(&app_ton_address));
+
+    // Payouts of a batch in flight are looked up on the current minter wallet
+    if address_changed {
+        if withdrawals::batch_in_flight() {
+            return Err("Can't change the minter wallet while a withdrawal batch is in flight".to_string());
+        }
+
+        // The TON backing ckTON is paid out from the minter wallet, and memo
+        // deposits are only scanned on it: the current one must be emptied first
+        let previous = read_config(|config| config.app_ton_address());
+
+        if previous != TonAddress::NULL {
+            let info = ton_api::get_ton_wallet_info(get_ton_address_from_address(&previous), Operation::Other).await?;
+            let balance = info.balance.parse::<u64>().map_err(|e| e.to_string())?;
+
+            if balance >= read_config(|config| config.min_sweep_balance()) {
+                return Err(format!(
+                    "Can't change the minter wallet while it holds {} nanotons, move them to {} first",
+                    balance, app_ton_address
+                ));
+            }
+        }
+
+        withdrawals::reset_minter_wallet();
     }
 
-    let wallet = create_ton_wallet(id(), None).await?;
+    mutate_config(|config| {
+        config.ledger_canister = ledger_canister;
+        config.indexer_canister = indexer_canister;
+
+        if let Some(ckton_transfer_fee) = ckton_transfer_fee {
+            config.ckton_transfer_fee = ckton_transfer_fee;
+        }
+
+        if let Some(ton_fee) = ton_fee {
+            config.ton_fee = ton_fee;
+        }
+
+        if memo_deposits.is_some() {
+            config.memo_deposits = memo_deposits;
+        }
+
+        if highload_wallet_code.is_some() {
+            config.highload_wallet_code = highload_wallet_code.clone();
+        }
+
+        // Only applies to accounts without a recorded wallet
+        if default_wallet_version.is_some() {
+            config.default_wallet_version = default_wallet_version;
+        }
+
+        if finality_policy.is_some() {
+            config.finality_policy = finality_policy;
+        }
+
+        if jettons.is_some() {
+            config.jettons = jettons.clone();
+        }
+
+        if ckton_metadata.is_some() {
+            config.ckton_metadata = ckton_metadata.clone();
+        }
+
+        config.app_ton_address = Some(app_ton_address.clone());
+    });
+
+    record_event(EventType::ConfigChanged {
+        ledger_canister: Some(ledger_canister),
+        indexer_canister: Some(indexer_canister),
+        ckton_transfer_fee,
+        ton_fee,
+        app_ton_address: Some(app_ton_address),
+        memo_deposits,
+        highload_wallet_code_hash,
+        default_wallet_version,
+        ton_providers: None,
+        ton_providers_threshold: None,
+        ton_outcall_mode: None,
+        subnet_size: None,
+        finality_policy,
+        jettons,
+        ckton_metadata,
+        ton_network: None,
+        schnorr_key_name: None,
+        proxy: None,
+        min_sweep_balance: None,
+        max_accounts_per_scan: None,
+    });
 
-    APP_TON_ADDRESS.set(wallet.address);
+    // The ckTON ledger advertises the minter address in its metadata
+    if address_changed && ledger_canister != Principal::anonymous() {
+        metadata::sync_ckton_metadata()
+            .await
+            .map_err(|err| format!("The setup was applied, but syncing the ckTON metadata failed: {}", err))?;
+    }
 
     Ok(())
 }
 
+// Admin: send the whole balance of the minter wallet to the wallet it is
+// switched to next, returns the message hash
+#[ic_cdk::update(guard = is_mint_controller)]
+async fn admin_empty_minter_wallet(to_ton_address: String) -> Result<String, String> {
+    let dest = to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
+
+    withdrawals::empty_minter_wallet(dest).await
+}
+
 // Admin: deploy the minter's TON wallet
 #[ic_cdk::update(guard = is_mint_controller)]
 async fn admin_mint_wallet_deploy() -> Result<(), String> {
     // deploy wallet
-    _deploy_wallet(id(), None, None).await?;
+    match read_config(|config| config.highload_wallet_code.clone()) {
+        Some(code) => {
+            withdrawals::deploy_highload_wallet(create_highload_wallet(&code).await?).await?;
+        }
+        None => {
+            _deploy_wallet(id(), None, None).await?;
+        }
+    }
 
     Ok(())
 }
 
-// Admin setup for local development: can auto-create ledger/indexer canisters
-#[cfg(network = "local")]
+// Admin setup: set canister principals and fees. Without arguments the ckTON
+// ledger suite is created from the uploaded (or embedded, see `LEDGER_WASM`) wasms.
 #[ic_cdk::update(guard = is_mint_controller)]
 async fn admin_setup(setup_args: Option<AdminSetup>) -> Result<(), String> {
 
-    let (ledger_record, indexer_record, ckton_transfer_fee, ton_fee) = match setup_args {
-        Some(setup) => {
-           (setup.ledger_canister, setup.indexer_canister, setup.ckton_transfer_fee, setup.ton_fee)
-        },
+    let setup = match setup_args {
+        Some(setup) => setup,
         None => {
-            let args = CreateCanisterArgument {
-                settings: Some(CanisterSettings {
-                    controllers: Some(vec![id(), caller()]),
-                    compute_allocation: None,
-                    memory_allocation: None,
-                    freezing_threshold: None,
-                    reserved_cycles_limit: None,
-                    log_visibility: None,
-                    wasm_memory_limit: None,
-                }),
-            };
-            let (ledger_record,) = create_canister(args.clone(), 20_000_000_000_000u128)
-                .await
-                .map_err(|s| s.1)?;
-        
-            let (indexer_record,) = create_canister(args, 20_000_000_000_000u128)
-                .await
-                .map_err(|s| s.1)?;
-        
-            let archive_options = ArchiveOptions {
-                num_blocks_to_archive: 1000,
-                max_transactions_per_response: None,
-                trigger_threshold: 2000,
-                more_controller_ids: None,
-                max_message_size_bytes: None,
-                cycles_for_archive_creation: None,
-                node_max_memory_size_bytes: None,
-                controller_id: id(),
-            };
-        
-            let ledger_init_args = InitArgs {
-                decimals: Some(9),
-                token_symbol: "ckTON".to_string(),
-                transfer_fee: CKTON_TRANSFER_FEE.with_borrow_mut(|fee| *fee).into(),
-                metadata: vec![],
-                minting_account: Account {
-                    owner: id(),
-                    subaccount: None,
-                },
-                initial_balances: vec![],
-                fee_collector_account: Some(Account {
-                    owner: id(),
-                    subaccount: None,
-                }),
-                archive_options,
-                max_memo_length: None,
-                token_name: "ckTON".to_string(),
-                feature_flags: Some(FeatureFlags { icrc2: true }),
-            };
-        
-            install_code(InstallCodeArgument {
-                canister_id: ledger_record.canister_id,
-                wasm_module: LEDGER_WASM.to_vec(),
-                arg: encode_args((LedgerArgument::Init(ledger_init_args),)).map_err(|s| s.to_string())?,
-                mode: ic_cdk::api::management_canister::main::CanisterInstallMode::Install,
-            })
-            .await
-            .unwrap();
-        
-            let index_args = InitArg {
-                ledger_id: ledger_record.canister_id,
-                retrieve_blocks_from_ledger_interval_seconds: None,
+            // The ckTON suite of an earlier setup is kept
+            let suite = match ledger_suite::ledger_suite("ckTON") {
+                Ok(suite) if suite.index_module_hash.is_some() => suite,
+                _ => {
+                    ledger_suite::add_ledger_suite(
+                        LedgerSuiteArgs {
+                            token_symbol: "ckTON".to_string(),
+                            token_name: "ckTON".to_string(),
+                            decimals: 9,
+                            transfer_fee: read_config(|config| config.ckton_transfer_fee),
+                            metadata: metadata::ckton_metadata(),
+                        },
+                        caller(),
+                    )
+                    .await?
+                }
             };
-        
-            install_code(InstallCodeArgument {
-                mode: ic_cdk::api::management_canister::main::CanisterInstallMode::Install,
-                canister_id: indexer_record.canister_id,
-                wasm_module: INDEXER_WASM.to_vec(),
-                arg: encode_args((IndexArg::Init(index_args),)).map_err(|s| s.to_string())?,
-            })
-            .await
-            .unwrap();
 
-            (ledger_record.canister_id, indexer_record.canister_id, None, None)
+            AdminSetup {
+                ledger_canister: suite.ledger,
+                indexer_canister: suite.index,
+                ckton_transfer_fee: None,
+                ton_fee: None,
+                memo_deposits: None,
+                highload_wallet_code: None,
+                default_wallet_version: None,
+                finality_policy: None,
+                jettons: None,
+                ckton_metadata: None,
+            }
         },
     };
-    
-
-    CK_LEDGER_CANISTER.set(ledger_record);
-    CK_INDEXER_CANISTER.set(indexer_record);
-    
-    if let Some(ckton_transfer_fee) = ckton_transfer_fee {
-        CKTON_TRANSFER_FEE.set(ckton_transfer_fee);
-    }
 
-    if let Some(ton_fee) = ton_fee {
-        TON_FEE.set(ton_fee);
-    }
-
-    let wallet = create_ton_wallet(id(), None).await?;
-
-    APP_TON_ADDRESS.set(wallet.address);
-
-    Ok(())
+    apply_admin_setup(setup).await
 }
 
 // Guard: only allow authenticated (non-anonymous) callers
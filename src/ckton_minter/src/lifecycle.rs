@@ -0,0 +1,233 @@
+// ==========================
+// ckton_minter/src/lifecycle.rs
+// Install and upgrade arguments of the minter.
+// Everything that differs between deployments (TON network, providers,
+// proxy, Schnorr key, fees, ledgers and limits) is passed when the canister
+// is installed or upgraded, so the same wasm runs in every environment and no
+// API key is built into it.
+// ==========================
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::{
+    events::{record_event, EventType},
+    state::{mutate_config, read_config},
+    ton_api::{self, ProxyConfig, TonProvider},
+    types::{FinalityPolicy, TonNetwork, TonOutcallMode},
+};
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum MinterArg {
+    Init(InitArg),
+    Upgrade(UpgradeArg),
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct InitArg {
+    pub ton_network: TonNetwork,
+    // e.g. "key_1" on mainnet, "test_key_1" on the IC test key, "dfx_test_key" locally
+    pub schnorr_key_name: String,
+    pub ton_providers: Vec<TonProvider>,
+    pub ton_providers_threshold: Option<u8>,
+    pub ton_outcall_mode: Option<TonOutcallMode>,
+    pub proxy: Option<ProxyConfig>,
+    pub ledger_canister: Option<Principal>,
+    pub indexer_canister: Option<Principal>,
+    pub ckton_transfer_fee: Option<u64>,
+    pub ton_fee: Option<u64>,
+    pub subnet_size: Option<u32>,
+    pub min_sweep_balance: Option<u64>,
+    pub max_accounts_per_scan: Option<u32>,
+}
+
+// Only the set fields are changed
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct UpgradeArg {
+    // The network and the key can't be changed once set, every derived TON
+    // address depends on them
+    pub ton_network: Option<TonNetwork>,
+    pub schnorr_key_name: Option<String>,
+    pub ton_providers: Option<Vec<TonProvider>>,
+    pub ton_providers_threshold: Option<u8>,
+    pub ton_outcall_mode: Option<TonOutcallMode>,
+    pub proxy: Option<ProxyConfig>,
+    pub ledger_canister: Option<Principal>,
+    pub indexer_canister: Option<Principal>,
+    pub ckton_transfer_fee: Option<u64>,
+    pub ton_fee: Option<u64>,
+    pub subnet_size: Option<u32>,
+    pub min_sweep_balance: Option<u64>,
+    pub max_accounts_per_scan: Option<u32>,
+}
+
+impl From<InitArg> for UpgradeArg {
+    fn from(arg: InitArg) -> Self {
+        UpgradeArg {
+            ton_network: Some(arg.ton_network),
+            schnorr_key_name: Some(arg.schnorr_key_name),
+            ton_providers: Some(arg.ton_providers),
+            ton_providers_threshold: arg.ton_providers_threshold,
+            ton_outcall_mode: arg.ton_outcall_mode,
+            proxy: arg.proxy,
+            ledger_canister: arg.ledger_canister,
+            indexer_canister: arg.indexer_canister,
+            ckton_transfer_fee: arg.ckton_transfer_fee,
+            ton_fee: arg.ton_fee,
+            subnet_size: arg.subnet_size,
+            min_sweep_balance: arg.min_sweep_balance,
+            max_accounts_per_scan: arg.max_accounts_per_scan,
+        }
+    }
+}
+
+pub fn init(arg: MinterArg) -> Result<(), String> {
+    match arg {
+        MinterArg::Init(arg) => apply(arg.into()),
+        MinterArg::Upgrade(_) => Err("The minter must be installed with an init argument".to_string()),
+    }
+}
+
+// Deployments from before runtime configuration have no network, key name or
+// providers yet, their first upgrade has to set them
+pub fn post_upgrade(arg: Option<MinterArg>) -> Result<(), String> {
+    match arg {
+        Some(MinterArg::Upgrade(arg)) => apply(arg),
+        None => {
+            if read_config(|config| config.ton_network.is_none() || config.schnorr_key_name().is_empty()) {
+                return Err("Upgrade with an argument that sets the TON network and the Schnorr key name".to_string());
+            }
+            if read_config(|config| config.ton_providers().is_empty()) {
+                return Err("Upgrade with an argument that sets the TON providers".to_string());
+            }
+            Ok(())
+        }
+        Some(MinterArg::Init(_)) => Err("The minter must be upgraded with an upgrade argument".to_string()),
+    }
+}
+
+fn apply(arg: UpgradeArg) -> Result<(), String> {
+    let UpgradeArg {
+        ton_network,
+        schnorr_key_name,
+        ton_providers,
+        ton_providers_threshold,
+        ton_outcall_mode,
+        proxy,
+        ledger_canister,
+        indexer_canister,
+        ckton_transfer_fee,
+        ton_fee,
+        subnet_size,
+        min_sweep_balance,
+        max_accounts_per_scan,
+    } = arg;
+
+    if ton_network.is_none() && read_config(|config| config.ton_network.is_none()) {
+        return Err("The TON network is not set".to_string());
+    }
+
+    if schnorr_key_name.as_ref().map_or_else(|| read_config(|config| config.schnorr_key_name().is_empty()), |name| name.is_empty()) {
+        return Err("The Schnorr key name is not set".to_string());
+    }
+
+    // Deposit addresses and the minter wallet would move to other addresses
+    if let (Some(network), Some(current)) = (ton_network, read_config(|config| config.ton_network)) {
+        if network != current {
+            return Err(format!("The TON network is already set to {:?}", current));
+        }
+    }
+
+    if let Some(name) = schnorr_key_name.as_ref() {
+        let current = read_config(|config| config.schnorr_key_name());
+        if !current.is_empty() && *name != current {
+            return Err(format!("The Schnorr key name is already set to {}", current));
+        }
+    }
+
+    let providers = ton_providers.clone().unwrap_or_else(|| read_config(|config| config.ton_providers()));
+    if providers.is_empty() {
+        return Err("At least one TON provider is needed".to_string());
+    }
+
+    if ton_providers.is_some() || ton_providers_threshold.is_some() {
+        let threshold = ton_providers_threshold.or_else(|| read_config(|config| config.ton_providers_threshold));
+        ton_api::check_threshold(&providers, threshold)?;
+    }
+
+    // Deposits proven with blocks need providers of signatures and raw blocks
+    if ton_providers.is_some() && read_config(|config| config.finality_policy) == Some(FinalityPolicy::BlockProof) {
+        ton_api::check_block_proof(&providers)?;
+    }
+
+    if max_accounts_per_scan == Some(0) {
+        return Err("At least one deposit address must be scanned per run".to_string());
+    }
+
+    mutate_config(|config| {
+        if ton_network.is_some() {
+            config.ton_network = ton_network;
+        }
+        if schnorr_key_name.is_some() {
+            config.schnorr_key_name = schnorr_key_name.clone();
+        }
+        if ton_providers.is_some() {
+            config.ton_providers = ton_providers.clone();
+        }
+        if ton_providers_threshold.is_some() {
+            config.ton_providers_threshold = ton_providers_threshold;
+        }
+        if ton_outcall_mode.is_some() {
+            config.ton_outcall_mode = ton_outcall_mode;
+        }
+        if proxy.is_some() {
+            config.proxy = proxy.clone();
+        }
+        if let Some(ledger_canister) = ledger_canister {
+            config.ledger_canister = ledger_canister;
+        }
+        if let Some(indexer_canister) = indexer_canister {
+            config.indexer_canister = indexer_canister;
+        }
+        if let Some(ckton_transfer_fee) = ckton_transfer_fee {
+            config.ckton_transfer_fee = ckton_transfer_fee;
+        }
+        if let Some(ton_fee) = ton_fee {
+            config.ton_fee = ton_fee;
+        }
+        if subnet_size.is_some() {
+            config.subnet_size = subnet_size;
+        }
+        if min_sweep_balance.is_some() {
+            config.min_sweep_balance = min_sweep_balance;
+        }
+        if max_accounts_per_scan.is_some() {
+            config.max_accounts_per_scan = max_accounts_per_scan;
+        }
+    });
+
+    record_event(EventType::ConfigChanged {
+        ledger_canister,
+        indexer_canister,
+        ckton_transfer_fee,
+        ton_fee,
+        app_ton_address: None,
+        memo_deposits: None,
+        highload_wallet_code_hash: None,
+        default_wallet_version: None,
+        ton_providers,
+        ton_providers_threshold,
+        ton_outcall_mode,
+        subnet_size,
+        finality_policy: None,
+        jettons: None,
+        ckton_metadata: None,
+        ton_network,
+        schnorr_key_name,
+        proxy,
+        min_sweep_balance,
+        max_accounts_per_scan,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,458 @@
+// ==========================
+// ckton_minter/src/lightclient.rs
+// Verification of masterchain blocks against a trusted validator set.
+// A controller seeds the light client with a key block and the validator set
+// it elects. The minter then follows the masterchain head and only accepts a
+// block once validators holding more than 2/3 of the weight signed it, so the
+// head doesn't depend on what a single provider reports. Each new key block
+// is checked the same way before the validator set it elects is trusted.
+// Deposits can be proven against signed blocks: the hashes linking a signed
+// masterchain block to the shard block holding the transaction are checked
+// block by block, see `prove_transaction`.
+// ==========================
+
+use std::cell::RefCell;
+
+use base64::prelude::*;
+use candid::CandidType;
+use ed25519_dalek::{Signature, VerifyingKey};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell};
+use ic_ton_lib::{cell::ArcCell, TonAddress};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    block,
+    consts::{MAX_KEY_BLOCKS_PER_SYNC, MAX_SHARD_BLOCKS_PER_PROOF, PROOF_MC_BLOCK_DELAY_SECS},
+    cycles::Operation,
+    state::{candid_storable, get_memory, Memory},
+    ton_api::{self, TonBlockId, TonBlockSignatures},
+};
+
+const LIGHT_CLIENT_MEMORY_ID: MemoryId = MemoryId::new(14);
+
+// TL constructor of `ton.blockId root_cell_hash:int256 file_hash:int256`, the
+// message validators sign
+const TON_BLOCK_ID_MAGIC: u32 = 0xc50b6e70;
+// TL constructor of `pub.ed25519 key:int256`, hashed into the validator short id
+const PUB_ED25519_MAGIC: u32 = 0x4813b4c6;
+
+const MASTERCHAIN: i32 = -1;
+
+thread_local! {
+    static LIGHT_CLIENT: RefCell<StableCell<LightClientState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LIGHT_CLIENT_MEMORY_ID), LightClientState::default())
+            .expect("failed to initialize the light client state")
+    );
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct Validator {
+    // Hex ed25519 public key, as listed in config param 34
+    pub public_key: String,
+    pub weight: u64,
+}
+
+// A key block and the validator set that signs the masterchain after it
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct TrustedKeyBlock {
+    pub block: TonBlockId,
+    pub validators: Vec<Validator>,
+}
+
+#[derive(Debug, Clone, Default, CandidType, Deserialize)]
+pub struct LightClientState {
+    pub key_block: Option<TrustedKeyBlock>,
+    // Newest masterchain block signed by the trusted validator set
+    pub last_verified: Option<TonBlockId>,
+    // Why the last sync failed, cleared by the next successful one
+    pub last_error: Option<String>,
+}
+
+candid_storable!(LightClientState);
+
+pub fn light_client_state() -> LightClientState {
+    LIGHT_CLIENT.with_borrow(|cell| cell.get().clone())
+}
+
+// Newest masterchain seqno signed by the trusted validators. `None` when the
+// light client is not seeded, an error while it has not verified a block yet.
+pub fn verified_head() -> Result<Option<u32>, String> {
+    LIGHT_CLIENT.with_borrow(|cell| {
+        let state = cell.get();
+        match (&state.key_block, &state.last_verified) {
+            (None, _) => Ok(None),
+            (Some(_), Some(block)) => Ok(Some(block.seqno)),
+            (Some(_), None) => Err("The light client has not verified a masterchain block yet".to_string()),
+        }
+    })
+}
+
+fn mutate_state<F: FnOnce(&mut LightClientState)>(f: F) {
+    LIGHT_CLIENT.with_borrow_mut(|cell| {
+        let mut state = cell.get().clone();
+        f(&mut state);
+        cell.set(state).expect("failed to write the light client state");
+    });
+}
+
+// Replaces the trust anchor. Blocks verified against the previous validator
+// set are kept only if they come after the new key block.
+pub fn set_trusted_key_block(key_block: TrustedKeyBlock) -> Result<(), String> {
+    if key_block.block.workchain != MASTERCHAIN {
+        return Err("The key block must be a masterchain block".to_string());
+    }
+
+    hash_bytes(&key_block.block.root_hash)?;
+    hash_bytes(&key_block.block.file_hash)?;
+
+    if key_block.validators.is_empty() {
+        return Err("The validator set is empty".to_string());
+    }
+
+    for validator in &key_block.validators {
+        verifying_key(&validator.public_key)?;
+    }
+
+    mutate_state(|state| {
+        if state.last_verified.as_ref().is_some_and(|block| block.seqno <= key_block.block.seqno) {
+            state.last_verified = None;
+        }
+        state.key_block = Some(key_block);
+        state.last_error = None;
+    });
+
+    Ok(())
+}
+
+// Moves the verified head to the newest masterchain block every provider
+// knows, once its signatures check out. Validator sets rotate with key
+// blocks: when the trusted set no longer reaches 2/3 of the signatures the
+// key blocks since the trusted one are followed first.
+pub async fn sync_masterchain() {
+    let Some(key_block) = light_client_state().key_block else {
+        return;
+    };
+
+    let result = sync_to_head(&key_block).await;
+
+    mutate_state(|state| match result {
+        Ok(block) => {
+            if state.last_verified.as_ref().map_or(true, |last| last.seqno < block.seqno) {
+                state.last_verified = Some(block);
+            }
+            state.last_error = None;
+        }
+        Err(err) => {
+            ic_cdk::println!("Light client sync failed: {}", err);
+            state.last_error = Some(err);
+        }
+    });
+}
+
+async fn sync_to_head(key_block: &TrustedKeyBlock) -> Result<TonBlockId, String> {
+    let heads = ton_api::get_masterchain_info(Operation::LightClient).await?;
+
+    // Every provider has the lowest head, a provider lying about it only
+    // delays the sync since its signatures won't verify
+    let seqno = heads
+        .iter()
+        .map(|info| info.last.seqno)
+        .min()
+        .ok_or("No masterchain head".to_string())?;
+
+    if seqno <= key_block.block.seqno {
+        return Err(format!("Masterchain head {} is not after key block {}", seqno, key_block.block.seqno));
+    }
+
+    match signed_block(seqno, &key_block.validators, Operation::LightClient).await {
+        Ok(block) => Ok(block),
+        Err(err) => match follow_key_blocks(key_block, seqno).await? {
+            Some(key_block) => signed_block(seqno, &key_block.validators, Operation::LightClient).await,
+            None => Err(err),
+        },
+    }
+}
+
+// The id of the masterchain block `seqno` once `validators` signed it
+async fn signed_block(seqno: u32, validators: &[Validator], operation: Operation) -> Result<TonBlockId, String> {
+    let responses = ton_api::get_masterchain_block_signatures(seqno, operation).await?;
+
+    let mut failure = None;
+    for response in responses.into_iter().filter(|response| response.id.seqno == seqno) {
+        match verify_signatures(&response, validators) {
+            Ok(()) => return Ok(response.id),
+            Err(err) => {
+                failure.get_or_insert(err);
+            }
+        }
+    }
+
+    Err(failure.unwrap_or("No signatures".to_string()))
+}
+
+// Trusts the key blocks that came after the trusted one and before the
+// masterchain block `head`, oldest first, each once it is signed by the
+// validators the previous one elected. Returns the newest key block, `None`
+// when there is no new one.
+async fn follow_key_blocks(trusted: &TrustedKeyBlock, head: u32) -> Result<Option<TrustedKeyBlock>, String> {
+    // The key blocks are found walking back from the head: a block only
+    // points at the previous key block, and isn't trusted before its
+    // signatures are checked below
+    let (_, head_root) = reported_block(head).await?;
+    let head_info = block::block_info(&head_root)?;
+
+    let mut seqno = if head_info.key_block { head } else { head_info.prev_key_block_seqno };
+    let mut key_blocks = vec![];
+
+    while seqno > trusted.block.seqno {
+        if key_blocks.len() >= MAX_KEY_BLOCKS_PER_SYNC {
+            return Err(format!("More than {} key blocks since key block {}", MAX_KEY_BLOCKS_PER_SYNC, trusted.block.seqno));
+        }
+
+        let (signatures, root) = reported_block(seqno).await?;
+        let info = block::block_info(&root)?;
+        if !info.key_block {
+            return Err(format!("Block {} is not a key block", seqno));
+        }
+
+        seqno = info.prev_key_block_seqno;
+        key_blocks.push((signatures, root));
+    }
+
+    let mut key_block = trusted.clone();
+    let mut followed = false;
+
+    for (signatures, root) in key_blocks.into_iter().rev() {
+        verify_signatures(&signatures, &key_block.validators)?;
+
+        // The BOC matches the signed block, see `reported_block`
+        key_block = TrustedKeyBlock {
+            block: signatures.id,
+            validators: block::validators(&root)?,
+        };
+        followed = true;
+
+        ic_cdk::println!("Light client follows key block {}", key_block.block.seqno);
+        mutate_state(|state| state.key_block = Some(key_block.clone()));
+    }
+
+    Ok(followed.then_some(key_block))
+}
+
+// The masterchain block `seqno` and its signatures as a provider reports
+// them, the BOC matching the block id the signatures are for. The signatures
+// are not verified.
+async fn reported_block(seqno: u32) -> Result<(TonBlockSignatures, ArcCell), String> {
+    let responses = ton_api::get_masterchain_block_signatures(seqno, Operation::LightClient).await?;
+
+    let mut failure = None;
+    for signatures in responses.into_iter().filter(|response| response.id.seqno == seqno) {
+        match block_root(&signatures.id, Operation::LightClient).await {
+            Ok(root) => return Ok((signatures, root)),
+            Err(err) => {
+                failure.get_or_insert(err);
+            }
+        }
+    }
+
+    Err(failure.unwrap_or(format!("No signatures of block {}", seqno)))
+}
+
+// Root cell of the first BOC of `block` that matches its hashes
+async fn block_root(block: &TonBlockId, operation: Operation) -> Result<ArcCell, String> {
+    let root_hash = hash_bytes(&block.root_hash)?;
+    let file_hash = hash_bytes(&block.file_hash)?;
+
+    let mut failure = None;
+    for response in ton_api::get_block(block.clone(), operation).await? {
+        match block::parse_block(&response.data, &root_hash, &file_hash) {
+            Ok(root) => return Ok(root),
+            Err(err) => {
+                failure.get_or_insert(err);
+            }
+        }
+    }
+
+    Err(format!("No valid BOC of block {}: {}", block.seqno, failure.unwrap_or_default()))
+}
+
+// Proves that the transaction of `account` at `lt` with `hash` is in a shard
+// block committed by a masterchain block the trusted validators signed, and
+// returns that masterchain block. Shard blocks are walked back through their
+// previous block hashes, from the one the masterchain block generated a bit
+// after the transaction commits for the shard of the account.
+pub async fn prove_transaction(account: &TonAddress, lt: u64, hash: &[u8; 32], utime: u64, operation: Operation) -> Result<TonBlockId, String> {
+    let key_block = light_client_state()
+        .key_block
+        .ok_or("The light client is not seeded".to_string())?;
+
+    if account.workchain == MASTERCHAIN {
+        return Err("Only basechain transactions can be proven".to_string());
+    }
+
+    let account_id: [u8; 32] = account
+        .hash_part
+        .as_slice()
+        .try_into()
+        .map_err(|_| "Invalid account id".to_string())?;
+
+    let mut seqnos: Vec<u32> = ton_api::lookup_masterchain_block(utime.saturating_add(PROOF_MC_BLOCK_DELAY_SECS), operation)
+        .await?
+        .iter()
+        .map(|block| block.seqno)
+        .collect();
+    seqnos.sort();
+    seqnos.dedup();
+
+    let mut failure = None;
+    for seqno in seqnos {
+        if seqno <= key_block.block.seqno {
+            failure.get_or_insert(format!("Masterchain block {} is before the trusted key block", seqno));
+            continue;
+        }
+
+        let result = async {
+            let mc_block = signed_block(seqno, &key_block.validators, operation).await?;
+            let mc_root = block_root(&mc_block, operation).await?;
+            let top = block::shard_top(&mc_root, account.workchain, &account_id)?;
+            find_in_shard(account.workchain, &account_id, lt, hash, top, operation).await?;
+            Ok::<_, String>(mc_block)
+        }
+        .await;
+
+        match result {
+            Ok(mc_block) => return Ok(mc_block),
+            Err(err) => {
+                failure.get_or_insert(err);
+            }
+        }
+    }
+
+    Err(failure.unwrap_or("No masterchain block found after the transaction".to_string()))
+}
+
+// Walks back the shard blocks from `next` to the one covering `lt` and checks
+// that it holds the transaction
+async fn find_in_shard(
+    workchain: i32,
+    account_id: &[u8; 32],
+    lt: u64,
+    hash: &[u8; 32],
+    mut next: block::BlockRef,
+    operation: Operation,
+) -> Result<(), String> {
+    if lt > next.end_lt {
+        return Err(format!("Transaction at lt {} is not committed by the masterchain yet", lt));
+    }
+
+    for _ in 0..MAX_SHARD_BLOCKS_PER_PROOF {
+        let id = TonBlockId {
+            workchain,
+            shard: (next.shard as i64).to_string(),
+            seqno: next.seqno,
+            root_hash: BASE64_STANDARD.encode(next.root_hash),
+            file_hash: BASE64_STANDARD.encode(next.file_hash),
+        };
+
+        let root = block_root(&id, operation).await?;
+        let info = block::block_info(&root)?;
+
+        if lt >= info.start_lt {
+            return match block::account_transactions(&root, account_id)?.contains(&(lt, *hash)) {
+                true => Ok(()),
+                false => Err(format!("Transaction at lt {} is not in shard block {}", lt, info.seqno)),
+            };
+        }
+
+        next = info
+            .prev
+            .into_iter()
+            .find(|prev| block::shard_contains(prev.shard, account_id))
+            .ok_or(format!("No block before shard block {} holds the account", info.seqno))?;
+    }
+
+    Err(format!("Transaction at lt {} is more than {} shard blocks back", lt, MAX_SHARD_BLOCKS_PER_PROOF))
+}
+
+// Checks that validators holding more than 2/3 of the weight of `validators`
+// signed the block `signatures.id`
+pub fn verify_signatures(signatures: &TonBlockSignatures, validators: &[Validator]) -> Result<(), String> {
+    let block = &signatures.id;
+
+    if block.workchain != MASTERCHAIN {
+        return Err(format!("Block {} is not a masterchain block", block.seqno));
+    }
+
+    let mut message = TON_BLOCK_ID_MAGIC.to_le_bytes().to_vec();
+    message.extend_from_slice(&hash_bytes(&block.root_hash)?);
+    message.extend_from_slice(&hash_bytes(&block.file_hash)?);
+
+    let total_weight: u128 = validators.iter().map(|validator| validator.weight as u128).sum();
+
+    let mut signed_weight: u128 = 0;
+    let mut signed = vec![false; validators.len()];
+
+    for signature in &signatures.signatures {
+        let node_id = BASE64_STANDARD
+            .decode(&signature.node_id_short)
+            .map_err(|e| format!("Invalid node id: {}", e))?;
+
+        // Signatures of unknown validators don't count
+        let Some(index) = validators.iter().position(|validator| {
+            verifying_key(&validator.public_key).is_ok_and(|key| node_id_short(&key) == node_id.as_slice())
+        }) else {
+            continue;
+        };
+
+        if signed[index] {
+            continue;
+        }
+
+        let signature_bytes = BASE64_STANDARD
+            .decode(&signature.signature)
+            .map_err(|e| format!("Invalid signature: {}", e))?;
+        let signature = Signature::from_slice(&signature_bytes).map_err(|e| format!("Invalid signature: {}", e))?;
+
+        verifying_key(&validators[index].public_key)?
+            .verify_strict(&message, &signature)
+            .map_err(|_| format!("Bad signature of validator {} on block {}", validators[index].public_key, block.seqno))?;
+
+        signed[index] = true;
+        signed_weight += validators[index].weight as u128;
+    }
+
+    if signed_weight * 3 <= total_weight * 2 {
+        return Err(format!(
+            "Block {} is signed by {} of {} validator weight, more than 2/3 required",
+            block.seqno, signed_weight, total_weight
+        ));
+    }
+
+    Ok(())
+}
+
+fn verifying_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(format!("Invalid validator public key {}", public_key))?;
+
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("Invalid validator public key {}: {}", public_key, e))
+}
+
+// sha256 of the TL serialized `pub.ed25519` key
+fn node_id_short(key: &VerifyingKey) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PUB_ED25519_MAGIC.to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher.finalize().into()
+}
+
+fn hash_bytes(hash: &str) -> Result<[u8; 32], String> {
+    BASE64_STANDARD
+        .decode(hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(format!("Invalid block hash {}", hash))
+}
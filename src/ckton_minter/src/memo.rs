@@ -0,0 +1,94 @@
+// ==========================
+// ckton_minter/src/memo.rs
+// Decoding of the ICRC account a deposit into the minter wallet is meant for.
+// The account is carried either as a text comment (the ICRC-1 textual
+// encoding) or as a binary payload prefixed with `ACCOUNT_DEPOSIT_OP`.
+// ==========================
+
+use std::str::FromStr;
+
+use base64::prelude::*;
+use candid::Principal;
+use ic_ton_lib::cell::{ArcCell, BagOfCells};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::ton_api::TonTransactionMessage;
+
+// Op of a text comment body
+pub const TEXT_COMMENT_OP: u32 = 0;
+// "cktn": account_deposit#636b746e owner_len:uint8 owner:(owner_len * uint8)
+//         subaccount:(Maybe bits256) = DepositPayload
+pub const ACCOUNT_DEPOSIT_OP: u32 = 0x636b_746e;
+
+pub fn account_from_comment(comment: &str) -> Result<Account, String> {
+    Account::from_str(comment.trim()).map_err(|e| format!("Invalid account in comment: {}", e))
+}
+
+pub fn account_from_body(body: &str) -> Result<Account, String> {
+    let boc = BagOfCells::parse_base64(body).map_err(|e| e.to_string())?;
+    let root = boc.single_root().map_err(|e| e.to_string())?;
+    account_from_cell(&root)
+}
+
+// Decodes a comment or `ACCOUNT_DEPOSIT_OP` payload, also used for the forward
+// payload of jetton transfers
+pub fn account_from_cell(root: &ArcCell) -> Result<Account, String> {
+    let mut parser = root.parser();
+
+    let op = parser.load_u32(32).map_err(|e| e.to_string())?;
+
+    match op {
+        TEXT_COMMENT_OP => {
+            let len = parser.remaining_bits() / 8;
+            let mut bytes = parser.load_bytes(len).map_err(|e| e.to_string())?;
+
+            // Long comments continue in a chain of references
+            let mut cell = root.clone();
+            while let Ok(next) = cell.reference(0) {
+                let next = next.clone();
+                let mut parser = next.parser();
+                let len = parser.remaining_bits() / 8;
+                bytes.extend(parser.load_bytes(len).map_err(|e| e.to_string())?);
+                cell = next;
+            }
+
+            let comment = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+            account_from_comment(&comment)
+        }
+        ACCOUNT_DEPOSIT_OP => {
+            let owner_len = parser.load_u8(8).map_err(|e| e.to_string())?;
+            let owner = parser.load_bytes(owner_len as usize).map_err(|e| e.to_string())?;
+            let owner = Principal::try_from_slice(&owner).map_err(|e| e.to_string())?;
+
+            let has_subaccount = parser.load_bit().map_err(|e| e.to_string())?;
+            let subaccount = if has_subaccount {
+                let bytes = parser.load_bytes(32).map_err(|e| e.to_string())?;
+                Some(<[u8; 32]>::try_from(bytes.as_slice()).unwrap())
+            } else {
+                None
+            };
+
+            Ok(Account { owner, subaccount })
+        }
+        op => Err(format!("Unknown deposit op: {:#x}", op)),
+    }
+}
+
+// Decodes the target account from the body of an incoming message
+pub fn account_from_message(msg: &TonTransactionMessage) -> Result<Account, String> {
+    let msg_data = msg.msg_data.as_ref().ok_or("Message has no body".to_string())?;
+
+    match msg_data.data_type.as_str() {
+        "msg.dataText" => {
+            let text = msg_data.text.as_ref().ok_or("Message has no comment".to_string())?;
+            let text = BASE64_STANDARD.decode(text).map_err(|e| e.to_string())?;
+            let text = String::from_utf8(text).map_err(|e| e.to_string())?;
+            account_from_comment(&text)
+        }
+        "msg.dataRaw" => {
+            let body = msg_data.body.as_ref().ok_or("Message has no body".to_string())?;
+            account_from_body(body)
+        }
+        data_type => Err(format!("Unsupported message data: {}", data_type)),
+    }
+}
@@ -0,0 +1,88 @@
+// ==========================
+// ckton_minter/src/metadata.rs
+// ICRC-1 metadata of the ckTON ledger.
+// The logo and description come from the admin setup, the TON bridge entries
+// from the minter config. The metadata is set when the ledger is created and
+// pushed to an existing ledger with an upgrade, also whenever the minter
+// address changes.
+// ==========================
+
+use candid::{CandidType, Principal};
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ledger_args::UpgradeArgs,
+    ledger_suite::{self, WasmKind},
+    state::read_config,
+};
+
+pub const LOGO_KEY: &str = "icrc1:logo";
+pub const DESCRIPTION_KEY: &str = "ckton:description";
+// Minter wallet address, where deposits with a memo and jettons are sent
+pub const TON_MINTER_ADDRESS_KEY: &str = "ckton:ton_minter_address";
+// Global id of the TON network, -239 for mainnet and -3 for testnet
+pub const TON_CHAIN_ID_KEY: &str = "ckton:ton_chain_id";
+
+#[derive(Debug, Clone, Default, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct TokenMetadata {
+    // Data URL of the logo, e.g. "data:image/svg+xml;base64,..."
+    pub logo: Option<String>,
+    pub description: Option<String>,
+}
+
+// Metadata entries of the ckTON ledger for the current config
+pub fn ckton_metadata() -> Vec<(String, MetadataValue)> {
+    let (metadata, app_ton_address, network) =
+        read_config(|config| (config.ckton_metadata(), config.app_ton_address.clone(), config.ton_network()));
+
+    let mut entries = vec![];
+
+    if let Some(logo) = metadata.logo {
+        entries.push((LOGO_KEY.to_string(), MetadataValue::Text(logo)));
+    }
+
+    if let Some(description) = metadata.description {
+        entries.push((DESCRIPTION_KEY.to_string(), MetadataValue::Text(description)));
+    }
+
+    if let Some(address) = app_ton_address {
+        entries.push((TON_MINTER_ADDRESS_KEY.to_string(), MetadataValue::Text(address)));
+    }
+
+    entries.push((TON_CHAIN_ID_KEY.to_string(), MetadataValue::Int(network.global_id().into())));
+
+    entries
+}
+
+// Replaces the metadata of the ckTON ledger with `ckton_metadata`, through an
+// upgrade to the uploaded ledger wasm. The ledger must already run that wasm,
+// syncing the metadata never changes its code.
+pub async fn sync_ckton_metadata() -> Result<Option<String>, String> {
+    let canister = read_config(|config| config.ledger_canister);
+    if canister == Principal::anonymous() {
+        return Err("The ckTON Ledger is not set up".to_string());
+    }
+
+    let installed = ledger_suite::module_hash(canister).await?;
+    let next = ledger_suite::next_module_hash(WasmKind::Ledger)?;
+    if installed.as_ref() != Some(&next) {
+        return Err(format!(
+            "The ckTON ledger runs {}, not the wasm {}, upgrade it with upgrade_ckton_ledger first",
+            installed.unwrap_or_else(|| "no module".to_string()),
+            next
+        ));
+    }
+
+    ledger_suite::upgrade_ckton_ledger(Some(UpgradeArgs {
+        change_archive_options: None,
+        token_symbol: None,
+        transfer_fee: None,
+        metadata: Some(ckton_metadata()),
+        change_fee_collector: None,
+        max_memo_length: None,
+        token_name: None,
+        feature_flags: None,
+    }))
+    .await
+}
@@ -0,0 +1,382 @@
+// ==========================
+// ckton_minter/src/state.rs
+// Stable-memory backed state of the minter canister.
+// Everything stored here survives canister upgrades.
+// ==========================
+
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering};
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use ic_ton_lib::TonAddress;
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+
+use crate::{
+    consts::{DEFAULT_MAX_ACCOUNTS_PER_SCAN, DEFAULT_MIN_SWEEP_BALANCE, DEFAULT_SUBNET_SIZE},
+    jettons::JettonConfig,
+    metadata::TokenMetadata,
+    ton_api::{ProxyConfig, TonProvider},
+    types::{FinalityPolicy, PendingTasks, TONDeployedWallet, TonNetwork, TonOutcallMode, TonWalletVersion},
+};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Memory ids must never be reused for a different structure once deployed
+const DEPLOYED_WALLET_MEMORY_ID: MemoryId = MemoryId::new(0);
+const PENDING_TASKS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const IN_FLIGHT_TASKS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(3);
+const WALLET_VERSIONS_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // Map of deployed wallets: Account -> TONDeployedWallet
+    pub static DEPLOYED_WALLET: RefCell<StableBTreeMap<StorableAccount, TONDeployedWallet, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPLOYED_WALLET_MEMORY_ID)));
+    // Wallet version of every account whose TON address was handed out, so the
+    // address stays the same when `default_wallet_version` changes
+    pub static WALLET_VERSIONS: RefCell<StableBTreeMap<StorableAccount, TonWalletVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WALLET_VERSIONS_MEMORY_ID)));
+    // Queue of pending tasks (mint, burn, deploy, etc.)
+    pub static PENDING_TASKS: RefCell<TaskQueue> = RefCell::new(TaskQueue::init(
+        get_memory(PENDING_TASKS_MEMORY_ID),
+        get_memory(IN_FLIGHT_TASKS_MEMORY_ID),
+    ));
+    // Ledger/indexer principals, fees and the minter TON address
+    static CONFIG: RefCell<StableCell<VersionedConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CONFIG_MEMORY_ID), VersionedConfig::default())
+            .expect("failed to initialize the config cell")
+    );
+}
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with_borrow(|manager| manager.get(id))
+}
+
+// Candid-encoded storable, used for every value kept in stable memory
+macro_rules! candid_storable {
+    ($t:ty) => {
+        impl ic_stable_structures::Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+                std::borrow::Cow::Owned(candid::encode_one(self).expect("failed to encode stable value"))
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                candid::decode_one(bytes.as_ref()).expect("failed to decode stable value")
+            }
+
+            const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+        }
+    };
+}
+pub(crate) use candid_storable;
+
+candid_storable!(TONDeployedWallet);
+candid_storable!(PendingTasks);
+candid_storable!(TonWalletVersion);
+
+// ICRC account usable as a stable map key.
+// Ordered by owner, then by the effective (default-filled) subaccount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorableAccount(pub Account);
+
+impl StorableAccount {
+    fn key(&self) -> (Principal, [u8; 32]) {
+        (self.0.owner, *self.0.effective_subaccount())
+    }
+}
+
+impl Ord for StorableAccount {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for StorableAccount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<Account> for StorableAccount {
+    fn from(account: Account) -> Self {
+        Self(account)
+    }
+}
+
+// Layout: 32-byte subaccount followed by the principal bytes
+impl Storable for StorableAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let (owner, subaccount) = self.key();
+        let mut bytes = subaccount.to_vec();
+        bytes.extend_from_slice(owner.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (subaccount, owner) = bytes.split_at(32);
+        let subaccount: [u8; 32] = subaccount.try_into().unwrap();
+        Self(Account {
+            owner: Principal::from_slice(owner),
+            subaccount: if subaccount == [0u8; 32] { None } else { Some(subaccount) },
+        })
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + 29,
+        is_fixed_size: false,
+    };
+}
+
+// FIFO queue of pending tasks.
+// Tasks handed out by `pop_many` stay in the in-flight map until `complete` is
+// called, so a task interrupted by an upgrade can be put back into the queue.
+pub struct TaskQueue {
+    pending: StableBTreeMap<u64, PendingTasks, Memory>,
+    in_flight: StableBTreeMap<u64, PendingTasks, Memory>,
+}
+
+impl TaskQueue {
+    pub fn init(pending: Memory, in_flight: Memory) -> Self {
+        Self {
+            pending: StableBTreeMap::init(pending),
+            in_flight: StableBTreeMap::init(in_flight),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        let last_pending = self.pending.last_key_value().map(|(id, _)| id);
+        let last_in_flight = self.in_flight.last_key_value().map(|(id, _)| id);
+        last_pending.max(last_in_flight).map_or(0, |id| id + 1)
+    }
+
+    pub fn push_back(&mut self, task: PendingTasks) {
+        let id = self.next_id();
+        self.pending.insert(id, task);
+    }
+
+    pub fn pop_many(&mut self, count: Option<u32>) -> Vec<Option<(u64, PendingTasks)>> {
+        let count = count.unwrap_or(1);
+        (0..count)
+            .map(|_| {
+                let (id, task) = self.pending.pop_first()?;
+                self.in_flight.insert(id, task.clone());
+                Some((id, task))
+            })
+            .collect()
+    }
+
+    pub fn complete(&mut self, id: u64) {
+        self.in_flight.remove(&id);
+    }
+
+    // Moves every in-flight task back to the pending queue, keeping its id
+    pub fn requeue_in_flight(&mut self) -> u64 {
+        let ids: Vec<u64> = self.in_flight.iter().map(|(id, _)| id).collect();
+        for id in ids.iter() {
+            if let Some(task) = self.in_flight.remove(id) {
+                self.pending.insert(*id, task);
+            }
+        }
+        ids.len() as u64
+    }
+
+    pub fn len(&self) -> u64 {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// Marks an in-flight task as completed once the task's future is dropped
+pub struct TaskGuard {
+    id: u64,
+}
+
+impl TaskGuard {
+    pub fn new(id: u64) -> Self {
+        Self { id }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        PENDING_TASKS.with_borrow_mut(|tasks| tasks.complete(self.id));
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct Config {
+    // The main TON address for the minter canister (base64 url form)
+    pub app_ton_address: Option<String>,
+    // Principal of the ICRC ledger canister
+    pub ledger_canister: Principal,
+    // Principal of the indexer canister
+    pub indexer_canister: Principal,
+    // Fee for transferring ckTON
+    pub ckton_transfer_fee: u64,
+    // Fee for sending TON
+    pub ton_fee: u64,
+    // Whether transfers to the minter wallet with an account memo are credited
+    pub memo_deposits: Option<bool>,
+    // Base64 BOC of the Highload Wallet V3 code, if set the minter wallet is
+    // a highload wallet instead of a V4R2 wallet
+    pub highload_wallet_code: Option<String>,
+    // Wallet version of accounts seen for the first time, V4R2 if unset
+    pub default_wallet_version: Option<TonWalletVersion>,
+    // TON RPC providers, set by the init or upgrade argument
+    pub ton_providers: Option<Vec<TonProvider>>,
+    // Number of providers that must return the same data, a majority if unset
+    pub ton_providers_threshold: Option<u8>,
+    // Whether outcalls go through the proxy (the default) or to the providers
+    pub ton_outcall_mode: Option<TonOutcallMode>,
+    // Nodes of the subnet the minter runs on, used to price outcalls
+    pub subnet_size: Option<u32>,
+    // When deposits are final enough to be minted, immediately if unset
+    pub finality_policy: Option<FinalityPolicy>,
+    // Bridged jettons, with the jetton wallet of the minter resolved
+    pub jettons: Option<Vec<JettonConfig>>,
+    // Logo and description of ckTON, set on the ledger at creation and upgrades
+    pub ckton_metadata: Option<TokenMetadata>,
+    // TON network the minter is bridged to, testnet if unset
+    pub ton_network: Option<TonNetwork>,
+    // Name of the threshold Schnorr key the TON wallets are derived from
+    pub schnorr_key_name: Option<String>,
+    // Proxy the outcalls go through in `TonOutcallMode::Proxy`
+    pub proxy: Option<ProxyConfig>,
+    // Deposit wallets holding less nanotons are not swept
+    pub min_sweep_balance: Option<u64>,
+    // Deposit addresses checked by one run of the deposit scanner
+    pub max_accounts_per_scan: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            app_ton_address: None,
+            ledger_canister: Principal::anonymous(),
+            indexer_canister: Principal::anonymous(),
+            ckton_transfer_fee: 1000,
+            ton_fee: 5_500u64,
+            memo_deposits: None,
+            highload_wallet_code: None,
+            default_wallet_version: None,
+            ton_providers: None,
+            ton_providers_threshold: None,
+            ton_outcall_mode: None,
+            subnet_size: None,
+            finality_policy: None,
+            jettons: None,
+            ckton_metadata: None,
+            ton_network: None,
+            schnorr_key_name: None,
+            proxy: None,
+            min_sweep_balance: None,
+            max_accounts_per_scan: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn app_ton_address(&self) -> TonAddress {
+        self.app_ton_address
+            .as_ref()
+            .and_then(|address| address.parse::<TonAddress>().ok())
+            .unwrap_or(TonAddress::NULL)
+    }
+
+    pub fn memo_deposits_enabled(&self) -> bool {
+        self.memo_deposits.unwrap_or(false)
+    }
+
+    pub fn ton_providers(&self) -> Vec<TonProvider> {
+        self.ton_providers.clone().unwrap_or_default()
+    }
+
+    // At least 1, also while no provider is configured
+    pub fn ton_providers_threshold(&self) -> usize {
+        let providers = self.ton_providers().len();
+        match self.ton_providers_threshold {
+            Some(threshold) => (threshold as usize).clamp(1, providers.max(1)),
+            None => providers / 2 + 1,
+        }
+    }
+
+    pub fn ton_outcall_mode(&self) -> TonOutcallMode {
+        self.ton_outcall_mode.unwrap_or_default()
+    }
+
+    pub fn subnet_size(&self) -> u32 {
+        self.subnet_size.unwrap_or(DEFAULT_SUBNET_SIZE)
+    }
+
+    pub fn finality_policy(&self) -> FinalityPolicy {
+        self.finality_policy.unwrap_or_default()
+    }
+
+    pub fn jettons(&self) -> Vec<JettonConfig> {
+        self.jettons.clone().unwrap_or_default()
+    }
+
+    pub fn ckton_metadata(&self) -> TokenMetadata {
+        self.ckton_metadata.clone().unwrap_or_default()
+    }
+
+    pub fn ton_network(&self) -> TonNetwork {
+        self.ton_network.unwrap_or_default()
+    }
+
+    // Always set after install, see `lifecycle`
+    pub fn schnorr_key_name(&self) -> String {
+        self.schnorr_key_name.clone().unwrap_or_default()
+    }
+
+    pub fn min_sweep_balance(&self) -> u64 {
+        self.min_sweep_balance.unwrap_or(DEFAULT_MIN_SWEEP_BALANCE)
+    }
+
+    pub fn max_accounts_per_scan(&self) -> usize {
+        self.max_accounts_per_scan.unwrap_or(DEFAULT_MAX_ACCOUNTS_PER_SCAN) as usize
+    }
+}
+
+// Every field added to `Config` is optional, so a config written before the
+// field existed still decodes, with the field unset. A new variant is only
+// needed for a change Candid can't decode, like removing a required field.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum VersionedConfig {
+    V1(Config),
+}
+
+impl Default for VersionedConfig {
+    fn default() -> Self {
+        VersionedConfig::V1(Config::default())
+    }
+}
+
+candid_storable!(VersionedConfig);
+
+pub fn read_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with_borrow(|cell| match cell.get() {
+        VersionedConfig::V1(config) => f(config),
+    })
+}
+
+pub fn mutate_config<R>(f: impl FnOnce(&mut Config) -> R) -> R {
+    CONFIG.with_borrow_mut(|cell| {
+        let VersionedConfig::V1(mut config) = cell.get().clone();
+        let result = f(&mut config);
+        cell.set(VersionedConfig::V1(config))
+            .expect("failed to write the config cell");
+        result
+    })
+}
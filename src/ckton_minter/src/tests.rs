@@ -1,12 +1,59 @@
 
+use std::cell::RefCell;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
+use base64::prelude::*;
 use candid::{decode_args, encode_args, Decode, Nat, Principal};
+use ed25519_dalek::{Signer, SigningKey};
+use futures::executor::block_on;
+use futures::future::{ready, Ready};
+use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs};
+use ic_ton_lib::cell::{BagOfCells, Cell, CellBuilder};
+use ic_ton_lib::num_bigint::BigUint;
+use ic_ton_lib::TonAddress;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
 use icrc_ledger_types::icrc1::account::{Account, Subaccount};
-use pocket_ic::common::rest::{CanisterHttpHeader, CanisterHttpReply, CanisterHttpResponse, MockCanisterHttpResponse, RawMessageId};
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use pocket_ic::common::rest::{
+    CanisterHttpHeader, CanisterHttpReply, CanisterHttpRequest, CanisterHttpResponse, MockCanisterHttpResponse, RawMessageId,
+};
 use pocket_ic::{PocketIc, PocketIcBuilder};
 use reqwest::blocking::Client;
+use sha2::{Digest, Sha256};
+
+use crate::block::{account_transactions, block_info, parent_shard, parse_block, shard_contains, shard_top, validators};
+use crate::cycles::{cycles_stats, http_request_cost, record_outcall, Operation};
+use crate::events::{replay, Event, EventType, ReplayedWithdrawalStatus};
+use crate::consts::{
+    HIGHLOAD_SUBWALLET_ID, HIGHLOAD_TIMEOUT_SECS, MAX_IDLE_SCANS, MAX_WITHDRAWALS_PER_BATCH, TX_PAGE_SIZE, WITHDRAWAL_BATCH_INTERVAL_SECS, WITHDRAWAL_CHECK_INTERVAL_SECS, WITHDRAWAL_EXPIRY_MARGIN_SECS,
+};
+use crate::deposits::{
+    get_deposit_account, incoming_value, is_credited, mint_once_with, next_accounts_to_scan, owner_deposit_accounts, record_scan, store_deposit_account,
+    DepositAccount, TonTxId,
+};
+use crate::finality::{forget, is_final, pending_deposits, remember, Deposit, PendingDeposit};
+use crate::highload::{HighloadWalletV3, QueryId, PAYOUT_SEND_MODE};
+use crate::history::{commit_scan, find_transaction_with, scan_new_transactions_with, Scan, ScanCursor};
+use crate::jettons::{
+    decode_notification, payout_outcome, transfer_message, PayoutTrace, JETTON_INTERNAL_TRANSFER_OP, JETTON_TRANSFER_NOTIFICATION_OP, JETTON_TRANSFER_OP,
+};
+use crate::ledger_args::UpgradeArgs;
+use crate::memo::{account_from_body, account_from_cell, account_from_message, ACCOUNT_DEPOSIT_OP, TEXT_COMMENT_OP};
+use crate::ledger_suite::{LedgerSuite, WasmKind};
+use crate::lifecycle::{InitArg, MinterArg, UpgradeArg};
+use crate::lightclient::{verify_signatures, LightClientState, TrustedKeyBlock, Validator};
+use crate::ton_api::{
+    check_block_proof, check_threshold, parse_stack_num, ProxyConfig, TonApiError, TonBlockId, TonBlockSignature, TonBlockSignatures,
+    TonMessageData, TonProvider, TonProviderKind, TonTransaction, TonTransactionId, TonTransactionMessage,
+};
+use crate::transaction::{boc_hash, decode_transaction, dict_values};
+use crate::state::{mutate_config, read_config};
+use crate::types::{AdminSetup, FinalityPolicy, ICTonSigner, MinterError, TonNetwork, TonOutcallMode, TonWalletVersion};
+use crate::utils::mint_deduplicated;
+use crate::withdrawals::{batch_in_flight, claim_payout, get_request, requests_by_account, set_status, store_request, WithdrawalRequest, WithdrawalStatus};
 
 const WASM_BYTES: &[u8] = include_bytes!("../../../target/wasm32-unknown-unknown/release/ckton_minter.wasm");
 
@@ -58,7 +105,7 @@ fn test_wallet_balance() {
         pocket_ic::WasmResult::Reject(d) => panic!("Wallet balance rejected with :{}", d),
     };
 
-    let balance : u64 = Decode!(&data, u64).unwrap();
+    let balance : u64 = Decode!(&data, Result<u64, TonApiError>).unwrap().unwrap();
 
     println!("Balance: {}", balance);
 }
@@ -350,6 +397,2114 @@ fn test_mint_ton_wallet() {
 
 }
 
+#[test]
+fn test_withdraw_ton_burns_approved_ckton() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+    let other = Principal::from_slice(&[42]);
+
+    let (pic, minter_id) = setup(sender);
+
+    assert!(call_admin_setup(&pic, minter_id, sender).is_ok(), "Admin setup failed");
+
+    let ckton_ledger = Principal::from_str(&ledger_id(&pic, minter_id, sender)).unwrap();
+    let ton_fee = crate::state::Config::default().ton_fee;
+    let dest = TonAddress::new(0, &[6u8; 32]).to_base64_url();
+
+    // The minter is the minting account of the ckTON ledger
+    for owner in [sender, other] {
+        ledger_mint(&pic, ckton_ledger, minter_id, Account { owner, subaccount: None }, 1_000_000_000);
+    }
+    ledger_approve(&pic, ckton_ledger, sender, minter_id, 1_000_000_000);
+
+    let balance = ledger_balance(&pic, ckton_ledger, sender);
+
+    // Nothing is burned for a withdrawal that doesn't cover the TON fee or
+    // pays an invalid address
+    let result = withdraw_ton(&pic, minter_id, sender, dest.clone(), ton_fee);
+    assert_eq!(result, Err("Amount does not cover the withdrawal fees".to_string()));
+    assert!(withdraw_ton(&pic, minter_id, sender, "not an address".to_string(), 100_000_000).is_err());
+    assert_eq!(ledger_balance(&pic, ckton_ledger, sender), balance);
+
+    let withdrawal_id = withdraw_ton(&pic, minter_id, sender, dest.clone(), 100_000_000).unwrap();
+
+    // A burn pays no ledger fee
+    assert_eq!(ledger_balance(&pic, ckton_ledger, sender), balance - 100_000_000);
+
+    let request = retrieve_ton_status(&pic, minter_id, sender, withdrawal_id).expect("Withdrawal not stored");
+    assert_eq!(request.from, Account { owner: sender, subaccount: None });
+    assert_eq!(request.to_ton_address, dest);
+    assert_eq!(request.amount, 100_000_000);
+    assert_eq!(request.ton_amount, 100_000_000 - ton_fee);
+    assert_eq!(request.status, WithdrawalStatus::Pending);
+
+    // Without an approval the ledger refuses the burn
+    let result = withdraw_ton(&pic, minter_id, other, dest, 100_000_000);
+    assert!(result.unwrap_err().contains("InsufficientAllowance"));
+    assert_eq!(ledger_balance(&pic, ckton_ledger, other), 1_000_000_000);
+}
+
+#[test]
+fn test_withdrawal_batches_own_the_seqno() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup_with(sender, mocked_init_arg());
+
+    assert!(call_admin_setup(&pic, minter_id, sender).is_ok(), "Admin setup failed");
+
+    let ckton_ledger = Principal::from_str(&ledger_id(&pic, minter_id, sender)).unwrap();
+    let wallet: TonAddress = minter_ton_address(&pic, minter_id, sender).parse().unwrap();
+    let dest = TonAddress::new(0, &[6u8; 32]);
+
+    ledger_mint(&pic, ckton_ledger, minter_id, Account { owner: sender, subaccount: None }, 1_000_000_000);
+    ledger_approve(&pic, ckton_ledger, sender, minter_id, 1_000_000_000);
+
+    let ids: Vec<u64> = (0..=MAX_WITHDRAWALS_PER_BATCH)
+        .map(|_| withdraw_ton(&pic, minter_id, sender, dest.to_base64_url(), 100_000_000).unwrap())
+        .collect();
+
+    let mut ton = MockTon::new(7);
+
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_BATCH_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    // The oldest withdrawals share one message, signed for the seqno read
+    // from the chain
+    assert_eq!(ton.sent.len(), 1);
+    assert_eq!(ton.wallet_reads, 1);
+
+    let hash = boc_hash(&ton.sent[0]).unwrap();
+    for id in &ids[..MAX_WITHDRAWALS_PER_BATCH] {
+        match retrieve_ton_status(&pic, minter_id, sender, *id).unwrap().status {
+            WithdrawalStatus::Sent { seqno, hash: sent, .. } => {
+                assert_eq!(seqno, 7);
+                assert_eq!(sent, hash);
+            }
+            status => panic!("Withdrawal {} not sent: {:?}", id, status),
+        }
+    }
+
+    let last = ids[MAX_WITHDRAWALS_PER_BATCH];
+    assert_eq!(retrieve_ton_status(&pic, minter_id, sender, last).unwrap().status, WithdrawalStatus::Pending);
+
+    // No other batch is signed while this one may still land
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_BATCH_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(ton.sent.len(), 1, "Batch signed while another one was in flight");
+    assert_eq!(retrieve_ton_status(&pic, minter_id, sender, last).unwrap().status, WithdrawalStatus::Pending);
+
+    // The batch lands and pays every withdrawal
+    let ton_amount = retrieve_ton_status(&pic, minter_id, sender, ids[0]).unwrap().ton_amount;
+    let payouts = vec![(dest.clone(), ton_amount); MAX_WITHDRAWALS_PER_BATCH];
+    ton.transactions.push(payout_transaction(&wallet, 1, pic_time_in_secs(&pic), &ton.sent[0], &payouts));
+    ton.seqno = 8;
+
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_CHECK_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    for id in &ids[..MAX_WITHDRAWALS_PER_BATCH] {
+        let status = retrieve_ton_status(&pic, minter_id, sender, *id).unwrap().status;
+        assert!(matches!(status, WithdrawalStatus::Confirmed { .. }), "Withdrawal {} not confirmed: {:?}", id, status);
+    }
+
+    // The next batch takes the next seqno without reading it again
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_BATCH_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(ton.sent.len(), 2);
+    assert_eq!(ton.wallet_reads, 1, "Seqno read from the chain again");
+
+    let status = retrieve_ton_status(&pic, minter_id, sender, last).unwrap().status;
+    assert!(matches!(status, WithdrawalStatus::Sent { seqno: 8, .. }), "Next batch not signed for seqno 8: {:?}", status);
+}
+
+#[test]
+fn test_expired_withdrawals_are_requeued_or_reimbursed() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup_with(sender, mocked_init_arg());
+
+    assert!(call_admin_setup(&pic, minter_id, sender).is_ok(), "Admin setup failed");
+
+    let ckton_ledger = Principal::from_str(&ledger_id(&pic, minter_id, sender)).unwrap();
+    let dest = TonAddress::new(0, &[6u8; 32]).to_base64_url();
+
+    ledger_mint(&pic, ckton_ledger, minter_id, Account { owner: sender, subaccount: None }, 1_000_000_000);
+    ledger_approve(&pic, ckton_ledger, sender, minter_id, 1_000_000_000);
+
+    let balance = ledger_balance(&pic, ckton_ledger, sender);
+
+    let id = withdraw_ton(&pic, minter_id, sender, dest, 100_000_000).unwrap();
+
+    // A message every provider refused may still land, it stays signed
+    let mut ton = MockTon::new(7);
+    ton.accept_messages = false;
+
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_BATCH_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    let status = retrieve_ton_status(&pic, minter_id, sender, id).unwrap().status;
+    assert!(matches!(status, WithdrawalStatus::Signed { seqno: 7, .. }), "Withdrawal not signed: {:?}", status);
+
+    // Once it expired without the seqno moving it never landed, the
+    // withdrawal goes back to the queue
+    let expiry = Duration::from_secs(60 + WITHDRAWAL_EXPIRY_MARGIN_SECS as u64 + 1);
+
+    pic.advance_time(expiry);
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(retrieve_ton_status(&pic, minter_id, sender, id).unwrap().status, WithdrawalStatus::Pending);
+
+    // The unused seqno is read from the chain again for the next batch
+    ton.accept_messages = true;
+    let wallet_reads = ton.wallet_reads;
+
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_BATCH_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(ton.wallet_reads, wallet_reads + 1);
+    assert_eq!(ton.sent.len(), 1);
+
+    let status = retrieve_ton_status(&pic, minter_id, sender, id).unwrap().status;
+    assert!(matches!(status, WithdrawalStatus::Sent { seqno: 7, .. }), "Withdrawal not sent: {:?}", status);
+    assert_eq!(ledger_balance(&pic, ckton_ledger, sender), balance - 100_000_000);
+
+    // A sent message that expired without landing fails the withdrawal, whose
+    // ckTON is minted back
+    pic.advance_time(expiry);
+    answer_ton_api(&pic, &mut ton);
+
+    let status = retrieve_ton_status(&pic, minter_id, sender, id).unwrap().status;
+    assert!(matches!(status, WithdrawalStatus::Reimbursed { .. }), "Withdrawal not reimbursed: {:?}", status);
+    assert_eq!(ledger_balance(&pic, ckton_ledger, sender), balance);
+
+    // Resolving it again mints nothing more
+    pic.advance_time(Duration::from_secs(WITHDRAWAL_CHECK_INTERVAL_SECS));
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(ledger_balance(&pic, ckton_ledger, sender), balance);
+}
+
+#[test]
+fn test_minter_wallet_switch_needs_an_empty_wallet() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup_with(sender, mocked_init_arg());
+
+    assert!(call_admin_setup(&pic, minter_id, sender).is_ok(), "Admin setup failed");
+
+    let suite = ledger_suites(&pic, minter_id, sender).into_iter().find(|suite| suite.token_symbol == "ckTON").unwrap();
+
+    let code = CellBuilder::new().store_u32(32, 0xc0de).and_then(|b| b.build()).unwrap();
+    let code_boc = BASE64_STANDARD.encode(BagOfCells::from_root(code).serialize(true).unwrap());
+
+    let switch = || AdminSetup {
+        ledger_canister: suite.ledger,
+        indexer_canister: suite.index,
+        ckton_transfer_fee: None,
+        ton_fee: None,
+        memo_deposits: None,
+        highload_wallet_code: Some(code_boc.clone()),
+        default_wallet_version: None,
+        finality_policy: None,
+        jettons: None,
+        ckton_metadata: None,
+    };
+
+    let current: TonAddress = minter_ton_address(&pic, minter_id, sender).parse().unwrap();
+    let mut ton = MockTon::new(7);
+
+    // The TON backing ckTON is still in the current wallet
+    let call = pic.submit_call(minter_id, sender, "admin_setup", encode_args((Some(switch()),)).unwrap()).unwrap();
+    answer_ton_api(&pic, &mut ton);
+
+    let err = await_update::<Result<(), String>>(&pic, call).unwrap_err();
+    assert!(err.contains("holds 10000000000 nanotons"), "Unexpected error: {}", err);
+
+    let new_address = err.rsplit(" to ").next().and_then(|rest| rest.strip_suffix(" first")).unwrap().to_string();
+    let new: TonAddress = new_address.parse().unwrap();
+    assert_ne!(new, current);
+    assert_eq!(minter_ton_address(&pic, minter_id, sender).parse::<TonAddress>().unwrap(), current);
+
+    // The current wallet sends its whole balance to the new one
+    let call = pic.submit_call(minter_id, sender, "admin_empty_minter_wallet", encode_args((new_address,)).unwrap()).unwrap();
+    answer_ton_api(&pic, &mut ton);
+
+    let hash = await_update::<Result<String, String>>(&pic, call).unwrap();
+    assert_eq!(ton.sent.len(), 1);
+    assert_eq!(hash, boc_hash(&ton.sent[0]).unwrap());
+
+    // Once it landed the switch goes through
+    ton.balance = 0;
+
+    let call = pic.submit_call(minter_id, sender, "admin_setup", encode_args((Some(switch()),)).unwrap()).unwrap();
+    answer_ton_api(&pic, &mut ton);
+
+    assert_eq!(await_update::<Result<(), String>>(&pic, call), Ok(()));
+    assert_eq!(minter_ton_address(&pic, minter_id, sender).parse::<TonAddress>().unwrap(), new);
+}
+
+#[test]
+fn test_state_survives_upgrade() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let admin_setup_result = call_admin_setup(&pic, minter_id, sender);
+
+    assert!(admin_setup_result.is_ok(), "Admin setup failed");
+
+    let ledger_before = ledger_id(&pic, minter_id, sender);
+    let minter_address_before = minter_ton_address(&pic, minter_id, sender);
+
+    pic.upgrade_canister(minter_id, WASM_BYTES.to_vec(), encode_args(()).unwrap(), Some(sender)).unwrap();
+
+    assert_eq!(ledger_id(&pic, minter_id, sender), ledger_before, "Ledger id lost on upgrade");
+    assert_eq!(minter_ton_address(&pic, minter_id, sender), minter_address_before, "Minter TON address lost on upgrade");
+}
+
+#[test]
+fn test_upgrade_rejects_init_arg() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let result = pic.upgrade_canister(minter_id, WASM_BYTES.to_vec(), encode_args((Some(MinterArg::Init(init_arg())),)).unwrap(), Some(sender));
+
+    assert!(result.is_err(), "Upgrade with an init argument succeeded");
+}
+
+#[test]
+fn test_wallet_version_is_kept() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let v5_address = generate_ton_address_with_version(&pic, minter_id, sender, Some(TonWalletVersion::V5R1)).unwrap();
+
+    assert_eq!(generate_ton_address(&pic, minter_id, sender), v5_address, "Address changed without a version");
+
+    let result = generate_ton_address_with_version(&pic, minter_id, sender, Some(TonWalletVersion::V4R2));
+
+    assert!(result.is_err(), "Wallet version of a handed out address changed");
+}
+
+#[test]
+fn test_admin_setup_records_event() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    // The init argument is recorded as ConfigChanged
+    assert_eq!(event_count(&pic, minter_id, sender), 1);
+
+    let admin_setup_result = call_admin_setup(&pic, minter_id, sender);
+
+    assert!(admin_setup_result.is_ok(), "Admin setup failed");
+
+    // LedgerSuiteCreated, ConfigChanged and the CkTonCanisterUpgraded of the
+    // metadata sync for the new minter address
+    assert_eq!(event_count(&pic, minter_id, sender), 4, "Admin setup events not recorded");
+}
+
+#[test]
+fn test_replay_rebuilds_totals_from_events() {
+    let account = Account { owner: Principal::from_slice(&[1]), subaccount: None };
+    let event = |payload| Event { timestamp: 0, payload };
+
+    let events = vec![
+        event(EventType::DepositDetected { account, ton_address: "EQ-deposit".to_string(), hash: "h1".to_string(), lt: 1, amount: 500 }),
+        event(EventType::CkTonMinted { to: account, amount: 500, hash: "h1".to_string(), block_index: 0 }),
+        event(EventType::CkTonBurned { withdrawal_id: 1, from: account, to_ton_address: "EQ-dest".to_string(), amount: 200, ton_amount: 190 }),
+        event(EventType::WithdrawalSent { withdrawal_id: 1, hash: "h2".to_string(), seqno: 3 }),
+        event(EventType::JettonBurned {
+            symbol: "ckUSDT".to_string(),
+            withdrawal_id: 1 << 63,
+            burn_block_index: 0,
+            from: account,
+            to_ton_address: "EQ-dest".to_string(),
+            amount: 1_000,
+            jetton_amount: 900,
+            ckton_gas: Some(50),
+        }),
+        event(EventType::WithdrawalFailed { withdrawal_id: 1 << 63, reason: "bounced".to_string() }),
+        event(EventType::WithdrawalReimbursed { withdrawal_id: 1 << 63, to: account, amount: 50, block_index: 7 }),
+        event(EventType::JettonReimbursed { symbol: "ckUSDT".to_string(), withdrawal_id: 1 << 63, to: account, amount: 1_000, block_index: 3 }),
+        event(EventType::CkTonCanisterUpgraded {
+            kind: WasmKind::Ledger,
+            canister: Principal::anonymous(),
+            upgrade_args: Some(UpgradeArgs { transfer_fee: Some(Nat::from(20u64)), ..Default::default() }),
+            module_hash: None,
+            error: None,
+        }),
+    ];
+
+    let state = replay(events.into_iter());
+
+    assert_eq!(state.total_minted, 550);
+    assert_eq!(state.total_burned, 250);
+    assert!(state.pending_mints.is_empty(), "Minted deposit still pending");
+    assert_eq!(state.minted_deposits.get("h1"), Some(&(account, 500)));
+    assert_eq!(state.withdrawals[&1].status, ReplayedWithdrawalStatus::Sent { hash: "h2".to_string() });
+    // The gas reimbursement doesn't resolve the jetton withdrawal, its jettons do
+    assert_eq!(state.withdrawals[&(1 << 63)].status, ReplayedWithdrawalStatus::Reimbursed { block_index: 3 });
+    assert_eq!(state.jetton_burned.get("ckUSDT"), Some(&1_000));
+    assert_eq!(state.jetton_minted.get("ckUSDT"), Some(&1_000));
+    assert_eq!(state.config.ckton_transfer_fee, 20);
+}
+
+#[test]
+fn test_withdrawal_lifecycle() {
+    let from = Account { owner: Principal::from_slice(&[7]), subaccount: None };
+    let other = Account { owner: Principal::from_slice(&[8]), subaccount: None };
+
+    store_request(&WithdrawalRequest {
+        id: 42,
+        from,
+        to_ton_address: "EQ-dest".to_string(),
+        amount: 200,
+        ton_amount: 190,
+        created_at: 0,
+        status: WithdrawalStatus::Pending,
+        jetton: None,
+        ckton_gas: None,
+        ckton_gas_block_index: None,
+    });
+
+    // A pending withdrawal is left to the batcher
+    assert!(!batch_in_flight(), "Pending withdrawal counted as in flight");
+
+    let sent = WithdrawalStatus::Sent { seqno: 3, expire_at: 60, hash: "h".to_string() };
+    set_status(42, sent.clone());
+
+    assert!(batch_in_flight(), "Sent withdrawal not counted as in flight");
+    assert_eq!(get_request(42).unwrap().status, sent);
+
+    let requests = requests_by_account(from);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].id, 42);
+    assert!(requests_by_account(other).is_empty(), "Withdrawal listed for another account");
+
+    // A confirmed withdrawal is resolved and no longer holds back the next batch
+    let confirmed = WithdrawalStatus::Confirmed { hash: "h".to_string(), tx_hash: "tx".to_string() };
+    set_status(42, confirmed.clone());
+
+    assert!(!batch_in_flight(), "Confirmed withdrawal still in flight");
+    assert_eq!(get_request(42).unwrap().status, confirmed);
+    assert!(get_request(43).is_none());
+}
+
+#[test]
+fn test_payout_confirms_a_single_withdrawal() {
+    let from = Account { owner: Principal::from_slice(&[7]), subaccount: None };
+    let dest = TonAddress::new(0, &[6u8; 32]).to_base64_url();
+
+    let request = |id: u64| WithdrawalRequest {
+        id,
+        from,
+        to_ton_address: dest.clone(),
+        amount: 200,
+        ton_amount: 190,
+        created_at: 0,
+        status: WithdrawalStatus::Sent { seqno: 3, expire_at: 60, hash: "h".to_string() },
+        jetton: None,
+        ckton_gas: None,
+        ckton_gas_block_index: None,
+    };
+    let payout = |hash: &str, value: &str| TonTransactionMessage {
+        hash: hash.to_string(),
+        destination: dest.clone(),
+        value: value.to_string(),
+        ..Default::default()
+    };
+    let tx = |out_msgs: Vec<TonTransactionMessage>| TonTransaction {
+        transaction_id: TonTransactionId { lt: "1".to_string(), hash: "payout-tx".to_string() },
+        utime: 0,
+        in_msg: Default::default(),
+        out_msgs,
+        data: String::new(),
+    };
+
+    // The value must be the TON amount of the withdrawal
+    assert_eq!(claim_payout(&tx(vec![payout("m0", "200")]), &request(1)), Ok(false));
+
+    // Two withdrawals to the same address in one batch need two messages
+    let single = tx(vec![payout("m1", "190")]);
+    assert_eq!(claim_payout(&single, &request(1)), Ok(true));
+    assert_eq!(claim_payout(&single, &request(2)), Ok(false), "One message confirmed two withdrawals");
+    // Checking a withdrawal again finds its own message
+    assert_eq!(claim_payout(&single, &request(1)), Ok(true));
+
+    let both = tx(vec![payout("m2", "190"), payout("m3", "190")]);
+    assert_eq!(claim_payout(&both, &request(3)), Ok(true));
+    assert_eq!(claim_payout(&both, &request(4)), Ok(true));
+    assert_eq!(claim_payout(&both, &request(5)), Ok(false));
+}
+
+#[test]
+fn test_deposit_scan_skips_idle_accounts() {
+    let owner = Principal::from_slice(&[9]);
+    let deposit_account = |subaccount: Option<[u8; 32]>| DepositAccount {
+        account: Account { owner, subaccount },
+        ton_address: "EQ-deposit".to_string(),
+        version: None,
+        idle_scans: None,
+    };
+
+    let first = deposit_account(None);
+    let second = deposit_account(Some([1; 32]));
+    let third = deposit_account(Some([2; 32]));
+
+    for deposit_account in [&first, &second, &third] {
+        store_deposit_account(deposit_account);
+    }
+
+    assert_eq!(owner_deposit_accounts(owner), 3);
+    assert_eq!(owner_deposit_accounts(Principal::from_slice(&[10])), 0);
+
+    // Each run continues after the last scanned account and wraps around
+    assert_eq!(next_accounts_to_scan(2), vec![first.account, second.account]);
+    assert_eq!(next_accounts_to_scan(2), vec![third.account, first.account]);
+
+    for _ in 0..MAX_IDLE_SCANS {
+        record_scan(&get_deposit_account(&second.account).unwrap(), true);
+    }
+
+    assert!(get_deposit_account(&second.account).unwrap().is_idle(), "Account not idle after {} empty scans", MAX_IDLE_SCANS);
+    assert_eq!(next_accounts_to_scan(3), vec![third.account, first.account], "Idle account scanned");
+
+    // A call of the owner resumes the scan
+    record_scan(&get_deposit_account(&second.account).unwrap(), false);
+
+    assert_eq!(get_deposit_account(&second.account).unwrap().idle_scans, Some(0));
+    assert_eq!(next_accounts_to_scan(1), vec![second.account]);
+}
+
+#[test]
+fn test_incoming_value() {
+    let tx = |source: &str, value: &str, out_msgs: usize| TonTransaction {
+        transaction_id: TonTransactionId { lt: "1".to_string(), hash: "tx".to_string() },
+        utime: 0,
+        in_msg: TonTransactionMessage {
+            source: source.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        },
+        out_msgs: vec![Default::default(); out_msgs],
+        data: String::new(),
+    };
+
+    assert_eq!(incoming_value(&tx("EQ-sender", "500", 0)), Some(500));
+    // External messages carry no value
+    assert_eq!(incoming_value(&tx("", "500", 0)), None);
+    // The wallet bounced the transfer
+    assert_eq!(incoming_value(&tx("EQ-sender", "500", 1)), None);
+    assert_eq!(incoming_value(&tx("EQ-sender", "0", 0)), None);
+}
+
+#[test]
+fn test_mint_deduplicated_retries_old_mints_without_created_at_time() {
+    let to = Account { owner: Principal::from_slice(&[11]), subaccount: None };
+    let ledger = RefCell::new(MockLedger::new(NANOS_PER_DAY * 10));
+    let now = ledger.borrow().now;
+
+    // The ledger answers a repeated mint with the block of the first one
+    assert_eq!(block_on(mint_deduplicated(mock_transfer(&ledger), to, 100, b"m1".to_vec(), now)), Ok(0));
+    assert_eq!(block_on(mint_deduplicated(mock_transfer(&ledger), to, 100, b"m1".to_vec(), now)), Ok(0));
+    assert_eq!(ledger.borrow().blocks.len(), 1, "Repeated mint minted twice");
+
+    // Outside of the deduplication window the mint is sent without created_at_time
+    let old = now - 2 * NANOS_PER_DAY;
+    assert_eq!(block_on(mint_deduplicated(mock_transfer(&ledger), to, 100, b"m2".to_vec(), old)), Ok(1));
+
+    let future = now + 2 * NANOS_PER_DAY;
+    assert_eq!(block_on(mint_deduplicated(mock_transfer(&ledger), to, 100, b"m3".to_vec(), future)), Ok(2));
+
+    let created_at_times: Vec<Option<u64>> = ledger.borrow().calls.iter().map(|arg| arg.created_at_time).collect();
+    assert_eq!(created_at_times, vec![Some(now), Some(now), Some(old), None, Some(future), None]);
+
+    // Other errors are returned as they are
+    ledger.borrow_mut().failure = Some(TransferError::TemporarilyUnavailable);
+    let result = block_on(mint_deduplicated(mock_transfer(&ledger), to, 100, b"m4".to_vec(), now));
+    assert!(result.unwrap_err().contains("TemporarilyUnavailable"));
+}
+
+#[test]
+fn test_mint_once_credits_a_transaction_once() {
+    let account = Account { owner: Principal::from_slice(&[12]), subaccount: None };
+    let ledger = RefCell::new(MockLedger::new(NANOS_PER_DAY * 10));
+    let utime = ledger.borrow().now / 1_000_000_000;
+
+    // A mint whose response was lost is found by the ledger's deduplication
+    let tx_id = TonTxId { lt: 7, hash: [3u8; 32] };
+    let lost = block_on(mint_deduplicated(mock_transfer(&ledger), account, 100, tx_id.hash.to_vec(), utime * 1_000_000_000));
+    assert_eq!(lost, Ok(0));
+
+    assert_eq!(block_on(mint_once_with(mock_transfer(&ledger), account, tx_id, utime, 100)), Ok(0));
+    assert!(is_credited(&tx_id));
+    assert_eq!(ledger.borrow().blocks.len(), 1, "Deposit minted twice");
+
+    // A transaction too old for the ledger is minted without created_at_time,
+    // the credited set refuses it afterwards without calling the ledger
+    let old_tx_id = TonTxId { lt: 8, hash: [4u8; 32] };
+    let old_utime = utime - 2 * 24 * 60 * 60;
+    assert_eq!(block_on(mint_once_with(mock_transfer(&ledger), account, old_tx_id, old_utime, 100)), Ok(1));
+    assert!(is_credited(&old_tx_id));
+
+    let calls = ledger.borrow().calls.len();
+    let result = block_on(mint_once_with(mock_transfer(&ledger), account, old_tx_id, old_utime, 100));
+    assert!(result.unwrap_err().contains("already credited"));
+    assert_eq!(ledger.borrow().calls.len(), calls, "Credited transaction reached the ledger");
+    assert_eq!(ledger.borrow().blocks.len(), 2);
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// A ledger deduplicating transfers like the ICRC-1 ledger: over a window of a
+// day, with a minute of allowed drift, by the whole transfer argument
+struct MockLedger {
+    now: u64,
+    blocks: Vec<TransferArg>,
+    // Every transfer sent, accepted or not
+    calls: Vec<TransferArg>,
+    // Returned for every transfer when set
+    failure: Option<TransferError>,
+}
+
+impl MockLedger {
+    fn new(now: u64) -> Self {
+        Self { now, blocks: vec![], calls: vec![], failure: None }
+    }
+
+    fn transfer(&mut self, arg: TransferArg) -> Result<Nat, TransferError> {
+        self.calls.push(arg.clone());
+
+        if let Some(failure) = self.failure.clone() {
+            return Err(failure);
+        }
+
+        if let Some(created_at_time) = arg.created_at_time {
+            if created_at_time + NANOS_PER_DAY < self.now {
+                return Err(TransferError::TooOld);
+            }
+            if created_at_time > self.now + 60_000_000_000 {
+                return Err(TransferError::CreatedInFuture { ledger_time: self.now });
+            }
+            if let Some(index) = self.blocks.iter().position(|block| *block == arg) {
+                return Err(TransferError::Duplicate { duplicate_of: Nat::from(index as u64) });
+            }
+        }
+
+        self.blocks.push(arg);
+        Ok(Nat::from(self.blocks.len() as u64 - 1))
+    }
+}
+
+// `icrc1_transfer` answered by the mock ledger
+fn mock_transfer(ledger: &RefCell<MockLedger>) -> impl Fn(TransferArg) -> Ready<Result<Result<Nat, TransferError>, String>> + '_ {
+    move |arg| ready(Ok(ledger.borrow_mut().transfer(arg)))
+}
+
+#[test]
+fn test_scan_resumes_from_its_cursor() {
+    let address = "EQ-scanned";
+    let history = RefCell::new(MockHistory::new(60));
+    let lts = |scan: &Scan| -> Vec<u64> { scan.transactions.iter().map(|tx| tx.transaction_id.lt.parse().unwrap()).collect() };
+
+    // A history longer than one scan is walked newest first over several scans
+    let first = block_on(scan_new_transactions_with(address, mock_page(&history))).unwrap();
+    assert!(first.cursor.walk.is_some(), "Scan of a long history completed");
+    assert_eq!(first.cursor.synced_lt, 0);
+
+    // The cursor only moves once the scan is committed
+    let again = block_on(scan_new_transactions_with(address, mock_page(&history))).unwrap();
+    assert_eq!(lts(&again), lts(&first));
+
+    commit_scan(address, first.cursor.clone());
+
+    let second = block_on(scan_new_transactions_with(address, mock_page(&history))).unwrap();
+    assert_eq!(second.cursor, ScanCursor { synced_lt: 60, walk: None });
+
+    let scanned: Vec<u64> = lts(&first).into_iter().chain(lts(&second)).collect();
+    assert_eq!(scanned, (1..=60).rev().collect::<Vec<u64>>(), "Transactions skipped or scanned twice");
+
+    commit_scan(address, second.cursor);
+
+    // Later scans only read the transactions above the synced one
+    history.borrow_mut().push(3);
+
+    let third = block_on(scan_new_transactions_with(address, mock_page(&history))).unwrap();
+    assert_eq!(lts(&third), vec![63, 62, 61]);
+    assert_eq!(third.cursor.synced_lt, 63);
+    assert!(history.borrow().requests.iter().rev().take(2).all(|(_, to_lt)| *to_lt == Some(60)));
+}
+
+#[test]
+fn test_find_transaction_resumes_after_searched() {
+    let history = RefCell::new(MockHistory::new(5));
+    let found_lt = |tx: Option<TonTransaction>| tx.map(|tx| tx.transaction_id.lt);
+
+    assert_eq!(found_lt(block_on(find_transaction_with("m7", mock_page(&history))).unwrap()), None);
+
+    // A retried lookup only reads the transactions that landed since
+    history.borrow_mut().push(4);
+
+    assert_eq!(found_lt(block_on(find_transaction_with("m7", mock_page(&history))).unwrap()), Some("7".to_string()));
+    assert_eq!(history.borrow().requests.last(), Some(&(None, Some(5))));
+
+    // The cursor of a found message is dropped, a new lookup starts over
+    assert_eq!(found_lt(block_on(find_transaction_with("m7", mock_page(&history))).unwrap()), Some("7".to_string()));
+    assert_eq!(history.borrow().requests.last(), Some(&(None, None)));
+}
+
+// History of an account as a provider pages through it: newest first, from a
+// transaction (included) back to a logical time (excluded)
+struct MockHistory {
+    transactions: Vec<TonTransaction>,
+    // Every page requested
+    requests: Vec<(Option<TonTransactionId>, Option<u64>)>,
+}
+
+impl MockHistory {
+    // Transactions with the logical times 1 to `count`
+    fn new(count: u64) -> Self {
+        let mut history = Self { transactions: vec![], requests: vec![] };
+        history.push(count);
+        history
+    }
+
+    // Adds `count` newer transactions, the incoming message of the one with
+    // the logical time `lt` hashes to "m<lt>"
+    fn push(&mut self, count: u64) {
+        let newest = self.transactions.len() as u64;
+        for lt in newest + 1..=newest + count {
+            self.transactions.insert(0, TonTransaction {
+                transaction_id: TonTransactionId { lt: lt.to_string(), hash: format!("tx{}", lt) },
+                utime: lt,
+                in_msg: TonTransactionMessage { hash: format!("m{}", lt), ..Default::default() },
+                out_msgs: vec![],
+                data: String::new(),
+            });
+        }
+    }
+
+    fn page(&mut self, from: Option<TonTransactionId>, to_lt: Option<u64>) -> Vec<TonTransaction> {
+        self.requests.push((from.clone(), to_lt));
+
+        let start = match from {
+            Some(from) => self.transactions.iter().position(|tx| tx.transaction_id == from).unwrap_or(self.transactions.len()),
+            None => 0,
+        };
+
+        self.transactions[start..]
+            .iter()
+            .filter(|tx| tx.transaction_id.lt.parse::<u64>().unwrap() > to_lt.unwrap_or(0))
+            .take(TX_PAGE_SIZE as usize)
+            .cloned()
+            .collect()
+    }
+}
+
+// Pages of transactions answered from the mock history
+fn mock_page(history: &RefCell<MockHistory>) -> impl Fn(Option<TonTransactionId>, Option<u64>) -> Ready<Result<Vec<TonTransaction>, TonApiError>> + '_ {
+    move |from, to_lt| ready(Ok(history.borrow_mut().page(from, to_lt)))
+}
+
+#[test]
+fn test_memo_account_decoding() {
+    let owner = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+    let account = Account { owner, subaccount: Some([0xab; 32]) };
+    let text = account.to_string();
+
+    // A comment too long for one cell continues in a reference
+    let (head, tail) = text.as_bytes().split_at(100);
+    let tail = CellBuilder::new().store_slice(tail).and_then(|b| b.build()).unwrap();
+    let comment = CellBuilder::new()
+        .store_u32(32, TEXT_COMMENT_OP)
+        .and_then(|b| b.store_slice(head))
+        .and_then(|b| b.store_reference(&Arc::new(tail)))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    assert_eq!(account_from_cell(&Arc::new(comment.clone())), Ok(account));
+
+    let body = BASE64_STANDARD.encode(BagOfCells::from_root(comment).serialize(false).unwrap());
+    assert_eq!(account_from_body(&body), Ok(account));
+
+    let message = TonTransactionMessage {
+        msg_data: Some(TonMessageData {
+            data_type: "msg.dataText".to_string(),
+            body: None,
+            text: Some(BASE64_STANDARD.encode(format!(" {}\n", text))),
+        }),
+        ..Default::default()
+    };
+    assert_eq!(account_from_message(&message), Ok(account));
+
+    // The binary payload, with and without a subaccount
+    let payload = |subaccount: Option<[u8; 32]>| {
+        let mut builder = CellBuilder::new();
+        builder
+            .store_u32(32, ACCOUNT_DEPOSIT_OP)
+            .and_then(|b| b.store_u8(8, owner.as_slice().len() as u8))
+            .and_then(|b| b.store_slice(owner.as_slice()))
+            .and_then(|b| b.store_bit(subaccount.is_some()))
+            .unwrap();
+        if let Some(subaccount) = subaccount {
+            builder.store_slice(&subaccount).unwrap();
+        }
+        Arc::new(builder.build().unwrap())
+    };
+
+    assert_eq!(account_from_cell(&payload(Some([0xab; 32]))), Ok(account));
+    assert_eq!(account_from_cell(&payload(None)), Ok(Account { owner, subaccount: None }));
+
+    let unknown_op = CellBuilder::new().store_u32(32, 0x1234).and_then(|b| b.build()).unwrap();
+    assert!(account_from_cell(&Arc::new(unknown_op)).is_err(), "Unknown op decoded");
+
+    let not_an_account = CellBuilder::new()
+        .store_u32(32, TEXT_COMMENT_OP)
+        .and_then(|b| b.store_slice(b"top-up"))
+        .and_then(|b| b.build())
+        .unwrap();
+    assert!(account_from_cell(&Arc::new(not_an_account)).is_err(), "Plain comment decoded as an account");
+
+    // A body without data can't carry an account
+    assert!(account_from_message(&TonTransactionMessage::default()).is_err());
+}
+
+#[test]
+fn test_http_request_cost() {
+    let mut arg = CanisterHttpRequestArgument {
+        url: "https://x.io/api".to_string(),
+        max_response_bytes: Some(1_000),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader { name: "X-API-Key".to_string(), value: "k".to_string() }],
+        body: None,
+        transform: None,
+    };
+
+    // 26 request bytes: (3M + 60K * n) * n + 400 * n * 26 + 800 * n * 1_000
+    assert_eq!(http_request_cost(&arg, 13), 49_140_000 + 135_200 + 10_400_000);
+    assert_eq!(http_request_cost(&arg, 34), 171_360_000 + 353_600 + 27_200_000);
+
+    // The body counts as request bytes
+    arg.body = Some(vec![0; 4]);
+    assert_eq!(http_request_cost(&arg, 13), 49_140_000 + 156_000 + 10_400_000);
+
+    // Without a limit the IC charges for a 2MB response
+    arg.max_response_bytes = None;
+    assert_eq!(http_request_cost(&arg, 13), 49_140_000 + 156_000 + 20_800_000_000);
+}
+
+#[test]
+fn test_cycles_stats_add_up_per_operation() {
+    assert_eq!(cycles_stats().total_outcalls, 0);
+
+    record_outcall(Operation::Deposit, 100);
+    record_outcall(Operation::Withdrawal, 7);
+    record_outcall(Operation::Deposit, 50);
+
+    let stats = cycles_stats();
+
+    assert_eq!(stats.total_outcalls, 3);
+    assert_eq!(stats.total_cycles, 157);
+    assert_eq!(stats.operations.len(), 2);
+    assert_eq!(stats.operations[0].operation, Operation::Deposit);
+    assert_eq!((stats.operations[0].outcalls, stats.operations[0].cycles), (2, 150));
+    assert_eq!(stats.operations[1].operation, Operation::Withdrawal);
+    assert_eq!((stats.operations[1].outcalls, stats.operations[1].cycles), (1, 7));
+}
+
+#[test]
+fn test_trusted_key_block_must_be_masterchain() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let key_block = TrustedKeyBlock {
+        block: TonBlockId {
+            workchain: 0,
+            shard: "-9223372036854775808".to_string(),
+            seqno: 1,
+            root_hash: BASE64_STANDARD.encode([0u8; 32]),
+            file_hash: BASE64_STANDARD.encode([0u8; 32]),
+        },
+        validators: vec![],
+    };
+
+    let result = pic.update_call(minter_id, sender, "set_trusted_key_block", encode_args((key_block,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Set trusted key block rejected with :{}", d),
+    };
+
+    assert!(Decode!(&data, Result<(), String>).unwrap().is_err());
+
+    let result = pic.query_call(minter_id, sender, "get_light_client_state", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Get light client state rejected with :{}", d),
+    };
+
+    let state = Decode!(&data, LightClientState).unwrap();
+
+    assert!(state.key_block.is_none());
+    assert!(state.last_verified.is_none());
+}
+
+#[test]
+fn test_masterchain_blocks_finality() {
+    mutate_config(|config| config.finality_policy = Some(FinalityPolicy::MasterchainBlocks(3)));
+
+    let account = Account { owner: Principal::from_slice(&[9]), subaccount: None };
+    let deposit = Deposit {
+        account,
+        ton_address: TonAddress::new(0, &[1u8; 32]).to_base64_url(),
+        tx_id: TonTxId { lt: 5, hash: [2u8; 32] },
+        utime: 0,
+        amount: 1_000,
+    };
+
+    // The transaction is in masterchain block 100, as the providers would
+    // resolve it from its time
+    remember(
+        deposit.tx_id,
+        PendingDeposit {
+            account,
+            tx_hash: deposit.tx_id.hash_base64(),
+            lt: deposit.tx_id.lt,
+            amount: deposit.amount,
+            utime: deposit.utime,
+            tx_mc_seqno: Some(100),
+            checked_mc_seqno: None,
+            policy: FinalityPolicy::MasterchainBlocks(3),
+            proof_error: None,
+        },
+    );
+
+    // Confirmations are counted from the block of the transaction, not from
+    // the head the deposit is first checked against
+    assert!(!block_on(is_final(&deposit, &mut Some(99))).unwrap(), "Deposit final behind its own block");
+    assert!(!block_on(is_final(&deposit, &mut Some(102))).unwrap(), "Deposit final after 2 blocks");
+
+    let pending = pending_deposits(&account);
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].tx_mc_seqno, Some(100));
+    assert_eq!(pending[0].checked_mc_seqno, Some(102));
+    assert_eq!(pending[0].policy, FinalityPolicy::MasterchainBlocks(3));
+
+    assert!(block_on(is_final(&deposit, &mut Some(103))).unwrap(), "Deposit not final after 3 blocks");
+
+    forget(&deposit.tx_id);
+    assert!(pending_deposits(&account).is_empty(), "Forgotten deposit still pending");
+
+    // Without a policy deposits are final right away and never stored
+    mutate_config(|config| config.finality_policy = Some(FinalityPolicy::Immediate));
+    assert!(block_on(is_final(&deposit, &mut None)).unwrap());
+    assert!(pending_deposits(&account).is_empty());
+}
+
+#[test]
+fn test_jetton_notification_decoding() {
+    let account = Account { owner: Principal::from_slice(&[1, 2, 3]), subaccount: Some([4u8; 32]) };
+    let sender = TonAddress::new(0, &[5u8; 32]);
+
+    let comment = CellBuilder::new()
+        .store_u32(32, 0)
+        .and_then(|b| b.store_slice(account.to_string().as_bytes()))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    // Forward payload in a reference
+    let body = notification_body(JETTON_TRANSFER_NOTIFICATION_OP, 1_500, &sender, |b| {
+        b.store_bit(true).and_then(|b| b.store_reference(&Arc::new(comment.clone()))).unwrap();
+    });
+    assert_eq!(decode_notification(&body), Ok((1_500, account)));
+
+    // Forward payload inline, after the sender
+    let body = notification_body(JETTON_TRANSFER_NOTIFICATION_OP, 7, &sender, |b| {
+        b.store_bit(false)
+            .and_then(|b| b.store_u32(32, 0))
+            .and_then(|b| b.store_slice(account.to_string().as_bytes()))
+            .unwrap();
+    });
+    assert_eq!(decode_notification(&body), Ok((7, account)));
+
+    // Another op, and a payload that isn't an account
+    let body = notification_body(JETTON_TRANSFER_OP, 7, &sender, |b| {
+        b.store_bit(true).and_then(|b| b.store_reference(&Arc::new(comment.clone()))).unwrap();
+    });
+    assert!(decode_notification(&body).is_err(), "Transfer decoded as a notification");
+
+    let body = notification_body(JETTON_TRANSFER_NOTIFICATION_OP, 7, &sender, |b| {
+        b.store_bit(false).and_then(|b| b.store_u32(32, 0)).and_then(|b| b.store_slice(b"not an account")).unwrap();
+    });
+    assert!(decode_notification(&body).is_err(), "Invalid account accepted");
+}
+
+// transfer_notification query_id amount sender forward_payload, as a base64 BOC
+fn notification_body(op: u32, amount: u64, sender: &TonAddress, payload: impl FnOnce(&mut CellBuilder)) -> String {
+    let mut builder = CellBuilder::new();
+    builder
+        .store_u32(32, op)
+        .and_then(|b| b.store_u64(64, 0))
+        .and_then(|b| b.store_coins(&BigUint::from(amount)))
+        .and_then(|b| b.store_address(sender))
+        .unwrap();
+    payload(&mut builder);
+    BASE64_STANDARD.encode(BagOfCells::from_root(builder.build().unwrap()).serialize(false).unwrap())
+}
+
+#[test]
+fn test_wasm_upload_checks_order_and_hash() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let upload = |index: u32, chunk: &[u8]| {
+        let result = pic
+            .update_call(minter_id, sender, "upload_wasm_chunk", encode_args((WasmKind::Index, index, chunk.to_vec())).unwrap())
+            .unwrap();
+
+        match result {
+            pocket_ic::WasmResult::Reply(items) => Decode!(&items, Result<(), String>).unwrap(),
+            pocket_ic::WasmResult::Reject(d) => panic!("Upload wasm chunk rejected with :{}", d),
+        }
+    };
+
+    assert!(upload(1, b"asm").is_err());
+    assert!(upload(0, b"\0asm").is_ok());
+    assert!(upload(1, &[1, 0, 0, 0]).is_ok());
+
+    let result = pic
+        .update_call(minter_id, sender, "finish_wasm_upload", encode_args((WasmKind::Index, "00".repeat(32))).unwrap())
+        .unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Finish wasm upload rejected with :{}", d),
+    };
+
+    assert!(Decode!(&data, Result<String, String>).unwrap().is_err());
+
+    let result = pic.query_call(minter_id, sender, "get_wasm_hash", encode_args((WasmKind::Index,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Get wasm hash rejected with :{}", d),
+    };
+
+    assert_eq!(Decode!(&data, Option<String>).unwrap(), None);
+}
+
+#[test]
+fn test_ckton_suite_upgrade() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    // The ckTON index can't be upgraded before the setup
+    let result = pic.update_call(minter_id, sender, "upgrade_ckton_index", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Upgrade ckTON index rejected with :{}", d),
+    };
+
+    assert!(Decode!(&data, Result<Option<String>, String>).unwrap().is_err());
+
+    assert!(call_admin_setup(&pic, minter_id, sender).is_ok(), "Admin setup failed");
+
+    // The ckTON suite goes through the ckTON upgrade, which keeps the new fee
+    let upgrade_args = UpgradeArgs { transfer_fee: Some(Nat::from(20u64)), ..Default::default() };
+
+    let result = pic
+        .update_call(minter_id, sender, "upgrade_ledger_suite", encode_args(("ckTON".to_string(), Some(upgrade_args))).unwrap())
+        .unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Upgrade ledger suite rejected with :{}", d),
+    };
+
+    let suite = Decode!(&data, Result<LedgerSuite, String>).unwrap().unwrap();
+
+    assert!(suite.ledger_module_hash.is_some(), "Ledger module hash not tracked");
+    assert_eq!(suite.ledger.to_text(), ledger_id(&pic, minter_id, sender));
+
+    let result = pic.query_call(suite.ledger, sender, "icrc1_fee", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Ledger fee rejected with :{}", d),
+    };
+
+    assert_eq!(Decode!(&data, Nat).unwrap(), Nat::from(20u64));
+
+    let result = pic.update_call(minter_id, sender, "upgrade_ckton_index", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Upgrade ckTON index rejected with :{}", d),
+    };
+
+    let index_module_hash = Decode!(&data, Result<Option<String>, String>).unwrap().unwrap();
+
+    assert!(index_module_hash.is_some(), "Index module hash not returned");
+    assert_eq!(index_module_hash, suite.index_module_hash);
+}
+
+#[test]
+fn test_ckton_metadata_has_chain_id() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let result = pic.query_call(minter_id, sender, "get_ckton_metadata", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Get ckTON metadata rejected with :{}", d),
+    };
+
+    let metadata = Decode!(&data, Vec<(String, MetadataValue)>).unwrap();
+
+    // No logo, description or minter address before the admin setup
+    assert_eq!(metadata.len(), 1);
+    assert_eq!(metadata[0].0, "ckton:ton_chain_id");
+}
+
+#[test]
+fn test_network_and_key_are_fixed() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    let upgrade = |arg: UpgradeArg| pic.upgrade_canister(minter_id, WASM_BYTES.to_vec(), encode_args((Some(MinterArg::Upgrade(arg)),)).unwrap(), Some(sender));
+
+    let unchanged = UpgradeArg::from(init_arg());
+
+    assert!(upgrade(unchanged.clone()).is_ok(), "Upgrade with the same network and key failed");
+    assert!(upgrade(UpgradeArg { ton_network: Some(TonNetwork::Mainnet), ..unchanged.clone() }).is_err(), "TON network changed");
+    assert!(upgrade(UpgradeArg { schnorr_key_name: Some("key_1".to_string()), ..unchanged.clone() }).is_err(), "Schnorr key changed");
+    assert!(upgrade(UpgradeArg { ton_providers: Some(vec![]), ..unchanged }).is_err(), "Providers removed");
+}
+
+#[test]
+fn test_threshold_must_fit_providers() {
+    let provider = TonProvider { url: "https://toncenter.com/api/v2".to_string(), api_key: None, kind: None };
+    let providers = vec![provider.clone(), provider];
+
+    assert!(check_threshold(&providers, Some(2)).is_ok());
+    assert!(check_threshold(&providers, None).is_ok());
+    assert!(check_threshold(&providers, Some(0)).is_err(), "Zero threshold accepted");
+    assert!(check_threshold(&providers[..1], Some(2)).is_err(), "Stored threshold above a shorter provider list accepted");
+
+    // toncenter v3 doesn't serve transactions, only one provider is left for them
+    let v3 = TonProvider { url: "https://toncenter.com/api/v3".to_string(), api_key: None, kind: Some(TonProviderKind::ToncenterV3) };
+    let mixed = vec![providers[0].clone(), v3];
+    assert!(check_threshold(&mixed, Some(1)).is_ok());
+    assert!(check_threshold(&mixed, Some(2)).is_err(), "Threshold above the providers serving transactions accepted");
+
+    // The threshold in use stays at least 1 without providers
+    mutate_config(|config| {
+        config.ton_providers = None;
+        config.ton_providers_threshold = Some(2);
+    });
+    assert_eq!(read_config(|config| config.ton_providers_threshold()), 1);
+}
+
+#[test]
+fn test_block_proof_needs_block_providers() {
+    let v2 = TonProvider { url: "https://toncenter.com/api/v2".to_string(), api_key: None, kind: None };
+    let tonapi = TonProvider { url: "https://tonapi.io".to_string(), api_key: None, kind: Some(TonProviderKind::TonApi) };
+
+    // Only tonapi returns raw blocks, only toncenter v2 block signatures
+    assert!(check_block_proof(&[v2.clone()]).is_err(), "Proofs accepted without a provider of raw blocks");
+    assert!(check_block_proof(&[tonapi.clone()]).is_err(), "Proofs accepted without a provider of signatures");
+    assert!(check_block_proof(&[v2, tonapi]).is_ok());
+}
+
+#[test]
+fn test_verify_signatures() {
+    let keys: Vec<SigningKey> = (1..=3u8).map(|i| SigningKey::from_bytes(&[i; 32])).collect();
+    let validators: Vec<Validator> = keys
+        .iter()
+        .map(|key| Validator { public_key: hex::encode(key.verifying_key().as_bytes()), weight: 10 })
+        .collect();
+
+    let block = TonBlockId {
+        workchain: -1,
+        shard: "-9223372036854775808".to_string(),
+        seqno: 7,
+        root_hash: BASE64_STANDARD.encode([1u8; 32]),
+        file_hash: BASE64_STANDARD.encode([2u8; 32]),
+    };
+
+    // ton.blockId root_cell_hash file_hash
+    let mut message = 0xc50b6e70u32.to_le_bytes().to_vec();
+    message.extend_from_slice(&[1u8; 32]);
+    message.extend_from_slice(&[2u8; 32]);
+
+    let sign = |key: &SigningKey, message: &[u8]| {
+        let mut node_id = Sha256::new();
+        node_id.update(0x4813b4c6u32.to_le_bytes());
+        node_id.update(key.verifying_key().as_bytes());
+        TonBlockSignature {
+            node_id_short: BASE64_STANDARD.encode(node_id.finalize()),
+            signature: BASE64_STANDARD.encode(key.sign(message).to_bytes()),
+        }
+    };
+
+    let signed = |signatures: Vec<TonBlockSignature>| TonBlockSignatures { id: block.clone(), signatures };
+
+    assert!(verify_signatures(&signed(keys.iter().map(|key| sign(key, &message)).collect()), &validators).is_ok());
+
+    // Exactly 2/3 of the weight is not enough
+    assert!(verify_signatures(&signed(vec![sign(&keys[0], &message), sign(&keys[1], &message)]), &validators).is_err());
+
+    // A validator signing twice counts once
+    let twice = vec![sign(&keys[0], &message), sign(&keys[0], &message), sign(&keys[1], &message)];
+    assert!(verify_signatures(&signed(twice), &validators).is_err(), "Duplicate signature counted");
+
+    // A signature of another block fails the check
+    let mut forged = keys.iter().map(|key| sign(key, &message)).collect::<Vec<_>>();
+    forged[2] = sign(&keys[2], b"another block");
+    assert!(verify_signatures(&signed(forged), &validators).is_err(), "Bad signature accepted");
+
+    // Signatures of validators outside the set are ignored
+    let outsider = SigningKey::from_bytes(&[9u8; 32]);
+    let outside = vec![sign(&keys[0], &message), sign(&keys[1], &message), sign(&outsider, &message)];
+    assert!(verify_signatures(&signed(outside), &validators).is_err(), "Outside validator counted");
+}
+
+#[test]
+fn test_shard_ids() {
+    let left = 0x4000_0000_0000_0000u64;
+    let right = 0xc000_0000_0000_0000u64;
+
+    assert_eq!(parent_shard(left), 0x8000_0000_0000_0000);
+    assert_eq!(parent_shard(right), 0x8000_0000_0000_0000);
+    assert_eq!(parent_shard(0x6000_0000_0000_0000), left);
+
+    assert!(shard_contains(left, &[0x42; 32]));
+    assert!(!shard_contains(left, &[0xc2; 32]));
+    assert!(shard_contains(right, &[0xc2; 32]));
+    assert!(shard_contains(0x8000_0000_0000_0000, &[0xc2; 32]));
+}
+
+#[test]
+fn test_shard_block_decoding() {
+    let account = [0x42u8; 32];
+    let tx = CellBuilder::new().store_u64(64, 150).and_then(|b| b.build()).unwrap();
+    let tx_hash: [u8; 32] = tx.cell_hash().as_slice().try_into().unwrap();
+
+    // The left half of the basechain, just split from the whole
+    let shard = 0x4000_0000_0000_0000u64;
+    let block = block_fixture(
+        block_info_fixture(0, shard, 5, 100, 200, false, 3, true),
+        block_extra_fixture(account_blocks_fixture(&account, 150, tx), None),
+    );
+    let (data, root_hash, file_hash) = block_boc(block);
+
+    assert!(parse_block(&data, &root_hash, &[0u8; 32]).is_err(), "BOC accepted with another file hash");
+    assert!(parse_block(&data, &[0u8; 32], &file_hash).is_err(), "BOC accepted with another root hash");
+
+    let root = parse_block(&data, &root_hash, &file_hash).unwrap();
+    let info = block_info(&root).unwrap();
+
+    assert_eq!(info.workchain, 0);
+    assert_eq!(info.shard, shard);
+    assert_eq!(info.seqno, 5);
+    assert_eq!((info.start_lt, info.end_lt), (100, 200));
+    assert!(!info.key_block);
+    assert_eq!(info.prev_key_block_seqno, 3);
+
+    // After a split the previous block is the one of the parent shard
+    assert_eq!(info.prev.len(), 1);
+    assert_eq!(info.prev[0].shard, 0x8000_0000_0000_0000);
+    assert_eq!(info.prev[0].seqno, 4);
+    assert_eq!(info.prev[0].root_hash, [7u8; 32]);
+
+    assert_eq!(account_transactions(&root, &account).unwrap(), vec![(150, tx_hash)]);
+    assert!(account_transactions(&root, &[0x43u8; 32]).unwrap().is_empty());
+
+    assert!(shard_top(&root, 0, &account).is_err(), "Shard block read as a masterchain block");
+}
+
+#[test]
+fn test_masterchain_block_decoding() {
+    let public_keys = [[1u8; 32], [2u8; 32]];
+    let block = block_fixture(
+        block_info_fixture(-1, 0x8000_0000_0000_0000, 50, 0, 10, true, 40, false),
+        block_extra_fixture(account_blocks_fixture(&[0u8; 32], 1, CellBuilder::new().build().unwrap()), Some(mc_extra_fixture(Some(&public_keys)))),
+    );
+    let (data, root_hash, file_hash) = block_boc(block);
+    let root = parse_block(&data, &root_hash, &file_hash).unwrap();
+
+    let info = block_info(&root).unwrap();
+    assert_eq!(info.workchain, -1);
+    assert!(info.key_block);
+
+    // Accounts starting with a 0 bit are in the left shard
+    let left = shard_top(&root, 0, &[0x42u8; 32]).unwrap();
+    assert_eq!(left.shard, 0x4000_0000_0000_0000);
+    assert_eq!((left.seqno, left.end_lt), (10, 1000));
+    assert_eq!((left.root_hash, left.file_hash), ([10u8; 32], [11u8; 32]));
+
+    let right = shard_top(&root, 0, &[0xc2u8; 32]).unwrap();
+    assert_eq!(right.shard, 0xc000_0000_0000_0000);
+    assert_eq!(right.seqno, 20);
+
+    assert!(shard_top(&root, 1, &[0x42u8; 32]).is_err(), "Shard of an unknown workchain found");
+
+    // Only the first `main` validator signs the masterchain
+    assert_eq!(validators(&root).unwrap(), vec![Validator { public_key: hex::encode([1u8; 32]), weight: 100 }]);
+
+    let block = block_fixture(
+        block_info_fixture(-1, 0x8000_0000_0000_0000, 51, 10, 20, false, 50, false),
+        block_extra_fixture(account_blocks_fixture(&[0u8; 32], 11, CellBuilder::new().build().unwrap()), Some(mc_extra_fixture(None))),
+    );
+    let (data, root_hash, file_hash) = block_boc(block);
+    let root = parse_block(&data, &root_hash, &file_hash).unwrap();
+
+    assert!(validators(&root).is_err(), "Validators read from a block that is not a key block");
+}
+
+// Base64 BOC, root hash and file hash of a block
+fn block_boc(block: Cell) -> (String, [u8; 32], [u8; 32]) {
+    let root_hash = block.cell_hash().as_slice().try_into().unwrap();
+    let boc = BagOfCells::from_root(block).serialize(true).unwrap();
+    let file_hash = Sha256::digest(&boc).into();
+    (BASE64_STANDARD.encode(boc), root_hash, file_hash)
+}
+
+// block#11ef55aa global_id:0 info value_flow:(empty) state_update:(empty) extra
+fn block_fixture(info: Cell, extra: Cell) -> Cell {
+    let empty = Arc::new(CellBuilder::new().build().unwrap());
+    CellBuilder::new()
+        .store_u32(32, 0x11ef55aa)
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_reference(&Arc::new(info)))
+        .and_then(|b| b.store_reference(&empty))
+        .and_then(|b| b.store_reference(&empty))
+        .and_then(|b| b.store_reference(&Arc::new(extra)))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// block_info#9bc7a987 with a previous block `seqno - 1` whose hashes are
+// [7; 32] and [8; 32]
+#[allow(clippy::too_many_arguments)]
+fn block_info_fixture(workchain: i32, shard: u64, seqno: u32, start_lt: u64, end_lt: u64, key_block: bool, prev_key_block_seqno: u32, after_split: bool) -> Cell {
+    let not_master = workchain != -1;
+    let ext_blk_ref = |seqno: u32| {
+        CellBuilder::new()
+            .store_u64(64, start_lt.saturating_sub(1))
+            .and_then(|b| b.store_u32(32, seqno))
+            .and_then(|b| b.store_slice(&[7u8; 32]))
+            .and_then(|b| b.store_slice(&[8u8; 32]))
+            .and_then(|b| b.build())
+            .map(Arc::new)
+            .unwrap()
+    };
+
+    let mut info = CellBuilder::new();
+    info.store_u32(32, 0x9bc7a987)
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_bit(not_master))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(after_split))
+        .and_then(|b| b.store_u8(2, 0))
+        .and_then(|b| b.store_bit(key_block))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_u8(8, 0))
+        .and_then(|b| b.store_u32(32, seqno))
+        .and_then(|b| b.store_u32(32, 0))
+        // shard_ident$00 shard_pfx_bits workchain_id shard_prefix
+        .and_then(|b| b.store_u8(2, 0))
+        .and_then(|b| b.store_u8(6, 63 - shard.trailing_zeros() as u8))
+        .and_then(|b| b.store_u32(32, workchain as u32))
+        .and_then(|b| b.store_u64(64, shard & (shard - 1)))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_u64(64, start_lt))
+        .and_then(|b| b.store_u64(64, end_lt))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_u32(32, prev_key_block_seqno))
+        .unwrap();
+    if not_master {
+        info.store_reference(&ext_blk_ref(0)).unwrap();
+    }
+    info.store_reference(&ext_blk_ref(seqno - 1)).unwrap();
+    info.build().unwrap()
+}
+
+// block_extra#4a33f6fd in_msg_descr:(empty) out_msg_descr:(empty)
+// account_blocks rand_seed created_by custom
+fn block_extra_fixture(account_blocks: Cell, custom: Option<Cell>) -> Cell {
+    let empty = Arc::new(CellBuilder::new().build().unwrap());
+    let mut extra = CellBuilder::new();
+    extra
+        .store_u32(32, 0x4a33f6fd)
+        .and_then(|b| b.store_reference(&empty))
+        .and_then(|b| b.store_reference(&empty))
+        .and_then(|b| b.store_reference(&Arc::new(account_blocks)))
+        .and_then(|b| b.store_slice(&[0u8; 64]))
+        .and_then(|b| b.store_bit(custom.is_some()))
+        .unwrap();
+    if let Some(custom) = custom {
+        extra.store_reference(&Arc::new(custom)).unwrap();
+    }
+    extra.build().unwrap()
+}
+
+// ShardAccountBlocks with a single transaction of a single account: the
+// whole keys in hml_long labels, zero CurrencyCollection extras
+fn account_blocks_fixture(account: &[u8; 32], lt: u64, tx: Cell) -> Cell {
+    let empty = Arc::new(CellBuilder::new().build().unwrap());
+    let zero = BigUint::ZERO;
+
+    // ahm_edge label ahmn_leaf extra value:(acc_trans#5 account_addr
+    // transactions:(ahm_edge label ahmn_leaf extra ^Transaction) state_update)
+    let account_block = CellBuilder::new()
+        .store_u8(2, 0b10)
+        .and_then(|b| b.store_u32(9, 256))
+        .and_then(|b| b.store_slice(account))
+        .and_then(|b| b.store_coins(&zero))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_u8(4, 0x5))
+        .and_then(|b| b.store_slice(account))
+        .and_then(|b| b.store_u8(2, 0b10))
+        .and_then(|b| b.store_u8(7, 64))
+        .and_then(|b| b.store_u64(64, lt))
+        .and_then(|b| b.store_coins(&zero))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_reference(&Arc::new(tx)))
+        .and_then(|b| b.store_reference(&empty))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    // ahme_root$1 root extra
+    CellBuilder::new()
+        .store_bit(true)
+        .and_then(|b| b.store_reference(&Arc::new(account_block)))
+        .and_then(|b| b.store_coins(&zero))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// masterchain_block_extra#cca5 committing basechain shards split on the
+// first bit: block 10 on the left, 20 on the right. A key block elects the
+// validators with these keys, the first one signing the masterchain.
+fn mc_extra_fixture(public_keys: Option<&[[u8; 32]; 2]>) -> Cell {
+    let zero = BigUint::ZERO;
+
+    // bt_leaf$0 shard_descr#b seq_no reg_mc_seqno start_lt end_lt root_hash file_hash
+    let shard_descr = |seqno: u32| {
+        CellBuilder::new()
+            .store_bit(false)
+            .and_then(|b| b.store_u8(4, 0xb))
+            .and_then(|b| b.store_u32(32, seqno))
+            .and_then(|b| b.store_u32(32, 0))
+            .and_then(|b| b.store_u64(64, 0))
+            .and_then(|b| b.store_u64(64, seqno as u64 * 100))
+            .and_then(|b| b.store_slice(&[seqno as u8; 32]))
+            .and_then(|b| b.store_slice(&[seqno as u8 + 1; 32]))
+            .and_then(|b| b.build())
+            .map(Arc::new)
+            .unwrap()
+    };
+
+    // bt_fork$1 left right
+    let tree = CellBuilder::new()
+        .store_bit(true)
+        .and_then(|b| b.store_reference(&shard_descr(10)))
+        .and_then(|b| b.store_reference(&shard_descr(20)))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    // Workchain 0 in an hml_long label
+    let shard_hashes = CellBuilder::new()
+        .store_u8(2, 0b10)
+        .and_then(|b| b.store_u8(6, 32))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_reference(&Arc::new(tree)))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let mut extra = CellBuilder::new();
+    extra
+        .store_u32(16, 0xcca5)
+        .and_then(|b| b.store_bit(public_keys.is_some()))
+        .and_then(|b| b.store_bit(true))
+        .and_then(|b| b.store_reference(&Arc::new(shard_hashes)))
+        // No shard fees, zero fees and created
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_coins(&zero))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_coins(&zero))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_reference(&Arc::new(CellBuilder::new().build().unwrap())))
+        .unwrap();
+
+    if let Some(public_keys) = public_keys {
+        // validator#53 ed25519_pubkey#8e81278a pubkey weight, in a leaf with
+        // an empty label
+        let validator = |public_key: &[u8; 32]| {
+            CellBuilder::new()
+                .store_u8(2, 0b00)
+                .and_then(|b| b.store_u8(8, 0x53))
+                .and_then(|b| b.store_u32(32, 0x8e81278a))
+                .and_then(|b| b.store_slice(public_key))
+                .and_then(|b| b.store_u64(64, 100))
+                .and_then(|b| b.build())
+                .map(Arc::new)
+                .unwrap()
+        };
+
+        // Keys 0 and 1: an hml_same label of 15 zero bits, then a fork
+        let list = CellBuilder::new()
+            .store_u8(2, 0b11)
+            .and_then(|b| b.store_bit(false))
+            .and_then(|b| b.store_u8(5, 15))
+            .and_then(|b| b.store_reference(&validator(&public_keys[0])))
+            .and_then(|b| b.store_reference(&validator(&public_keys[1])))
+            .and_then(|b| b.build())
+            .unwrap();
+
+        // validators_ext#12 utime_since utime_until total:2 main:1 total_weight list
+        let param = CellBuilder::new()
+            .store_u8(8, 0x12)
+            .and_then(|b| b.store_u32(32, 0))
+            .and_then(|b| b.store_u32(32, 0))
+            .and_then(|b| b.store_u32(16, 2))
+            .and_then(|b| b.store_u32(16, 1))
+            .and_then(|b| b.store_u64(64, 200))
+            .and_then(|b| b.store_bit(true))
+            .and_then(|b| b.store_reference(&Arc::new(list)))
+            .and_then(|b| b.build())
+            .unwrap();
+
+        // Param 34 in an hml_long label
+        let config = CellBuilder::new()
+            .store_u8(2, 0b10)
+            .and_then(|b| b.store_u8(6, 32))
+            .and_then(|b| b.store_u32(32, 34))
+            .and_then(|b| b.store_reference(&Arc::new(param)))
+            .and_then(|b| b.build())
+            .unwrap();
+
+        extra
+            .store_slice(&[0u8; 32])
+            .and_then(|b| b.store_reference(&Arc::new(config)))
+            .unwrap();
+    }
+
+    extra.build().unwrap()
+}
+
+#[test]
+fn test_parse_stack_num() {
+    assert_eq!(parse_stack_num("0x0").unwrap(), 0);
+    assert_eq!(parse_stack_num("-0x1").unwrap(), -1);
+    assert_eq!(parse_stack_num("0x2a").unwrap(), 42);
+    assert!(parse_stack_num("1").is_err(), "Decimal stack number accepted");
+    assert!(parse_stack_num("0xzz").is_err(), "Invalid hex accepted");
+}
+
+#[test]
+fn test_decode_transaction() {
+    let account = TonAddress::new(0, &[1u8; 32]);
+    let first = TonAddress::new(0, &[2u8; 32]);
+    let second = TonAddress::new(0, &[3u8; 32]);
+
+    let in_msg = external_message(&account);
+    let out_msgs = vec![internal_message(&account, &first, 100), internal_message(&account, &second, 200)];
+
+    // The two messages are keyed 0 and 1: a 14 bit hml_same label then a fork
+    let left = dict_leaf(&out_msgs[0]);
+    let right = dict_leaf(&out_msgs[1]);
+    let dict = CellBuilder::new()
+        .store_u8(2, 0b11)
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_u8(4, 14))
+        .and_then(|b| b.store_reference(&Arc::new(left)))
+        .and_then(|b| b.store_reference(&Arc::new(right)))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let (tx, hash) = transaction_fixture(&account, 42, 1_700_000_000, Some(&in_msg), Some(dict));
+
+    let decoded = decode_transaction(&tx, &account).unwrap();
+
+    assert_eq!(decoded.transaction_id.hash, hash);
+    assert_eq!(decoded.utime, 1_700_000_000);
+    assert_eq!(decoded.in_msg.hash, BASE64_STANDARD.encode(in_msg.cell_hash()));
+    assert_eq!(decoded.in_msg.destination, account.to_base64_url());
+    assert_eq!(decoded.out_msgs.len(), 2);
+    assert_eq!(decoded.out_msgs[0].value, "100");
+    assert_eq!(decoded.out_msgs[0].destination, first.to_base64_url());
+    assert_eq!(decoded.out_msgs[1].value, "200");
+    assert_eq!(decoded.out_msgs[1].destination, second.to_base64_url());
+    assert_eq!(decoded.out_msgs[1].source, account.to_base64_url());
+
+    // A BOC that doesn't hash to the id, of another account or at another lt
+    let mut tampered = tx.clone();
+    tampered.transaction_id.hash = BASE64_STANDARD.encode([0u8; 32]);
+    assert!(decode_transaction(&tampered, &account).is_err(), "BOC of another hash accepted");
+
+    assert!(decode_transaction(&tx, &first).is_err(), "Transaction of another account accepted");
+
+    let mut tampered = tx.clone();
+    tampered.transaction_id.lt = "43".to_string();
+    assert!(decode_transaction(&tampered, &account).is_err(), "Transaction at another lt accepted");
+
+    // Without any message
+    let (tx, _) = transaction_fixture(&account, 7, 1, None, None);
+    let decoded = decode_transaction(&tx, &account).unwrap();
+
+    assert_eq!(decoded.in_msg, Default::default());
+    assert!(decoded.out_msgs.is_empty());
+}
+
+#[test]
+fn test_dict_values() {
+    let account = TonAddress::new(0, &[1u8; 32]);
+    let message = Arc::new(internal_message(&account, &account, 1));
+
+    // A single value keyed 5, the whole key in an hml_long label
+    let single = CellBuilder::new()
+        .store_u8(2, 0b10)
+        .and_then(|b| b.store_u8(4, 15))
+        .and_then(|b| b.store_u32(15, 5))
+        .and_then(|b| b.store_reference(&message))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let mut values = vec![];
+    dict_values(&Arc::new(single), 15, &mut values).unwrap();
+
+    assert_eq!(values.len(), 1);
+    assert_eq!(values[0].cell_hash(), message.cell_hash());
+
+    // Keys 0 and 1 of a 2 bit dictionary: an hml_short label of one zero bit
+    // ($0, unary 1 as 10, then the bit), then a fork into two leaves with
+    // empty labels
+    let fork = CellBuilder::new()
+        .store_u8(4, 0b0100)
+        .and_then(|b| b.store_reference(&Arc::new(dict_leaf(&internal_message(&account, &account, 1)))))
+        .and_then(|b| b.store_reference(&Arc::new(dict_leaf(&internal_message(&account, &account, 2)))))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let mut values = vec![];
+    dict_values(&Arc::new(fork), 2, &mut values).unwrap();
+
+    assert_eq!(values.len(), 2);
+    assert_eq!(values[0].cell_hash(), internal_message(&account, &account, 1).cell_hash());
+    assert_eq!(values[1].cell_hash(), internal_message(&account, &account, 2).cell_hash());
+
+    // A label longer than the key
+    let invalid = CellBuilder::new()
+        .store_u8(2, 0b10)
+        .and_then(|b| b.store_u8(2, 3))
+        .and_then(|b| b.store_u8(3, 0))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    assert!(dict_values(&Arc::new(invalid), 2, &mut vec![]).is_err(), "Label longer than the key accepted");
+}
+
+#[test]
+fn test_highload_query_id_wraps_around() {
+    assert_eq!(QueryId::default().next(), QueryId { shift: 0, bit_number: 1 });
+
+    // bit_number stays below 1023, the next shift starts over at 0
+    let last_bit = QueryId { shift: 3, bit_number: 1022 };
+    assert_eq!(last_bit.next(), QueryId { shift: 4, bit_number: 0 });
+
+    // Once every id was used the wallet timeout has long passed, ids start over
+    let last = QueryId { shift: (1 << 13) - 1, bit_number: 1022 };
+    assert_eq!(last.next(), QueryId::default());
+
+    // The u64 form is the one of the `processed?` get-method: shift << 10 | bit_number
+    assert_eq!(last_bit.to_u64(), (3 << 10) | 1022);
+    for query_id in [QueryId::default(), last_bit, last_bit.next(), last] {
+        assert_eq!(QueryId::from_u64(query_id.to_u64()), query_id);
+    }
+
+    // Walking through every id comes back to the first one
+    let mut query_id = QueryId::default();
+    let mut count = 0u64;
+    loop {
+        query_id = QueryId::from_u64(query_id.next().to_u64());
+        count += 1;
+        if query_id == QueryId::default() {
+            break;
+        }
+    }
+    assert_eq!(count, (1 << 13) * 1023);
+}
+
+#[test]
+fn test_highload_message_layout() {
+    let code = CellBuilder::new().store_u32(32, 0xc0de).and_then(|b| b.build()).unwrap();
+    let code_boc = BASE64_STANDARD.encode(BagOfCells::from_root(code).serialize(true).unwrap());
+
+    let wallet = HighloadWalletV3::new(ICTonSigner::new(vec![1u8; 32], vec![]), &code_boc).unwrap();
+
+    let dest = TonAddress::new(0, &[6u8; 32]);
+    let payouts: Vec<Cell> = vec![internal_message(&TonAddress::NULL, &dest, 100), internal_message(&TonAddress::NULL, &dest, 200)];
+    let query_id = QueryId { shift: 5, bit_number: 1022 };
+
+    let inner = wallet
+        .signed_part(payouts.iter().cloned().map(Arc::new).collect(), PAYOUT_SEND_MODE, query_id, 1_700_000_000)
+        .unwrap();
+
+    // subwallet_id:uint32 message_to_send:^Cell send_mode:uint8
+    // query_id:(shift:uint13 bit_number:uint10) created_at:uint64 timeout:uint22
+    let mut parser = inner.parser();
+    assert_eq!(parser.load_u32(32).unwrap(), HIGHLOAD_SUBWALLET_ID);
+    assert_eq!(parser.load_u8(8).unwrap(), 128);
+    assert_eq!(parser.load_u32(13).unwrap(), 5);
+    assert_eq!(parser.load_u32(10).unwrap(), 1022);
+    assert_eq!(parser.load_u64(64).unwrap(), 1_700_000_000);
+    assert_eq!(parser.load_u32(22).unwrap(), HIGHLOAD_TIMEOUT_SECS);
+    assert_eq!(parser.remaining_bits(), 0);
+
+    // int_msg_info$0 ihr_disabled bounce bounced src:addr_none dest:wallet
+    // value:0 ihr_fee fwd_fee created_lt created_at init:none body:^Cell
+    let message_to_send = inner.reference(0).unwrap();
+    let mut parser = message_to_send.parser();
+    assert!(!parser.load_bit().unwrap(), "Not an internal message");
+    parser.skip_bits(3).unwrap();
+    assert_eq!(parser.load_address().unwrap(), TonAddress::NULL);
+    assert_eq!(parser.load_address().unwrap(), wallet.address);
+    assert_eq!(parser.load_coins().unwrap(), BigUint::ZERO);
+    assert!(!parser.load_bit().unwrap(), "Extra currencies attached");
+    parser.load_coins().unwrap();
+    parser.load_coins().unwrap();
+    parser.skip_bits(64 + 32).unwrap();
+    assert!(!parser.load_bit().unwrap(), "State init attached");
+    assert!(parser.load_bit().unwrap(), "Body not in a reference");
+
+    // internal_transfer#ae42e5a4 query_id:uint64 actions:^OutList
+    let body = message_to_send.reference(0).unwrap();
+    let mut parser = body.parser();
+    assert_eq!(parser.load_u32(32).unwrap(), 0xae42_e5a4);
+    assert_eq!(parser.load_u64(64).unwrap(), query_id.to_u64());
+
+    // out_list$_ prev:^OutList action_send_msg#0ec3c86d mode:uint8 out_msg:^MessageRelaxed,
+    // the last payout at the head of the list
+    let mut actions = vec![];
+    let mut list = body.reference(0).unwrap().clone();
+    while !list.references().is_empty() {
+        let mut parser = list.parser();
+        assert_eq!(parser.load_u32(32).unwrap(), 0x0ec3_c86d);
+        assert_eq!(parser.load_u8(8).unwrap(), PAYOUT_SEND_MODE);
+        actions.push(list.reference(1).unwrap().cell_hash());
+        list = list.reference(0).unwrap().clone();
+    }
+    assert_eq!(list.parser().remaining_bits(), 0, "List does not end with out_list_empty");
+
+    actions.reverse();
+    assert_eq!(actions, payouts.iter().map(|payout| payout.cell_hash()).collect::<Vec<_>>());
+
+    // ext_in_msg_info$10 src:addr_none dest:wallet import_fee:0 init body:^[ signature ^inner ]
+    let signature = [7u8; 64];
+    for with_state_init in [false, true] {
+        let external = wallet.external(&signature, inner.clone(), with_state_init).unwrap();
+
+        let mut parser = external.parser();
+        assert_eq!(parser.load_u8(2).unwrap(), 0b10);
+        assert_eq!(parser.load_address().unwrap(), TonAddress::NULL);
+        assert_eq!(parser.load_address().unwrap(), wallet.address);
+        assert_eq!(parser.load_coins().unwrap(), BigUint::ZERO);
+        assert_eq!(parser.load_bit().unwrap(), with_state_init);
+
+        let body = match with_state_init {
+            true => {
+                assert!(parser.load_bit().unwrap(), "State init not in a reference");
+
+                // The wallet address is the hash of its state init
+                let state_init = external.reference(0).unwrap();
+                assert_eq!(TonAddress::new(0, &state_init.cell_hash()), wallet.address);
+
+                // public_key:bits256 subwallet_id:uint32 old_queries:none queries:none
+                // last_clean_time:uint64 timeout:uint22
+                let mut data = state_init.reference(1).unwrap().parser();
+                assert_eq!(data.load_bytes(32).unwrap(), vec![1u8; 32]);
+                assert_eq!(data.load_u32(32).unwrap(), HIGHLOAD_SUBWALLET_ID);
+                assert!(!data.load_bit().unwrap() && !data.load_bit().unwrap(), "Query dictionaries not empty");
+                assert_eq!(data.load_u64(64).unwrap(), 0);
+                assert_eq!(data.load_u32(22).unwrap(), HIGHLOAD_TIMEOUT_SECS);
+
+                external.reference(1).unwrap()
+            }
+            false => external.reference(0).unwrap(),
+        };
+        assert!(parser.load_bit().unwrap(), "Body not in a reference");
+
+        let mut parser = body.parser();
+        assert_eq!(parser.load_bytes(64).unwrap(), signature.to_vec());
+        assert_eq!(body.reference(0).unwrap().cell_hash(), inner.cell_hash());
+    }
+}
+
+#[test]
+fn test_jetton_transfer_message() {
+    let jetton_wallet = TonAddress::new(0, &[1u8; 32]);
+    let destination = TonAddress::new(0, &[2u8; 32]);
+    let response = TonAddress::new(0, &[3u8; 32]);
+
+    let message = transfer_message(&jetton_wallet, 1 << 63, 900, &destination, &response).unwrap();
+
+    let mut parser = message.parser();
+    // int_msg_info$0 ihr_disabled bounce bounced
+    assert_eq!(parser.load_u8(4).unwrap(), 0b0110, "Payout transfer must be bounceable");
+    parser.load_address().unwrap();
+    assert_eq!(parser.load_address().unwrap(), jetton_wallet);
+
+    let body = message.reference(0).unwrap();
+    let mut parser = body.parser();
+    assert_eq!(parser.load_u32(32).unwrap(), JETTON_TRANSFER_OP);
+    assert_eq!(parser.load_u64(64).unwrap(), 1 << 63);
+    assert_eq!(parser.load_coins().unwrap(), BigUint::from(900u64));
+    assert_eq!(parser.load_address().unwrap(), destination);
+    assert_eq!(parser.load_address().unwrap(), response);
+}
+
+#[test]
+fn test_jetton_payout_outcome() {
+    let query_id = (1 << 63) + 5;
+
+    // The jetton wallet forwards the jettons
+    let tx = jetton_wallet_tx(vec![jetton_body(JETTON_INTERNAL_TRANSFER_OP, query_id, 900, false)]);
+    assert_eq!(payout_outcome(&tx, query_id, 900), Ok(PayoutTrace::Delivered { tx_hash: "jetton-tx".to_string() }));
+
+    // Another amount than the one burned
+    assert!(payout_outcome(&tx, query_id, 901).is_err(), "Payout of another amount accepted");
+
+    // The forwarded jettons belong to another withdrawal of the batch
+    assert!(matches!(payout_outcome(&tx, query_id + 1, 900), Ok(PayoutTrace::Failed { .. })));
+
+    // The transfer bounced back to the minter wallet
+    let tx = jetton_wallet_tx(vec![jetton_body(JETTON_TRANSFER_OP, query_id, 900, true)]);
+    assert_eq!(payout_outcome(&tx, query_id, 900), Ok(PayoutTrace::Failed { reason: "Jetton transfer bounced".to_string() }));
+
+    // Nothing was sent, e.g. the action phase failed
+    let tx = jetton_wallet_tx(vec![]);
+    assert!(matches!(payout_outcome(&tx, query_id, 900), Ok(PayoutTrace::Failed { .. })));
+}
+
+// A jetton wallet transaction with the given outgoing message bodies
+fn jetton_wallet_tx(bodies: Vec<Cell>) -> TonTransaction {
+    let out_msgs = bodies
+        .into_iter()
+        .map(|body| TonTransactionMessage {
+            msg_data: Some(TonMessageData {
+                data_type: "msg.dataRaw".to_string(),
+                body: Some(BASE64_STANDARD.encode(BagOfCells::from_root(body).serialize(false).unwrap())),
+                text: None,
+            }),
+            ..Default::default()
+        })
+        .collect();
+
+    TonTransaction {
+        transaction_id: TonTransactionId { lt: "1".to_string(), hash: "jetton-tx".to_string() },
+        utime: 0,
+        in_msg: Default::default(),
+        out_msgs,
+        data: String::new(),
+    }
+}
+
+// op query_id amount, prefixed with 0xffffffff when bounced
+fn jetton_body(op: u32, query_id: u64, amount: u64, bounced: bool) -> Cell {
+    let mut builder = CellBuilder::new();
+    if bounced {
+        builder.store_u32(32, 0xffffffff).unwrap();
+    }
+    builder
+        .store_u32(32, op)
+        .and_then(|b| b.store_u64(64, query_id))
+        .and_then(|b| b.store_coins(&BigUint::from(amount)))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// transaction$0111 account_addr lt prev_trans_hash prev_trans_lt now
+// outmsg_cnt orig_status end_status ^[ in_msg out_msgs ], as a provider
+// returns it
+fn transaction_fixture(account: &TonAddress, lt: u64, now: u32, in_msg: Option<&Cell>, out_msgs: Option<Cell>) -> (TonTransaction, String) {
+    let mut messages = CellBuilder::new();
+    messages.store_bit(in_msg.is_some()).unwrap();
+    if let Some(in_msg) = in_msg {
+        messages.store_reference(&Arc::new(in_msg.clone())).unwrap();
+    }
+    messages.store_bit(out_msgs.is_some()).unwrap();
+    if let Some(out_msgs) = out_msgs {
+        messages.store_reference(&Arc::new(out_msgs)).unwrap();
+    }
+    let messages = messages.build().unwrap();
+
+    let root = CellBuilder::new()
+        .store_u8(4, 0b0111)
+        .and_then(|b| b.store_slice(account.hash_part.as_slice()))
+        .and_then(|b| b.store_u64(64, lt))
+        .and_then(|b| b.store_slice(&[0u8; 32]))
+        .and_then(|b| b.store_u64(64, 0))
+        .and_then(|b| b.store_u32(32, now))
+        .and_then(|b| b.store_u32(15, 0))
+        .and_then(|b| b.store_u8(2, 0b10))
+        .and_then(|b| b.store_u8(2, 0b10))
+        .and_then(|b| b.store_reference(&Arc::new(messages)))
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let hash = BASE64_STANDARD.encode(root.cell_hash());
+    let data = BASE64_STANDARD.encode(BagOfCells::from_root(root).serialize(true).unwrap());
+
+    let tx = TonTransaction {
+        transaction_id: TonTransactionId { lt: lt.to_string(), hash: hash.clone() },
+        utime: 0,
+        in_msg: Default::default(),
+        out_msgs: vec![],
+        data,
+    };
+
+    (tx, hash)
+}
+
+// ext_in_msg_info$10 src:addr_none dest import_fee:0 init:none body:(inline, empty)
+fn external_message(dest: &TonAddress) -> Cell {
+    CellBuilder::new()
+        .store_u8(2, 0b10)
+        .and_then(|b| b.store_address(&TonAddress::NULL))
+        .and_then(|b| b.store_address(dest))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// int_msg_info$0 ihr_disabled:1 bounce:0 bounced:0 src dest value ihr_fee:0
+// fwd_fee:0 created_lt:0 created_at:0 init:none body:(inline, empty)
+fn internal_message(src: &TonAddress, dest: &TonAddress, value: u64) -> Cell {
+    CellBuilder::new()
+        .store_bit(false)
+        .and_then(|b| b.store_bit(true))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_address(src))
+        .and_then(|b| b.store_address(dest))
+        .and_then(|b| b.store_coins(&BigUint::from(value)))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_coins(&BigUint::ZERO))
+        .and_then(|b| b.store_u64(64, 0))
+        .and_then(|b| b.store_u32(32, 0))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// hm_edge with an empty hml_short label and hmn_leaf value:^Message
+fn dict_leaf(message: &Cell) -> Cell {
+    CellBuilder::new()
+        .store_u8(2, 0b00)
+        .and_then(|b| b.store_reference(&Arc::new(message.clone())))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// The out_msgs dictionary of `messages`, keyed 0.. in order: an hml_same label
+// of zeros, then a fork per remaining key bit. Takes a power of two messages.
+fn out_msgs_fixture(messages: &[Cell]) -> Cell {
+    fn node(messages: &[Cell]) -> Cell {
+        if let [message] = messages {
+            return dict_leaf(message);
+        }
+
+        let (left, right) = messages.split_at(messages.len() / 2);
+        CellBuilder::new()
+            .store_u8(2, 0b00)
+            .and_then(|b| b.store_reference(&Arc::new(node(left))))
+            .and_then(|b| b.store_reference(&Arc::new(node(right))))
+            .and_then(|b| b.build())
+            .unwrap()
+    }
+
+    assert!(messages.len() >= 2 && messages.len().is_power_of_two());
+
+    let (left, right) = messages.split_at(messages.len() / 2);
+    CellBuilder::new()
+        .store_u8(2, 0b11)
+        .and_then(|b| b.store_bit(false))
+        .and_then(|b| b.store_u8(4, 15 - messages.len().trailing_zeros() as u8))
+        .and_then(|b| b.store_reference(&Arc::new(node(left))))
+        .and_then(|b| b.store_reference(&Arc::new(node(right))))
+        .and_then(|b| b.build())
+        .unwrap()
+}
+
+// The transaction of the minter wallet `wallet` that accepted the external
+// message `boc` and sent the `payouts`
+fn payout_transaction(wallet: &TonAddress, lt: u64, now: u32, boc: &str, payouts: &[(TonAddress, u64)]) -> TonTransaction {
+    let external = BagOfCells::parse_base64(boc).and_then(|boc| boc.single_root()).unwrap();
+
+    let messages: Vec<Cell> = payouts.iter().map(|(dest, value)| internal_message(wallet, dest, *value)).collect();
+
+    transaction_fixture(wallet, lt, now, Some(external.as_ref()), Some(out_msgs_fixture(&messages))).0
+}
+
+#[test]
+fn test_http_transform_is_canonical() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    // Same wallet information as two providers would return it, with
+    // different field order and extra fields
+    let first = br#"{"ok":true,"result":{"wallet":true,"balance":"1000","account_state":"active","seqno":3,"last_transaction_id":{"lt":"1","hash":"a"}},"@extra":"1700000000.1:0:0.1"}"#;
+    let second = br#"{"@extra":"1700000000.2:1:0.2","result":{"account_state":"active","seqno":3,"balance":"1000","wallet":true,"wallet_id":698983191},"ok":true}"#;
+
+    // 2 is the `getWalletInformation` transform
+    let first = http_transform(&pic, minter_id, sender, first.to_vec(), vec![2]);
+    let second = http_transform(&pic, minter_id, sender, second.to_vec(), vec![2]);
+
+    assert_eq!(first, second);
+    assert!(!String::from_utf8(first).unwrap().contains("@extra"));
+}
+
+#[test]
+fn test_http_transform_translates_other_apis() {
+    let sender = Principal::from_str("lxa3f-k4w5m-3jyvz-gau5t-bceou-czpd4-thtwe-b4unx-vvrho-dtvwh-qqe").unwrap();
+
+    let (pic, minter_id) = setup(sender);
+
+    // The same wallet through toncenter v2 and v3, transform 2 with kinds 0 and 1
+    let v2 = br#"{"ok":true,"result":{"wallet":true,"balance":"1000","account_state":"uninitialized","seqno":null}}"#;
+    let v3 = br#"{"balance":"1000","wallet_type":"wallet v4 r2","seqno":null,"status":"uninit","last_transaction_lt":"1"}"#;
+
+    let v2 = http_transform(&pic, minter_id, sender, v2.to_vec(), vec![2, 0]);
+    let v3 = http_transform(&pic, minter_id, sender, v3.to_vec(), vec![2, 1]);
+
+    assert_eq!(v2, v3);
+
+    // The same `processed?` result through toncenter v2 and tonapi, transform 4
+    // with kinds 0 and 2
+    let v2 = br#"{"ok":true,"result":{"exit_code":0,"stack":[["num","-0x1"]]}}"#;
+    let tonapi = br#"{"success":true,"exit_code":0,"stack":[{"type":"num","num":"-1"}]}"#;
+
+    let v2 = http_transform(&pic, minter_id, sender, v2.to_vec(), vec![4, 0]);
+    let tonapi = http_transform(&pic, minter_id, sender, tonapi.to_vec(), vec![4, 2]);
+
+    assert_eq!(v2, tonapi);
+
+    // The masterchain head of tonapi, with a hex shard and hex hashes
+    let tonapi = br#"{"workchain_id":-1,"shard":"8000000000000000","seqno":7,"root_hash":"00ff","file_hash":"ff00","gen_utime":1}"#;
+    let tonapi = http_transform(&pic, minter_id, sender, tonapi.to_vec(), vec![5, 2]);
+    let info: serde_json::Value = serde_json::from_slice(&tonapi).unwrap();
+
+    assert_eq!(info["result"]["last"]["shard"], "-9223372036854775808");
+    assert_eq!(info["result"]["last"]["root_hash"], "AP8=");
+
+    // The same masterchain block looked up through toncenter v2 and v3,
+    // transform 7 with kinds 0 and 1
+    let v2 = br#"{"ok":true,"result":{"@type":"ton.blockIdExt","workchain":-1,"shard":"-9223372036854775808","seqno":7,"root_hash":"AP8=","file_hash":"/wA="}}"#;
+    let v3 = br#"{"blocks":[{"workchain":-1,"shard":"8000000000000000","seqno":7,"root_hash":"AP8=","file_hash":"/wA=","gen_utime":"1"}]}"#;
+
+    let v2 = http_transform(&pic, minter_id, sender, v2.to_vec(), vec![7, 0]);
+    let v3 = http_transform(&pic, minter_id, sender, v3.to_vec(), vec![7, 1]);
+
+    assert_eq!(v2, v3);
+
+    // A raw block of tonapi, transform 8, as a base64 BOC
+    let tonapi = br#"{"id":{"workchain":-1,"shard":"8000000000000000","seqno":7,"root_hash":"00ff","file_hash":"ff00"},"data":"00ff"}"#;
+    let tonapi = http_transform(&pic, minter_id, sender, tonapi.to_vec(), vec![8, 2]);
+    let block: serde_json::Value = serde_json::from_slice(&tonapi).unwrap();
+
+    assert_eq!(block["result"]["data"], "AP8=");
+}
+
+fn http_transform(pic: &PocketIc, minter_id: Principal, sender: Principal, body: Vec<u8>, context: Vec<u8>) -> Vec<u8> {
+    let arg = TransformArgs {
+        response: HttpResponse {
+            status: Nat::from(200u32),
+            headers: vec![HttpHeader { name: "Date".to_string(), value: "Thu, 01 Jan 2026 00:00:00 GMT".to_string() }],
+            body,
+        },
+        context,
+    };
+
+    let result = pic.query_call(minter_id, sender, "http_transform", encode_args((arg,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Http transform rejected with :{}", d),
+    };
+
+    let response = Decode!(&data, HttpResponse).unwrap();
+
+    assert!(response.headers.is_empty());
+
+    response.body
+}
+
+fn event_count(pic: &PocketIc, minter_id: Principal, sender: Principal) -> u64 {
+    let result = pic.query_call(minter_id, sender, "get_event_count", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Get event count rejected with :{}", d),
+    };
+
+    Decode!(&data, u64).unwrap()
+}
+
+fn ledger_suites(pic: &PocketIc, minter_id: Principal, sender: Principal) -> Vec<LedgerSuite> {
+    let result = pic.query_call(minter_id, sender, "get_ledger_suites", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Get ledger suites rejected with :{}", d),
+    };
+
+    Decode!(&data, Vec<LedgerSuite>).unwrap()
+}
+
+// The reply of an update call submitted earlier, e.g. before answering its outcalls
+fn await_update<T: candid::CandidType + for<'de> candid::Deserialize<'de>>(pic: &PocketIc, call: RawMessageId) -> T {
+    let data = match pic.await_call(call).unwrap() {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Call rejected with :{}", d),
+    };
+
+    candid::decode_one(&data).unwrap()
+}
+
+fn minter_ton_address(pic: &PocketIc, minter_id: Principal, sender: Principal) -> String {
+    let result = pic.query_call(minter_id, sender, "minter_ton_address", encode_args(()).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Minter ton address rejected with :{}", d),
+    };
+
+    Decode!(&data, String).unwrap()
+}
+
 fn ledger_balance(pic: &PocketIc, ledger_id: Principal, sender: Principal) -> u64 {
     let account = Account{
         owner: sender,
@@ -368,6 +2523,73 @@ fn ledger_balance(pic: &PocketIc, ledger_id: Principal, sender: Principal) -> u6
     balance.0.try_into().unwrap()
 }
 
+// Mints `amount` on the ledger, whose minting account is the minter
+fn ledger_mint(pic: &PocketIc, ledger_id: Principal, minter_id: Principal, to: Account, amount: u64) {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to,
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: Nat::from(amount),
+    };
+
+    let result = pic.update_call(ledger_id, minter_id, "icrc1_transfer", encode_args((arg,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Ledger mint rejected with :{}", d),
+    };
+
+    Decode!(&data, Result<Nat, TransferError>).unwrap().unwrap();
+}
+
+fn ledger_approve(pic: &PocketIc, ledger_id: Principal, sender: Principal, spender: Principal, amount: u64) {
+    let arg = ApproveArgs {
+        from_subaccount: None,
+        spender: Account { owner: spender, subaccount: None },
+        amount: Nat::from(amount),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let result = pic.update_call(ledger_id, sender, "icrc2_approve", encode_args((arg,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Ledger approve rejected with :{}", d),
+    };
+
+    Decode!(&data, Result<Nat, ApproveError>).unwrap().unwrap();
+}
+
+fn withdraw_ton(pic: &PocketIc, minter_id: Principal, sender: Principal, to_ton_address: String, amount: u64) -> Result<u64, String> {
+    let result = pic
+        .update_call(minter_id, sender, "withdraw_ton", encode_args((to_ton_address, amount, None::<Subaccount>)).unwrap())
+        .unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Withdraw TON rejected with :{}", d),
+    };
+
+    Decode!(&data, Result<u64, String>).unwrap()
+}
+
+fn retrieve_ton_status(pic: &PocketIc, minter_id: Principal, sender: Principal, withdrawal_id: u64) -> Option<WithdrawalRequest> {
+    let result = pic.query_call(minter_id, sender, "retrieve_ton_status", encode_args((withdrawal_id,)).unwrap()).unwrap();
+
+    let data = match result {
+        pocket_ic::WasmResult::Reply(items) => items,
+        pocket_ic::WasmResult::Reject(d) => panic!("Retrieve TON status rejected with :{}", d),
+    };
+
+    Decode!(&data, Option<WithdrawalRequest>).unwrap()
+}
+
 fn ledger_id(pic: &PocketIc, minter_id: Principal, sender: Principal) -> String {
     let result = pic.query_call(minter_id, sender, "ledger_id", encode_args(()).unwrap()).unwrap();
 
@@ -447,17 +2669,148 @@ fn wallet_balance(pic: &PocketIc, minter_id: Principal, ton_address: String, sen
 }
 
 fn setup(sender: Principal) -> (PocketIc, Principal) {
+    setup_with(sender, init_arg())
+}
+
+fn setup_with(sender: Principal, arg: InitArg) -> (PocketIc, Principal) {
     let pic = PocketIcBuilder::new().with_nns_subnet().with_ii_subnet().with_application_subnet().build();
 
     let minter_id = pic.create_canister_with_settings(Some(sender), None);
 
     pic.add_cycles(minter_id, 200_000_000_000_000);
 
-    pic.install_canister(minter_id, WASM_BYTES.to_vec(), encode_args(()).unwrap(), Some(sender));
+    pic.install_canister(minter_id, WASM_BYTES.to_vec(), encode_args((MinterArg::Init(arg),)).unwrap(), Some(sender));
 
     (pic, minter_id)
 }
 
+// Testnet setup, providers and proxy from the environment of the test run
+fn init_arg() -> InitArg {
+    InitArg {
+        ton_network: TonNetwork::Testnet,
+        schnorr_key_name: "dfx_test_key".to_string(),
+        ton_providers: vec![TonProvider {
+            url: std::env::var("TON_RPC_URL").unwrap_or_else(|_| "https://testnet.toncenter.com/api/v2".to_string()),
+            api_key: std::env::var("TON_API_KEY").ok(),
+            kind: None,
+        }],
+        ton_providers_threshold: None,
+        ton_outcall_mode: None,
+        proxy: std::env::var("PROXY_URL").ok().map(|url| ProxyConfig { url, api_key: None }),
+        ledger_canister: None,
+        indexer_canister: None,
+        ckton_transfer_fee: None,
+        ton_fee: None,
+        subnet_size: None,
+        min_sweep_balance: None,
+        max_accounts_per_scan: None,
+    }
+}
+
+const MOCK_PROVIDER_URL: &str = "https://toncenter.mock/api/v2";
+
+// A single provider reached directly, whose outcalls the test answers, see
+// `answer_ton_api`
+fn mocked_init_arg() -> InitArg {
+    InitArg {
+        ton_providers: vec![TonProvider {
+            url: MOCK_PROVIDER_URL.to_string(),
+            api_key: None,
+            kind: Some(TonProviderKind::ToncenterV2),
+        }],
+        ton_outcall_mode: Some(TonOutcallMode::Direct),
+        proxy: None,
+        ..init_arg()
+    }
+}
+
+// The TON chain as the mocked provider shows it: the minter wallet at `seqno`
+// holding `balance`, with its transactions newest first. Messages are accepted unless
+// `accept_messages` is unset.
+struct MockTon {
+    seqno: u64,
+    balance: u64,
+    transactions: Vec<TonTransaction>,
+    accept_messages: bool,
+    // The BOC of every accepted message
+    sent: Vec<String>,
+    wallet_reads: usize,
+}
+
+impl MockTon {
+    fn new(seqno: u64) -> Self {
+        Self { seqno, balance: 10_000_000_000, transactions: vec![], accept_messages: true, sent: vec![], wallet_reads: 0 }
+    }
+
+    // The status and body a toncenter v2 provider answers `request` with
+    fn answer(&mut self, request: &CanisterHttpRequest) -> (u16, serde_json::Value) {
+        let path = request.url.strip_prefix(MOCK_PROVIDER_URL).unwrap_or_default().trim_start_matches('/');
+        let (endpoint, query) = path.split_once('?').unwrap_or((path, ""));
+
+        let param = |name: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.parse::<u64>().unwrap())
+        };
+
+        match endpoint {
+            "getWalletInformation" => {
+                self.wallet_reads += 1;
+                let info = serde_json::json!({ "balance": self.balance.to_string(), "wallet": true, "seqno": self.seqno, "account_state": "active" });
+                (200, serde_json::json!({ "ok": true, "result": info }))
+            }
+            "sendBocReturnHash" if self.accept_messages => {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                self.sent.push(body["boc"].as_str().unwrap().to_string());
+                (200, serde_json::json!({ "ok": true, "result": { "hash": "" } }))
+            }
+            "sendBocReturnHash" => (500, serde_json::json!({ "ok": false, "code": 500, "error": "Failed to unpack account state" })),
+            // From the transaction at `lt` (included) back to `to_lt` (excluded)
+            "getTransactions" => {
+                let page: Vec<&TonTransaction> = self
+                    .transactions
+                    .iter()
+                    .filter(|tx| {
+                        let lt = tx.transaction_id.lt.parse::<u64>().unwrap();
+                        param("lt").map_or(true, |from| lt <= from) && param("to_lt").map_or(true, |to_lt| lt > to_lt)
+                    })
+                    .take(param("limit").unwrap_or(u64::MAX) as usize)
+                    .collect();
+                (200, serde_json::json!({ "ok": true, "result": page }))
+            }
+            endpoint => (500, serde_json::json!({ "ok": false, "error": format!("{} is not mocked", endpoint) })),
+        }
+    }
+}
+
+// Runs the canister for a while, answering its outcalls from `ton`
+fn answer_ton_api(pic: &PocketIc, ton: &mut MockTon) {
+    for _ in 0..50 {
+        pic.tick();
+
+        for request in pic.get_canister_http() {
+            let (status, body) = ton.answer(&request);
+
+            pic.mock_canister_http_response(MockCanisterHttpResponse {
+                subnet_id: request.subnet_id,
+                request_id: request.request_id,
+                response: CanisterHttpResponse::CanisterHttpReply(CanisterHttpReply {
+                    status,
+                    headers: vec![],
+                    body: body.to_string().into_bytes(),
+                }),
+                additional_responses: vec![],
+            });
+        }
+    }
+}
+
+fn pic_time_in_secs(pic: &PocketIc) -> u32 {
+    pic.get_time().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as u32
+}
+
 fn generate_ton_address(pic: &PocketIc, minter_id: Principal, sender: Principal) -> String {
     let result = pic.update_call(minter_id, sender, "generate_ton_address", encode_args((None::<Principal>, None::<Subaccount>)).unwrap()).unwrap();
     let data = match result {
@@ -465,8 +2818,19 @@ fn generate_ton_address(pic: &PocketIc, minter_id: Principal, sender: Principal)
         pocket_ic::WasmResult::Reject(d) => panic!("Generat ton address rejected with :{}", d),
     };
 
-    let ton_addr : String = Decode!(&data, String).unwrap();
+    let ton_addr : Result<String, MinterError> = Decode!(&data, Result<String, MinterError>).unwrap();
+
+    ton_addr.unwrap()
 
-    ton_addr
+}
+
+fn generate_ton_address_with_version(pic: &PocketIc, minter_id: Principal, sender: Principal, version: Option<TonWalletVersion>) -> Result<String, String> {
+    let result = pic
+        .update_call(minter_id, sender, "generate_ton_address", encode_args((None::<Principal>, None::<Subaccount>, version)).unwrap())
+        .map_err(|e| format!("{:?}", e))?;
 
-}
\ No newline at end of file
+    match result {
+        pocket_ic::WasmResult::Reply(items) => Decode!(&items, Result<String, MinterError>).unwrap().map_err(|e| e.to_string()),
+        pocket_ic::WasmResult::Reject(d) => Err(d),
+    }
+}
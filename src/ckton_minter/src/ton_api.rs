@@ -1,37 +1,304 @@
-use candid::Nat;
-use ic_cdk::{api::management_canister::{http_request::{self, http_request, CanisterHttpRequestArgument, HttpHeader, HttpResponse, TransformArgs, TransformContext}, main::raw_rand}, query, update};
-use serde::Deserialize;
-use serde_json::json;
+use std::{collections::BTreeMap, fmt};
 
-use crate::{consts::{PROXY_API_KEY, PROXY_URL, TON_API_KEY, TON_RPC_URL}, types::ProxyRequest};
+use base64::prelude::*;
+use candid::CandidType;
+use futures::future::join_all;
+use ic_cdk::{api::{call::{msg_cycles_refunded128, RejectionCode}, management_canister::{http_request::{self, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext}, main::raw_rand}}, query};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 
-#[derive(Debug, Deserialize)]
+use ic_ton_lib::{cell::{BagOfCells, CellBuilder}, TonAddress};
+
+use crate::{consts::MINTER_CYCLES_RESERVE, cycles::{http_request_cost, record_outcall, Operation}, state::read_config, transaction::{boc_hash, decode_transaction}, types::{ProxyMethod, ProxyRequest, TonOutcallMode}};
+
+// API spoken by a TON provider. Responses of every kind are translated to the
+// toncenter v2 format in `http_transform`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, CandidType, Deserialize, Serialize)]
+pub enum TonProviderKind {
+    // toncenter.com/api/v2, which returns no raw blocks
+    #[default]
+    ToncenterV2,
+    // toncenter.com/api/v3, which returns no transaction BOCs, block
+    // signatures nor raw blocks
+    ToncenterV3,
+    // tonapi.io, which has no wallet information, block signatures nor block
+    // lookup
+    TonApi,
+    // A self-hosted ton-http-api, which speaks the toncenter v2 API
+    SelfHosted,
+}
+
+impl TonProviderKind {
+    fn from_context(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::ToncenterV2),
+            1 => Some(Self::ToncenterV3),
+            2 => Some(Self::TonApi),
+            3 => Some(Self::SelfHosted),
+            _ => None,
+        }
+    }
+
+    fn context(self) -> u8 {
+        match self {
+            Self::ToncenterV2 => 0,
+            Self::ToncenterV3 => 1,
+            Self::TonApi => 2,
+            Self::SelfHosted => 3,
+        }
+    }
+
+    // Whether providers of this kind can answer requests to `endpoint`
+    fn serves(self, endpoint: TonEndpoint) -> bool {
+        match self {
+            Self::ToncenterV2 | Self::SelfHosted => endpoint != TonEndpoint::GetBlock,
+            Self::ToncenterV3 => !matches!(
+                endpoint,
+                TonEndpoint::GetTransactions | TonEndpoint::GetMasterchainBlockSignatures | TonEndpoint::GetBlock
+            ),
+            Self::TonApi => !matches!(
+                endpoint,
+                TonEndpoint::GetWalletInformation | TonEndpoint::GetMasterchainBlockSignatures | TonEndpoint::LookupBlock
+            ),
+        }
+    }
+}
+
+// A TON RPC provider
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct TonProvider {
+    // Base url of the API, e.g. https://toncenter.com/api/v2,
+    // https://toncenter.com/api/v3 or https://tonapi.io
+    pub url: String,
+    pub api_key: Option<String>,
+    // Unset for providers configured before other APIs than toncenter v2
+    // were supported
+    pub kind: Option<TonProviderKind>,
+}
+
+impl TonProvider {
+    pub fn kind(&self) -> TonProviderKind {
+        self.kind.unwrap_or_default()
+    }
+
+    // Without the API key, for the event log
+    pub fn redacted(&self) -> TonProvider {
+        TonProvider {
+            url: self.url.clone(),
+            api_key: None,
+            kind: self.kind,
+        }
+    }
+
+    // Header carrying the API key
+    fn auth_header(&self) -> Option<(String, String)> {
+        let api_key = self.api_key.clone()?;
+        match self.kind() {
+            TonProviderKind::TonApi => Some(("Authorization".to_string(), format!("Bearer {}", api_key))),
+            _ => Some(("X-API-Key".to_string(), api_key)),
+        }
+    }
+}
+
+// Checks a provider list against the threshold it is used with, either of
+// them being the new or the stored value. Every read that needs agreement
+// must be served by at least `threshold` providers.
+pub fn check_threshold(providers: &[TonProvider], threshold: Option<u8>) -> Result<(), String> {
+    if threshold == Some(0) {
+        return Err("Threshold must be at least 1".to_string());
+    }
+
+    if providers.is_empty() && threshold.is_none() {
+        return Ok(());
+    }
+
+    let threshold = threshold.map(usize::from).unwrap_or(providers.len() / 2 + 1);
+
+    for endpoint in TonEndpoint::AGREED {
+        let serving = providers.iter().filter(|provider| provider.kind().serves(endpoint)).count();
+        if threshold > serving {
+            return Err(format!(
+                "Threshold {} is above the {} providers serving {:?}",
+                threshold, serving, endpoint
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Checks that the providers can prove deposits: each endpoint of a proof is
+// served by at least one of them
+pub fn check_block_proof(providers: &[TonProvider]) -> Result<(), String> {
+    for endpoint in TonEndpoint::BLOCK_PROOF {
+        if !providers.iter().any(|provider| provider.kind().serves(endpoint)) {
+            return Err(format!("Block proofs need a provider serving {:?}", endpoint));
+        }
+    }
+
+    Ok(())
+}
+
+// The off-chain proxy used in `TonOutcallMode::Proxy`
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct ProxyConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+}
+
+impl ProxyConfig {
+    // Without the API key, for the event log
+    pub fn redacted(&self) -> ProxyConfig {
+        ProxyConfig {
+            url: self.url.clone(),
+            api_key: None,
+        }
+    }
+}
+
+// Why a TON API call failed
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum TonApiError {
+    // The outcall failed or the provider answered with an unexpected status
+    Http(String),
+    // The provider throttled the request
+    RateLimited,
+    // The provider answered with an error, or the providers disagree
+    Provider { code: Option<u16>, message: String },
+    // The response could not be decoded
+    Decode(String),
+    // The provider doesn't know the requested data
+    NotFound(String),
+    // The canister can't pay for the outcall
+    OutOfCycles,
+}
+
+impl TonApiError {
+    // Whether the same call may succeed later. Rejections of the request
+    // itself (4xx) and unknown data won't change on a retry.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TonApiError::Provider { code: Some(code), .. } => *code >= 500,
+            TonApiError::NotFound(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for TonApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TonApiError::Http(message) => write!(f, "HTTP request failed: {}", message),
+            TonApiError::RateLimited => write!(f, "Rate limited by the TON provider"),
+            TonApiError::Provider { code: Some(code), message } => write!(f, "TON provider error {}: {}", code, message),
+            TonApiError::Provider { code: None, message } => write!(f, "TON provider error: {}", message),
+            TonApiError::Decode(message) => write!(f, "Failed to decode the TON provider response: {}", message),
+            TonApiError::NotFound(message) => write!(f, "Not found: {}", message),
+            TonApiError::OutOfCycles => write!(f, "Not enough cycles for the HTTP outcall"),
+        }
+    }
+}
+
+impl From<TonApiError> for String {
+    fn from(error: TonApiError) -> Self {
+        error.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TonTransactionMessage {
     pub body_hash : String,
     pub hash : String,
     pub value : String,
     pub destination : String,
+    // Empty for external messages
+    #[serde(default)]
+    pub source : String,
+    #[serde(default)]
+    pub msg_data : Option<TonMessageData>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonMessageData {
+    // "msg.dataRaw", "msg.dataText", ...
+    #[serde(rename = "@type")]
+    pub data_type : String,
+    // Base64 BOC of the message body (msg.dataRaw)
+    #[serde(default)]
+    pub body : Option<String>,
+    // Base64 of the comment text (msg.dataText)
+    #[serde(default)]
+    pub text : Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct TonTransactionId {
+    pub lt : String,
+    pub hash : String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TonTransaction {
+    pub transaction_id : TonTransactionId,
+    #[serde(default)]
+    pub utime : u64,
     pub in_msg : TonTransactionMessage,
     pub out_msgs : Vec<TonTransactionMessage>,
+    // Base64 BOC of the transaction, see `transaction::decode_transaction`
+    #[serde(default)]
+    pub data : String,
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TonWalletInfo {
     pub balance: String,
     pub wallet: bool,
     pub seqno: Option<u64>,
     pub account_state: String,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TonSendBocRetunHashResult {
     pub hash: String,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonRunGetMethodResult {
+    pub exit_code: i32,
+    // Stack entries as [type, value], e.g. ["num", "-0x1"]
+    pub stack: Vec<(String, serde_json::Value)>,
+}
+// A block, as in `tonNode.blockIdExt`
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct TonBlockId {
+    pub workchain: i32,
+    // Shard prefix as a signed decimal, "-9223372036854775808" for the masterchain
+    pub shard: String,
+    pub seqno: u32,
+    // Base64 hashes of the block root cell and of the block file
+    pub root_hash: String,
+    pub file_hash: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonMasterchainInfo {
+    pub last: TonBlockId,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonBlockSignature {
+    // Base64 short id of the validator, the hash of its public key
+    pub node_id_short: String,
+    pub signature: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonBlockSignatures {
+    pub id: TonBlockId,
+    pub signatures: Vec<TonBlockSignature>,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TonBlockData {
+    // Base64 BOC of the whole block
+    pub data: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TonResponse<T> {
     pub ok: bool,
     pub code: Option<u16>,
@@ -40,150 +307,915 @@ pub struct TonResponse<T> {
     pub result: Option<T>
 }
 
-pub async fn send_boc_to_ton(boc: String) -> Result<TonResponse<TonSendBocRetunHashResult>, String> {
+// Endpoints of the toncenter v2 API, each with its own response transform.
+// The discriminant and the provider kind are passed to `http_transform` as
+// the transform context.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TonEndpoint {
+    GetTransactions = 1,
+    GetWalletInformation = 2,
+    SendBocReturnHash = 3,
+    RunGetMethod = 4,
+    GetMasterchainInfo = 5,
+    GetMasterchainBlockSignatures = 6,
+    LookupBlock = 7,
+    GetBlock = 8,
+}
+
+impl TonEndpoint {
+    // Endpoints read through `read` or `read_transactions`, which need
+    // `threshold` providers to agree
+    const AGREED: [TonEndpoint; 3] = [Self::GetTransactions, Self::GetWalletInformation, Self::RunGetMethod];
+    // Endpoints the light client proves deposits with
+    const BLOCK_PROOF: [TonEndpoint; 3] = [Self::GetMasterchainBlockSignatures, Self::LookupBlock, Self::GetBlock];
+
+    // Upper bound of the response size, headers included. Lists of raw
+    // transactions are large: a highload payout carries up to 254 messages.
+    fn max_response_bytes(self) -> u64 {
+        match self {
+            Self::GetTransactions => 1_000_000,
+            Self::GetWalletInformation => 4_096,
+            Self::SendBocReturnHash => 2_048,
+            Self::RunGetMethod => 8_192,
+            Self::GetMasterchainInfo => 4_096,
+            Self::GetMasterchainBlockSignatures => 65_536,
+            Self::LookupBlock => 4_096,
+            // The most an outcall may return, key blocks carry the whole config
+            Self::GetBlock => 2_000_000,
+        }
+    }
+
+    // A context of a single byte predates the provider kinds and is
+    // toncenter v2
+    fn from_context(context: &[u8]) -> Option<(Self, TonProviderKind)> {
+        let (endpoint, kind) = match context {
+            [endpoint] => (*endpoint, TonProviderKind::ToncenterV2),
+            [endpoint, kind] => (*endpoint, TonProviderKind::from_context(*kind)?),
+            _ => return None,
+        };
 
-    // TON Center API endpoint for sendBoc
-    let url = format!("{}/sendBocReturnHash", TON_RPC_URL);
+        let endpoint = match endpoint {
+            1 => Self::GetTransactions,
+            2 => Self::GetWalletInformation,
+            3 => Self::SendBocReturnHash,
+            4 => Self::RunGetMethod,
+            5 => Self::GetMasterchainInfo,
+            6 => Self::GetMasterchainBlockSignatures,
+            7 => Self::LookupBlock,
+            8 => Self::GetBlock,
+            _ => return None,
+        };
+
+        Some((endpoint, kind))
+    }
 
-    let (idem_key,) = raw_rand().await.unwrap();
+    // Re-encodes a provider response as the minimal structure the minter
+    // reads, so that every replica ends up with the same bytes regardless of
+    // request ids, timestamps, extra fields or field order in the response
+    fn canonical_body(self, kind: TonProviderKind, status: u16, body: &[u8]) -> Vec<u8> {
+        let body = match kind {
+            TonProviderKind::ToncenterV2 | TonProviderKind::SelfHosted => body.to_vec(),
+            TonProviderKind::ToncenterV3 | TonProviderKind::TonApi => v2_envelope(self, kind, status, body),
+        };
+
+        match self {
+            Self::GetTransactions => canonical::<Vec<TonTransaction>>(&body),
+            Self::GetWalletInformation => canonical::<TonWalletInfo>(&body),
+            Self::SendBocReturnHash => canonical::<TonSendBocRetunHashResult>(&body),
+            Self::RunGetMethod => canonical::<TonRunGetMethodResult>(&body),
+            Self::GetMasterchainInfo => canonical::<TonMasterchainInfo>(&body),
+            Self::GetMasterchainBlockSignatures => canonical::<TonBlockSignatures>(&body),
+            Self::LookupBlock => canonical::<TonBlockId>(&body),
+            Self::GetBlock => canonical::<TonBlockData>(&body),
+        }
+    }
+}
 
-    // Prepare the HTTP request
-    let request = json!({
-        "boc": boc,
+fn canonical<T: DeserializeOwned + Serialize>(body: &[u8]) -> Vec<u8> {
+    let response = serde_json::from_slice::<TonResponse<T>>(body).unwrap_or_else(|_| TonResponse {
+        ok: false,
+        code: None,
+        error: Some("Malformed provider response".to_string()),
+        message: None,
+        result: None,
     });
 
-    let proxy_request = ProxyRequest{ destination_url: url.to_string(), method: crate::types::ProxyMethod::POST, headers: vec![("X-API-Key".to_string(), TON_API_KEY.to_string()), ("Content-Type".to_string(), "application/json".to_string()) ], body: Some(request), idempotency_key: hex::encode(idem_key) };
+    serde_json::to_vec(&response).unwrap_or_default()
+}
 
-    let json_bytes = serde_json::to_string(&proxy_request).map_err(|e| e.to_string())?;
+// Wraps a toncenter v3 or tonapi response in the toncenter v2 envelope, with
+// its result translated to the v2 format. These APIs report errors through
+// the HTTP status only.
+fn v2_envelope(endpoint: TonEndpoint, kind: TonProviderKind, status: u16, body: &[u8]) -> Vec<u8> {
+    let value = serde_json::from_slice::<Value>(body).unwrap_or(Value::Null);
 
-    let header = vec![
-        HttpHeader{name: "X-API-Key".to_string(), value: PROXY_API_KEY.to_string()}, 
-        HttpHeader{name: "Content-Type".to_string(), value: "application/json".to_string()}
-    ];
+    let response = if !(200..300).contains(&status) {
+        let message = value
+            .get("error")
+            .or(value.get("detail"))
+            .and_then(|message| message.as_str())
+            .map(str::to_string)
+            .unwrap_or(format!("Status {}", status));
+        json!({ "ok": false, "code": status, "error": message })
+    } else {
+        match v2_result(endpoint, kind, &value) {
+            Some(result) => json!({ "ok": true, "result": result }),
+            None => json!({ "ok": false, "error": "Malformed provider response" }),
+        }
+    };
+
+    response.to_string().into_bytes()
+}
 
-    let transform_context = TransformContext::from_name("http_transform".to_string(), vec![]);
+// The result a toncenter v2 provider would have returned for `value`
+fn v2_result(endpoint: TonEndpoint, kind: TonProviderKind, value: &Value) -> Option<Value> {
+    match (endpoint, kind) {
+        (TonEndpoint::GetTransactions, TonProviderKind::TonApi) => {
+            let txs = value
+                .get("transactions")?
+                .as_array()?
+                .iter()
+                .map(|tx| {
+                    Some(TonTransaction {
+                        transaction_id: TonTransactionId {
+                            lt: tx.get("lt")?.as_u64()?.to_string(),
+                            hash: hex_to_base64(tx.get("hash")?.as_str()?)?,
+                        },
+                        utime: tx.get("utime")?.as_u64()?,
+                        // Rebuilt from the BOC by `decode_transaction`
+                        in_msg: TonTransactionMessage::default(),
+                        out_msgs: vec![],
+                        data: hex_to_base64(tx.get("raw")?.as_str()?)?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            serde_json::to_value(txs).ok()
+        }
+        (TonEndpoint::GetWalletInformation, TonProviderKind::ToncenterV3) => {
+            let account_state = match value.get("status")?.as_str()? {
+                "uninit" | "nonexist" => "uninitialized",
+                status => status,
+            };
+            Some(json!({
+                "balance": value.get("balance")?,
+                "wallet": value.get("wallet_type").is_some_and(|wallet_type| !wallet_type.is_null()),
+                "seqno": value.get("seqno"),
+                "account_state": account_state,
+            }))
+        }
+        // The hash is computed from the message, see `send_boc_to_ton`
+        (TonEndpoint::SendBocReturnHash, _) => Some(json!({ "hash": "" })),
+        (TonEndpoint::RunGetMethod, _) => Some(json!({
+            "exit_code": value.get("exit_code")?,
+            "stack": v2_stack(kind, value.get("stack")?)?,
+        })),
+        (TonEndpoint::GetMasterchainInfo, TonProviderKind::ToncenterV3) => Some(json!({ "last": v2_block(kind, value.get("last")?)? })),
+        (TonEndpoint::GetMasterchainInfo, TonProviderKind::TonApi) => Some(json!({ "last": v2_block(kind, value)? })),
+        (TonEndpoint::LookupBlock, TonProviderKind::ToncenterV3) => v2_block(kind, value.get("blocks")?.get(0)?),
+        (TonEndpoint::GetBlock, TonProviderKind::TonApi) => Some(json!({ "data": hex_to_base64(value.get("data")?.as_str()?)? })),
+        _ => None,
+    }
+}
 
-    let http_arg = CanisterHttpRequestArgument{ url: PROXY_URL.to_string(), max_response_bytes: Some(1_000_000), method: ic_cdk::api::management_canister::http_request::HttpMethod::POST, headers: header, body: Some(json_bytes.as_bytes().to_vec()), transform: Some(transform_context) };
+// Stack entries as [type, value] like toncenter v2 returns them, from the
+// `{ type, value }` entries of toncenter v3 and the `{ type, <type>: value }`
+// entries of tonapi. Cells and slices become ["cell", { "bytes": <base64 BOC> }].
+fn v2_stack(kind: TonProviderKind, stack: &Value) -> Option<Value> {
+    let entries = stack
+        .as_array()?
+        .iter()
+        .map(|entry| {
+            let entry_type = entry.get("type")?.as_str()?;
+            let field = match kind {
+                TonProviderKind::TonApi => entry_type,
+                _ => "value",
+            };
+            match entry_type {
+                "num" => Some(json!(["num", hex_num(entry.get(field)?.as_str()?)?])),
+                "cell" | "slice" => {
+                    let boc = entry.get(field)?.as_str()?;
+                    let boc = match kind {
+                        TonProviderKind::TonApi => hex_to_base64(boc)?,
+                        _ => boc.to_string(),
+                    };
+                    Some(json!(["cell", { "bytes": boc }]))
+                }
+                _ => Some(json!([entry_type, entry])),
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
 
-    // Make the HTTP outcall
-    let (response,) = http_request::http_request(http_arg, 20_000_000_000)
-    .await
-    .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    Some(Value::from(entries))
+}
+
+// A block in the `tonNode.blockIdExt` form of toncenter v2: a signed decimal
+// shard and base64 hashes, where toncenter v3 and tonapi use a hex shard and
+// tonapi hex hashes
+fn v2_block(kind: TonProviderKind, block: &Value) -> Option<Value> {
+    let shard = u64::from_str_radix(block.get("shard")?.as_str()?, 16).ok()? as i64;
+
+    let (workchain, root_hash, file_hash) = match kind {
+        TonProviderKind::TonApi => (
+            block.get("workchain_id")?,
+            hex_to_base64(block.get("root_hash")?.as_str()?)?,
+            hex_to_base64(block.get("file_hash")?.as_str()?)?,
+        ),
+        _ => (
+            block.get("workchain")?,
+            block.get("root_hash")?.as_str()?.to_string(),
+            block.get("file_hash")?.as_str()?.to_string(),
+        ),
+    };
+
+    Some(json!({
+        "workchain": workchain,
+        "shard": shard.to_string(),
+        "seqno": block.get("seqno")?,
+        "root_hash": root_hash,
+        "file_hash": file_hash,
+    }))
+}
 
-    if response.status != Nat::from(200u32) {
-        return Err(format!("HTTP request failed: {:?}", response.status));
+fn hex_to_base64(value: &str) -> Option<String> {
+    hex::decode(value).ok().map(|bytes| BASE64_STANDARD.encode(bytes))
+}
+
+// A stack number in the hex form of toncenter v2, tonapi may return decimals
+fn hex_num(value: &str) -> Option<String> {
+    if value.starts_with("0x") || value.starts_with("-0x") {
+        return Some(value.to_string());
     }
 
-    // Parse the response
-    let response_body = String::from_utf8(response.body)
-        .map_err(|e| format!("Failed to parse response body: {:?}", e))?;
+    let number = value.parse::<i128>().ok()?;
+    Some(match number < 0 {
+        true => format!("-0x{:x}", number.unsigned_abs()),
+        false => format!("0x{:x}", number),
+    })
+}
 
-    let ton_response : TonResponse<TonSendBocRetunHashResult> = serde_json::from_str(&response_body).unwrap();
-    Ok(ton_response)
+// Argument of a get-method
+pub enum StackArg {
+    Num(u64),
+    Address(TonAddress),
 }
 
+impl StackArg {
+    // Base64 BOC of a slice holding the address
+    fn address_boc(address: &TonAddress) -> Result<String, TonApiError> {
+        let slice = CellBuilder::new()
+            .store_address(address)
+            .and_then(|builder| builder.build())
+            .map_err(|e| TonApiError::Decode(e.to_string()))?;
 
-#[query]
-async fn http_transform(arg : TransformArgs) -> HttpResponse {
-    let res = HttpResponse { status: arg.response.status, headers: vec![], body: arg.response.body };
-    res
+        let boc = BagOfCells::from_root(slice)
+            .serialize(false)
+            .map_err(|e| TonApiError::Decode(e.to_string()))?;
+
+        Ok(BASE64_STANDARD.encode(boc))
+    }
+}
+
+// What is asked of the providers
+enum TonQuery {
+    // Up to `limit` transactions from `from` (included) back to `to_lt`
+    // (excluded), see `get_ton_transactions_page`
+    Transactions { address: String, from: Option<TonTransactionId>, to_lt: Option<u64>, limit: u32 },
+    WalletInformation { address: String },
+    SendBoc { boc: String },
+    RunGetMethod { address: String, method: String, stack: Vec<StackArg> },
+    MasterchainInfo,
+    MasterchainBlockSignatures { seqno: u32 },
+    // The first masterchain block generated at or after `utime`
+    LookupMasterchainBlock { utime: u64 },
+    Block { block: TonBlockId },
+}
+
+impl TonQuery {
+    fn endpoint(&self) -> TonEndpoint {
+        match self {
+            Self::Transactions { .. } => TonEndpoint::GetTransactions,
+            Self::WalletInformation { .. } => TonEndpoint::GetWalletInformation,
+            Self::SendBoc { .. } => TonEndpoint::SendBocReturnHash,
+            Self::RunGetMethod { .. } => TonEndpoint::RunGetMethod,
+            Self::MasterchainInfo => TonEndpoint::GetMasterchainInfo,
+            Self::MasterchainBlockSignatures { .. } => TonEndpoint::GetMasterchainBlockSignatures,
+            Self::LookupMasterchainBlock { .. } => TonEndpoint::LookupBlock,
+            Self::Block { .. } => TonEndpoint::GetBlock,
+        }
+    }
+
+    // Method, path relative to the provider url and body of the query in the
+    // API of `kind`
+    fn http_parts(&self, kind: TonProviderKind) -> Result<(ProxyMethod, String, Option<Value>), TonApiError> {
+        let parts = match kind {
+            TonProviderKind::ToncenterV2 | TonProviderKind::SelfHosted => self.toncenter_v2(),
+            TonProviderKind::ToncenterV3 => self.toncenter_v3(),
+            TonProviderKind::TonApi => self.tonapi(),
+        };
+
+        parts?.ok_or(TonApiError::Http(format!("{:?} providers don't serve {:?}", kind, self.endpoint())))
+    }
+
+    fn toncenter_v2(&self) -> Result<Option<(ProxyMethod, String, Option<Value>)>, TonApiError> {
+        let parts = match self {
+            Self::Transactions { address, from, to_lt, limit } => {
+                let mut path = format!("getTransactions?address={}&limit={}&archival=true", url_encode(address), limit);
+                if let Some(from) = from {
+                    path.push_str(&format!("&lt={}&hash={}", from.lt, url_encode(&from.hash)));
+                }
+                if let Some(to_lt) = to_lt {
+                    path.push_str(&format!("&to_lt={}", to_lt));
+                }
+                (ProxyMethod::GET, path, None)
+            }
+            Self::WalletInformation { address } => {
+                (ProxyMethod::GET, format!("getWalletInformation?address={}", url_encode(address)), None)
+            }
+            Self::SendBoc { boc } => (ProxyMethod::POST, "sendBocReturnHash".to_string(), Some(json!({ "boc": boc }))),
+            Self::RunGetMethod { address, method, stack } => {
+                let stack = stack
+                    .iter()
+                    .map(|arg| match arg {
+                        StackArg::Num(num) => Ok(("num".to_string(), num.to_string())),
+                        StackArg::Address(address) => Ok(("tvm.Slice".to_string(), StackArg::address_boc(address)?)),
+                    })
+                    .collect::<Result<Vec<_>, TonApiError>>()?;
+                let stack = serde_json::to_string(&stack).map_err(|e| TonApiError::Decode(e.to_string()))?;
+                let path = format!(
+                    "runGetMethod?address={}&method={}&stack={}",
+                    url_encode(address),
+                    url_encode(method),
+                    url_encode(&stack)
+                );
+                (ProxyMethod::GET, path, None)
+            }
+            Self::MasterchainInfo => (ProxyMethod::GET, "getMasterchainInfo".to_string(), None),
+            Self::MasterchainBlockSignatures { seqno } => {
+                (ProxyMethod::GET, format!("getMasterchainBlockSignatures?seqno={}", seqno), None)
+            }
+            Self::LookupMasterchainBlock { utime } => {
+                let path = format!("lookupBlock?workchain=-1&shard={}&unixtime={}", i64::MIN, utime);
+                (ProxyMethod::GET, path, None)
+            }
+            Self::Block { .. } => return Ok(None),
+        };
+
+        Ok(Some(parts))
+    }
+
+    fn toncenter_v3(&self) -> Result<Option<(ProxyMethod, String, Option<Value>)>, TonApiError> {
+        let parts = match self {
+            Self::WalletInformation { address } => {
+                (ProxyMethod::GET, format!("walletInformation?address={}&use_v2=false", url_encode(address)), None)
+            }
+            Self::SendBoc { boc } => (ProxyMethod::POST, "message".to_string(), Some(json!({ "boc": boc }))),
+            Self::RunGetMethod { address, method, stack } => {
+                let stack = stack
+                    .iter()
+                    .map(|arg| match arg {
+                        StackArg::Num(num) => Ok(json!({ "type": "num", "value": format!("0x{:x}", num) })),
+                        StackArg::Address(address) => Ok(json!({ "type": "slice", "value": StackArg::address_boc(address)? })),
+                    })
+                    .collect::<Result<Vec<_>, TonApiError>>()?;
+                let body = json!({ "address": address, "method": method, "stack": stack });
+                (ProxyMethod::POST, "runGetMethod".to_string(), Some(body))
+            }
+            Self::MasterchainInfo => (ProxyMethod::GET, "masterchainInfo".to_string(), None),
+            Self::LookupMasterchainBlock { utime } => {
+                let path = format!("blocks?workchain=-1&start_utime={}&sort=asc&limit=1", utime);
+                (ProxyMethod::GET, path, None)
+            }
+            Self::Transactions { .. } | Self::MasterchainBlockSignatures { .. } | Self::Block { .. } => return Ok(None),
+        };
+
+        Ok(Some(parts))
+    }
+
+    fn tonapi(&self) -> Result<Option<(ProxyMethod, String, Option<Value>)>, TonApiError> {
+        let parts = match self {
+            Self::Transactions { address, from, to_lt, limit } => {
+                let mut path = format!("v2/blockchain/accounts/{}/transactions?limit={}", url_encode(address), limit);
+                // `before_lt` is excluded
+                if let Some(from) = from {
+                    let lt = from
+                        .lt
+                        .parse::<u64>()
+                        .map_err(|e| TonApiError::Decode(format!("Invalid lt {}: {}", from.lt, e)))?;
+                    path.push_str(&format!("&before_lt={}", lt + 1));
+                }
+                if let Some(to_lt) = to_lt {
+                    path.push_str(&format!("&after_lt={}", to_lt));
+                }
+                (ProxyMethod::GET, path, None)
+            }
+            Self::SendBoc { boc } => (ProxyMethod::POST, "v2/blockchain/message".to_string(), Some(json!({ "boc": boc }))),
+            Self::RunGetMethod { address, method, stack } => {
+                let mut path = format!("v2/blockchain/accounts/{}/methods/{}", url_encode(address), url_encode(method));
+                for (index, arg) in stack.iter().enumerate() {
+                    let arg = match arg {
+                        StackArg::Num(num) => num.to_string(),
+                        StackArg::Address(address) => address.to_base64_url(),
+                    };
+                    path.push_str(if index == 0 { "?" } else { "&" });
+                    path.push_str(&format!("args={}", url_encode(&arg)));
+                }
+                (ProxyMethod::GET, path, None)
+            }
+            Self::MasterchainInfo => (ProxyMethod::GET, "v2/blockchain/masterchain-head".to_string(), None),
+            // (workchain,shard,seqno,root_hash,file_hash) with a hex shard and hashes
+            Self::Block { block } => {
+                let hash = |hash: &str| {
+                    BASE64_STANDARD
+                        .decode(hash)
+                        .map(hex::encode)
+                        .map_err(|e| TonApiError::Decode(format!("Invalid block hash {}: {}", hash, e)))
+                };
+                let shard = block
+                    .shard
+                    .parse::<i64>()
+                    .map_err(|e| TonApiError::Decode(format!("Invalid shard {}: {}", block.shard, e)))?;
+                let id = format!(
+                    "({},{:x},{},{},{})",
+                    block.workchain,
+                    shard as u64,
+                    block.seqno,
+                    hash(&block.root_hash)?,
+                    hash(&block.file_hash)?
+                );
+                (ProxyMethod::GET, format!("v2/liteserver/get_block/{}", url_encode(&id)), None)
+            }
+            Self::WalletInformation { .. } | Self::MasterchainBlockSignatures { .. } | Self::LookupMasterchainBlock { .. } => return Ok(None),
+        };
+
+        Ok(Some(parts))
+    }
 }
 
-pub async fn get_ton_transactions(address: String) -> Result<TonResponse<Vec<TonTransaction>>, String> {
-    // TON Center API endpoint for address balance
-    let url = format!("{}/getTransactions?address={}", TON_RPC_URL, address);
+// A request to the providers
+struct TonRequest {
+    // Operation the cycles of the outcall are accounted to
+    operation: Operation,
+    query: TonQuery,
+}
 
-    let (idem_key,) = raw_rand().await.unwrap();
+// Wraps a request to one provider in an envelope for the proxy, which
+// forwards it once per idempotency key
+async fn proxy_request(provider: &TonProvider, request: &TonRequest) -> Result<CanisterHttpRequestArgument, TonApiError> {
+    let proxy = read_config(|config| config.proxy.clone())
+        .ok_or(TonApiError::Http("No proxy configured".to_string()))?;
+
+    let (method, path, body) = request.query.http_parts(provider.kind())?;
+
+    let url = format!("{}/{}", provider.url.trim_end_matches('/'), path);
+
+    let (idem_key,) = raw_rand()
+        .await
+        .map_err(|e| TonApiError::Http(format!("Failed to get randomness: {:?}", e)))?;
+
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    headers.extend(provider.auth_header());
 
     let proxy_request = ProxyRequest {
         destination_url: url,
-        method: crate::types::ProxyMethod::GET,
-        headers: vec![("X-API-Key".to_string(), TON_API_KEY.to_string()), ("Content-Type".to_string(), "application/json".to_string()) ],
-        body: None,
+        method,
+        headers,
+        body,
         idempotency_key: hex::encode(idem_key)
     };
 
-    let json_bytes = serde_json::to_string(&proxy_request).map_err(|e| e.to_string())?;
+    let json_bytes = serde_json::to_string(&proxy_request).map_err(|e| TonApiError::Decode(e.to_string()))?;
 
     let header = vec![
-        HttpHeader{name: "X-API-Key".to_string(), value: PROXY_API_KEY.to_string()},
+        HttpHeader{name: "X-API-Key".to_string(), value: proxy.api_key.unwrap_or_default()},
         HttpHeader{name: "Content-Type".to_string(), value: "application/json".to_string()}
     ];
 
-    let transform_context = TransformContext::from_name("http_transform".to_string(), vec![]);
-
-    let http_arg = CanisterHttpRequestArgument {
-        url: PROXY_URL.to_string(),
-        max_response_bytes: Some(1_000_000),
-        method: ic_cdk::api::management_canister::http_request::HttpMethod::POST,
+    Ok(CanisterHttpRequestArgument {
+        url: proxy.url,
+        max_response_bytes: Some(request.query.endpoint().max_response_bytes()),
+        method: HttpMethod::POST,
         headers: header,
         body: Some(json_bytes.as_bytes().to_vec()),
-        transform: Some(transform_context),
+        transform: Some(transform_context(provider, request)),
+    })
+}
+
+// A request sent by every replica straight to the provider. Reads are GETs or
+// side-effect free POSTs, sending a message is safe to repeat: the wallet
+// contract accepts a signed message at most once.
+fn direct_request(provider: &TonProvider, request: &TonRequest) -> Result<CanisterHttpRequestArgument, TonApiError> {
+    let (method, path, body) = request.query.http_parts(provider.kind())?;
+
+    let url = format!("{}/{}", provider.url.trim_end_matches('/'), path);
+
+    let mut headers = vec![];
+    if let Some((name, value)) = provider.auth_header() {
+        headers.push(HttpHeader{name, value});
+    }
+
+    let (method, body) = match method {
+        ProxyMethod::GET => (HttpMethod::GET, None),
+        ProxyMethod::POST => {
+            headers.push(HttpHeader{name: "Content-Type".to_string(), value: "application/json".to_string()});
+            (HttpMethod::POST, body.map(|body| body.to_string().into_bytes()))
+        }
+    };
+
+    Ok(CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(request.query.endpoint().max_response_bytes()),
+        method,
+        headers,
+        body,
+        transform: Some(transform_context(provider, request)),
+    })
+}
+
+fn transform_context(provider: &TonProvider, request: &TonRequest) -> TransformContext {
+    TransformContext::from_name(
+        "http_transform".to_string(),
+        vec![request.query.endpoint() as u8, provider.kind().context()],
+    )
+}
+
+// Sends a request to one provider and returns the HTTP status with the body
+async fn http_call(provider: &TonProvider, request: &TonRequest) -> Result<(u16, Vec<u8>), TonApiError> {
+    let http_arg = match read_config(|config| config.ton_outcall_mode()) {
+        TonOutcallMode::Proxy => proxy_request(provider, request).await?,
+        TonOutcallMode::Direct => direct_request(provider, request)?,
     };
 
+    let cycles = http_request_cost(&http_arg, read_config(|config| config.subnet_size()));
+
+    if ic_cdk::api::canister_balance128() < cycles {
+        return Err(TonApiError::OutOfCycles);
+    }
+
     // Make the HTTP outcall
-    let (response,) = http_request::http_request(http_arg, 20_000_000_000)
-        .await
-        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    let result = http_request::http_request(http_arg, cycles).await;
+
+    record_outcall(request.operation, cycles.saturating_sub(msg_cycles_refunded128()));
+
+    let (response,) = result
+        .map_err(|(code, message)| match code {
+            // An outcall that can't be enqueued because the canister can't
+            // attach its cycles above the freezing threshold is rejected as
+            // transient, like a provider timeout. A canister short of its
+            // reserve is taken as out of cycles.
+            RejectionCode::SysTransient if ic_cdk::api::canister_balance128() < cycles + MINTER_CYCLES_RESERVE => {
+                TonApiError::OutOfCycles
+            }
+            code => TonApiError::Http(format!("{:?}: {}", code, message)),
+        })?;
+
+    let status = u16::try_from(&response.status.0)
+        .map_err(|_| TonApiError::Http(format!("Invalid status {}", response.status)))?;
+
+    Ok((status, response.body))
+}
+
+// Turns a toncenter response into its result or the error it reports
+fn into_result<T>(response: TonResponse<T>) -> Result<T, TonApiError> {
+    match response {
+        TonResponse { ok: true, result: Some(result), .. } => Ok(result),
+        TonResponse { ok: true, result: None, .. } => Err(TonApiError::Decode("Response has no result".to_string())),
+        TonResponse { code, error, message, .. } => {
+            let message = error.or(message).unwrap_or("Unknown error".to_string());
+            match code {
+                Some(404) => Err(TonApiError::NotFound(message)),
+                Some(429) => Err(TonApiError::RateLimited),
+                code => Err(TonApiError::Provider { code, message }),
+            }
+        }
+    }
+}
 
-    if response.status != Nat::from(200u32) {
-        return Err(format!("HTTP request failed: {:?}", response.status));
+async fn call<T: DeserializeOwned>(provider: &TonProvider, request: &TonRequest) -> Result<T, TonApiError> {
+    let (status, body) = http_call(provider, request).await?;
+
+    if status == 429 {
+        return Err(TonApiError::RateLimited);
     }
 
-    // Parse the response
-    let response_body = String::from_utf8(response.body)
-        .map_err(|e| format!("Failed to parse response body: {:?}", e))?;
+    let response = serde_json::from_slice::<TonResponse<T>>(&body)
+        .map_err(|e| TonApiError::Decode(format!("Response of {}: {}", provider.url, e)))?;
 
+    // A failure without a toncenter error code didn't come from the API,
+    // e.g. a gateway error in front of it
+    if !(200..300).contains(&status) && response.code.is_none() {
+        return Err(TonApiError::Http(format!("{} answered with status {}", provider.url, status)));
+    }
 
-    let ton_response : TonResponse<Vec<TonTransaction>> = serde_json::from_str(&response_body).unwrap();
-    Ok(ton_response)
+    into_result(response)
 }
 
-pub async fn get_ton_wallet_info(address: String) -> Result<TonResponse<TonWalletInfo>, String> {
-    // TON Center API endpoint for address state
-    let url = format!("{}/getWalletInformation?address={}",TON_RPC_URL, address);
+// Calls every configured provider that serves the request with the same
+// request
+async fn call_all<T: DeserializeOwned>(request: &TonRequest) -> (Vec<Result<T, TonApiError>>, usize) {
+    let (providers, threshold) = read_config(|config| (config.ton_providers(), config.ton_providers_threshold()));
 
-    let (idem_key,) = raw_rand().await.unwrap();
+    let endpoint = request.query.endpoint();
 
-        let proxy_request = ProxyRequest {
-        destination_url: url,
-        method: crate::types::ProxyMethod::GET,
-        headers: vec![("X-API-Key".to_string(), TON_API_KEY.to_string()), ("Content-Type".to_string(), "application/json".to_string())],
-        body: None,
-        idempotency_key: hex::encode(idem_key)
-    };
+    let responses = join_all(
+        providers
+            .iter()
+            .filter(|provider| provider.kind().serves(endpoint))
+            .map(|provider| call::<T>(provider, request)),
+    )
+    .await;
 
-    let json_bytes = serde_json::to_string(&proxy_request).map_err(|e| e.to_string())?;
+    (responses, threshold)
+}
 
-    let header = vec![
-        HttpHeader{name: "X-API-Key".to_string(), value: PROXY_API_KEY.to_string()},
-        HttpHeader{name: "Content-Type".to_string(), value: "application/json".to_string()}
-    ];
+// The successful result of every provider, or the first error when no
+// provider succeeded
+fn successful<T>(responses: Vec<Result<T, TonApiError>>) -> Result<Vec<T>, TonApiError> {
+    let mut results = vec![];
+    let mut failure = None;
 
-    let transform_context = TransformContext::from_name("http_transform".to_string(), vec![]);
+    for response in responses {
+        match response {
+            Ok(result) => results.push(result),
+            Err(err) => {
+                failure.get_or_insert(err);
+            }
+        }
+    }
 
-    let http_arg = CanisterHttpRequestArgument {
-        url: PROXY_URL.to_string(),
-        max_response_bytes: Some(1_000_000),
-        method: ic_cdk::api::management_canister::http_request::HttpMethod::POST,
-        headers: header,
-        body: Some(json_bytes.as_bytes().to_vec()),
-        transform: Some(transform_context),
+    if results.is_empty() {
+        return Err(failure.unwrap_or(TonApiError::Provider {
+            code: None,
+            message: "No TON provider configured".to_string(),
+        }));
+    }
+
+    Ok(results)
+}
+
+// Reads a value and only accepts it if at least `threshold` providers
+// returned exactly the same one
+async fn read<T: DeserializeOwned + PartialEq>(request: TonRequest) -> Result<T, TonApiError> {
+    let (responses, threshold) = call_all::<T>(&request).await;
+
+    let results = successful(responses)?;
+
+    let total = results.len();
+
+    let mut groups: Vec<(T, usize)> = vec![];
+    for result in results {
+        match groups.iter_mut().find(|(value, _)| *value == result) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((result, 1)),
+        }
+    }
+
+    match groups.into_iter().find(|(_, count)| *count >= threshold) {
+        Some((result, _)) => Ok(result),
+        None => Err(TonApiError::Provider {
+            code: None,
+            message: format!(
+                "TON providers disagree: {} successful responses, {} matching ones required",
+                total, threshold
+            ),
+        }),
+    }
+}
+
+// Reads transactions and only keeps those returned by at least `threshold`
+// providers, providers being at different heights is expected. A BOC only
+// proves the transaction id its own provider returned: a transaction counts
+// for a provider if its BOC hashes to the id, and is kept once enough
+// providers, and never fewer than two when two serve transactions, returned
+// that id. The agreed transactions are ordered newest first.
+async fn read_transactions(request: TonRequest) -> Result<Vec<TonTransaction>, TonApiError> {
+    let (responses, threshold) = call_all::<Vec<TonTransaction>>(&request).await;
+
+    let required = threshold.max(responses.len().min(2));
+
+    let lists = successful(responses)?;
+
+    if lists.len() < required {
+        return Err(TonApiError::Provider {
+            code: None,
+            message: format!("Only {} TON providers responded, {} required", lists.len(), required),
+        });
+    }
+
+    let mut counts: BTreeMap<(u64, String), (TonTransaction, usize)> = BTreeMap::new();
+    for list in lists {
+        for tx in list {
+            match boc_hash(&tx.data) {
+                Ok(hash) if hash == tx.transaction_id.hash => {}
+                _ => {
+                    ic_cdk::println!("Ignoring transaction {} whose BOC doesn't match its hash", tx.transaction_id.hash);
+                    continue;
+                }
+            }
+
+            let lt = tx.transaction_id.lt.parse::<u64>().unwrap_or_default();
+            let entry = counts.entry((lt, tx.transaction_id.hash.clone())).or_insert((tx, 0));
+            entry.1 += 1;
+        }
+    }
+
+    let txs = counts
+        .into_values()
+        .rev()
+        .filter(|(_, count)| *count >= required)
+        .map(|(tx, _)| tx)
+        .collect();
+
+    Ok(txs)
+}
+
+// Broadcasts a message through every provider, one accepting it is enough.
+// A `Provider` error means the message was rejected and can't land. The
+// returned hash is the one of the message cell, which not every API returns.
+pub async fn send_boc_to_ton(boc: String, operation: Operation) -> Result<TonSendBocRetunHashResult, TonApiError> {
+    let hash = BagOfCells::parse_base64(&boc)
+        .and_then(|boc| boc.single_root())
+        .map(|root| BASE64_STANDARD.encode(root.cell_hash()))
+        .map_err(|e| TonApiError::Decode(format!("Invalid message BOC: {}", e)))?;
+
+    let request = TonRequest {
+        operation,
+        query: TonQuery::SendBoc { boc },
     };
 
-    // Make the HTTP outcall
-    let (response,) = http_request::http_request(http_arg, 20_000_000_000)
-        .await
-        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+    let (responses, _) = call_all::<TonSendBocRetunHashResult>(&request).await;
+
+    match successful(responses) {
+        Ok(_) => Ok(TonSendBocRetunHashResult { hash }),
+        // Every replica sends the message, the replicas that were late may see
+        // it rejected because it already landed. A rejection doesn't prove the
+        // message was not delivered, callers must look it up on chain.
+        Err(TonApiError::Provider { message, .. }) if read_config(|config| config.ton_outcall_mode()) == TonOutcallMode::Direct => {
+            Err(TonApiError::Http(format!("Message may not have been delivered: {}", message)))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn run_get_method(address: String, method: &str, stack: Vec<StackArg>, operation: Operation) -> Result<TonRunGetMethodResult, TonApiError> {
+    read(TonRequest {
+        operation,
+        query: TonQuery::RunGetMethod { address, method: method.to_string(), stack },
+    })
+    .await
+}
+
+// Whether a highload wallet processed the message with the given query id,
+// through its `processed?(query_id, need_clean)` get-method
+pub async fn is_highload_query_processed(address: String, query_id: u64, operation: Operation) -> Result<bool, TonApiError> {
+    let result = run_get_method(
+        address,
+        "processed?",
+        vec![StackArg::Num(query_id), StackArg::Num(0)],
+        operation,
+    )
+    .await?;
+
+    if result.exit_code != 0 {
+        return Err(TonApiError::Provider {
+            code: None,
+            message: format!("Get method failed with exit code {}", result.exit_code),
+        });
+    }
 
-    if response.status != Nat::from(200u32) {
-        return Err(format!("HTTP request failed: {:?}", response.status));
+    // TVM booleans: -1 is true, 0 is false
+    match result.stack.first() {
+        Some((kind, value)) if kind == "num" => match value.as_str().map(parse_stack_num) {
+            Some(Ok(0)) => Ok(false),
+            Some(Ok(-1)) => Ok(true),
+            Some(Ok(value)) => Err(TonApiError::Decode(format!("Get method returned {} instead of a boolean", value))),
+            Some(Err(err)) => Err(err),
+            None => Err(TonApiError::Decode(format!("Get method returned {} instead of a number", value))),
+        },
+        Some((kind, _)) => Err(TonApiError::Decode(format!("Get method returned a {} instead of a number", kind))),
+        None => Err(TonApiError::Decode("Get method returned an empty stack".to_string())),
     }
+}
+
+// Parses a `num` stack entry, a hex integer with an optional sign, e.g. "-0x1"
+pub fn parse_stack_num(value: &str) -> Result<i128, TonApiError> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+
+    let digits = digits
+        .strip_prefix("0x")
+        .ok_or(TonApiError::Decode(format!("Stack number {} is not hex", value)))?;
+
+    let number = i128::from_str_radix(digits, 16)
+        .map_err(|e| TonApiError::Decode(format!("Invalid stack number {}: {}", value, e)))?;
+
+    Ok(if negative { -number } else { number })
+}
 
-    // Parse the response
-    let response_body = String::from_utf8(response.body)
-        .map_err(|e| format!("Failed to parse response body: {:?}", e))?;
 
-    let ton_response : TonResponse<TonWalletInfo> = serde_json::from_str(&response_body).unwrap();
-    Ok(ton_response)
-}
\ No newline at end of file
+// Percent-encodes a query parameter value
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[query]
+async fn http_transform(arg : TransformArgs) -> HttpResponse {
+    let status = u16::try_from(&arg.response.status.0).unwrap_or_default();
+
+    let body = match TonEndpoint::from_context(&arg.context) {
+        Some((endpoint, kind)) => endpoint.canonical_body(kind, status, &arg.response.body),
+        None => arg.response.body,
+    };
+
+    HttpResponse { status: arg.response.status, headers: vec![], body }
+}
+
+// Up to `limit` transactions of `address` newest first, starting at the
+// transaction `from` (included, the newest ones if unset) and going back to
+// the logical time `to_lt` (excluded). Every transaction is rebuilt from its
+// BOC, see `transaction::decode_transaction`.
+pub async fn get_ton_transactions_page(
+    address: String,
+    from: Option<TonTransactionId>,
+    to_lt: Option<u64>,
+    limit: u32,
+    operation: Operation,
+) -> Result<Vec<TonTransaction>, TonApiError> {
+    let account = address
+        .parse::<TonAddress>()
+        .map_err(|e| TonApiError::Decode(format!("Invalid address {}: {}", address, e)))?;
+
+    let txs = read_transactions(TonRequest {
+        operation,
+        query: TonQuery::Transactions { address, from, to_lt, limit },
+    })
+    .await?;
+
+    txs.iter()
+        .map(|tx| decode_transaction(tx, &account).map_err(TonApiError::Decode))
+        .collect()
+}
+
+pub async fn get_ton_wallet_info(address: String, operation: Operation) -> Result<TonWalletInfo, TonApiError> {
+    read(TonRequest {
+        operation,
+        query: TonQuery::WalletInformation { address },
+    })
+    .await
+}
+
+// The last masterchain block known to each provider that answered. Providers
+// are rarely at the same height, the caller verifies what it uses.
+pub async fn get_masterchain_info(operation: Operation) -> Result<Vec<TonMasterchainInfo>, TonApiError> {
+    let (responses, _) = call_all::<TonMasterchainInfo>(&TonRequest {
+        operation,
+        query: TonQuery::MasterchainInfo,
+    })
+    .await;
+
+    successful(responses)
+}
+
+// Validator signatures of the masterchain block `seqno` as seen by each
+// provider that answered. No agreement is required: signatures are checked
+// against the trusted validator set, see `lightclient::verify_signatures`.
+pub async fn get_masterchain_block_signatures(seqno: u32, operation: Operation) -> Result<Vec<TonBlockSignatures>, TonApiError> {
+    let (responses, _) = call_all::<TonBlockSignatures>(&TonRequest {
+        operation,
+        query: TonQuery::MasterchainBlockSignatures { seqno },
+    })
+    .await;
+
+    successful(responses)
+}
+
+// The first masterchain block generated at or after `utime` as seen by each
+// provider that answered. The caller checks the signatures of the block.
+pub async fn lookup_masterchain_block(utime: u64, operation: Operation) -> Result<Vec<TonBlockId>, TonApiError> {
+    let (responses, _) = call_all::<TonBlockId>(&TonRequest {
+        operation,
+        query: TonQuery::LookupMasterchainBlock { utime },
+    })
+    .await;
+
+    successful(responses)
+}
+
+// The BOC of `block` from each provider that answered. The caller checks it
+// against the block hashes, see `block::parse_block`.
+pub async fn get_block(block: TonBlockId, operation: Operation) -> Result<Vec<TonBlockData>, TonApiError> {
+    let (responses, _) = call_all::<TonBlockData>(&TonRequest {
+        operation,
+        query: TonQuery::Block { block },
+    })
+    .await;
+
+    successful(responses)
+}
@@ -0,0 +1,249 @@
+// ==========================
+// ckton_minter/src/transaction.rs
+// Decoding of raw TON transactions.
+// Providers return the BOC of every transaction next to their JSON view of
+// it. The minter rebuilds messages, values and addresses from the BOC and
+// checks its hash against the transaction id, so the JSON fields are never
+// trusted on their own.
+// ==========================
+
+use base64::prelude::*;
+use ic_ton_lib::{
+    cell::{ArcCell, BagOfCells, Cell, CellBuilder, CellParser},
+    TonAddress,
+};
+
+use crate::ton_api::{TonMessageData, TonTransaction, TonTransactionId, TonTransactionMessage};
+
+// transaction$0111
+const TRANSACTION_TAG: u8 = 0b0111;
+// Key length of the `out_msgs` dictionary
+const OUT_MSGS_KEY_BITS: usize = 15;
+
+// Base64 hash of the root cell of a BOC, the id of the transaction it holds
+pub fn boc_hash(data: &str) -> Result<String, String> {
+    let root = BagOfCells::parse_base64(data)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| format!("Invalid transaction BOC: {}", e))?;
+
+    Ok(BASE64_STANDARD.encode(root.cell_hash()))
+}
+
+// Rebuilds `tx` from its BOC. Fails if the BOC doesn't hash to the transaction
+// id or belongs to another account than `account`. The id itself is only
+// trusted because enough providers returned it, see `ton_api::read_transactions`.
+pub fn decode_transaction(tx: &TonTransaction, account: &TonAddress) -> Result<TonTransaction, String> {
+    let root = BagOfCells::parse_base64(&tx.data)
+        .and_then(|boc| boc.single_root())
+        .map_err(|e| format!("Invalid transaction BOC: {}", e))?;
+
+    let hash = BASE64_STANDARD.encode(root.cell_hash());
+    if hash != tx.transaction_id.hash {
+        return Err(format!("Transaction BOC hashes to {}, not {}", hash, tx.transaction_id.hash));
+    }
+
+    // transaction$0111 account_addr:bits256 lt:uint64 prev_trans_hash:bits256
+    // prev_trans_lt:uint64 now:uint32 outmsg_cnt:uint15 orig_status:AccountStatus
+    // end_status:AccountStatus ^[ in_msg:(Maybe ^Message) out_msgs:(HashmapE 15 ^Message) ] ...
+    let mut parser = root.parser();
+
+    if parser.load_u8(4).map_err(|e| e.to_string())? != TRANSACTION_TAG {
+        return Err("Not a transaction".to_string());
+    }
+
+    let account_addr = parser.load_bytes(32).map_err(|e| e.to_string())?;
+    if account_addr.as_slice() != account.hash_part.as_slice() {
+        return Err(format!("Transaction {} belongs to another account", hash));
+    }
+
+    let lt = parser.load_u64(64).map_err(|e| e.to_string())?;
+    if lt.to_string() != tx.transaction_id.lt {
+        return Err(format!("Transaction {} has lt {}, not {}", hash, lt, tx.transaction_id.lt));
+    }
+
+    parser.skip_bits(256 + 64).map_err(|e| e.to_string())?;
+    let now = parser.load_u32(32).map_err(|e| e.to_string())?;
+
+    let messages = root.reference(0).map_err(|e| e.to_string())?;
+    let mut messages_parser = messages.parser();
+
+    let has_in_msg = messages_parser.load_bit().map_err(|e| e.to_string())?;
+    let in_msg = if has_in_msg {
+        decode_message(messages.reference(0).map_err(|e| e.to_string())?)?
+    } else {
+        TonTransactionMessage::default()
+    };
+
+    let out_msgs = if messages_parser.load_bit().map_err(|e| e.to_string())? {
+        let out_msgs_ref = if has_in_msg { 1 } else { 0 };
+        let mut cells = vec![];
+        dict_values(messages.reference(out_msgs_ref).map_err(|e| e.to_string())?, OUT_MSGS_KEY_BITS, &mut cells)?;
+        cells.iter().map(decode_message).collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![]
+    };
+
+    Ok(TonTransaction {
+        transaction_id: TonTransactionId {
+            lt: lt.to_string(),
+            hash,
+        },
+        utime: now as u64,
+        in_msg,
+        out_msgs,
+        data: tx.data.clone(),
+    })
+}
+
+// message$_ info:CommonMsgInfo init:(Maybe (Either StateInit ^StateInit))
+// body:(Either X ^X) = Message X;
+fn decode_message(cell: &ArcCell) -> Result<TonTransactionMessage, String> {
+    let mut parser = cell.parser();
+    let mut next_ref = 0;
+
+    let (source, destination, value) = if !parser.load_bit().map_err(|e| e.to_string())? {
+        // int_msg_info$0 ihr_disabled:Bool bounce:Bool bounced:Bool src dest
+        // value:CurrencyCollection ihr_fee:Grams fwd_fee:Grams created_lt:uint64 created_at:uint32
+        parser.skip_bits(3).map_err(|e| e.to_string())?;
+        let source = parser.load_address().map_err(|e| e.to_string())?;
+        let destination = parser.load_address().map_err(|e| e.to_string())?;
+        let value = parser.load_coins().map_err(|e| e.to_string())?;
+        // Extra currencies
+        if parser.load_bit().map_err(|e| e.to_string())? {
+            next_ref += 1;
+        }
+        parser.load_coins().map_err(|e| e.to_string())?;
+        parser.load_coins().map_err(|e| e.to_string())?;
+        parser.skip_bits(64 + 32).map_err(|e| e.to_string())?;
+        (address_string(&source), address_string(&destination), value.to_string())
+    } else if !parser.load_bit().map_err(|e| e.to_string())? {
+        // ext_in_msg_info$10 src:MsgAddressExt dest:MsgAddressInt import_fee:Grams
+        skip_external_address(&mut parser)?;
+        let destination = parser.load_address().map_err(|e| e.to_string())?;
+        parser.load_coins().map_err(|e| e.to_string())?;
+        (String::new(), address_string(&destination), "0".to_string())
+    } else {
+        // ext_out_msg_info$11 src:MsgAddressInt dest:MsgAddressExt created_lt:uint64 created_at:uint32
+        let source = parser.load_address().map_err(|e| e.to_string())?;
+        skip_external_address(&mut parser)?;
+        parser.skip_bits(64 + 32).map_err(|e| e.to_string())?;
+        (address_string(&source), String::new(), "0".to_string())
+    };
+
+    if parser.load_bit().map_err(|e| e.to_string())? {
+        if parser.load_bit().map_err(|e| e.to_string())? {
+            next_ref += 1;
+        } else {
+            // split_depth:(Maybe (## 5)) special:(Maybe TickTock) code:(Maybe ^Cell)
+            // data:(Maybe ^Cell) library:(Maybe ^Cell)
+            if parser.load_bit().map_err(|e| e.to_string())? {
+                parser.skip_bits(5).map_err(|e| e.to_string())?;
+            }
+            if parser.load_bit().map_err(|e| e.to_string())? {
+                parser.skip_bits(2).map_err(|e| e.to_string())?;
+            }
+            for _ in 0..3 {
+                if parser.load_bit().map_err(|e| e.to_string())? {
+                    next_ref += 1;
+                }
+            }
+        }
+    }
+
+    let body = if parser.load_bit().map_err(|e| e.to_string())? {
+        cell.reference(next_ref).map_err(|e| e.to_string())?.as_ref().clone()
+    } else {
+        // The body is the rest of the message cell
+        remainder(cell, &mut parser, next_ref)?
+    };
+
+    Ok(TonTransactionMessage {
+        body_hash: BASE64_STANDARD.encode(body.cell_hash()),
+        hash: BASE64_STANDARD.encode(cell.cell_hash()),
+        value,
+        destination,
+        source,
+        msg_data: Some(TonMessageData {
+            data_type: "msg.dataRaw".to_string(),
+            body: Some(body_boc(body)?),
+            text: None,
+        }),
+    })
+}
+
+// The unread bits of `cell` and its references from `first_ref` on, as a cell
+// of their own: an `X` stored inline in an `(Either X ^X)`
+pub fn remainder(cell: &ArcCell, parser: &mut CellParser, first_ref: usize) -> Result<Cell, String> {
+    let bits = parser.remaining_bits();
+    let data = parser.load_bits(bits).map_err(|e| e.to_string())?;
+    let mut builder = CellBuilder::new();
+    builder.store_bits(bits, &data).map_err(|e| e.to_string())?;
+    for reference in cell.references().iter().skip(first_ref) {
+        builder.store_reference(reference).map_err(|e| e.to_string())?;
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn body_boc(body: Cell) -> Result<String, String> {
+    let boc = BagOfCells::from_root(body);
+    Ok(BASE64_STANDARD.encode(boc.serialize(false).map_err(|e| e.to_string())?))
+}
+
+fn address_string(address: &TonAddress) -> String {
+    if *address == TonAddress::NULL {
+        return String::new();
+    }
+    address.to_base64_url()
+}
+
+// addr_none$00 | addr_extern$01 len:(## 9) external_address:(bits len)
+fn skip_external_address(parser: &mut CellParser) -> Result<(), String> {
+    if parser.load_u8(2).map_err(|e| e.to_string())? == 0b01 {
+        let len = parser.load_u32(9).map_err(|e| e.to_string())?;
+        parser.skip_bits(len as usize).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Collects the `^X` values of a `Hashmap n ^X` in key order:
+// hm_edge#_ label:(HmLabel ~l n) node:(HashmapNode m X), n = m + l
+pub fn dict_values(cell: &ArcCell, n: usize, values: &mut Vec<ArcCell>) -> Result<(), String> {
+    let mut parser = cell.parser();
+
+    let label_len = load_label_len(&mut parser, n)?;
+    let m = n.checked_sub(label_len).ok_or("Invalid dictionary label".to_string())?;
+
+    if m == 0 {
+        // hmn_leaf#_ value:X
+        values.push(cell.reference(0).map_err(|e| e.to_string())?.clone());
+    } else {
+        // hmn_fork#_ left:^(Hashmap m-1 X) right:^(Hashmap m-1 X)
+        dict_values(cell.reference(0).map_err(|e| e.to_string())?, m - 1, values)?;
+        dict_values(cell.reference(1).map_err(|e| e.to_string())?, m - 1, values)?;
+    }
+
+    Ok(())
+}
+
+// hml_short$0 len:(Unary ~n) s:(n * Bit) | hml_long$10 n:(#<= m) s:(n * Bit)
+// | hml_same$11 v:Bit n:(#<= m)
+fn load_label_len(parser: &mut CellParser, m: usize) -> Result<usize, String> {
+    // Bits of a number up to m
+    let len_bits = (usize::BITS - m.leading_zeros()) as usize;
+
+    if !parser.load_bit().map_err(|e| e.to_string())? {
+        let mut len = 0;
+        while parser.load_bit().map_err(|e| e.to_string())? {
+            len += 1;
+        }
+        parser.skip_bits(len).map_err(|e| e.to_string())?;
+        Ok(len)
+    } else if !parser.load_bit().map_err(|e| e.to_string())? {
+        let len = parser.load_u32(len_bits).map_err(|e| e.to_string())? as usize;
+        parser.skip_bits(len).map_err(|e| e.to_string())?;
+        Ok(len)
+    } else {
+        parser.skip_bits(1).map_err(|e| e.to_string())?;
+        Ok(parser.load_u32(len_bits).map_err(|e| e.to_string())? as usize)
+    }
+}
@@ -1,50 +1,70 @@
-use std::{
-    collections::VecDeque,
-    ops::{Deref, DerefMut},
-    sync::Arc,
-};
+use std::fmt;
 
 use candid::{CandidType, Principal};
 use ic_cdk::api::management_canister::schnorr::{self, SchnorrKeyId, SignWithSchnorrArgument};
-use ic_ton_lib::types::ICSigner;
+use ic_ton_lib::{types::ICSigner, wallet::WalletVersion, TonAddress};
 use icrc_ledger_types::icrc1::account::Account;
 use serde::{Deserialize, Serialize};
 
-use crate::consts::SCHNORR_KEY_NAME;
+use crate::{jettons::JettonConfig, metadata::TokenMetadata, state::read_config, ton_api::TonApiError};
 
-impl<T> Deref for MultiPOPVec<T> {
-    type Target = VecDeque<T>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ProxyMethod {
+    GET,
+    POST,
+}
+// How the minter reaches the TON providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, CandidType, Deserialize, Serialize)]
+pub enum TonOutcallMode {
+    // Through the off-chain proxy of the config, which sends each request once
+    #[default]
+    Proxy,
+    // Every replica calls the providers, which must be reachable over IPv6
+    Direct,
 }
 
-impl<T> DerefMut for MultiPOPVec<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+// TON network the minter is bridged to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, CandidType, Deserialize, Serialize)]
+pub enum TonNetwork {
+    Mainnet,
+    #[default]
+    Testnet,
 }
 
-impl<T> MultiPOPVec<T> {
-    pub fn pop_many(&mut self, count: Option<u32>) -> Vec<Option<T>> {
-        let count = count.unwrap_or(1);
-        (0..count).map(|_| self.0.pop_front()).collect()
+impl TonNetwork {
+    // Global id of the network, -239 for mainnet and -3 for testnet
+    pub fn global_id(self) -> i32 {
+        match self {
+            TonNetwork::Mainnet => -239,
+            TonNetwork::Testnet => -3,
+        }
     }
-}
-pub struct MultiPOPVec<T>(VecDeque<T>);
 
-impl<T> MultiPOPVec<T> {
-    pub fn new() -> Self {
-        Self(VecDeque::new())
+    // User-friendly form of an address: bounceable on mainnet, the
+    // non-bounceable testnet form on testnet
+    pub fn format_address(self, address: &TonAddress) -> String {
+        match self {
+            TonNetwork::Mainnet => address.to_base64_url(),
+            TonNetwork::Testnet => address.to_base64_url_flags(true, true),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum ProxyMethod {
-    GET,
-    POST,
+// When a deposit seen on chain is final enough to mint ckTON for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, CandidType, Deserialize, Serialize)]
+pub enum FinalityPolicy {
+    // As soon as the providers return the transaction
+    #[default]
+    Immediate,
+    // Once the masterchain is this many blocks past the transaction
+    MasterchainBlocks(u32),
+    // Once the transaction is this many seconds old
+    MinAgeSecs(u64),
+    // Once the transaction is proven to be in a masterchain block the light
+    // client verified, see `lightclient::prove_transaction`
+    BlockProof,
 }
+
 // Request model that includes the idempotency key
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProxyRequest {
@@ -55,14 +75,40 @@ pub struct ProxyRequest {
     pub body: Option<serde_json::Value>,
 }
 
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct TONDeployedWallet {
     pub ton_address: String,
+    // Wallets recorded before versions were tracked are V4R2
+    pub version: Option<TonWalletVersion>,
+}
+
+// Wallet contract of the TON wallet derived for an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, CandidType, Deserialize, Serialize)]
+pub enum TonWalletVersion {
+    V3R2,
+    #[default]
+    V4R2,
+    V5R1,
 }
 
+impl From<TonWalletVersion> for WalletVersion {
+    fn from(version: TonWalletVersion) -> Self {
+        match version {
+            TonWalletVersion::V3R2 => WalletVersion::V3R2,
+            TonWalletVersion::V4R2 => WalletVersion::V4R2,
+            TonWalletVersion::V5R1 => WalletVersion::V5R1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub enum PendingTasks {
     DeployWallet(Account, String, u32),
     Mint(Account, u64, String, String, u32),
     Burn(Principal, u64, String, String, u32),
+    Sweep(Account, u32),
+    Withdraw(u64, u32),
+    ConfirmWithdrawal(u64, u32),
 }
 
 #[derive(Clone)]
@@ -88,7 +134,7 @@ impl ICSigner for ICTonSigner {
             derivation_path: self.path.clone(),
             key_id: SchnorrKeyId {
                 algorithm: schnorr::SchnorrAlgorithm::Ed25519,
-                name: SCHNORR_KEY_NAME.to_string(),
+                name: read_config(|config| config.schnorr_key_name()),
             },
         };
         let (sign_result,) = schnorr::sign_with_schnorr(arg).await.map_err(|err| err.1)?;
@@ -97,6 +143,40 @@ impl ICSigner for ICTonSigner {
     }
 }
 
+// Error of the endpoints that call the TON providers. A failed provider call
+// keeps its `TonApiError`, for the caller to tell whether to retry.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum MinterError {
+    TonApi(TonApiError),
+    Other(String),
+}
+
+impl fmt::Display for MinterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MinterError::TonApi(error) => error.fmt(f),
+            MinterError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<TonApiError> for MinterError {
+    fn from(error: TonApiError) -> Self {
+        MinterError::TonApi(error)
+    }
+}
+
+impl From<String> for MinterError {
+    fn from(message: String) -> Self {
+        MinterError::Other(message)
+    }
+}
+
+impl From<MinterError> for String {
+    fn from(error: MinterError) -> Self {
+        error.to_string()
+    }
+}
 
 #[derive(Debug, CandidType, Deserialize, Serialize)]
 pub struct AdminSetup {
@@ -104,4 +184,10 @@ pub struct AdminSetup {
     pub indexer_canister: Principal,
     pub ckton_transfer_fee: Option<u64>,
     pub ton_fee: Option<u64>,
+    pub memo_deposits: Option<bool>,
+    pub highload_wallet_code: Option<String>,
+    pub default_wallet_version: Option<TonWalletVersion>,
+    pub finality_policy: Option<FinalityPolicy>,
+    pub jettons: Option<Vec<JettonConfig>>,
+    pub ckton_metadata: Option<TokenMetadata>,
 }
@@ -1,7 +1,10 @@
-use candid::Principal;
+use std::future::Future;
+
+use candid::{Nat, Principal};
 use ic_cdk::{api::management_canister::schnorr::{self, SchnorrKeyId, SchnorrPublicKeyArgument}, caller};
+use icrc_ledger_types::icrc1::{account::Account, transfer::{Memo, TransferArg, TransferError}};
 
-use crate::consts::SCHNORR_KEY_NAME;
+use crate::state::read_config;
 
 pub async fn get_ic_pub_key(path: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
     let (resp,) = schnorr::schnorr_public_key(SchnorrPublicKeyArgument {
@@ -9,7 +12,7 @@ pub async fn get_ic_pub_key(path: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
         derivation_path: path,
         key_id: SchnorrKeyId {
             algorithm: schnorr::SchnorrAlgorithm::Ed25519,
-            name: SCHNORR_KEY_NAME.to_string(),
+            name: read_config(|config| config.schnorr_key_name()),
         },
     })
     .await
@@ -31,3 +34,52 @@ pub fn get_path(owner: Option<Principal>, subaccount: Option<[u8; 32]>) -> Vec<V
 
     return vec![vec];
 }
+
+// Calls `icrc1_transfer` on a ledger
+pub async fn ledger_transfer(ledger_canister: Principal, arg: TransferArg) -> Result<Result<Nat, TransferError>, String> {
+    let (res,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger_canister, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|e| format!("Failed to call ledger: {:?}", e))?;
+
+    Ok(res)
+}
+
+// Mints `amount` to `to` through `transfer`, see `ledger_transfer`. The memo
+// and `created_at` (in nanoseconds) identify the mint, so the ledger answers a
+// repeated one with the block of the first. Outside of the ledger's
+// deduplication window the mint is sent without `created_at_time`, and only
+// the caller's own records prevent a second one.
+pub async fn mint_deduplicated<F, Fut>(transfer: F, to: Account, amount: u64, memo: Vec<u8>, created_at: u64) -> Result<u64, String>
+where
+    F: Fn(TransferArg) -> Fut,
+    Fut: Future<Output = Result<Result<Nat, TransferError>, String>>,
+{
+    let mut created_at_time = Some(created_at);
+
+    let block_index = loop {
+        let arg = TransferArg {
+            from_subaccount: None,
+            to,
+            fee: None,
+            created_at_time,
+            memo: Some(Memo::from(memo.clone())),
+            amount: amount.into(),
+        };
+
+        match transfer(arg).await? {
+            Ok(block_index) => break block_index,
+            Err(TransferError::Duplicate { duplicate_of }) => break duplicate_of,
+            Err(TransferError::TooOld) | Err(TransferError::CreatedInFuture { .. })
+                if created_at_time.is_some() =>
+            {
+                created_at_time = None;
+            }
+            Err(err) => return Err(format!("Mint failed: {:?}", err)),
+        }
+    };
+
+    block_index
+        .0
+        .try_into()
+        .map_err(|_| "Block index too large to convert to u64".to_string())
+}
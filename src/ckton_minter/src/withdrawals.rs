@@ -0,0 +1,1024 @@
+// ==========================
+// ckton_minter/src/withdrawals.rs
+// ckTON -> TON withdrawals.
+// The ckTON is burned first, only then the TON payout is queued, so the
+// minter never pays out TON it did not collect. A withdrawal is identified
+// by the index of its burn block and moves through `WithdrawalStatus`.
+// Queued payouts are sent in batches, one external message of the minter
+// wallet at a time.
+// Payouts that provably never landed are reimbursed by minting the burned
+// ckTON back.
+// Jetton withdrawals burn on the ledger of the jetton and go through the same
+// batches, as `transfer` messages to the jetton wallet of the minter. The TON
+// attached to the transfer is burned in ckTON next to the jettons.
+// ==========================
+
+use std::{cell::RefCell, collections::BTreeSet, sync::Arc};
+
+use base64::prelude::*;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{api::time, id};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap, StableCell};
+use ic_ton_lib::{
+    cell::{ArcCell, BagOfCells, Cell},
+    message::{CommonMsgInfo, InternalMessage, TonMessage, TransferMessage},
+    num_bigint::BigUint,
+    TonAddress,
+};
+use icrc_ledger_types::{
+    icrc1::{
+        account::{principal_to_subaccount, Account},
+        transfer::TransferArg,
+    },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
+use serde::Deserialize;
+
+use crate::{
+    consts::{
+        HIGHLOAD_CREATED_AT_LAG_SECS, MAX_WITHDRAWALS_PER_BATCH,
+        MAX_WITHDRAWALS_PER_CHECK, WITHDRAWAL_EXPIRY_MARGIN_SECS,
+    },
+    create_highload_wallet, create_ton_wallet,
+    cycles::Operation,
+    events::{record_event, EventType},
+    get_ton_address_from_address,
+    highload::{self, HighloadWalletV3, QueryId},
+    history,
+    jettons::{self, JettonConfig, PayoutTrace, JETTON_TRANSFER_TON_AMOUNT},
+    nanos_to_seconds,
+    state::{candid_storable, get_memory, read_config, Memory, StorableAccount, PENDING_TASKS},
+    ton_api::{self, TonTransaction},
+    types::PendingTasks,
+    utils::{ledger_transfer, mint_deduplicated},
+};
+
+const WITHDRAWAL_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const WITHDRAWALS_BY_ACCOUNT_MEMORY_ID: MemoryId = MemoryId::new(9);
+const UNRESOLVED_WITHDRAWALS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const MINTER_WALLET_MEMORY_ID: MemoryId = MemoryId::new(11);
+const CONFIRMED_PAYOUTS_MEMORY_ID: MemoryId = MemoryId::new(22);
+
+// Send mode emptying the minter wallet: carry its whole remaining balance
+const SWEEP_SEND_MODE: u8 = 128;
+
+// Jetton burns are indexed by their own ledgers, jetton withdrawals are
+// numbered from here on so their ids don't collide with ckTON burn indices
+const JETTON_WITHDRAWAL_ID_BASE: u64 = 1 << 63;
+
+thread_local! {
+    // Withdrawal requests by id (burn block index)
+    static WITHDRAWAL_REQUESTS: RefCell<StableBTreeMap<u64, WithdrawalRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WITHDRAWAL_REQUESTS_MEMORY_ID)));
+    // Index of withdrawal ids by the account the ckTON was burned from
+    static WITHDRAWALS_BY_ACCOUNT: RefCell<StableBTreeMap<(StorableAccount, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WITHDRAWALS_BY_ACCOUNT_MEMORY_ID)));
+    // Ids of withdrawals that are neither confirmed nor reimbursed yet
+    static UNRESOLVED_WITHDRAWALS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UNRESOLVED_WITHDRAWALS_MEMORY_ID)));
+    // Seqno and query id bookkeeping of the minter wallet
+    static MINTER_WALLET: RefCell<StableCell<MinterWalletState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(MINTER_WALLET_MEMORY_ID), MinterWalletState::default())
+            .expect("failed to initialize the minter wallet state")
+    );
+    // Withdrawal confirmed by each out message of the minter wallet, by
+    // message hash, so that a payout confirms a single withdrawal
+    static CONFIRMED_PAYOUTS: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONFIRMED_PAYOUTS_MEMORY_ID)));
+    // Withdrawals currently checked or reimbursed
+    static RESOLVING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+    // Set while a withdrawal batch is signed and sent
+    static BATCH_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[derive(Debug, Clone, Default, CandidType, Deserialize)]
+struct MinterWalletState {
+    next_seqno: Option<u64>,
+    // Encoded `QueryId` of the next highload wallet message
+    next_query_id: Option<u64>,
+}
+
+candid_storable!(MinterWalletState);
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum WithdrawalStatus {
+    // ckTON burned, the TON payout is queued
+    Pending,
+    // The payout message was signed for the given minter wallet seqno (query
+    // id for a highload wallet), `hash` is the hash of the external message
+    Signed { seqno: u64, expire_at: u32, hash: String },
+    // The payout message was accepted by the TON RPC
+    Sent { seqno: u64, expire_at: u32, hash: String },
+    // The payout transaction was found on the minter wallet, on its jetton
+    // wallet for a jetton payout
+    Confirmed { hash: String, tx_hash: String },
+    // The payout could not be delivered
+    Failed { reason: String },
+    // The burned ckTON was minted back to the user
+    Reimbursed { block_index: u64 },
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct WithdrawalRequest {
+    pub id: u64,
+    pub from: Account,
+    pub to_ton_address: String,
+    // ckTON burned
+    pub amount: u64,
+    // TON paid out, the TON fee is kept by the minter. Jettons for a jetton
+    // withdrawal.
+    pub ton_amount: u64,
+    pub created_at: u64,
+    pub status: WithdrawalStatus,
+    // Symbol of the jetton paid out, `None` for TON
+    pub jetton: Option<String>,
+    // ckTON burned for the TON attached to a jetton payout, minted back with
+    // the jettons on a reimbursement
+    pub ckton_gas: Option<u64>,
+    // Block of the ckTON minted back for `ckton_gas`, set once it is minted so
+    // that a retried reimbursement only mints the jettons
+    pub ckton_gas_block_index: Option<u64>,
+}
+
+candid_storable!(WithdrawalRequest);
+
+// Result of `withdraw_native`
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct NativeWithdrawal {
+    pub withdrawal_id: u64,
+    // TON paid out
+    pub ton_amount: u64,
+    // Hash of the payout message, unset if it is sent with a later batch
+    pub hash: Option<String>,
+}
+
+pub fn get_request(id: u64) -> Option<WithdrawalRequest> {
+    WITHDRAWAL_REQUESTS.with_borrow(|requests| requests.get(&id))
+}
+
+pub fn requests_by_account(account: Account) -> Vec<WithdrawalRequest> {
+    let account = StorableAccount::from(account);
+    let ids: Vec<u64> = WITHDRAWALS_BY_ACCOUNT.with_borrow(|index| {
+        index
+            .range((account, 0)..=(account, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.into_iter().filter_map(get_request).collect()
+}
+
+pub fn set_status(id: u64, status: WithdrawalStatus) {
+    let resolved = matches!(status, WithdrawalStatus::Confirmed { .. } | WithdrawalStatus::Reimbursed { .. });
+
+    WITHDRAWAL_REQUESTS.with_borrow_mut(|requests| {
+        if let Some(mut request) = requests.get(&id) {
+            request.status = status;
+            requests.insert(id, request);
+        }
+    });
+
+    if resolved {
+        UNRESOLVED_WITHDRAWALS.with_borrow_mut(|unresolved| unresolved.remove(&id));
+    }
+}
+
+fn set_gas_block_index(id: u64, block_index: u64) {
+    WITHDRAWAL_REQUESTS.with_borrow_mut(|requests| {
+        if let Some(mut request) = requests.get(&id) {
+            request.ckton_gas_block_index = Some(block_index);
+            requests.insert(id, request);
+        }
+    });
+}
+
+// Stores a withdrawal whose ckTON was burned in block `burn_block_index` and
+// queues its payout
+fn accept_withdrawal(from: Account, to_ton_address: String, amount: u64, ton_amount: u64, burn_block_index: u64) -> WithdrawalRequest {
+    let request = WithdrawalRequest {
+        id: burn_block_index,
+        from,
+        to_ton_address,
+        amount,
+        ton_amount,
+        created_at: time(),
+        status: WithdrawalStatus::Pending,
+        jetton: None,
+        ckton_gas: None,
+        ckton_gas_block_index: None,
+    };
+
+    store_request(&request);
+
+    record_event(EventType::CkTonBurned {
+        withdrawal_id: request.id,
+        from,
+        to_ton_address: request.to_ton_address.clone(),
+        amount,
+        ton_amount,
+    });
+
+    request
+}
+
+// Stores a withdrawal, indexed by account and as unresolved
+pub fn store_request(request: &WithdrawalRequest) {
+    let from = request.from;
+
+    WITHDRAWAL_REQUESTS.with_borrow_mut(|requests| {
+        requests.insert(request.id, request.clone());
+    });
+    WITHDRAWALS_BY_ACCOUNT.with_borrow_mut(|index| {
+        index.insert((from.into(), request.id), ());
+    });
+    UNRESOLVED_WITHDRAWALS.with_borrow_mut(|unresolved| {
+        unresolved.insert(request.id, ());
+    });
+}
+
+fn next_jetton_withdrawal_id() -> u64 {
+    WITHDRAWAL_REQUESTS.with_borrow(|requests| {
+        requests
+            .range(JETTON_WITHDRAWAL_ID_BASE..)
+            .last()
+            .map_or(JETTON_WITHDRAWAL_ID_BASE, |(id, _)| id + 1)
+    })
+}
+
+fn check_withdrawal(to_ton_address: &str, ton_amount: Option<u64>) -> Result<u64, String> {
+    // Fail before burning if the destination can't be paid
+    to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
+
+    if read_config(|config| config.app_ton_address()) == TonAddress::NULL {
+        return Err("Minter TON address is not set".to_string());
+    }
+
+    ton_amount
+        .filter(|ton_amount| *ton_amount > 0)
+        .ok_or("Amount does not cover the withdrawal fees".to_string())
+}
+
+fn block_index_to_u64(block_index: Nat) -> Result<u64, String> {
+    block_index
+        .0
+        .try_into()
+        .map_err(|_| "Block index too large to convert to u64".to_string())
+}
+
+// Burns `amount` ckTON of `from` through its ICRC-2 approval to the minter and
+// queues the TON payout. Returns the withdrawal id.
+pub async fn withdraw_ton(from: Account, to_ton_address: String, amount: u64) -> Result<u64, String> {
+    let (ledger_canister, ton_fee) = read_config(|config| (config.ledger_canister, config.ton_fee));
+
+    let ton_amount = check_withdrawal(&to_ton_address, amount.checked_sub(ton_fee))?;
+
+    record_event(EventType::WithdrawalRequested {
+        caller: from.owner,
+        to_ton_address: to_ton_address.clone(),
+        amount,
+    });
+
+    let burn_block_index = burn_from(ledger_canister, from, amount).await?;
+
+    let request = accept_withdrawal(from, to_ton_address, amount, ton_amount, burn_block_index);
+
+    Ok(request.id)
+}
+
+// Burns `amount` tokens of the jetton `symbol` of `from` through its ICRC-2
+// approval to the minter and queues the jetton payout. The TON attached to the
+// payout is burned in ckTON through a second approval on the ckTON ledger, so
+// jetton payouts don't spend the TON backing ckTON. Returns the withdrawal id.
+pub async fn withdraw_jetton(from: Account, symbol: String, to_ton_address: String, amount: u64) -> Result<u64, String> {
+    let jetton = jettons::jetton(&symbol)?;
+
+    jettons::minter_wallet(&jetton)?;
+
+    let jetton_amount = check_withdrawal(&to_ton_address, amount.checked_sub(jetton.withdrawal_fee))?;
+
+    let ckton_ledger = read_config(|config| config.ledger_canister);
+
+    let gas_block_index = burn_from(ckton_ledger, from, JETTON_TRANSFER_TON_AMOUNT).await?;
+
+    let burn_block_index = match burn_from(jetton.ledger_canister, from, amount).await {
+        Ok(burn_block_index) => burn_block_index,
+        Err(err) => {
+            // The withdrawal was not accepted, the ckTON goes back
+            mint_to(ckton_ledger, from, JETTON_TRANSFER_TON_AMOUNT, format!("refund-gas:{}", gas_block_index), time())
+                .await
+                .map_err(|refund_err| format!("{}, refunding ckTON burn {} failed: {}", err, gas_block_index, refund_err))?;
+            return Err(err);
+        }
+    };
+
+    let request = WithdrawalRequest {
+        id: next_jetton_withdrawal_id(),
+        from,
+        to_ton_address,
+        amount,
+        ton_amount: jetton_amount,
+        created_at: time(),
+        status: WithdrawalStatus::Pending,
+        jetton: Some(symbol),
+        ckton_gas: Some(JETTON_TRANSFER_TON_AMOUNT),
+        ckton_gas_block_index: None,
+    };
+
+    store_request(&request);
+
+    record_event(EventType::JettonBurned {
+        symbol: jetton.symbol,
+        withdrawal_id: request.id,
+        burn_block_index,
+        from,
+        to_ton_address: request.to_ton_address.clone(),
+        amount,
+        jetton_amount,
+        ckton_gas: request.ckton_gas,
+    });
+
+    Ok(request.id)
+}
+
+// Burns `amount` tokens of `from` on `ledger_canister` through its ICRC-2
+// approval to the minter. Returns the burn block index.
+async fn burn_from(ledger_canister: Principal, from: Account, amount: u64) -> Result<u64, String> {
+    // A transfer to the minting account is a burn
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to: Account {
+            owner: id(),
+            subaccount: None,
+        },
+        amount: amount.into(),
+        fee: None,
+        memo: None,
+        created_at_time: Some(time()),
+    };
+
+    let (res,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger_canister, "icrc2_transfer_from", (arg,))
+            .await
+            .map_err(|e| format!("Failed to call ledger: {:?}", e))?;
+
+    block_index_to_u64(res.map_err(|e| format!("Burn failed: {:?}", e))?)
+}
+
+// The jetton of a jetton withdrawal
+fn withdrawal_jetton(request: &WithdrawalRequest) -> Result<Option<JettonConfig>, String> {
+    request.jetton.as_deref().map(jettons::jetton).transpose()
+}
+
+// The jetton wallet of the minter that pays out a jetton withdrawal
+fn jetton_wallet(request: &WithdrawalRequest) -> Result<Option<TonAddress>, String> {
+    match withdrawal_jetton(request)? {
+        Some(jetton) => jettons::minter_wallet(&jetton).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Burns `amount` ckTON the caller deposited on its minter subaccount
+// (see `get_deposit_address`) and returns the accepted withdrawal
+pub async fn withdraw_deposited(caller: candid::Principal, to_ton_address: String, amount: u64) -> Result<WithdrawalRequest, String> {
+    let (ledger_canister, ckton_transfer_fee, ton_fee) =
+        read_config(|config| (config.ledger_canister, config.ckton_transfer_fee, config.ton_fee));
+
+    let amount_to_burn = amount
+        .checked_sub(ckton_transfer_fee)
+        .ok_or("Amount does not cover the withdrawal fees".to_string())?;
+
+    let ton_amount = check_withdrawal(&to_ton_address, amount_to_burn.checked_sub(ton_fee))?;
+
+    record_event(EventType::WithdrawalRequested {
+        caller,
+        to_ton_address: to_ton_address.clone(),
+        amount,
+    });
+
+    let from_subaccount = principal_to_subaccount(caller);
+
+    // A transfer to the minting account is a burn
+    let arg = TransferArg {
+        from_subaccount: Some(from_subaccount),
+        to: Account {
+            owner: id(),
+            subaccount: None,
+        },
+        fee: None,
+        created_at_time: Some(time()),
+        memo: None,
+        amount: amount_to_burn.into(),
+    };
+
+    let res = ledger_transfer(ledger_canister, arg).await?;
+
+    let burn_block_index = block_index_to_u64(res.map_err(|e| format!("Burn failed: {:?}", e))?)?;
+
+    let from = Account {
+        owner: id(),
+        subaccount: Some(from_subaccount),
+    };
+
+    Ok(accept_withdrawal(from, to_ton_address, amount_to_burn, ton_amount, burn_block_index))
+}
+
+// The minter wallet seqno the next batch is signed with, `None` until it
+// was read from the chain
+fn next_seqno() -> Option<u64> {
+    MINTER_WALLET.with_borrow(|cell| cell.get().next_seqno)
+}
+
+fn set_next_seqno(next_seqno: Option<u64>) {
+    mutate_minter_wallet(|state| state.next_seqno = next_seqno);
+}
+
+// Hands out the query id of the next highload wallet message
+fn take_query_id() -> QueryId {
+    mutate_minter_wallet(|state| {
+        let query_id = state.next_query_id.map(QueryId::from_u64).unwrap_or_default();
+        state.next_query_id = Some(query_id.next().to_u64());
+        query_id
+    })
+}
+
+fn mutate_minter_wallet<R>(f: impl FnOnce(&mut MinterWalletState) -> R) -> R {
+    MINTER_WALLET.with_borrow_mut(|cell| {
+        let mut state = cell.get().clone();
+        let result = f(&mut state);
+        cell.set(state).expect("failed to write the minter wallet state");
+        result
+    })
+}
+
+// Forgets the seqno and query id, called when the minter wallet changes
+pub fn reset_minter_wallet() {
+    mutate_minter_wallet(|state| *state = MinterWalletState::default());
+}
+
+// A signed batch may still land, the next one is only signed once it resolved
+pub fn batch_in_flight() -> bool {
+    unresolved_with(|status| matches!(status, WithdrawalStatus::Signed { .. } | WithdrawalStatus::Sent { .. }), 1)
+        .next()
+        .is_some()
+}
+
+fn unresolved_with(filter: impl Fn(&WithdrawalStatus) -> bool, limit: usize) -> std::vec::IntoIter<WithdrawalRequest> {
+    let ids: Vec<u64> = UNRESOLVED_WITHDRAWALS.with_borrow(|unresolved| unresolved.iter().map(|(id, _)| id).collect());
+
+    ids.into_iter()
+        .filter_map(get_request)
+        .filter(|request| filter(&request.status))
+        .take(limit)
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+struct BatchGuard;
+
+impl BatchGuard {
+    fn new() -> Option<Self> {
+        BATCH_RUNNING.with_borrow_mut(|running| {
+            if *running {
+                return None;
+            }
+            *running = true;
+            Some(Self)
+        })
+    }
+}
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        BATCH_RUNNING.with_borrow_mut(|running| *running = false);
+    }
+}
+
+// Signs the oldest pending withdrawals into one external message of the
+// minter wallet and broadcasts it. The seqno (or the query id of a highload
+// wallet) is owned by the canister: it is read from the chain once and then
+// advanced with every signed batch.
+// Returns the ids of the withdrawals sent and the message hash, or `None` if
+// there was nothing to send.
+pub async fn send_withdrawal_batch() -> Result<Option<(Vec<u64>, String)>, String> {
+    let Some(_guard) = BatchGuard::new() else {
+        return Ok(None);
+    };
+
+    if batch_in_flight() {
+        return Ok(None);
+    }
+
+    let batch_size = if read_config(|config| config.highload_wallet_code.is_some()) {
+        highload::MAX_MESSAGES_PER_EXTERNAL
+    } else {
+        MAX_WITHDRAWALS_PER_BATCH
+    };
+
+    let pending: Vec<WithdrawalRequest> =
+        unresolved_with(|status| *status == WithdrawalStatus::Pending, batch_size).collect();
+
+    let app_ton_address = read_config(|config| config.app_ton_address());
+
+    let mut batch = Vec::with_capacity(pending.len());
+    let mut messages = Vec::with_capacity(pending.len());
+
+    for request in pending {
+        let dest: TonAddress = request.to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
+
+        let jetton_wallet = match jetton_wallet(&request) {
+            Ok(jetton_wallet) => jetton_wallet,
+            Err(err) => {
+                fail_withdrawal(request.id, format!("Payout not sent: {}", err));
+                continue;
+            }
+        };
+
+        if let Some(jetton_wallet) = jetton_wallet {
+            let message = jettons::transfer_message(&jetton_wallet, request.id, request.ton_amount, &dest, &app_ton_address)?;
+            messages.push(Arc::new(message));
+            batch.push(request);
+            continue;
+        }
+
+        let common_message = CommonMsgInfo::InternalMessage(InternalMessage {
+            ihr_disabled: false,
+            bounce: false,
+            bounced: false,
+            src: TonAddress::NULL,
+            dest,
+            value: BigUint::from(request.ton_amount),
+            ihr_fee: BigUint::ZERO,
+            fwd_fee: BigUint::ZERO,
+            created_lt: 0,
+            created_at: 0,
+        });
+
+        let transfer_message = TransferMessage::new(common_message)
+            .build()
+            .map_err(|s| s.to_string())?;
+
+        messages.push(transfer_message.to_arc());
+        batch.push(request);
+    }
+
+    if batch.is_empty() {
+        return Ok(None);
+    }
+
+    let (seqno, expire_at, wrapped) = sign_minter_message(messages, highload::PAYOUT_SEND_MODE).await?;
+
+    // From here on the message may land even if the send below fails, so the
+    // withdrawals are only resolved once the message expired
+    let message_hash = BASE64_STANDARD.encode(wrapped.cell_hash());
+
+    for request in batch.iter() {
+        set_status(request.id, WithdrawalStatus::Signed { seqno, expire_at, hash: message_hash.clone() });
+    }
+
+    let boc = BagOfCells::from_root(wrapped);
+
+    let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
+
+    // A failed send leaves the batch signed: another provider may have
+    // broadcast the message, `resolve_withdrawal` settles it after the expiry
+    let hash = ton_api::send_boc_to_ton(enc, Operation::Withdrawal)
+        .await
+        .map_err(|err| err.to_string())?
+        .hash;
+
+    let ids: Vec<u64> = batch.iter().map(|request| request.id).collect();
+
+    for id in ids.iter() {
+        set_status(*id, WithdrawalStatus::Sent { seqno, expire_at, hash: hash.clone() });
+
+        record_event(EventType::WithdrawalSent {
+            withdrawal_id: *id,
+            hash: hash.clone(),
+            seqno,
+        });
+
+        PENDING_TASKS.with_borrow_mut(|tasks| {
+            tasks.push_back(PendingTasks::ConfirmWithdrawal(*id, 0));
+        });
+    }
+
+    Ok(Some((ids, hash)))
+}
+
+// Signs an external message of the minter wallet sending `messages` with
+// `send_mode`. Returns the seqno it was signed for (the query id for a
+// highload wallet), its expiry and the message.
+async fn sign_minter_message(messages: Vec<ArcCell>, send_mode: u8) -> Result<(u64, u32, Cell), String> {
+    match read_config(|config| config.highload_wallet_code.clone()) {
+        Some(code) => {
+            let wallet = create_highload_wallet(&code).await?;
+
+            let query_id = take_query_id();
+            let created_at = highload::created_at(nanos_to_seconds(time()));
+
+            let external = wallet.create_external(messages, send_mode, query_id, created_at, false).await?;
+
+            Ok((query_id.to_u64(), highload::expire_at(created_at), external))
+        }
+        None => {
+            let seqno = match next_seqno() {
+                Some(seqno) => seqno,
+                None => {
+                    let app_ton_address = read_config(|config| config.app_ton_address());
+
+                    let result = ton_api::get_ton_wallet_info(get_ton_address_from_address(&app_ton_address), Operation::Withdrawal).await?;
+
+                    if !result.wallet {
+                        return Err("Wallet not deployed".to_string());
+                    }
+
+                    result.seqno.ok_or("Minter wallet has no seqno".to_string())?
+                }
+            };
+
+            let seqno_u32 = u32::try_from(seqno)
+                .map_err(|_| "Sequence number too large to convert to u32".to_string())?;
+
+            let ton_wallet = create_ton_wallet(ic_cdk::id(), None).await?;
+
+            let expire_at = nanos_to_seconds(time()) + 60;
+
+            let messages = messages.into_iter().map(|message| (send_mode, message)).collect();
+
+            let external_mssg = ton_wallet
+                .create_external_body_with_mode(expire_at, seqno_u32, messages)
+                .map_err(|s| s.to_string())?;
+
+            let signed = ton_wallet
+                .sign_external_body(&external_mssg)
+                .await
+                .map_err(|s| s.to_string())?;
+
+            let wrapped = ton_wallet
+                .wrap_signed_body(signed, false)
+                .map_err(|s| s.to_string())?;
+
+            set_next_seqno(Some(seqno + 1));
+
+            Ok((seqno, expire_at, wrapped))
+        }
+    }
+}
+
+// Sends the whole balance of the minter wallet to `dest`, the wallet the
+// minter is about to switch to: the TON backing ckTON moves along with the
+// minter wallet, see `apply_admin_setup`. Refused while a withdrawal batch may
+// still land, its payouts need the balance.
+pub async fn empty_minter_wallet(dest: TonAddress) -> Result<String, String> {
+    let Some(_guard) = BatchGuard::new() else {
+        return Err("A withdrawal batch is being sent".to_string());
+    };
+
+    if batch_in_flight() {
+        return Err("Can't empty the minter wallet while a withdrawal batch is in flight".to_string());
+    }
+
+    if dest == read_config(|config| config.app_ton_address()) {
+        return Err("The destination is the minter wallet itself".to_string());
+    }
+
+    let common_message = CommonMsgInfo::InternalMessage(InternalMessage {
+        ihr_disabled: false,
+        bounce: false,
+        bounced: false,
+        src: TonAddress::NULL,
+        dest,
+        value: BigUint::ZERO,
+        ihr_fee: BigUint::ZERO,
+        fwd_fee: BigUint::ZERO,
+        created_lt: 0,
+        created_at: 0,
+    });
+
+    let transfer_message = TransferMessage::new(common_message)
+        .build()
+        .map_err(|s| s.to_string())?;
+
+    let (_, _, wrapped) = sign_minter_message(vec![transfer_message.to_arc()], SWEEP_SEND_MODE).await?;
+
+    let boc = BagOfCells::from_root(wrapped);
+
+    let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
+
+    let hash = ton_api::send_boc_to_ton(enc, Operation::Withdrawal)
+        .await
+        .map_err(|err| err.to_string())?
+        .hash;
+
+    Ok(hash)
+}
+
+// Periodic run of the withdrawal batcher
+pub async fn send_withdrawals() {
+    match send_withdrawal_batch().await {
+        Ok(Some((ids, hash))) => ic_cdk::println!("Sent {} withdrawals: {}", ids.len(), hash),
+        Ok(None) => {}
+        Err(err) => ic_cdk::println!("Failed to send withdrawals: {}", err),
+    }
+}
+
+struct ResolveClaim(u64);
+
+impl ResolveClaim {
+    fn new(id: u64) -> Result<Self, String> {
+        RESOLVING.with_borrow_mut(|resolving| {
+            if !resolving.insert(id) {
+                return Err(format!("Withdrawal {} is already being resolved", id));
+            }
+            Ok(Self(id))
+        })
+    }
+}
+
+impl Drop for ResolveClaim {
+    fn drop(&mut self) {
+        RESOLVING.with_borrow_mut(|resolving| resolving.remove(&self.0));
+    }
+}
+
+// Settles a signed or sent withdrawal: confirms it if its payout landed, or
+// fails and reimburses it once the payout message can no longer land.
+// Failed withdrawals are reimbursed. Returns whether the withdrawal is resolved.
+pub async fn resolve_withdrawal(id: u64) -> Result<bool, String> {
+    let _claim = ResolveClaim::new(id)?;
+
+    let request = get_request(id).ok_or(format!("Unknown withdrawal {}", id))?;
+
+    let (seqno, expire_at, hash) = match request.status.clone() {
+        WithdrawalStatus::Pending => return Ok(false),
+        WithdrawalStatus::Confirmed { .. } | WithdrawalStatus::Reimbursed { .. } => return Ok(true),
+        WithdrawalStatus::Failed { .. } => {
+            reimburse(&request).await?;
+            return Ok(true);
+        }
+        WithdrawalStatus::Signed { seqno, expire_at, hash } => (seqno, expire_at, hash),
+        WithdrawalStatus::Sent { seqno, expire_at, hash } => (seqno, expire_at, hash),
+    };
+
+    let highload = read_config(|config| config.highload_wallet_code.is_some());
+
+    let app_ton_address = get_ton_address_from_address(&read_config(|config| config.app_ton_address()));
+
+    // Transactions back to the creation of the withdrawal. An expired message
+    // can't land anymore, it did not land if a complete lookup misses it.
+    let (tx_list, lookup_complete) =
+        history::transactions_since(&app_ton_address, nanos_to_seconds(request.created_at) as u64, Operation::Withdrawal).await?;
+
+    let external_tx = tx_list.iter().find(|tx| tx.in_msg.hash == hash);
+
+    // A highload wallet pays out from the `internal_transfer` it sends itself
+    let payout_tx = match external_tx {
+        Some(external_tx) if highload => tx_list
+            .iter()
+            .find(|tx| external_tx.out_msgs.iter().any(|msg| msg.hash == tx.in_msg.hash)),
+        tx => tx,
+    };
+
+    if let Some(tx) = payout_tx {
+        // Jetton payouts go on through the jetton wallet of the minter
+        let trace = match withdrawal_jetton(&request)? {
+            Some(jetton) => jettons::trace_payout(&jetton, tx, id, request.ton_amount).await?,
+            // The external was accepted, but the payout may have failed in the
+            // action phase (e.g. insufficient balance of the minter wallet)
+            None if claim_payout(tx, &request)? => PayoutTrace::Delivered {
+                tx_hash: tx.transaction_id.hash.clone(),
+            },
+            None => PayoutTrace::Failed {
+                reason: format!("No payout of {} to {}", request.ton_amount, request.to_ton_address),
+            },
+        };
+
+        match trace {
+            PayoutTrace::InFlight => return Ok(false),
+            PayoutTrace::Delivered { tx_hash } => {
+                set_status(id, WithdrawalStatus::Confirmed {
+                    hash,
+                    tx_hash: tx_hash.clone(),
+                });
+
+                record_event(EventType::WithdrawalConfirmed { withdrawal_id: id, tx_hash });
+
+                return Ok(true);
+            }
+            PayoutTrace::Failed { reason } => fail_withdrawal(id, format!("Payout not delivered: {}", reason)),
+        }
+    } else if external_tx.is_some() {
+        // The `internal_transfer` was not processed yet
+        return Ok(false);
+    } else {
+        let now = nanos_to_seconds(time());
+
+        if now <= expire_at.saturating_add(WITHDRAWAL_EXPIRY_MARGIN_SECS) {
+            return Ok(false);
+        }
+
+        if highload {
+            let processed = ton_api::is_highload_query_processed(app_ton_address, seqno, Operation::Withdrawal).await?;
+
+            // A processed query id stays recorded by the wallet at least until
+            // `HIGHLOAD_CREATED_AT_LAG_SECS` after the expiry, see `highload::created_at`
+            let conclusive = now < expire_at.saturating_add(HIGHLOAD_CREATED_AT_LAG_SECS);
+
+            if processed || (!conclusive && !lookup_complete) {
+                ic_cdk::println!("Withdrawal {} expired but its query id {} may have been processed", id, seqno);
+                return Ok(false);
+            }
+
+            return settle_expired(request, expire_at).await;
+        }
+
+        let current_seqno = ton_api::get_ton_wallet_info(app_ton_address, Operation::Withdrawal).await?.seqno.unwrap_or(0);
+
+        // If the seqno did not move past the message, it never landed
+        if current_seqno > seqno && !lookup_complete {
+            ic_cdk::println!("Withdrawal {} expired but its seqno {} was used, the payout is unknown", id, seqno);
+            return Ok(false);
+        }
+
+        // The seqno of the batch was not used, it is read from the chain again
+        if current_seqno <= seqno {
+            set_next_seqno(None);
+        }
+
+        return settle_expired(request, expire_at).await;
+    }
+
+    let request = get_request(id).ok_or(format!("Unknown withdrawal {}", id))?;
+    reimburse(&request).await?;
+
+    Ok(true)
+}
+
+// Finds the out message of `tx` that pays the TON withdrawal `request` and
+// records it as its payout: one to its destination with exactly its TON
+// amount, which no other withdrawal claimed. Two withdrawals of a batch to the
+// same address each need their own message.
+pub fn claim_payout(tx: &TonTransaction, request: &WithdrawalRequest) -> Result<bool, String> {
+    let dest = request.to_ton_address.parse::<TonAddress>().map_err(|s| s.to_string())?;
+
+    let payout = tx
+        .out_msgs
+        .iter()
+        .filter(|msg| msg.destination.parse::<TonAddress>().is_ok_and(|address| address == dest))
+        .filter(|msg| msg.value.parse::<u64>().ok() == Some(request.ton_amount))
+        .find(|msg| {
+            CONFIRMED_PAYOUTS
+                .with_borrow(|payouts| payouts.get(&msg.hash))
+                .map_or(true, |withdrawal_id| withdrawal_id == request.id)
+        });
+
+    let Some(payout) = payout else {
+        return Ok(false);
+    };
+
+    CONFIRMED_PAYOUTS.with_borrow_mut(|payouts| payouts.insert(payout.hash.clone(), request.id));
+
+    Ok(true)
+}
+
+// Settles a withdrawal whose payout message expired without landing. A batch
+// no provider accepted is signed again with the next one, a payout that was
+// accepted but never landed is failed and reimbursed.
+async fn settle_expired(request: WithdrawalRequest, expire_at: u32) -> Result<bool, String> {
+    if let WithdrawalStatus::Signed { .. } = request.status {
+        set_status(request.id, WithdrawalStatus::Pending);
+        record_event(EventType::WithdrawalRequeued {
+            withdrawal_id: request.id,
+            reason: format!("Payout message expired at {} without being sent", expire_at),
+        });
+        return Ok(false);
+    }
+
+    fail_withdrawal(request.id, format!("Payout message expired at {}", expire_at));
+
+    let request = get_request(request.id).ok_or(format!("Unknown withdrawal {}", request.id))?;
+    reimburse(&request).await?;
+
+    Ok(true)
+}
+
+// Gives up on delivering a withdrawal whose payout message was never signed or
+// can't land anymore
+pub fn fail_withdrawal(id: u64, reason: String) {
+    set_status(id, WithdrawalStatus::Failed { reason: reason.clone() });
+    record_event(EventType::WithdrawalFailed { withdrawal_id: id, reason });
+}
+
+// Mints the burned ckTON of a failed withdrawal back to the account it was
+// burned from, the burned jettons and the ckTON paid for the attached TON for
+// a jetton withdrawal.
+async fn reimburse(request: &WithdrawalRequest) -> Result<u64, String> {
+    if !matches!(request.status, WithdrawalStatus::Failed { .. }) {
+        return Err(format!("Withdrawal {} has not failed: {:?}", request.id, request.status));
+    }
+
+    let jetton = withdrawal_jetton(request)?;
+
+    let ckton_ledger = read_config(|config| config.ledger_canister);
+
+    let ledger_canister = match &jetton {
+        Some(jetton) => jetton.ledger_canister,
+        None => ckton_ledger,
+    };
+
+    // The gas is minted first and at most once, outside of the ledger's
+    // deduplication window a retry after a failed jetton mint would mint it
+    // again otherwise
+    if let (Some(amount), None) = (request.ckton_gas, request.ckton_gas_block_index) {
+        let block_index = mint_to(ckton_ledger, request.from, amount, format!("reimburse-gas:{}", request.id), request.created_at).await?;
+
+        set_gas_block_index(request.id, block_index);
+
+        record_event(EventType::WithdrawalReimbursed {
+            withdrawal_id: request.id,
+            to: request.from,
+            amount,
+            block_index,
+        });
+    }
+
+    let block_index = mint_to(ledger_canister, request.from, request.amount, format!("reimburse:{}", request.id), request.created_at).await?;
+
+    set_status(request.id, WithdrawalStatus::Reimbursed { block_index });
+
+    match jetton {
+        Some(jetton) => record_event(EventType::JettonReimbursed {
+            symbol: jetton.symbol,
+            withdrawal_id: request.id,
+            to: request.from,
+            amount: request.amount,
+            block_index,
+        }),
+        None => record_event(EventType::WithdrawalReimbursed {
+            withdrawal_id: request.id,
+            to: request.from,
+            amount: request.amount,
+            block_index,
+        }),
+    }
+
+    Ok(block_index)
+}
+
+// Mints `amount` on `ledger_canister` to `to`. The memo and `created_at_time`
+// identify the mint, outside of the ledger's deduplication window only the
+// status prevents a second reimbursement.
+async fn mint_to(ledger_canister: Principal, to: Account, amount: u64, memo: String, created_at: u64) -> Result<u64, String> {
+    mint_deduplicated(|arg| ledger_transfer(ledger_canister, arg), to, amount, memo.into_bytes(), created_at).await
+}
+
+// Periodic check of the unresolved withdrawals
+pub async fn resolve_withdrawals() {
+    // Pending withdrawals are left to the batcher
+    let ids: Vec<u64> = unresolved_with(|status| *status != WithdrawalStatus::Pending, usize::MAX)
+        .map(|request| request.id)
+        .filter(|id| !RESOLVING.with_borrow(|resolving| resolving.contains(id)))
+        .take(MAX_WITHDRAWALS_PER_CHECK)
+        .collect();
+
+    for id in ids {
+        match resolve_withdrawal(id).await {
+            Ok(true) => ic_cdk::println!("Resolved withdrawal {}", id),
+            Ok(false) => {}
+            Err(err) => ic_cdk::println!("Failed to resolve withdrawal {}: {}", id, err),
+        }
+    }
+}
+
+// Deploys the highload minter wallet with an external message that pays out
+// nothing. The wallet address must hold enough TON for the deployment.
+pub async fn deploy_highload_wallet(wallet: HighloadWalletV3) -> Result<String, String> {
+    let ton_address = get_ton_address_from_address(&wallet.address);
+
+    if ton_api::get_ton_wallet_info(ton_address.clone(), Operation::WalletDeployment).await?.account_state == "active" {
+        return Err("Wallet already deployed".to_string());
+    }
+
+    let created_at = highload::created_at(nanos_to_seconds(time()));
+
+    let external = wallet.create_external(vec![], highload::PAYOUT_SEND_MODE, take_query_id(), created_at, true).await?;
+
+    let boc = BagOfCells::from_root(external);
+
+    let enc = BASE64_STANDARD.encode(boc.serialize(true).map_err(|s| s.to_string())?);
+
+    let result = ton_api::send_boc_to_ton(enc, Operation::WalletDeployment).await?;
+
+    PENDING_TASKS.with_borrow_mut(|tasks| {
+        tasks.push_back(PendingTasks::DeployWallet(
+            Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+            ton_address,
+            0,
+        ));
+    });
+
+    Ok(result.hash)
+}